@@ -66,6 +66,21 @@ impl Apath {
         true
     }
 
+    /// Return an error if this apath is not well-formed.
+    ///
+    /// Apaths read from an index might be corrupt or malicious, and one with
+    /// `..` or empty components could point outside the tree, so this should
+    /// be checked before they're used to name files.
+    pub fn check_valid(&self) -> crate::Result<()> {
+        if Apath::is_valid(&self.0) {
+            Ok(())
+        } else {
+            Err(crate::Error::InvalidApath {
+                apath: self.0.clone(),
+            })
+        }
+    }
+
     /// True if self is a parent directory of, or equal to, `a`.
     ///
     /// ```
@@ -90,6 +105,43 @@ impl Apath {
             }
         }
     }
+
+    /// True if self sorts after every apath within `subtree`.
+    ///
+    /// In apath order the entries inside a directory are contiguous, but they
+    /// are not adjacent to the directory itself, so this is not the same as
+    /// comparing to any single apath.
+    ///
+    /// ```
+    /// use conserve::Apath;
+    /// use std::ops::Not;
+    ///
+    /// let subtree = Apath::from("/home/user");
+    /// assert!(Apath::from("/home/zed/file").is_after_subtree(&subtree));
+    /// assert!(Apath::from("/zed/file").is_after_subtree(&subtree));
+    /// // Direct children of /home sort before the contents of /home/user.
+    /// assert!(Apath::from("/home/zed").is_after_subtree(&subtree).not());
+    /// assert!(Apath::from("/home/aaa/file").is_after_subtree(&subtree).not());
+    /// assert!(Apath::from("/home/user/file").is_after_subtree(&subtree).not());
+    /// ```
+    pub fn is_after_subtree(&self, subtree: &Apath) -> bool {
+        if subtree.is_prefix_of(self) {
+            return false;
+        }
+        let mut ait = self.0.split('/').filter(|c| !c.is_empty()).peekable();
+        let mut sit = subtree.0.split('/').filter(|c| !c.is_empty());
+        loop {
+            match (ait.next(), sit.next()) {
+                (Some(ac), Some(sc)) if ac == sc => continue,
+                // At the first differing component: entries within the
+                // subtree are always in a subdirectory at this level, so they
+                // sort after any direct child here, and otherwise by name.
+                (Some(ac), Some(sc)) => return ait.peek().is_some() && ac > sc,
+                // self is an ancestor of the subtree, so sorts before it.
+                _ => return false,
+            }
+        }
+    }
 }
 
 impl FromStr for Apath {
@@ -309,12 +361,32 @@ mod tests {
             "/a/b/../c",
             "../a",
             "/hello\0",
+            "/..",
+            "/a/..",
+            "/a/../../b",
+            "/../../etc/cron.d/x",
+            "/a/./b/",
+            "/a/b/",
+            "a/b",
+            "/a\0/b",
         ];
         for v in invalid_cases.iter() {
             assert!(!Apath::is_valid(v), "{:?} incorrectly marked valid", v);
         }
     }
 
+    #[test]
+    fn check_valid_deserialized_apaths() {
+        // Deserialization doesn't check apaths, so they might be invalid.
+        let apath: Apath = serde_json::from_str("\"/a/../../b\"").unwrap();
+        match apath.check_valid() {
+            Err(crate::Error::InvalidApath { apath }) => assert_eq!(apath, "/a/../../b"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(Apath::from("/a/b").check_valid().is_ok());
+        assert!(Apath::from("/").check_valid().is_ok());
+    }
+
     #[test]
     pub fn valid_and_ordered() {
         let ordered = [
@@ -363,4 +435,34 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn is_after_subtree_agrees_with_order() {
+        let apaths: Vec<Apath> = [
+            "/", "/a", "/b", "/c", "/a/a", "/a/z", "/b/a", "/b/b", "/b/c", "/c/a", "/b/a/a",
+            "/b/b/a", "/b/b/z", "/b/b/a/a", "/b/c/a",
+        ]
+        .iter()
+        .map(|a| Apath::from(*a))
+        .collect();
+        for subtree in &apaths {
+            let contents: Vec<&Apath> = apaths
+                .iter()
+                .filter(|a| subtree.is_prefix_of(a) && *a != subtree)
+                .collect();
+            if contents.is_empty() {
+                continue;
+            }
+            for a in apaths.iter().filter(|a| !subtree.is_prefix_of(a)) {
+                let expected = contents.iter().all(|c| a > c);
+                assert_eq!(
+                    a.is_after_subtree(subtree),
+                    expected,
+                    "{:?}.is_after_subtree({:?})",
+                    a,
+                    subtree
+                );
+            }
+        }
+    }
 }
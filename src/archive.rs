@@ -13,27 +13,31 @@
 
 //! Archives holding backup material.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::ErrorKind;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+use chrono::{DateTime, Utc};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::blockhash::BlockHash;
+use crate::config::ArchiveConfig;
+use crate::crypt::{ArchiveKey, EncryptedTransport, EncryptionHeader};
 use crate::errors::Error;
 use crate::jsonio::{read_json, write_json};
 use crate::kind::Kind;
 use crate::misc::remove_item;
-use crate::stats::ValidateStats;
+use crate::referenced_blocks::measure_band_usage;
+use crate::stats::{BackupStats, BandUsage, BlockReport, SyncStats, ValidateStats};
 use crate::stitch::IterStitchedIndexHunks;
 use crate::transport::local::LocalTransport;
 use crate::transport::{DirEntry, Transport};
 use crate::*;
 
-const HEADER_FILENAME: &str = "CONSERVE";
+pub(crate) const HEADER_FILENAME: &str = "CONSERVE";
 static BLOCK_DIR: &str = "d";
 
 /// An archive holding backup material.
@@ -43,11 +47,63 @@ pub struct Archive {
     block_dir: BlockDir,
 
     transport: Box<dyn Transport>,
+
+    /// Master key, if the archive is encrypted.
+    key: Option<Arc<ArchiveKey>>,
+
+    /// Default options stored in the archive.
+    config: ArchiveConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ArchiveHeader {
     conserve_archive_version: String,
+
+    /// Present if the archive is encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<EncryptionHeader>,
+}
+
+/// Parse a `major.minor` archive version.
+fn parse_archive_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor))
+}
+
+/// True if this program can read and write archives with this header version.
+fn archive_version_supported(version: &str) -> bool {
+    let min = parse_archive_version(MIN_ARCHIVE_VERSION).unwrap();
+    let max = parse_archive_version(ARCHIVE_VERSION).unwrap();
+    match parse_archive_version(version) {
+        Some(v) => v >= min && v <= max,
+        None => false,
+    }
+}
+
+/// Describe the range of supported archive versions, for error messages.
+fn supported_archive_versions() -> String {
+    if MIN_ARCHIVE_VERSION == ARCHIVE_VERSION {
+        ARCHIVE_VERSION.to_owned()
+    } else {
+        format!("{} to {}", MIN_ARCHIVE_VERSION, ARCHIVE_VERSION)
+    }
+}
+
+fn content_transport(
+    transport: &dyn Transport,
+    key: &Option<Arc<ArchiveKey>>,
+    relpath: &str,
+) -> Box<dyn Transport> {
+    let sub_transport = transport.sub_transport(relpath);
+    match key {
+        Some(key) => Box::new(EncryptedTransport::new(sub_transport, key.clone(), relpath)),
+        None => sub_transport,
+    }
 }
 
 #[derive(Default, Debug)]
@@ -57,32 +113,143 @@ pub struct DeleteOptions {
     pub no_gc: bool,
 }
 
+/// Options for [Archive::validate].
+#[derive(Debug, Clone)]
+pub struct ValidateOptions {
+    /// The maximum number of threads to use, or by default one per CPU.
+    ///
+    /// On spinning disks, fewer threads may be faster, since parallel reads
+    /// cause more seeking.
+    pub threads: Option<usize>,
+    /// Told about each problem as it's found, or by default the UI's
+    /// problem sink.
+    pub problems: Option<Arc<dyn ProblemSink>>,
+    /// Check only these bands, and only the blocks they reference, or by
+    /// default the whole archive.
+    ///
+    /// Blocks referenced by no selected band aren't read, and unreferenced
+    /// blocks aren't counted.
+    pub bands: Option<Vec<BandId>>,
+    /// Check that the content of each block read matches its hash. If
+    /// false, blocks are only decompressed to find their length.
+    ///
+    /// True by default.
+    pub check_block_hashes: bool,
+    /// Don't read blocks at all, but only check that the blocks referenced
+    /// are present.
+    pub quick: bool,
+}
+
+impl Default for ValidateOptions {
+    fn default() -> ValidateOptions {
+        ValidateOptions {
+            threads: None,
+            problems: None,
+            bands: None,
+            check_block_hashes: true,
+            quick: false,
+        }
+    }
+}
+
+/// Options for creating a new archive.
+///
+/// Everything except encryption is stored in the archive's config file, as
+/// defaults for later operations.
+#[derive(Debug, Default, Clone)]
+pub struct InitOptions {
+    /// Glob patterns to exclude from every backup into the archive.
+    pub excludes: Vec<String>,
+    /// Skip the contents of directories marked as caches.
+    pub exclude_caches: bool,
+    /// Serialization of the index of new backups, if not the default.
+    pub index_format: Option<IndexFormat>,
+}
+
 impl Archive {
     /// Make a new archive in a local direcotry.
     pub fn create_path(path: &Path) -> Result<Archive> {
         Archive::create(Box::new(LocalTransport::new(path)))
     }
 
+    /// Make a new encrypted archive in a local directory.
+    pub fn create_path_encrypted(path: &Path, secret: &Secret) -> Result<Archive> {
+        Archive::create_encrypted(Box::new(LocalTransport::new(path)), secret)
+    }
+
+    /// Make a new archive in a local directory, encrypted if a `secret` is
+    /// given, with defaults from `options` stored in its config.
+    pub fn create_path_with_options(
+        path: &Path,
+        secret: Option<&Secret>,
+        options: &InitOptions,
+    ) -> Result<Archive> {
+        Archive::create_with_options(Box::new(LocalTransport::new(path)), secret, options)
+    }
+
     /// Make a new archive in a new directory accessed by a Transport.
     pub fn create(transport: Box<dyn Transport>) -> Result<Archive> {
+        Archive::create_with_options(transport, None, &InitOptions::default())
+    }
+
+    /// Make a new archive whose blocks and indexes are encrypted by a key
+    /// derived from `secret`.
+    pub fn create_encrypted(transport: Box<dyn Transport>, secret: &Secret) -> Result<Archive> {
+        Archive::create_with_options(transport, Some(secret), &InitOptions::default())
+    }
+
+    /// Make a new archive, encrypted if a `secret` is given, with defaults
+    /// from `options` stored in its config.
+    ///
+    /// The directory must be empty or not yet exist. If it already holds an
+    /// archive this fails with `Error::ArchiveAlreadyExists`, and if it holds
+    /// anything else with `Error::NewArchiveDirectoryNotEmpty`, without
+    /// writing anything.
+    pub fn create_with_options(
+        transport: Box<dyn Transport>,
+        secret: Option<&Secret>,
+        options: &InitOptions,
+    ) -> Result<Archive> {
         transport
             .create_dir("")
             .map_err(|source| Error::CreateArchiveDirectory { source })?;
         let names = transport.list_dir_names("").map_err(Error::from)?;
-        if !names.files.is_empty() || !names.dirs.is_empty() {
+        if names.files.iter().any(|name| name == HEADER_FILENAME) {
+            return Err(Error::ArchiveAlreadyExists);
+        } else if !names.files.is_empty() || !names.dirs.is_empty() {
             return Err(Error::NewArchiveDirectoryNotEmpty);
         }
-        let block_dir = BlockDir::create(transport.sub_transport(BLOCK_DIR))?;
+        let (key, encryption) = match secret {
+            Some(secret) => {
+                let (key, encryption) = ArchiveKey::generate(secret)?;
+                (Some(Arc::new(key)), Some(encryption))
+            }
+            None => (None, None),
+        };
+        let block_dir = BlockDir::create(content_transport(transport.as_ref(), &key, BLOCK_DIR))?;
+        let config = ArchiveConfig {
+            excludes: options.excludes.clone(),
+            exclude_caches: options.exclude_caches,
+            index_format: options.index_format,
+        };
+        if config != ArchiveConfig::default() {
+            config.write(&transport)?;
+        }
+        // The header is written last, so that an interrupted init doesn't
+        // leave something that looks like a usable archive.
         write_json(
             &transport,
             HEADER_FILENAME,
             &ArchiveHeader {
                 conserve_archive_version: String::from(ARCHIVE_VERSION),
+                encryption,
             },
         )?;
         Ok(Archive {
             block_dir,
             transport,
+            key,
+            config,
         })
     }
 
@@ -93,7 +260,24 @@ impl Archive {
         Archive::open(Box::new(LocalTransport::new(path)))
     }
 
+    /// Open an existing archive, which may be encrypted, in a local directory.
+    pub fn open_path_with_secret(path: &Path, secret: Option<&Secret>) -> Result<Archive> {
+        Archive::open_with_secret(Box::new(LocalTransport::new(path)), secret)
+    }
+
     pub fn open(transport: Box<dyn Transport>) -> Result<Archive> {
+        Archive::open_with_secret(transport, None)
+    }
+
+    /// Open an existing archive, using `secret` to decrypt it if it's encrypted.
+    ///
+    /// The secret is ignored if the archive is not encrypted. Opening an encrypted
+    /// archive fails with `Error::ArchiveEncrypted` if no secret is given, or with
+    /// `Error::WrongPassphrase` if it doesn't match.
+    pub fn open_with_secret(
+        transport: Box<dyn Transport>,
+        secret: Option<&Secret>,
+    ) -> Result<Archive> {
         let header: ArchiveHeader =
             read_json(&transport, HEADER_FILENAME).map_err(|err| match err {
                 Error::IOError { source } if source.kind() == ErrorKind::NotFound => {
@@ -102,18 +286,68 @@ impl Archive {
                 Error::IOError { source } => Error::ReadArchiveHeader { source },
                 other => other,
             })?;
-        if header.conserve_archive_version != ARCHIVE_VERSION {
+        if !archive_version_supported(&header.conserve_archive_version) {
             return Err(Error::UnsupportedArchiveVersion {
-                version: header.conserve_archive_version,
+                found: header.conserve_archive_version,
+                supported: supported_archive_versions(),
             });
         }
-        let block_dir = BlockDir::open(transport.sub_transport(BLOCK_DIR));
+        let key = match (&header.encryption, secret) {
+            (None, _) => None,
+            (Some(_), None) => return Err(Error::ArchiveEncrypted),
+            (Some(encryption), Some(secret)) => {
+                Some(Arc::new(ArchiveKey::unwrap(encryption, secret)?))
+            }
+        };
+        let block_dir = BlockDir::open(content_transport(transport.as_ref(), &key, BLOCK_DIR));
+        let config = ArchiveConfig::read(&transport)?;
         Ok(Archive {
             block_dir,
             transport,
+            key,
+            config,
         })
     }
 
+    /// Rewrite the archive header to mark it as the current version.
+    ///
+    /// Returns true if the header was (or, in a dry run, would be) changed.
+    pub(crate) fn upgrade_header(&self, dry_run: bool) -> Result<bool> {
+        let mut header: ArchiveHeader = read_json(&self.transport, HEADER_FILENAME)?;
+        if header.conserve_archive_version == ARCHIVE_VERSION {
+            return Ok(false);
+        }
+        header.conserve_archive_version = String::from(ARCHIVE_VERSION);
+        if !dry_run {
+            write_json(&self.transport, HEADER_FILENAME, &header)?;
+        }
+        Ok(true)
+    }
+
+    /// Return the default options stored in this archive.
+    pub fn config(&self) -> &ArchiveConfig {
+        &self.config
+    }
+
+    /// Store new default options in this archive.
+    pub fn set_config(&mut self, config: ArchiveConfig) -> Result<()> {
+        config.write(&self.transport)?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// True if the archive's blocks and indexes are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Return a transport for a subdirectory holding encrypted content, such as
+    /// an index directory: if the archive is encrypted, files are encrypted and
+    /// decrypted as they're written and read.
+    pub(crate) fn content_transport(&self, relpath: &str) -> Box<dyn Transport> {
+        content_transport(self.transport.as_ref(), &self.key, relpath)
+    }
+
     pub fn block_dir(&self) -> &BlockDir {
         &self.block_dir
     }
@@ -139,29 +373,149 @@ impl Archive {
     }
 
     /// Returns a vector of band ids, in sorted order from first to last.
-    pub fn list_band_ids(&self) -> Result<Vec<BandId>> {
+    ///
+    /// This is part of the stable API.
+    pub fn band_ids(&self) -> Result<Vec<BandId>> {
         let mut band_ids: Vec<BandId> = self.iter_band_ids_unsorted()?.collect();
         band_ids.sort_unstable();
         Ok(band_ids)
     }
 
+    /// Returns a vector of band ids, in sorted order from first to last.
+    #[deprecated(since = "0.6.11", note = "Use Archive::band_ids")]
+    pub fn list_band_ids(&self) -> Result<Vec<BandId>> {
+        self.band_ids()
+    }
+
     pub(crate) fn transport(&self) -> &dyn Transport {
         self.transport.as_ref()
     }
 
-    pub fn resolve_band_id(&self, band_selection: BandSelectionPolicy) -> Result<BandId> {
+    /// Find the id of the band selected by a policy.
+    ///
+    /// This is the one place band selections are resolved, so that restore,
+    /// ls, diff, and the other commands explain a missing band the same way.
+    ///
+    /// `BandSelectionPolicy::Latest` selects the newest band even if it is
+    /// incomplete, in which case a warning is shown, because its tree may be
+    /// partial.
+    pub fn resolve_band(&self, band_selection: &BandSelectionPolicy) -> Result<BandId> {
         match band_selection {
-            BandSelectionPolicy::LatestClosed => self
-                .last_complete_band()?
-                .map(|band| band.id().clone())
-                .ok_or(Error::ArchiveEmpty),
-            BandSelectionPolicy::Specified(band_id) => Ok(band_id),
-            BandSelectionPolicy::Latest => self.last_band_id()?.ok_or(Error::ArchiveEmpty),
+            BandSelectionPolicy::LatestClosed => {
+                if let Some(band) = self.last_complete_band()? {
+                    Ok(band.id().clone())
+                } else if let Some(newest) = self.last_band_id()? {
+                    Err(Error::NoCompleteBands { newest })
+                } else {
+                    Err(Error::ArchiveEmpty)
+                }
+            }
+            BandSelectionPolicy::Specified(band_id) => {
+                if self.band_exists(band_id)? {
+                    Ok(band_id.clone())
+                } else {
+                    Err(Error::BandNotFound {
+                        band_id: band_id.clone(),
+                        available: self.band_ids()?,
+                    })
+                }
+            }
+            BandSelectionPolicy::Latest => {
+                let band_id = self.last_band_id()?.ok_or(Error::ArchiveEmpty)?;
+                if !self.band_is_closed(&band_id)? {
+                    ui::report_problem(Problem::new(
+                        ProblemKind::IncompleteBand,
+                        None,
+                        format!(
+                            "Backup {} is incomplete, so its tree may be partial",
+                            band_id
+                        ),
+                    ));
+                }
+                Ok(band_id)
+            }
+            BandSelectionPolicy::Tagged(tag) => self.find_tagged_band(tag),
+            BandSelectionPolicy::LatestClosedBefore(cutoff) => {
+                let mut infos = Vec::new();
+                for band_id in self.band_ids()? {
+                    infos.push(Band::open(self, &band_id)?.get_info()?);
+                }
+                latest_closed_before(infos, *cutoff).ok_or(Error::NoBandBefore { cutoff: *cutoff })
+            }
+        }
+    }
+
+    /// Return the id of the most recent band carrying a tag.
+    fn find_tagged_band(&self, tag: &str) -> Result<BandId> {
+        let mut available: Vec<String> = Vec::new();
+        for band_id in self.band_ids()?.into_iter().rev() {
+            let tags = Band::open(self, &band_id)?.tags()?;
+            if tags.iter().any(|t| t == tag) {
+                return Ok(band_id);
+            }
+            for t in tags {
+                if !available.contains(&t) {
+                    available.push(t);
+                }
+            }
         }
+        available.sort();
+        Err(Error::TagNotFound {
+            tag: tag.to_owned(),
+            available,
+        })
+    }
+
+    /// Add and remove tags on an existing band.
+    ///
+    /// Returns the tags the band has afterwards.
+    pub fn edit_band_tags(
+        &self,
+        band_id: &BandId,
+        add: &[String],
+        remove: &[String],
+        break_lock: bool,
+    ) -> Result<Vec<String>> {
+        let _lock = self.lock(break_lock)?;
+        Band::open(self, band_id)?.edit_tags(add, remove)
+    }
+
+    /// Back up a source directory into a new band in this archive.
+    ///
+    /// Returns statistics about what was copied.
+    ///
+    /// This is part of the stable API.
+    pub fn backup(&self, source: &Path, options: &BackupOptions) -> Result<BackupStats> {
+        let source = LiveTree::open(source)?
+            .with_exclude_caches(options.exclude_caches)
+            .with_exclude_other_archives(options.exclude_other_archives)
+            .with_one_file_system(options.one_file_system)
+            .with_problems(ui::problem_sink_or(&options.problems));
+        crate::backup::backup_tree(self, &source, options)
     }
 
+    /// Back up several source directories into one new band, each under the
+    /// apath given by its root.
+    ///
+    /// Returns statistics about what was copied.
+    pub fn backup_roots(
+        &self,
+        roots: Vec<SourceRoot>,
+        options: &BackupOptions,
+    ) -> Result<BackupStats> {
+        let source = LiveTree::open_roots(roots)?
+            .with_exclude_caches(options.exclude_caches)
+            .with_exclude_other_archives(options.exclude_other_archives)
+            .with_one_file_system(options.one_file_system)
+            .with_problems(ui::problem_sink_or(&options.problems));
+        crate::backup::backup_tree(self, &source, options)
+    }
+
+    /// Open the version of the tree selected by `band_selection`.
+    ///
+    /// This is part of the stable API.
     pub fn open_stored_tree(&self, band_selection: BandSelectionPolicy) -> Result<StoredTree> {
-        StoredTree::open(self, &self.resolve_band_id(band_selection)?)
+        StoredTree::open(self, &self.resolve_band(&band_selection)?)
     }
 
     /// Return an iterator of valid band ids in this archive, in arbitrary order.
@@ -188,7 +542,7 @@ impl Archive {
 
     /// Return the last completely-written band id, if any.
     pub fn last_complete_band(&self) -> Result<Option<Band>> {
-        for id in self.list_band_ids()?.iter().rev() {
+        for id in self.band_ids()?.iter().rev() {
             let b = Band::open(self, &id)?;
             if b.is_closed()? {
                 return Ok(Some(b));
@@ -197,37 +551,123 @@ impl Archive {
         Ok(None)
     }
 
-    /// Returns all blocks referenced by all bands.
-    ///
-    /// Shows a progress bar as they're collected.
-    pub fn referenced_blocks(&self) -> Result<HashSet<BlockHash>> {
-        self.iter_referenced_blocks().map(Iterator::collect)
+    /// Measure the storage used by the archive's blocks, and the size of the
+    /// latest complete backup.
+    pub fn summary(&self) -> Result<ArchiveSummary> {
+        let mut summary = ArchiveSummary::default();
+        for hash in self.iter_present_blocks()? {
+            summary.block_count += 1;
+            summary.compressed_block_bytes += self.block_dir.compressed_size(&hash)?;
+        }
+        if let Some(band) = self.last_complete_band()? {
+            let band_id = band.id().clone();
+            summary.latest_logical_bytes = self
+                .open_stored_tree(BandSelectionPolicy::Specified(band_id.clone()))?
+                .size(None)?
+                .file_bytes;
+            summary.latest_band = Some(band_id);
+        }
+        Ok(summary)
     }
 
-    /// Iterate all blocks referenced by all bands.
-    ///
-    /// The iterator returns repeatedly-referenced blocks repeatedly, without deduplicating.
+    /// Measure how much block storage each band uses by itself, and how much
+    /// it shares with other bands.
     ///
-    /// This shows a progress bar as indexes are iterated.
-    fn iter_referenced_blocks(&self) -> Result<impl Iterator<Item = BlockHash>> {
-        let archive = self.clone();
-        let mut progress_bar = ProgressBar::new();
-        progress_bar.set_phase("Find referenced blocks...".to_owned());
-        let band_ids = self.list_band_ids()?;
-        let num_bands = band_ids.len();
-        Ok(band_ids
+    /// The results are cached in each band, and reused until bands are added
+    /// to or deleted from the archive. They're not cached while any band is
+    /// incomplete, since its index may still grow, or when the archive can't
+    /// be locked, and failing to write the cache is not an error.
+    pub fn band_usage(&self) -> Result<BTreeMap<BandId, BandUsage>> {
+        let band_ids = self.band_ids()?;
+        let cache_key: Vec<String> = band_ids.iter().map(BandId::to_string).collect();
+        let bands = band_ids
+            .iter()
+            .map(|band_id| Band::open(self, band_id))
+            .collect::<Result<Vec<Band>>>()?;
+        let cached = bands
+            .iter()
+            .map(|band| band.read_usage(&cache_key))
+            .collect::<Result<Vec<Option<BandUsage>>>>()?
             .into_iter()
-            .enumerate()
-            .inspect(move |(i, _)| progress_bar.set_fraction(*i, num_bands))
-            .map(move |(_i, band_id)| Band::open(&archive, &band_id).expect("Failed to open band"))
-            .flat_map(|band| band.iter_entries())
-            .flat_map(|entry| entry.addrs)
-            .map(|addr| addr.hash))
+            .collect::<Option<Vec<BandUsage>>>();
+        let usage = match cached {
+            Some(usage) => usage,
+            None => {
+                let usage = measure_band_usage(self, &band_ids)?;
+                let mut all_closed = true;
+                for band in &bands {
+                    all_closed &= band.is_closed()?;
+                }
+                if all_closed {
+                    // The cache is only an optimization, so it's not written
+                    // if the archive is locked or read-only.
+                    let _ = self.write_usage_cache(&bands, &cache_key, &usage);
+                }
+                usage
+            }
+        };
+        Ok(band_ids.into_iter().zip(usage).collect())
+    }
+
+    /// Cache band usage measured when the archive held the bands in
+    /// `cache_key`, unless bands were added or deleted since.
+    fn write_usage_cache(
+        &self,
+        bands: &[Band],
+        cache_key: &[String],
+        usage: &[BandUsage],
+    ) -> Result<()> {
+        let _lock = ArchiveLock::acquire(self)?;
+        let current_key: Vec<String> = self.band_ids()?.iter().map(BandId::to_string).collect();
+        if current_key != cache_key {
+            return Ok(());
+        }
+        for (band, band_usage) in bands.iter().zip(usage) {
+            band.write_usage(cache_key, band_usage)?;
+        }
+        Ok(())
+    }
+
+    /// Describe which blocks are used by which bands, which are unreferenced,
+    /// and what other files are in the blockdir, without changing anything.
+    pub fn block_report(&self) -> Result<BlockReport> {
+        // As in gc, list present blocks before finding references.
+        let present: HashSet<BlockHash> = self.iter_present_blocks()?.collect();
+        let referenced = ReferencedBlocks::scan(self)?;
+        let mut unreferenced_blocks: Vec<BlockHash> = present
+            .iter()
+            .filter(|hash| !referenced.contains(hash))
+            .cloned()
+            .collect();
+        unreferenced_blocks.sort();
+        let reclaimable_bytes = unreferenced_blocks
+            .par_iter()
+            .map(|hash| self.block_dir.compressed_size(hash).unwrap_or_default())
+            .sum();
+        Ok(BlockReport {
+            present_blocks: present.len(),
+            referenced_blocks: referenced.len(),
+            missing_blocks: referenced
+                .hashes()
+                .filter(|hash| !present.contains(hash))
+                .count(),
+            unreferenced_blocks,
+            reclaimable_bytes,
+            bands_per_block: referenced.histogram(),
+            unexpected_files: self.block_dir.unexpected_files()?,
+        })
+    }
+
+    /// Returns all blocks referenced by all bands.
+    ///
+    /// Shows a progress bar as they're collected.
+    pub fn referenced_blocks(&self) -> Result<HashSet<BlockHash>> {
+        Ok(ReferencedBlocks::scan(self)?.hashes().cloned().collect())
     }
 
     /// Returns an iterator of blocks that are present and referenced by no index.
     pub fn unreferenced_blocks(&self) -> Result<impl Iterator<Item = BlockHash>> {
-        let referenced = self.referenced_blocks()?;
+        let referenced = ReferencedBlocks::scan(self)?;
         Ok(self
             .iter_present_blocks()?
             .filter(move |hash| !referenced.contains(hash)))
@@ -242,8 +682,24 @@ impl Archive {
             .inspect(move |_| progress_bar.increment_work_done(1)))
     }
 
+    /// Take an exclusive lock for writing to this archive, or break an existing
+    /// lock if `break_lock` is true.
+    pub fn lock(&self, break_lock: bool) -> Result<ArchiveLock> {
+        if break_lock {
+            ArchiveLock::break_lock(self)
+        } else {
+            ArchiveLock::acquire(self)
+        }
+    }
+
     /// Delete unreferenced blocks.
     pub fn delete_unreferenced(&self, options: &DeleteOptions) -> Result<DeleteStats> {
+        let _lock = self.lock(options.break_lock)?;
+        self.delete_unreferenced_locked(options)
+    }
+
+    /// Delete unreferenced blocks, while the caller holds the archive lock.
+    fn delete_unreferenced_locked(&self, options: &DeleteOptions) -> Result<DeleteStats> {
         let block_dir = self.block_dir();
         let mut stats = DeleteStats::default();
         let start = Instant::now();
@@ -253,14 +709,11 @@ impl Archive {
             gc_lock::GarbageCollectionLock::new(self)?
         };
 
+        // Find present blocks before referenced blocks, so that blocks written by
+        // any concurrent backup are not counted as unreferenced.
         let mut blocks: HashSet<BlockHash> = self.iter_present_blocks()?.collect();
-        for block_hash in self.iter_referenced_blocks()? {
-            // NOTE: We could potentially notice here blocks that are missing: referenced but
-            // not present. However, because the reference iter can contain duplicates,
-            // it would require keeping another set. On the whole that seems better left
-            // to validation.
-            blocks.remove(&block_hash);
-        }
+        let referenced = ReferencedBlocks::scan(self)?;
+        blocks.retain(|hash| !referenced.contains(hash));
         stats.unreferenced_block_count = blocks.len();
 
         let mut progress_bar = ProgressBar::new();
@@ -302,64 +755,301 @@ impl Archive {
     ) -> Result<DeleteStats> {
         let mut stats = DeleteStats::default();
         let start = Instant::now();
+        let _lock = self.lock(options.break_lock)?;
         for band_id in band_ids {
             if !options.dry_run {
                 Band::delete(self, band_id).map(|()| stats.deleted_band_count += 1)?
             }
         }
         if !options.no_gc {
-            stats += self.delete_unreferenced(options)?;
+            stats += self.delete_unreferenced_locked(options)?;
         }
         stats.elapsed = start.elapsed();
         Ok(stats)
     }
 
-    pub fn validate(&self) -> Result<ValidateStats> {
-        let mut stats = self.validate_archive_dir()?;
-        ui::println("Check blockdir...");
-        let block_lengths: HashMap<BlockHash, usize> = self.block_dir.validate(&mut stats)?;
+    /// Copy one complete band, and the blocks it references, into another archive.
+    ///
+    /// The band keeps the same id in the destination. If the destination already
+    /// has a complete band with that id and the same start time, it's taken to be
+    /// a copy from an earlier sync and nothing is copied; an incomplete copy left by
+    /// an interrupted sync is replaced. Any other band with that id is a conflict,
+    /// and `Error::BandConflict` is returned.
+    ///
+    /// Blocks already present in the destination are not copied again.
+    pub fn copy_band_to(&self, band_id: &BandId, dest: &Archive) -> Result<SyncStats> {
+        let _lock = dest.lock(false)?;
+        self.copy_band_to_locked(band_id, dest)
+    }
+
+    /// Copy a band into another archive, while the caller holds the destination's lock.
+    pub(crate) fn copy_band_to_locked(
+        &self,
+        band_id: &BandId,
+        dest: &Archive,
+    ) -> Result<SyncStats> {
+        let start = Instant::now();
+        let mut stats = SyncStats::default();
+        let band = Band::open(self, band_id)?;
+        let info = band.get_info()?;
+        if !info.is_closed {
+            return Err(Error::BandIncomplete {
+                band_id: band_id.clone(),
+            });
+        }
+        if dest.band_exists(band_id)? {
+            let dest_info = Band::open(dest, band_id)?.get_info()?;
+            if dest_info.start_time != info.start_time {
+                return Err(Error::BandConflict {
+                    band_id: band_id.clone(),
+                });
+            } else if dest_info.is_closed {
+                stats.bands_already_present += 1;
+                stats.elapsed = start.elapsed();
+                return Ok(stats);
+            }
+            Band::delete(dest, band_id)?;
+        }
 
-        ui::println("Check indexes...");
-        let band_ids = self.list_band_ids()?;
-        let num_bands = band_ids.len();
+        stats.index_hunks_copied += band.copy_head_and_index_to(dest)?;
 
         let mut progress_bar = ProgressBar::new();
-        progress_bar.set_phase("Check index".to_owned());
-        progress_bar.set_total_work(num_bands);
+        progress_bar.set_phase(format!("Copy blocks for {}", band_id));
+        let mut seen: HashSet<BlockHash> = HashSet::new();
+        let mut hunks = band.index().iter_hunks();
+        for hash in hunks
+            .by_ref()
+            .flatten()
+            .flat_map(|entry| entry.addrs)
+            .map(|addr| addr.hash)
+        {
+            if !seen.insert(hash.clone()) {
+                continue;
+            }
+            if dest.block_dir.contains(&hash)? {
+                stats.blocks_already_present += 1;
+            } else {
+                stats.copied_block_bytes += self.block_dir.copy_block_to(&hash, &dest.block_dir)?;
+                stats.blocks_copied += 1;
+            }
+            progress_bar.increment_work_done(1);
+        }
+        if let Some(err) = hunks.take_error() {
+            return Err(err);
+        }
+
+        // Only mark the band complete once all its blocks are present.
+        band.copy_tail_to(dest)?;
+        stats.bands_copied += 1;
+        stats.elapsed = start.elapsed();
+        Ok(stats)
+    }
+
+    /// Check the archive for damage or inconsistency.
+    ///
+    /// Block contents and band indexes are checked concurrently, on up to
+    /// `options.threads` threads.
+    pub fn validate(&self, options: &ValidateOptions) -> Result<ValidateStats> {
+        let mut pool = rayon::ThreadPoolBuilder::new();
+        if let Some(threads) = options.threads {
+            pool = pool.num_threads(threads);
+        }
+        pool.build()
+            .map_err(|source| Error::StartThreads { source })?
+            .install(|| {
+                let problems = ui::problem_sink_or(&options.problems);
+                self.validate_in_pool(options, problems.as_ref())
+            })
+    }
+
+    fn validate_in_pool(
+        &self,
+        options: &ValidateOptions,
+        problems: &dyn ProblemSink,
+    ) -> Result<ValidateStats> {
+        let all_band_ids = self.band_ids()?;
+        let band_ids = match &options.bands {
+            None => all_band_ids,
+            Some(selected) => {
+                if let Some(band_id) = selected.iter().find(|b| !all_band_ids.contains(b)) {
+                    return Err(Error::BandNotFound {
+                        band_id: band_id.clone(),
+                        available: all_band_ids,
+                    });
+                }
+                let mut selected = selected.clone();
+                selected.sort_unstable();
+                selected.dedup();
+                selected
+            }
+        };
+        let mut stats = self.validate_archive_dir(problems)?;
+        stats.skewed_bands = self.check_band_times(&band_ids, problems);
+
+        ui::println("Check blocks and indexes...");
+        let mut progress_bar = ProgressBar::new();
+        progress_bar.set_phase("Check blocks and indexes".to_owned());
+        progress_bar.set_total_work(band_ids.len());
         let progress_bar_mutex = Mutex::new(progress_bar);
+        let check_blocks = |blocks: Vec<BlockHash>| -> Result<_> {
+            let mut stats = ValidateStats::default();
+            if options.quick {
+                // Present blocks are assumed to be long enough.
+                let block_lengths = blocks.into_iter().map(|hash| (hash, usize::MAX)).collect();
+                return Ok((stats, block_lengths));
+            }
+            self.block_dir
+                .validate_blocks(
+                    blocks,
+                    options.check_block_hashes,
+                    &mut stats,
+                    &progress_bar_mutex,
+                )
+                .map(|block_lengths| (stats, block_lengths))
+        };
+        let (block_result, (band_stats, referenced, extents)) = if options.bands.is_none() {
+            rayon::join(
+                || check_blocks(self.block_dir.block_names()?.collect()),
+                || self.validate_band_indexes(&band_ids, &progress_bar_mutex, problems),
+            )
+        } else {
+            // Only the blocks referenced by the selected bands are checked, so
+            // they're found first.
+            let band_result = self.validate_band_indexes(&band_ids, &progress_bar_mutex, problems);
+            let present: HashSet<BlockHash> = self.block_dir.block_names()?.collect();
+            let blocks = band_result
+                .2
+                .keys()
+                .filter(|hash| present.contains(hash))
+                .cloned()
+                .collect();
+            (check_blocks(blocks), band_result)
+        };
+        drop(progress_bar_mutex);
+        let (block_stats, block_lengths) = block_result?;
+        stats += block_stats;
+        stats += band_stats;
+
+        // Addresses are only checked one by one if some block is missing or
+        // too short, which means reading the indexes again, but damage
+        // should be rare.
+        let damaged = extents.iter().any(|(hash, end)| {
+            block_lengths
+                .get(hash)
+                .is_none_or(|block_len| *end > *block_len as u64)
+        });
+        if damaged {
+            ui::println("Find damaged addresses...");
+            stats += band_ids
+                .into_par_iter()
+                .map(|band_id| {
+                    let mut stats = ValidateStats::default();
+                    if let Ok(st) = self.open_stored_tree(BandSelectionPolicy::Specified(band_id)) {
+                        if st.validate_addresses(&block_lengths, &mut stats).is_err() {
+                            stats.tree_validate_errors += 1
+                        }
+                    }
+                    stats
+                })
+                .reduce(ValidateStats::default, |a, b| a + b);
+        }
+
+        // Unreferenced blocks are not damage: they're reclaimed by gc. They
+        // can only be counted when every band was checked.
+        if options.bands.is_none() {
+            stats.unreferenced_block_count = block_lengths
+                .keys()
+                .filter(|hash| !referenced.contains(hash))
+                .count();
+        }
 
-        stats += band_ids
-            .into_par_iter()
+        Ok(stats)
+    }
+
+    /// Check the metadata and index of each band, and find which blocks they
+    /// reference and how far into each block they read.
+    fn validate_band_indexes(
+        &self,
+        band_ids: &[BandId],
+        progress_bar_mutex: &Mutex<ProgressBar>,
+        problems: &dyn ProblemSink,
+    ) -> (ValidateStats, ReferencedBlocks, HashMap<BlockHash, u64>) {
+        band_ids
+            .par_iter()
             .map(|band_id| {
                 let mut stats = ValidateStats::default();
+                let mut referenced = ReferencedBlocks::new();
+                let mut extents = HashMap::new();
 
-                if let Ok(b) = Band::open(self, &band_id) {
-                    if b.validate(&mut stats).is_err() {
+                if let Ok(b) = Band::open(self, band_id) {
+                    if b.validate(&mut stats, problems).is_err() {
                         stats.band_metadata_problems += 1;
                     }
                 } else {
                     stats.band_open_errors += 1;
                 }
 
-                if let Ok(st) = self.open_stored_tree(BandSelectionPolicy::Specified(band_id)) {
-                    if st.validate(&block_lengths, &mut stats).is_err() {
-                        stats.tree_validate_errors += 1
+                if let Ok(st) =
+                    self.open_stored_tree(BandSelectionPolicy::Specified(band_id.clone()))
+                {
+                    match st.validate(&mut stats) {
+                        Ok(band_extents) => {
+                            referenced.add_band(band_extents.keys().cloned());
+                            extents = band_extents;
+                        }
+                        Err(_) => stats.tree_validate_errors += 1,
                     }
                 } else {
                     stats.tree_open_errors += 1
                 }
 
-                if let Ok(mut progress_bar_lock) = progress_bar_mutex.lock() {
-                    progress_bar_lock.increment_work_done(1);
+                if let Ok(mut progress_bar) = progress_bar_mutex.lock() {
+                    progress_bar.increment_work_done(1);
                 }
-                stats
+                (stats, referenced, extents)
             })
-            .reduce(ValidateStats::default, |a, b| a + b);
+            .reduce(
+                || {
+                    (
+                        ValidateStats::default(),
+                        ReferencedBlocks::new(),
+                        HashMap::new(),
+                    )
+                },
+                |(a_stats, a_refs, mut a_extents), (b_stats, b_refs, b_extents)| {
+                    for (hash, end) in b_extents {
+                        let a_end = a_extents.entry(hash).or_default();
+                        *a_end = (*a_end).max(end);
+                    }
+                    (a_stats + b_stats, a_refs + b_refs, a_extents)
+                },
+            )
+    }
 
-        Ok(stats)
+    /// Report bands that started earlier than some lower-numbered band,
+    /// probably because the clock was wrong, and return how many there are.
+    ///
+    /// Bands that can't be opened are skipped here, and counted when their
+    /// indexes are checked.
+    fn check_band_times(&self, band_ids: &[BandId], problems: &dyn ProblemSink) -> usize {
+        let infos = band_ids
+            .iter()
+            .filter_map(|band_id| Band::open(self, band_id).and_then(|b| b.get_info()).ok());
+        let skewed = out_of_order_bands(infos);
+        for (band, earlier) in &skewed {
+            problems.report(Problem::new(
+                ProblemKind::ClockSkew,
+                None,
+                format!(
+                    "Band {} started at {}, before band {} at {}; the clock may have been wrong",
+                    band.id, band.start_time, earlier.id, earlier.start_time
+                ),
+            ));
+        }
+        skewed.len()
     }
 
-    fn validate_archive_dir(&self) -> Result<ValidateStats> {
+    fn validate_archive_dir(&self, problems: &dyn ProblemSink) -> Result<ValidateStats> {
         // TODO: Tests for the problems detected here.
         let mut stats = ValidateStats::default();
         ui::println("Check archive top-level directory...");
@@ -376,25 +1066,39 @@ impl Archive {
                     Kind::Dir => dirs.push(name),
                     Kind::File => files.push(name),
                     other_kind => {
-                        ui::problem(&format!(
-                            "Unexpected file kind in archive directory: {:?} of kind {:?}",
-                            name, other_kind
+                        problems.report(Problem::new(
+                            ProblemKind::ArchiveStructure,
+                            None,
+                            format!(
+                                "Unexpected file kind in archive directory: {:?} of kind {:?}",
+                                name, other_kind
+                            ),
                         ));
                         stats.unexpected_files += 1;
                     }
                 },
                 Err(source) => {
-                    ui::problem(&format!("Error listing archive directory: {:?}", source));
+                    problems.report(Problem::new(
+                        ProblemKind::ArchiveStructure,
+                        None,
+                        format!("Error listing archive directory: {:?}", source),
+                    ));
                     stats.io_errors += 1;
                 }
             }
         }
         remove_item(&mut files, &HEADER_FILENAME);
+        remove_item(&mut files, &lock::LOCK_FILENAME);
+        remove_item(&mut files, &config::CONFIG_FILENAME);
         if !files.is_empty() {
             stats.unexpected_files += 1;
-            ui::problem(&format!(
-                "Unexpected files in archive directory {:?}: {:?}",
-                self.transport, files
+            problems.report(Problem::new(
+                ProblemKind::ArchiveStructure,
+                None,
+                format!(
+                    "Unexpected files in archive directory {:?}: {:?}",
+                    self.transport, files
+                ),
             ));
         }
         remove_item(&mut dirs, &BLOCK_DIR);
@@ -404,18 +1108,20 @@ impl Archive {
             if let Ok(b) = d.parse() {
                 if bs.contains(&b) {
                     stats.structure_problems += 1;
-                    ui::problem(&format!(
-                        "Duplicated band directory in {:?}: {:?}",
-                        self.transport, d
+                    problems.report(Problem::new(
+                        ProblemKind::ArchiveStructure,
+                        None,
+                        format!("Duplicated band directory in {:?}: {:?}", self.transport, d),
                     ));
                 } else {
                     bs.insert(b);
                 }
             } else {
                 stats.structure_problems += 1;
-                ui::problem(&format!(
-                    "Unexpected directory in {:?}: {:?}",
-                    self.transport, d
+                problems.report(Problem::new(
+                    ProblemKind::ArchiveStructure,
+                    None,
+                    format!("Unexpected directory in {:?}: {:?}", self.transport, d),
                 ));
             }
         }
@@ -434,6 +1140,41 @@ impl Archive {
     }
 }
 
+/// Choose the complete band that started most recently, but not after `cutoff`.
+///
+/// If several bands started at the same time, the one with the highest id is chosen.
+fn latest_closed_before<I>(infos: I, cutoff: DateTime<Utc>) -> Option<BandId>
+where
+    I: IntoIterator<Item = band::Info>,
+{
+    infos
+        .into_iter()
+        .filter(|info| info.is_closed && info.start_time <= cutoff)
+        .max_by(|a, b| (a.start_time, &a.id).cmp(&(b.start_time, &b.id)))
+        .map(|info| info.id)
+}
+
+/// Find bands that started before a band with a lower id.
+///
+/// `infos` must be in band id order. Each skewed band is returned along with
+/// the lower-numbered band with the latest start time.
+fn out_of_order_bands<I>(infos: I) -> Vec<(band::Info, band::Info)>
+where
+    I: IntoIterator<Item = band::Info>,
+{
+    let mut latest: Option<band::Info> = None;
+    let mut skewed = Vec::new();
+    for info in infos {
+        match &latest {
+            Some(prev) if info.start_time < prev.start_time => {
+                skewed.push((info, prev.clone()));
+            }
+            _ => latest = Some(info),
+        }
+    }
+    skewed
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -442,6 +1183,8 @@ mod tests {
     use assert_fs::prelude::*;
     use assert_fs::TempDir;
 
+    use chrono::TimeZone;
+
     use crate::test_fixtures::ScratchArchive;
 
     use super::*;
@@ -452,14 +1195,68 @@ mod tests {
         let arch_path = testdir.path().join("arch");
         let arch = Archive::create_path(&arch_path).unwrap();
 
-        assert!(arch.list_band_ids().unwrap().is_empty());
+        assert!(arch.band_ids().unwrap().is_empty());
 
         // We can re-open it.
         Archive::open_path(&arch_path).unwrap();
-        assert!(arch.list_band_ids().unwrap().is_empty());
+        assert!(arch.band_ids().unwrap().is_empty());
         assert!(arch.last_complete_band().unwrap().is_none());
     }
 
+    #[test]
+    #[allow(deprecated)]
+    fn list_band_ids_is_band_ids() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        assert_eq!(af.list_band_ids().unwrap(), af.band_ids().unwrap());
+    }
+
+    #[test]
+    fn create_refuses_existing_archive() {
+        let af = ScratchArchive::new();
+        let header_before = fs::read(af.path().join("CONSERVE")).unwrap();
+        let result = Archive::create_path(af.path());
+        assert!(
+            matches!(result, Err(Error::ArchiveAlreadyExists)),
+            "{:?}",
+            result.err()
+        );
+        assert_eq!(fs::read(af.path().join("CONSERVE")).unwrap(), header_before);
+        assert!(!af.path().join(crate::config::CONFIG_FILENAME).exists());
+    }
+
+    #[test]
+    fn create_with_options_writes_config() {
+        let testdir = TempDir::new().unwrap();
+        let options = InitOptions {
+            excludes: vec!["*.tmp".to_owned()],
+            exclude_caches: true,
+            index_format: Some(IndexFormat::Cbor),
+        };
+        let archive = Archive::create_with_options(
+            Box::new(LocalTransport::new(testdir.path())),
+            None,
+            &options,
+        )
+        .unwrap();
+        let expected = ArchiveConfig {
+            excludes: vec!["*.tmp".to_owned()],
+            exclude_caches: true,
+            index_format: Some(IndexFormat::Cbor),
+        };
+        assert_eq!(*archive.config(), expected);
+        assert_eq!(
+            *Archive::open_path(testdir.path()).unwrap().config(),
+            expected
+        );
+    }
+
+    #[test]
+    fn create_without_options_writes_no_config() {
+        let af = ScratchArchive::new();
+        assert!(!af.path().join(crate::config::CONFIG_FILENAME).exists());
+    }
+
     #[test]
     fn fails_on_non_empty_directory() {
         let temp = TempDir::new().unwrap();
@@ -504,6 +1301,43 @@ mod tests {
         assert_eq!(af.block_dir.block_names().unwrap().count(), 0);
     }
 
+    fn archive_with_header(header: &str) -> (TempDir, Result<Archive>) {
+        let temp = TempDir::new().unwrap();
+        temp.child("d").create_dir_all().unwrap();
+        temp.child("CONSERVE").write_str(header).unwrap();
+        let result = Archive::open_path(temp.path());
+        (temp, result)
+    }
+
+    #[test]
+    fn open_current_version() {
+        let (_temp, result) = archive_with_header(r#"{"conserve_archive_version":"0.6"}"#);
+        result.unwrap();
+    }
+
+    #[test]
+    fn unknown_header_fields_are_ignored() {
+        let (_temp, result) = archive_with_header(
+            r#"{"conserve_archive_version":"0.6","added_in_the_future":[1,2]}"#,
+        );
+        result.unwrap();
+    }
+
+    #[test]
+    fn unsupported_archive_versions() {
+        for version in &["0.5", "0.7", "1.0", "0.6.1", "banana"] {
+            let (_temp, result) =
+                archive_with_header(&format!(r#"{{"conserve_archive_version":"{}"}}"#, version));
+            match result {
+                Err(Error::UnsupportedArchiveVersion { found, supported }) => {
+                    assert_eq!(found, *version);
+                    assert_eq!(supported, "0.6");
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
     #[test]
     fn create_bands() {
         let af = ScratchArchive::new();
@@ -516,13 +1350,13 @@ mod tests {
         assert!(band_path.join("BANDHEAD").is_file());
         assert!(band_path.join("i").is_dir());
 
-        assert_eq!(af.list_band_ids().unwrap(), vec![BandId::new(&[0])]);
+        assert_eq!(af.band_ids().unwrap(), vec![BandId::new(&[0])]);
         assert_eq!(af.last_band_id().unwrap(), Some(BandId::new(&[0])));
 
         // Try creating a second band.
         let _band2 = Band::create(&af).unwrap();
         assert_eq!(
-            af.list_band_ids().unwrap(),
+            af.band_ids().unwrap(),
             vec![BandId::new(&[0]), BandId::new(&[1])]
         );
         assert_eq!(af.last_band_id().unwrap(), Some(BandId::new(&[1])));
@@ -530,4 +1364,183 @@ mod tests {
         assert_eq!(af.referenced_blocks().unwrap().len(), 0);
         assert_eq!(af.block_dir.block_names().unwrap().count(), 0);
     }
+
+    fn band_info(id: u32, start_secs: i64, is_closed: bool) -> band::Info {
+        band::Info {
+            id: BandId::new(&[id]),
+            is_closed,
+            start_time: Utc.timestamp(start_secs, 0),
+            end_time: None,
+            index_hunk_count: None,
+            index_entry_count: None,
+            total_file_bytes: None,
+            block_count_written: None,
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn latest_closed_before_cutoff() {
+        let infos = || {
+            vec![
+                band_info(0, 1000, true),
+                band_info(1, 2000, true),
+                band_info(2, 3000, false),
+                band_info(3, 4000, true),
+            ]
+        };
+        let pick = |secs| latest_closed_before(infos(), Utc.timestamp(secs, 0));
+        assert_eq!(pick(999), None);
+        assert_eq!(pick(1000), Some(BandId::new(&[0])));
+        assert_eq!(pick(2500), Some(BandId::new(&[1])));
+        // b0002 is incomplete, so it's never chosen.
+        assert_eq!(pick(3500), Some(BandId::new(&[1])));
+        assert_eq!(pick(10_000), Some(BandId::new(&[3])));
+    }
+
+    #[test]
+    fn latest_closed_before_with_tied_start_times() {
+        let infos = vec![
+            band_info(0, 1000, true),
+            band_info(1, 1000, true),
+            band_info(2, 1000, false),
+        ];
+        assert_eq!(
+            latest_closed_before(infos, Utc.timestamp(1000, 0)),
+            Some(BandId::new(&[1]))
+        );
+    }
+
+    #[test]
+    fn latest_closed_before_with_out_of_order_start_times() {
+        // b0002 was made while the clock was wrong, so it seems older than b0001.
+        let infos = || {
+            vec![
+                band_info(0, 1000, true),
+                band_info(1, 3000, true),
+                band_info(2, 2000, true),
+                band_info(3, 3000, true),
+            ]
+        };
+        let pick = |secs| latest_closed_before(infos(), Utc.timestamp(secs, 0));
+        assert_eq!(pick(1500), Some(BandId::new(&[0])));
+        assert_eq!(pick(2500), Some(BandId::new(&[2])));
+        // Ties go to the higher id, and the input order doesn't matter.
+        assert_eq!(pick(3000), Some(BandId::new(&[3])));
+        let mut reversed = infos();
+        reversed.reverse();
+        assert_eq!(
+            latest_closed_before(reversed, Utc.timestamp(3000, 0)),
+            Some(BandId::new(&[3]))
+        );
+    }
+
+    #[test]
+    fn find_out_of_order_bands() {
+        let infos = vec![
+            band_info(0, 1000, true),
+            band_info(1, 3000, true),
+            band_info(2, 2000, true),
+            band_info(3, 2500, false),
+            band_info(4, 3000, true),
+            band_info(5, 4000, true),
+        ];
+        let skewed: Vec<(BandId, BandId)> = out_of_order_bands(infos)
+            .into_iter()
+            .map(|(band, earlier)| (band.id, earlier.id))
+            .collect();
+        assert_eq!(
+            skewed,
+            [
+                (BandId::new(&[2]), BandId::new(&[1])),
+                (BandId::new(&[3]), BandId::new(&[1])),
+            ]
+        );
+        assert!(
+            out_of_order_bands(vec![band_info(0, 1000, true), band_info(1, 1000, true)]).is_empty()
+        );
+    }
+
+    #[test]
+    fn resolve_band_before_when_all_bands_are_newer() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let result = af.resolve_band(&BandSelectionPolicy::LatestClosedBefore(
+            Utc.timestamp(0, 0),
+        ));
+        assert!(matches!(result, Err(Error::NoBandBefore { .. })));
+        assert_eq!(
+            af.resolve_band(&BandSelectionPolicy::LatestClosedBefore(Utc::now()))
+                .unwrap(),
+            BandId::new(&[1])
+        );
+    }
+
+    #[test]
+    fn resolve_band_in_empty_archive() {
+        let af = ScratchArchive::new();
+        assert!(matches!(
+            af.resolve_band(&BandSelectionPolicy::LatestClosed),
+            Err(Error::ArchiveEmpty)
+        ));
+        assert!(matches!(
+            af.resolve_band(&BandSelectionPolicy::Latest),
+            Err(Error::ArchiveEmpty)
+        ));
+        match af.resolve_band(&BandSelectionPolicy::Specified(BandId::zero())) {
+            Err(err @ Error::BandNotFound { .. }) => assert_eq!(
+                err.to_string(),
+                "No backup b0000 in archive; available backups: none"
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resolve_band_with_only_an_incomplete_band() {
+        let af = ScratchArchive::new();
+        af.setup_incomplete_empty_band();
+        match af.resolve_band(&BandSelectionPolicy::LatestClosed) {
+            Err(err @ Error::NoCompleteBands { .. }) => {
+                assert!(err.to_string().contains("`--backup b0000`"))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            af.resolve_band(&BandSelectionPolicy::Latest).unwrap(),
+            BandId::zero()
+        );
+        assert_eq!(
+            af.resolve_band(&BandSelectionPolicy::Specified(BandId::zero()))
+                .unwrap(),
+            BandId::zero()
+        );
+    }
+
+    #[test]
+    fn resolve_band_with_several_bands() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        af.setup_incomplete_empty_band();
+        assert_eq!(
+            af.resolve_band(&BandSelectionPolicy::LatestClosed).unwrap(),
+            BandId::new(&[1])
+        );
+        assert_eq!(
+            af.resolve_band(&BandSelectionPolicy::Latest).unwrap(),
+            BandId::new(&[2])
+        );
+        assert_eq!(
+            af.resolve_band(&BandSelectionPolicy::Specified(BandId::zero()))
+                .unwrap(),
+            BandId::zero()
+        );
+        match af.resolve_band(&BandSelectionPolicy::Specified(BandId::new(&[7]))) {
+            Err(err @ Error::BandNotFound { .. }) => assert_eq!(
+                err.to_string(),
+                "No backup b0007 in archive; available backups: b0000, b0001, b0002"
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
@@ -14,11 +14,13 @@
 //! Make a backup by walking a source directory and copying the contents
 //! into an archive.
 
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::fmt;
 use std::io::prelude::*;
+use std::sync::Arc;
 
-use globset::GlobSet;
-use itertools::Itertools;
+use chrono::Utc;
 
 use crate::blockdir::Address;
 use crate::io::read_with_retries;
@@ -27,15 +29,120 @@ use crate::tree::ReadTree;
 use crate::*;
 
 /// Configuration of how to make a backup.
+///
+/// Options can be set either as fields or by chaining the builder methods
+/// from the default:
+///
+/// ```
+/// # use conserve::BackupOptions;
+/// let options = BackupOptions::default()
+///     .print_filenames(true)
+///     .max_file_size(Some(1 << 30));
+/// ```
 #[derive(Debug, Clone)]
 pub struct BackupOptions {
     /// Print filenames to the UI as they're copied.
     pub print_filenames: bool,
 
-    /// Exclude these globs from the backup.
-    pub excludes: Option<GlobSet>,
+    /// Exclude entries matching these patterns from the backup.
+    pub excludes: Option<Exclude>,
+
+    /// Skip the contents of directories marked by a `CACHEDIR.TAG` file.
+    pub exclude_caches: bool,
+
+    /// Skip the contents of directories holding another Conserve archive.
+    ///
+    /// The archive being written is always skipped if it's inside the source.
+    pub exclude_other_archives: bool,
+
+    /// Don't descend into directories on a different filesystem from the
+    /// source directory, such as mount points. Only supported on Unix.
+    pub one_file_system: bool,
+
+    /// Skip files larger than this many bytes.
+    pub max_file_size: Option<u64>,
+
+    /// Told about each entry as it's stored.
+    pub monitor: Option<Arc<dyn BackupMonitor>>,
 
+    /// Told about problems reading the source, such as unreadable
+    /// directories, or by default the UI's problem sink.
+    ///
+    /// Errors that leave an entry out of the band are returned in the stats.
+    pub problems: Option<Arc<dyn ProblemSink>>,
+
+    /// Finish each index hunk after at most this many entries.
+    ///
+    /// Smaller hunks let restores of a subtree skip more of the index, while
+    /// larger hunks compress better.
     pub max_entries_per_hunk: usize,
+
+    /// Finish each index hunk once its entries are estimated to take about
+    /// this many bytes serialized, which bounds the memory used to hold
+    /// them.
+    pub max_hunk_bytes: usize,
+
+    /// Break any existing lock on the archive before starting.
+    pub break_lock: bool,
+
+    /// Tags to record on the new band.
+    pub tags: Vec<String>,
+
+    /// Serialization for the new band's index.
+    pub index_format: IndexFormat,
+
+    /// Store the new band's index hunks in packs of this many, if set,
+    /// rather than each in its own file.
+    ///
+    /// Packs need fewer files and writes, but can only be read by Conserve
+    /// 0.6.11 and later.
+    pub index_hunks_per_pack: Option<u32>,
+
+    /// How to split large files into blocks.
+    pub chunking: Chunking,
+
+    /// Read a file again, up to this many times, if its size or mtime
+    /// changed while it was being read.
+    ///
+    /// If it's still changing after that, it's stored as it was last read,
+    /// and marked as changed during backup.
+    pub retry_changed: usize,
+
+    /// Reuse the stored content of files that were moved or renamed since
+    /// the last backup, rather than reading them again.
+    ///
+    /// Moved files are recognized by their device and inode numbers, and
+    /// their content is only reused if their size and mtime are also
+    /// unchanged. This keeps a table of every file in the previous backup in
+    /// memory, so is off by default.
+    pub detect_moves: bool,
+
+    /// Refuse to start a backup if the clock reads earlier than the start
+    /// of the previous backup, rather than only warning.
+    ///
+    /// Versions are chosen by date using their start times, so a backup
+    /// made while the clock is wrong can be picked in place of a later one.
+    pub strict_time: bool,
+
+    /// Stop the backup, leaving the new band incomplete, when this is
+    /// cancelled.
+    pub cancel: CancellationToken,
+
+    /// If set, only report what would be stored, without writing anything
+    /// to the archive.
+    pub dry_run: Option<DryRun>,
+}
+
+/// How much work a dry run does to find what a backup would store.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DryRun {
+    /// Compare the source to the previous backup by metadata only, without
+    /// reading new or modified files.
+    Metadata,
+
+    /// Also read and hash new and modified files, to count how many of their
+    /// blocks are already in the archive.
+    Hash,
 }
 
 impl Default for BackupOptions {
@@ -43,9 +150,185 @@ impl Default for BackupOptions {
         BackupOptions {
             print_filenames: false,
             excludes: None,
+            exclude_caches: false,
+            exclude_other_archives: false,
+            one_file_system: false,
+            max_file_size: None,
+            monitor: None,
+            problems: None,
             max_entries_per_hunk: crate::index::MAX_ENTRIES_PER_HUNK,
+            max_hunk_bytes: crate::index::MAX_HUNK_BYTES,
+            break_lock: false,
+            tags: Vec::new(),
+            index_format: IndexFormat::default(),
+            index_hunks_per_pack: None,
+            chunking: Chunking::default(),
+            retry_changed: 0,
+            detect_moves: false,
+            strict_time: false,
+            cancel: CancellationToken::new(),
+            dry_run: None,
+        }
+    }
+}
+
+impl BackupOptions {
+    /// Set whether to print filenames to the UI as they're copied.
+    pub fn print_filenames(self, print_filenames: bool) -> BackupOptions {
+        BackupOptions {
+            print_filenames,
+            ..self
+        }
+    }
+
+    /// Set patterns to exclude from the backup.
+    pub fn excludes(self, excludes: Option<Exclude>) -> BackupOptions {
+        BackupOptions { excludes, ..self }
+    }
+
+    /// Set whether to skip the contents of cache directories.
+    pub fn exclude_caches(self, exclude_caches: bool) -> BackupOptions {
+        BackupOptions {
+            exclude_caches,
+            ..self
+        }
+    }
+
+    /// Set whether to skip the contents of directories holding other archives.
+    pub fn exclude_other_archives(self, exclude_other_archives: bool) -> BackupOptions {
+        BackupOptions {
+            exclude_other_archives,
+            ..self
+        }
+    }
+
+    /// Set whether to stay on the filesystem holding the source directory.
+    pub fn one_file_system(self, one_file_system: bool) -> BackupOptions {
+        BackupOptions {
+            one_file_system,
+            ..self
+        }
+    }
+
+    /// Set the size in bytes above which files are skipped, or `None` to
+    /// store files of any size.
+    pub fn max_file_size(self, max_file_size: Option<u64>) -> BackupOptions {
+        BackupOptions {
+            max_file_size,
+            ..self
+        }
+    }
+
+    /// Set a monitor to be told about each entry as it's stored.
+    pub fn monitor(self, monitor: Arc<dyn BackupMonitor>) -> BackupOptions {
+        BackupOptions {
+            monitor: Some(monitor),
+            ..self
+        }
+    }
+
+    /// Set a sink to be told about problems reading the source.
+    pub fn problems(self, problems: Arc<dyn ProblemSink>) -> BackupOptions {
+        BackupOptions {
+            problems: Some(problems),
+            ..self
+        }
+    }
+
+    /// Set the maximum number of entries in each index hunk.
+    pub fn max_entries_per_hunk(self, max_entries_per_hunk: usize) -> BackupOptions {
+        BackupOptions {
+            max_entries_per_hunk,
+            ..self
+        }
+    }
+
+    /// Set the approximate maximum serialized size of each index hunk.
+    pub fn max_hunk_bytes(self, max_hunk_bytes: usize) -> BackupOptions {
+        BackupOptions {
+            max_hunk_bytes,
+            ..self
+        }
+    }
+
+    /// Set whether to break an existing lock on the archive.
+    pub fn break_lock(self, break_lock: bool) -> BackupOptions {
+        BackupOptions { break_lock, ..self }
+    }
+
+    /// Set tags to record on the new band.
+    pub fn tags(self, tags: Vec<String>) -> BackupOptions {
+        BackupOptions { tags, ..self }
+    }
+
+    /// Set the serialization for the new band's index.
+    pub fn index_format(self, index_format: IndexFormat) -> BackupOptions {
+        BackupOptions {
+            index_format,
+            ..self
+        }
+    }
+
+    /// Set whether to store the new band's index hunks in packs, and how many
+    /// in each.
+    pub fn index_hunks_per_pack(self, index_hunks_per_pack: Option<u32>) -> BackupOptions {
+        BackupOptions {
+            index_hunks_per_pack,
+            ..self
+        }
+    }
+
+    /// Set how to split large files into blocks.
+    pub fn chunking(self, chunking: Chunking) -> BackupOptions {
+        BackupOptions { chunking, ..self }
+    }
+
+    /// Set how many times to read again a file that changes while it's read.
+    pub fn retry_changed(self, retry_changed: usize) -> BackupOptions {
+        BackupOptions {
+            retry_changed,
+            ..self
+        }
+    }
+
+    /// Set whether to reuse the content of files moved since the last backup.
+    pub fn detect_moves(self, detect_moves: bool) -> BackupOptions {
+        BackupOptions {
+            detect_moves,
+            ..self
+        }
+    }
+
+    /// Set whether to refuse to back up if the clock seems to have gone
+    /// backwards since the previous backup.
+    pub fn strict_time(self, strict_time: bool) -> BackupOptions {
+        BackupOptions {
+            strict_time,
+            ..self
         }
     }
+
+    /// Set a token that can be used to cancel the backup.
+    pub fn cancel(self, cancel: CancellationToken) -> BackupOptions {
+        BackupOptions { cancel, ..self }
+    }
+
+    /// Set whether to only report what would be stored.
+    pub fn dry_run(self, dry_run: Option<DryRun>) -> BackupOptions {
+        BackupOptions { dry_run, ..self }
+    }
+}
+
+/// Told about each entry as a backup proceeds, for example to show progress
+/// in an application using Conserve as a library.
+///
+/// Both methods do nothing by default.
+pub trait BackupMonitor: fmt::Debug + Send + Sync {
+    /// Called after an entry is added to the new band.
+    fn entry_stored(&self, _entry: &LiveEntry) {}
+
+    /// Called when an entry can't be stored, and is left out of the band.
+    fn entry_failed(&self, _entry: &LiveEntry, _error: &Error) {}
 }
 
 // This causes us to walk the source tree twice, which is probably an acceptable option
@@ -62,38 +345,114 @@ impl Default for BackupOptions {
 
 /// Backup a source directory into a new band in the archive.
 ///
-/// Returns statistics about what was copied.
+/// The `exclude_caches`, `exclude_other_archives`, and `one_file_system`
+/// options are taken from `source`, not from `options`.
+#[deprecated(since = "0.6.11", note = "Use Archive::backup")]
 pub fn backup(
     archive: &Archive,
     source: &LiveTree,
     options: &BackupOptions,
 ) -> Result<BackupStats> {
+    backup_tree(archive, source, options)
+}
+
+/// If the archive is inside the source tree, return a tree that skips the
+/// archive's contents, so that it's not backed up into itself.
+///
+/// This can't be turned off: the backup would grow without limit.
+fn exclude_archive(archive: &Archive, source: &LiveTree) -> LiveTree {
+    let mut source = source.clone();
+    if let Some(apath) = archive
+        .transport()
+        .local_path()
+        .and_then(|path| source.apath_of_path(&path))
+    {
+        ui::println(&format!(
+            "Archive is inside the source tree; excluding {}",
+            apath
+        ));
+        source.exclude_dir(apath);
+    }
+    source
+}
+
+/// Backup a source tree into a new band in the archive.
+///
+/// Returns statistics about what was copied.
+///
+/// If `options.cancel` is cancelled, the backup stops after the current block
+/// and returns [Error::BackupCancelled]. The index entries stored so far are
+/// flushed, but the band is left without a tail, so it's treated as incomplete.
+pub(crate) fn backup_tree(
+    archive: &Archive,
+    source: &LiveTree,
+    options: &BackupOptions,
+) -> Result<BackupStats> {
+    let source = &exclude_archive(archive, source);
     let mut writer = BackupWriter::begin(archive, options.clone())?;
     let mut stats = BackupStats::default();
     let mut progress_bar = ProgressBar::new();
 
     progress_bar.set_phase("Copying".to_owned());
-    let entry_iter = source.iter_filtered(None, options.excludes.clone())?;
-    for entry_group in entry_iter.chunks(options.max_entries_per_hunk).into_iter() {
-        for entry in entry_group {
-            progress_bar.set_filename(entry.apath().to_string());
-            if let Err(e) = writer.copy_entry(&entry, source) {
-                ui::show_error(&e);
+    let excludes = options.excludes.as_ref().map(Exclude::with_new_counts);
+    let entry_iter = source.iter_filtered(None, excludes.clone())?;
+    for entry in entry_iter {
+        if options.cancel.is_cancelled() {
+            break;
+        }
+        progress_bar.set_filename(entry.apath().to_string());
+        if entry.kind() == Kind::File
+            && options
+                .max_file_size
+                .is_some_and(|max| entry.size().unwrap_or(0) > max)
+        {
+            if options.print_filenames {
+                crate::ui::println(&format!("{} (too large, skipped)", entry.apath()));
+            }
+            stats.oversized_files += 1;
+            continue;
+        }
+        match writer.copy_entry(&entry, source) {
+            Ok(()) => {
+                if let Some(monitor) = &options.monitor {
+                    monitor.entry_stored(&entry);
+                }
+            }
+            Err(Error::Cancelled) => break,
+            Err(e) => {
+                if let Some(monitor) = &options.monitor {
+                    monitor.entry_failed(&entry, &e);
+                }
+                stats.problems.push_error(Some(entry.apath()), &e);
                 stats.errors += 1;
                 continue;
             }
-            progress_bar.increment_bytes_done(entry.size().unwrap_or(0));
         }
-        writer.flush_group()?;
+        progress_bar.increment_bytes_done(entry.size().unwrap_or(0));
+        if writer.hunk_is_full() {
+            writer.flush_group()?;
+        }
+    }
+    writer.flush_group()?;
+    if options.cancel.is_cancelled() {
+        stats.pattern_matches = excludes
+            .as_ref()
+            .map(Exclude::pattern_stats)
+            .unwrap_or_default();
+        return writer.abandon(stats);
     }
-    stats += writer.finish()?;
     // TODO: Merge in stats from the source tree?
-    Ok(stats)
+    stats.pattern_matches = excludes
+        .as_ref()
+        .map(Exclude::pattern_stats)
+        .unwrap_or_default();
+    writer.finish(stats)
 }
 
 /// Accepts files to write in the archive (in apath order.)
 struct BackupWriter {
-    band: Band,
+    /// The new band, or None in a dry run.
+    band: Option<Band>,
     index_builder: IndexWriter,
     stats: BackupStats,
     block_dir: BlockDir,
@@ -102,9 +461,45 @@ struct BackupWriter {
     /// stored files have changed.
     basis_index: Option<crate::index::IndexEntryIter<crate::stitch::IterStitchedIndexHunks>>,
 
+    /// Files in the last stored band, by their device and inode, if
+    /// `detect_moves` is set.
+    basis_files_by_id: HashMap<FileId, IndexEntry>,
+
     file_combiner: FileCombiner,
 
     options: BackupOptions,
+
+    /// Excludes other writers while the backup is underway, except in a
+    /// dry run, which doesn't write.
+    lock: Option<ArchiveLock>,
+}
+
+/// Check that the clock doesn't read earlier than the start of the previous
+/// band, which would give the new band an out-of-order start time.
+///
+/// If it does, warn, or with `strict` return [Error::ClockSkew].
+fn check_clock(
+    archive: &Archive,
+    previous_band: &BandId,
+    strict: bool,
+    problems: &dyn ProblemSink,
+) -> Result<()> {
+    let previous_start = Band::open(archive, previous_band)?.get_info()?.start_time;
+    let now = Utc::now();
+    if now >= previous_start {
+        return Ok(());
+    }
+    let err = Error::ClockSkew {
+        previous_band: previous_band.clone(),
+        previous_start,
+        now,
+    };
+    if strict {
+        Err(err)
+    } else {
+        problems.report(Problem::new(ProblemKind::ClockSkew, None, err.to_string()));
+        Ok(())
+    }
 }
 
 impl BackupWriter {
@@ -115,42 +510,122 @@ impl BackupWriter {
         if gc_lock::GarbageCollectionLock::is_locked(archive)? {
             return Err(Error::GarbageCollectionLockHeld);
         }
-        let basis_index = archive
-            .last_band_id()?
-            .map(|band_id| archive.iter_stitched_index_hunks(&band_id).iter_entries());
+        let dry_run = options.dry_run.is_some();
+        let lock = if dry_run {
+            None
+        } else {
+            Some(archive.lock(options.break_lock)?)
+        };
+        let basis_band_id = archive.last_band_id()?;
+        if let Some(band_id) = &basis_band_id {
+            check_clock(
+                archive,
+                band_id,
+                options.strict_time,
+                ui::problem_sink_or(&options.problems).as_ref(),
+            )?;
+        }
+        let basis_index = basis_band_id
+            .as_ref()
+            .map(|band_id| archive.iter_stitched_index_hunks(band_id).iter_entries());
+        let basis_files_by_id = match basis_band_id {
+            Some(band_id) if options.detect_moves => files_by_id(archive, &band_id),
+            _ => HashMap::new(),
+        };
+        if dry_run {
+            let block_dir = archive.block_dir().dry_run();
+            return Ok(BackupWriter {
+                band: None,
+                index_builder: IndexWriter::dry_run().with_format(options.index_format),
+                block_dir: block_dir.clone(),
+                stats: BackupStats::default(),
+                basis_index,
+                basis_files_by_id,
+                file_combiner: FileCombiner::new(block_dir),
+                options,
+                lock,
+            });
+        }
         // Create the new band only after finding the basis band!
-        let band = Band::create(archive)?;
+        let band = Band::create_with_options(
+            archive,
+            &BandOptions {
+                tags: options.tags.clone(),
+                index_format: options.index_format,
+                chunking: options.chunking,
+                index_hunks_per_pack: options.index_hunks_per_pack,
+            },
+        )?;
         let index_builder = band.index_builder();
         Ok(BackupWriter {
-            band,
+            band: Some(band),
             index_builder,
             block_dir: archive.block_dir().clone(),
             stats: BackupStats::default(),
             basis_index,
+            basis_files_by_id,
             file_combiner: FileCombiner::new(archive.block_dir().clone()),
             options,
+            lock,
         })
     }
 
-    fn finish(self) -> Result<BackupStats> {
+    fn finish(self, stats: BackupStats) -> Result<BackupStats> {
         let index_builder_stats = self.index_builder.finish()?;
-        self.band.close(index_builder_stats.index_hunks as u64)?;
-        Ok(BackupStats {
+        let stats = BackupStats {
             index_builder_stats,
-            ..self.stats
+            ..stats + self.stats
+        };
+        if let Some(band) = &self.band {
+            band.write_stats(&stats)?;
+            band.close_with_totals(&BandTotals::from_backup_stats(&stats))?;
+        }
+        Ok(stats)
+    }
+
+    /// Stop a cancelled backup, leaving the band without a tail.
+    ///
+    /// Everything already flushed stays in the archive, so blocks written so
+    /// far can be reused by the next backup.
+    fn abandon(mut self, stats: BackupStats) -> Result<BackupStats> {
+        // Hunks already finished are kept, as they would be if not packed.
+        self.index_builder.flush()?;
+        let stats = BackupStats {
+            index_builder_stats: self.index_builder.stats.clone(),
+            ..stats + self.stats
+        };
+        Err(Error::BackupCancelled {
+            stats: Box::new(stats),
         })
     }
 
+    /// True if the entries for the next index hunk, including those for
+    /// small files not yet written, have reached the limits in the options.
+    fn hunk_is_full(&self) -> bool {
+        self.index_builder.buffered_entries() + self.file_combiner.pending_entries()
+            >= self.options.max_entries_per_hunk
+            || self.index_builder.buffered_bytes() + self.file_combiner.pending_bytes
+                >= self.options.max_hunk_bytes
+    }
+
     /// Write out any pending data blocks, and then the pending index entries.
     fn flush_group(&mut self) -> Result<()> {
         // TODO: Finish FileCombiner, when this class has one.
         let (stats, mut entries) = self.file_combiner.drain()?;
         self.stats += stats;
         self.index_builder.append_entries(&mut entries);
-        self.index_builder.finish_hunk()
+        // Each source entry adds at most one index entry, and the hunk is
+        // flushed as soon as it's full.
+        debug_assert!(self.index_builder.buffered_entries() <= self.options.max_entries_per_hunk);
+        self.index_builder.finish_hunk()?;
+        match &mut self.lock {
+            Some(lock) => lock.refresh(),
+            None => Ok(()),
+        }
     }
 
     fn copy_entry(&mut self, entry: &LiveEntry, source: &LiveTree) -> Result<()> {
+        self.stats.unreadable_xattrs += entry.unreadable_xattrs();
         match entry.kind() {
             Kind::Dir => self.copy_dir(entry),
             Kind::File => self.copy_file(entry, source),
@@ -186,12 +661,22 @@ impl BackupWriter {
             .map(|bi| bi.advance_to(&apath))
             .flatten()
         {
-            if source_entry.is_unchanged_from(&basis_entry) {
+            if source_entry.is_unchanged_from(&basis_entry) && !basis_entry.changed_during_backup {
                 if self.options.print_filenames {
                     crate::ui::println(&format!("{} (unchanged)", apath));
                 }
                 self.stats.unmodified_files += 1;
-                self.index_builder.push_entry(basis_entry);
+                // Changing xattrs or permissions doesn't change the mtime,
+                // so take them from the source.
+                self.index_builder.push_entry(IndexEntry {
+                    xattrs: source_entry.xattrs().cloned().unwrap_or_default(),
+                    unix_mode: source_entry.unix_mode(),
+                    dev: source_entry.file_id().map(|id| id.dev),
+                    ino: source_entry.file_id().map(|id| id.ino),
+                    ..basis_entry
+                });
+                return Ok(());
+            } else if self.copy_moved_file(source_entry) {
                 return Ok(());
             } else {
                 if self.options.print_filenames {
@@ -199,34 +684,147 @@ impl BackupWriter {
                 }
                 self.stats.modified_files += 1;
             }
+        } else if self.copy_moved_file(source_entry) {
+            return Ok(());
         } else {
             if self.options.print_filenames {
                 crate::ui::println(&format!("{} (new)", apath));
             }
             self.stats.new_files += 1;
         }
-        let mut read_source = from_tree.file_contents(&source_entry)?;
-        let size = source_entry.size().expect("LiveEntry has a size");
-        if size == 0 {
+        if source_entry.size() == Some(0) {
+            // Empty files have no blocks, so there's no need to open them.
             self.index_builder
                 .push_entry(IndexEntry::metadata_from(source_entry));
             self.stats.empty_files += 1;
             return Ok(());
         }
-        if size <= SMALL_FILE_CAP {
-            return self.file_combiner.push_file(source_entry, &mut read_source);
+        if self.options.dry_run == Some(DryRun::Metadata) {
+            self.stats.unread_bytes += source_entry.size().unwrap_or(0);
+            self.index_builder
+                .push_entry(IndexEntry::metadata_from(source_entry));
+            return Ok(());
         }
-        let addrs = store_file_content(
-            &apath,
-            &mut read_source,
-            &mut self.block_dir,
-            &mut self.stats,
-        )?;
+        let mut entry = source_entry.clone();
+        let mut retries = 0;
+        loop {
+            #[cfg(test)]
+            test_hooks::before_read(&apath);
+            let content = self.read_file_content(&entry, from_tree)?;
+            match from_tree.recheck_file(&entry)? {
+                None => {
+                    let index_entry = IndexEntry::metadata_from(&entry);
+                    return self.push_file_content(index_entry, content);
+                }
+                Some(changed_entry) if retries < self.options.retry_changed => {
+                    if self.options.print_filenames {
+                        crate::ui::println(&format!("{} (changed, reading again)", apath));
+                    }
+                    retries += 1;
+                    self.stats.changed_files_retried += 1;
+                    entry = changed_entry;
+                }
+                Some(_) => {
+                    if self.options.print_filenames {
+                        crate::ui::println(&format!("{} (changed while reading)", apath));
+                    }
+                    self.stats.changed_during_backup += 1;
+                    let index_entry = IndexEntry {
+                        changed_during_backup: true,
+                        ..IndexEntry::metadata_from(&entry)
+                    };
+                    return self.push_file_content(index_entry, content);
+                }
+            }
+        }
+    }
+
+    /// If the source file was moved here from elsewhere in the basis tree,
+    /// and is otherwise unchanged, store it with the basis entry's content.
+    ///
+    /// Returns false if it needs to be read.
+    fn copy_moved_file(&mut self, source_entry: &LiveEntry) -> bool {
+        let (moved_from, addrs) = match source_entry
+            .file_id()
+            .and_then(|id| self.basis_files_by_id.get(&id))
+        {
+            Some(basis_entry) if source_entry.is_unchanged_from(basis_entry) => {
+                (basis_entry.apath.clone(), basis_entry.addrs.clone())
+            }
+            _ => return false,
+        };
+        if self.options.print_filenames {
+            crate::ui::println(&format!(
+                "{} (moved from {})",
+                source_entry.apath(),
+                moved_from
+            ));
+        }
+        self.stats.moved_files += 1;
         self.index_builder.push_entry(IndexEntry {
             addrs,
             ..IndexEntry::metadata_from(source_entry)
         });
-        Ok(())
+        true
+    }
+
+    /// Read the content of a file, either into memory if it's small enough
+    /// to be combined with others, or otherwise into blocks.
+    fn read_file_content(
+        &mut self,
+        entry: &LiveEntry,
+        from_tree: &LiveTree,
+    ) -> Result<FileContent> {
+        let mut read_source = from_tree.file_contents(entry)?;
+        let size = entry.size().expect("LiveEntry has a size");
+        if size == 0 {
+            Ok(FileContent::Empty)
+        } else if size <= SMALL_FILE_CAP {
+            let mut buf = Vec::with_capacity(size as usize);
+            read_source
+                .take(size)
+                .read_to_end(&mut buf)
+                .map_err(|source| Error::StoreFile {
+                    apath: entry.apath().clone(),
+                    source,
+                })?;
+            Ok(FileContent::Small(buf))
+        } else {
+            // If the file changes and is read again, these blocks may be
+            // left unreferenced, until they're removed by gc.
+            store_file_content(
+                entry.apath(),
+                &mut read_source,
+                &mut self.block_dir,
+                &mut self.stats,
+                &self.options.chunking,
+                &self.options.cancel,
+            )
+            .map(FileContent::Blocks)
+        }
+    }
+
+    /// Add the content read from a file to the index, or to the combined
+    /// block for small files.
+    fn push_file_content(&mut self, index_entry: IndexEntry, content: FileContent) -> Result<()> {
+        match content {
+            FileContent::Empty => {
+                self.index_builder.push_entry(index_entry);
+                self.stats.empty_files += 1;
+                Ok(())
+            }
+            FileContent::Small(buf) => {
+                self.file_combiner
+                    .push_file(index_entry, buf.len() as u64, &mut buf.as_slice())
+            }
+            FileContent::Blocks(addrs) => {
+                self.index_builder.push_entry(IndexEntry {
+                    addrs,
+                    ..index_entry
+                });
+                Ok(())
+            }
+        }
     }
 
     #[allow(clippy::unnecessary_wraps)]
@@ -243,16 +841,45 @@ impl BackupWriter {
     }
 }
 
-fn store_file_content(
+/// Map the files in a band whose content could be reused if they're moved, by
+/// their device and inode numbers.
+///
+/// Files that changed while they were backed up, and empty files, are left
+/// out.
+fn files_by_id(archive: &Archive, band_id: &BandId) -> HashMap<FileId, IndexEntry> {
+    archive
+        .iter_stitched_index_hunks(band_id)
+        .iter_entries()
+        .filter(|entry| !entry.changed_during_backup && !entry.addrs.is_empty())
+        .filter_map(|entry| entry.file_id().map(|id| (id, entry)))
+        .collect()
+}
+
+/// Content read from a source file, before it's added to the index.
+enum FileContent {
+    Empty,
+    /// A small file, to be stored in a combined block.
+    Small(Vec<u8>),
+    /// A larger file, already stored into these blocks.
+    Blocks(Vec<Address>),
+}
+
+/// Store the content of a file into blocks, split according to `chunking`.
+///
+/// Returns [Error::Cancelled] between blocks if `cancel` is cancelled.
+pub(crate) fn store_file_content(
     apath: &Apath,
     from_file: &mut dyn Read,
     block_dir: &mut BlockDir,
     stats: &mut BackupStats,
+    chunking: &Chunking,
+    cancel: &CancellationToken,
 ) -> Result<Vec<Address>> {
     let mut buffer = Vec::new();
     let mut addresses = Vec::<Address>::with_capacity(1);
     loop {
-        read_with_retries(&mut buffer, MAX_BLOCK_SIZE, from_file).map_err(|source| {
+        cancel.check()?;
+        read_with_retries(&mut buffer, chunking.max_size(), from_file).map_err(|source| {
             Error::StoreFile {
                 apath: apath.to_owned(),
                 source,
@@ -261,12 +888,14 @@ fn store_file_content(
         if buffer.is_empty() {
             break;
         }
-        let hash = block_dir.store_or_deduplicate(buffer.as_slice(), stats)?;
+        let len = chunking.cut(&buffer);
+        let hash = block_dir.store_or_deduplicate(&buffer[..len], stats)?;
         addresses.push(Address {
             hash,
             start: 0,
-            len: buffer.len() as u64,
+            len: len as u64,
         });
+        buffer.drain(..len);
     }
     match addresses.len() {
         0 => stats.empty_files += 1,
@@ -280,12 +909,14 @@ fn store_file_content(
 ///
 /// When the block is finished, and only then, this returns the index entries with the addresses
 /// completed.
-struct FileCombiner {
+pub(crate) struct FileCombiner {
     /// Buffer of concatenated data from small files.
     buf: Vec<u8>,
     queue: Vec<QueuedFile>,
     /// Entries for files that have been written to the blockdir, and that have complete addresses.
     finished: Vec<IndexEntry>,
+    /// Estimated serialized size of the entries in `queue` and `finished`.
+    pending_bytes: usize,
     stats: BackupStats,
     block_dir: BlockDir,
 }
@@ -305,19 +936,25 @@ struct QueuedFile {
 }
 
 impl FileCombiner {
-    fn new(block_dir: BlockDir) -> FileCombiner {
+    pub(crate) fn new(block_dir: BlockDir) -> FileCombiner {
         FileCombiner {
             block_dir,
             buf: Vec::new(),
             queue: Vec::new(),
             finished: Vec::new(),
+            pending_bytes: 0,
             stats: BackupStats::default(),
         }
     }
 
+    /// The number of entries queued or finished but not yet drained.
+    pub(crate) fn pending_entries(&self) -> usize {
+        self.queue.len() + self.finished.len()
+    }
+
     /// Flush any pending files, and return accumulated file entries and stats.
     /// The FileCombiner is then empty and ready for reuse.
-    fn drain(&mut self) -> Result<(BackupStats, Vec<IndexEntry>)> {
+    pub(crate) fn drain(&mut self) -> Result<(BackupStats, Vec<IndexEntry>)> {
         self.flush()?;
         debug_assert!(self.queue.is_empty());
         debug_assert!(self.buf.is_empty());
@@ -325,6 +962,7 @@ impl FileCombiner {
         self.stats = BackupStats::default();
         let finished = self.finished.drain(..).collect();
         debug_assert!(self.finished.is_empty());
+        self.pending_bytes = 0;
         Ok((stats, finished))
     }
 
@@ -358,33 +996,46 @@ impl FileCombiner {
 
     /// Add the contents of a small file into this combiner.
     ///
-    /// `entry` should be an IndexEntry that's complete apart from the block addresses.
-    fn push_file(&mut self, live_entry: &LiveEntry, from_file: &mut dyn Read) -> Result<()> {
+    /// `index_entry` should be an IndexEntry that's complete apart from the block addresses,
+    /// and `expected_len` the length of the file.
+    pub(crate) fn push_file(
+        &mut self,
+        index_entry: IndexEntry,
+        expected_len: u64,
+        from_file: &mut dyn Read,
+    ) -> Result<()> {
         let start = self.buf.len();
-        let expected_len: usize = live_entry
-            .size()
-            .expect("small file has no length")
-            .try_into()
-            .unwrap();
-        let index_entry = IndexEntry::metadata_from(live_entry);
+        let expected_len: usize = expected_len.try_into().unwrap();
         if expected_len == 0 {
             self.stats.empty_files += 1;
+            self.pending_bytes += index_entry.estimated_serialized_len();
             self.finished.push(index_entry);
             return Ok(());
         }
         self.buf.resize(start + expected_len, 0);
-        let len = from_file
-            .read(&mut self.buf[start..])
-            .map_err(|source| Error::StoreFile {
-                apath: live_entry.apath().to_owned(),
-                source,
-            })?;
+        let mut len = 0;
+        while start + len < self.buf.len() {
+            match from_file.read(&mut self.buf[(start + len)..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(source) => {
+                    self.buf.truncate(start);
+                    return Err(Error::StoreFile {
+                        apath: index_entry.apath,
+                        source,
+                    });
+                }
+            }
+        }
         self.buf.truncate(start + len);
+        self.pending_bytes += index_entry.estimated_serialized_len();
         if len == 0 {
             self.stats.empty_files += 1;
             self.finished.push(index_entry);
             return Ok(());
         }
+        self.pending_bytes += crate::index::ESTIMATED_ADDRESS_LEN;
         // TODO: Check whether this file is exactly the same as, or a prefix of,
         // one already stored inside this block. In that case trim the buffer and
         // use the existing start/len.
@@ -402,6 +1053,34 @@ impl FileCombiner {
     }
 }
 
+/// Hooks to let tests change the source tree while a backup is running.
+#[cfg(test)]
+pub(crate) mod test_hooks {
+    use std::cell::RefCell;
+
+    use crate::Apath;
+
+    type BeforeRead = Box<dyn FnMut(&Apath)>;
+
+    thread_local! {
+        static BEFORE_READ: RefCell<Option<BeforeRead>> = RefCell::new(None);
+    }
+
+    /// Call `hook` on this thread just before each attempt to read a file,
+    /// or stop calling any hook if it's None.
+    pub(crate) fn set_before_read(hook: Option<BeforeRead>) {
+        BEFORE_READ.with(|cell| *cell.borrow_mut() = hook);
+    }
+
+    pub(super) fn before_read(apath: &Apath) {
+        BEFORE_READ.with(|cell| {
+            if let Some(hook) = cell.borrow_mut().as_mut() {
+                hook(apath)
+            }
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -411,6 +1090,7 @@ mod tests {
     use tempfile::{NamedTempFile, TempDir};
 
     use crate::stats::Sizes;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
 
     use super::*;
 
@@ -446,6 +1126,8 @@ mod tests {
             &mut example_file,
             &mut block_dir,
             &mut stats,
+            &Chunking::Fixed,
+            &CancellationToken::new(),
         )
         .unwrap();
 
@@ -493,7 +1175,9 @@ mod tests {
         );
 
         let mut stats = ValidateStats::default();
-        block_dir.validate(&mut stats).unwrap();
+        block_dir
+            .validate(&mut stats, &std::sync::Mutex::new(ProgressBar::new()))
+            .unwrap();
         assert_eq!(stats.io_errors, 0);
         assert_eq!(stats.block_error_count, 0);
         assert_eq!(stats.block_read_count, 1);
@@ -507,6 +1191,8 @@ mod tests {
             &mut Cursor::new(b"0123456789abcdef"),
             &mut block_dir,
             &mut BackupStats::default(),
+            &Chunking::Fixed,
+            &CancellationToken::new(),
         )
         .unwrap();
         assert_eq!(addrs.len(), 1);
@@ -537,6 +1223,8 @@ mod tests {
             &mut Cursor::new(b"0123456789abcdef"),
             &mut block_dir,
             &mut BackupStats::default(),
+            &Chunking::Fixed,
+            &CancellationToken::new(),
         )
         .unwrap();
         assert_eq!(addrs.len(), 1);
@@ -586,6 +1274,8 @@ mod tests {
             &mut example_file,
             &mut block_dir,
             &mut stats,
+            &Chunking::Fixed,
+            &CancellationToken::new(),
         )
         .unwrap();
         assert_eq!(stats.deduplicated_blocks, 0);
@@ -600,6 +1290,8 @@ mod tests {
             &mut example_file,
             &mut block_dir,
             &mut stats2,
+            &Chunking::Fixed,
+            &CancellationToken::new(),
         )
         .unwrap();
         assert_eq!(stats2.deduplicated_blocks, 1);
@@ -629,8 +1321,15 @@ mod tests {
         tf.seek(SeekFrom::Start(0)).unwrap();
 
         let mut stats = BackupStats::default();
-        let addrs =
-            store_file_content(&Apath::from("/big"), &mut tf, &mut block_dir, &mut stats).unwrap();
+        let addrs = store_file_content(
+            &Apath::from("/big"),
+            &mut tf,
+            &mut block_dir,
+            &mut stats,
+            &Chunking::Fixed,
+            &CancellationToken::new(),
+        )
+        .unwrap();
 
         // Only one block needs to get compressed. The others are deduplicated.
         assert_eq!(stats.uncompressed_bytes, MAX_BLOCK_SIZE as u64);
@@ -651,4 +1350,152 @@ mod tests {
             assert_eq!(block_sizes.uncompressed, MAX_BLOCK_SIZE as u64);
         }
     }
+
+    /// Append to a file from the before-read hook, the first `times` times
+    /// it's about to be read.
+    fn append_before_read(path: std::path::PathBuf, times: usize) {
+        let mut remaining = times;
+        test_hooks::set_before_read(Some(Box::new(move |_apath| {
+            if remaining > 0 {
+                remaining -= 1;
+                let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+                file.write_all(b"another line\n").unwrap();
+            }
+        })));
+    }
+
+    fn stored_entry(archive: &Archive, apath: &str) -> IndexEntry {
+        archive
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap()
+            .iter_filtered(None, None)
+            .unwrap()
+            .find(|entry| entry.apath() == apath)
+            .unwrap()
+    }
+
+    #[test]
+    fn reread_file_that_changed_while_reading() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        let path = tf.create_file_with_contents("log", b"first line\n");
+        append_before_read(path, 1);
+        let options = BackupOptions {
+            retry_changed: 2,
+            ..BackupOptions::default()
+        };
+
+        let stats = af.backup(tf.path(), &options).unwrap();
+        test_hooks::set_before_read(None);
+
+        assert_eq!(stats.changed_files_retried, 1);
+        assert_eq!(stats.changed_during_backup, 0);
+        let entry = stored_entry(&af, "/log");
+        assert!(!entry.changed_during_backup);
+        assert_eq!(entry.size(), Some(24));
+    }
+
+    #[test]
+    fn mark_file_still_changing_after_retries() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        let path = tf.create_file_with_contents("log", b"first line\n");
+        append_before_read(path, 2);
+        let options = BackupOptions {
+            retry_changed: 1,
+            ..BackupOptions::default()
+        };
+
+        let stats = af.backup(tf.path(), &options).unwrap();
+        test_hooks::set_before_read(None);
+
+        assert_eq!(stats.changed_files_retried, 1);
+        assert_eq!(stats.changed_during_backup, 1);
+        assert!(stored_entry(&af, "/log").changed_during_backup);
+        let validate_stats = af.validate(&ValidateOptions::default()).unwrap();
+        assert_eq!(validate_stats.changed_during_backup_count, 1);
+        assert!(!validate_stats.has_problems());
+
+        // The next backup stores the file as it is now, without the mark.
+        let stats = af.backup(tf.path(), &options).unwrap();
+        assert_eq!(stats.modified_files, 1);
+        assert_eq!(stats.changed_during_backup, 0);
+        let entry = stored_entry(&af, "/log");
+        assert!(!entry.changed_during_backup);
+        assert_eq!(entry.size(), Some(37));
+    }
+
+    #[test]
+    fn empty_files_are_not_opened() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents("empty", b"");
+        tf.create_file_with_contents("full", b"contents");
+        let read = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let read_clone = read.clone();
+        test_hooks::set_before_read(Some(Box::new(move |apath| {
+            read_clone.lock().unwrap().push(apath.to_string())
+        })));
+
+        let stats = af.backup(tf.path(), &BackupOptions::default()).unwrap();
+        test_hooks::set_before_read(None);
+
+        assert_eq!(*read.lock().unwrap(), ["/full"]);
+        assert_eq!(stats.empty_files, 1);
+        assert_eq!(stats.files, 2);
+        let entry = stored_entry(&af, "/empty");
+        assert!(entry.addrs.is_empty());
+        assert_eq!(entry.size(), Some(0));
+    }
+
+    #[test]
+    fn cancel_leaves_incomplete_band() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents("a", b"hello");
+        tf.create_file_with_contents("big", &vec![b'b'; MAX_BLOCK_SIZE * 2]);
+        tf.create_file_with_contents("z", b"goodbye");
+        let options = BackupOptions::default();
+        let cancel = options.cancel.clone();
+        test_hooks::set_before_read(Some(Box::new(move |apath| {
+            if apath == "/big" {
+                cancel.cancel();
+            }
+        })));
+
+        let result = af.backup(tf.path(), &options);
+        test_hooks::set_before_read(None);
+
+        match result {
+            Err(Error::BackupCancelled { stats }) => {
+                assert_eq!(stats.written_blocks, 1);
+                assert_eq!(stats.index_builder_stats.index_hunks, 1);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(af.last_complete_band().unwrap().is_none());
+        let band = Band::open(&af, &af.last_band_id().unwrap().unwrap()).unwrap();
+        assert!(!band.is_closed().unwrap());
+        let apaths: Vec<String> = af
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap()
+            .iter_filtered(None, None)
+            .unwrap()
+            .map(|entry| entry.apath().to_string())
+            .collect();
+        assert_eq!(apaths, ["/", "/a"]);
+        for entry in walkdir::WalkDir::new(af.path()) {
+            let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+            assert!(
+                !name.starts_with(crate::TMP_PREFIX),
+                "{:?} left behind",
+                name
+            );
+        }
+
+        // The lock was released, and the next backup completes.
+        let stats = af.backup(tf.path(), &BackupOptions::default()).unwrap();
+        assert_eq!(stats.files, 3);
+        assert!(af.last_complete_band().unwrap().is_some());
+    }
 }
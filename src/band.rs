@@ -21,11 +21,15 @@
 //! To read a consistent tree possibly composed from several incremental backups, use
 //! StoredTree rather than the Band itself.
 
+use std::str::FromStr;
+
 use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::index::IndexFormat;
 use crate::jsonio::{read_json, write_json};
 use crate::misc::remove_item;
+use crate::stats::{BackupStats, BandUsage};
 use crate::transport::{ListDirNames, Transport};
 use crate::*;
 
@@ -33,21 +37,54 @@ static INDEX_DIR: &str = "i";
 
 /// Band format-compatibility. Bands written out by this program, can only be
 /// read correctly by versions equal or later than the stated version.
-pub const BAND_FORMAT_VERSION: &str = "0.6.3";
+///
+/// This is 0.6.11 because names in the index are escaped, and earlier
+/// versions would restore the escapes literally.
+pub const BAND_FORMAT_VERSION: &str = "0.6.11";
+
+/// Format version of bands whose index is written in CBOR.
+pub const CBOR_BAND_FORMAT_VERSION: &str = "0.6.11";
+
+/// Format version of bands whose index hunks are stored in packs.
+pub const PACKED_INDEX_BAND_FORMAT_VERSION: &str = "0.6.11";
 
 /// Describes how to select a band from an archive.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BandSelectionPolicy {
     /// Open the latest complete band.
     LatestClosed,
-    /// Open the latest band, regardless of whether it's complete.
+    /// Open the latest band, regardless of whether it's complete, with a
+    /// warning if it's not.
     Latest,
     /// Open the band with the specified id.
     Specified(BandId),
+    /// Open the most recent band carrying this tag.
+    Tagged(String),
+    /// Open the latest complete band that started no later than the given time.
+    LatestClosedBefore(DateTime<Utc>),
 }
 
+impl FromStr for BandSelectionPolicy {
+    type Err = Error;
+
+    /// Select a band by id if the string is a valid band id, and otherwise
+    /// by tag.
+    fn from_str(s: &str) -> Result<BandSelectionPolicy> {
+        if let Ok(band_id) = s.parse() {
+            Ok(BandSelectionPolicy::Specified(band_id))
+        } else {
+            validate_tag(s)?;
+            Ok(BandSelectionPolicy::Tagged(s.to_owned()))
+        }
+    }
+}
+
+/// Format version recorded by `conserve migrate` for bands written before
+/// versions were marked in the head.
+const UNMARKED_BAND_FORMAT_VERSION: &str = "0.6.0";
+
 fn band_version_requirement() -> semver::VersionReq {
-    semver::VersionReq::parse("<=0.6.3").unwrap()
+    semver::VersionReq::parse("<=0.6.11").unwrap()
 }
 
 fn band_version_supported(version: &str) -> bool {
@@ -61,8 +98,38 @@ fn band_version_supported(version: &str) -> bool {
 pub struct Band {
     band_id: BandId,
 
-    /// Transport pointing to the archive directory.
+    /// Transport pointing to the band directory.
     transport: Box<dyn Transport>,
+
+    /// Transport for the index directory, which decrypts the index if the
+    /// archive is encrypted.
+    index_transport: Box<dyn Transport>,
+
+    /// Serialization of the index hunks.
+    index_format: IndexFormat,
+
+    /// If set, index hunks are stored in packs of this many.
+    index_hunks_per_pack: Option<u32>,
+
+    /// True if names in the index are escaped.
+    escaped_names: bool,
+}
+
+/// Options for creating a new band.
+#[derive(Debug, Default, Clone)]
+pub struct BandOptions {
+    /// Tags to record on the band.
+    pub tags: Vec<String>,
+
+    /// Serialization for the band's index hunks.
+    pub index_format: IndexFormat,
+
+    /// How files were split into blocks, recorded for information.
+    pub chunking: Chunking,
+
+    /// Store index hunks in packs of this many, if set, rather than each in
+    /// its own file.
+    pub index_hunks_per_pack: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -73,6 +140,29 @@ struct Head {
     /// Semver string for the minimum Conserve version to read this band
     /// correctly.
     band_format_version: Option<String>,
+
+    /// User-assigned names for this band.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+
+    /// Serialization of the index hunks, if not json.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_format: Option<IndexFormat>,
+
+    /// How files were split into blocks, if not fixed-size.
+    #[serde(default, skip_serializing_if = "Chunking::is_fixed")]
+    chunking: Chunking,
+
+    /// Number of index hunks stored in each pack, if they're packed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_hunks_per_pack: Option<u32>,
+
+    /// True if names that aren't UTF-8, or that contain the characters used
+    /// as escapes, are escaped in the index, as described in [crate::names].
+    ///
+    /// Set in bands written by 0.6.11 and later.
+    #[serde(default, skip_serializing_if = "crate::misc::is_false")]
+    escaped_names: bool,
 }
 
 /// Format of the on-disk tail file.
@@ -85,9 +175,55 @@ struct Tail {
     ///
     /// Present from 0.6.4 onwards.
     index_hunk_count: Option<u64>,
+
+    /// Number of entries in the index.
+    ///
+    /// Present in bands written by backups from 0.6.11 onwards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_entry_count: Option<u64>,
+
+    /// Total size of the files in this band.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_file_bytes: Option<u64>,
+
+    /// Number of new blocks written while making this band.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    block_count_written: Option<u64>,
+}
+
+/// Counts recorded in the tail when a band is closed, so that they can be
+/// read back without scanning the index.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BandTotals {
+    pub index_hunk_count: u64,
+    pub index_entry_count: u64,
+    pub total_file_bytes: u64,
+    pub block_count_written: u64,
+}
+
+impl BandTotals {
+    /// Take the totals from the stats of the backup that wrote the band.
+    pub(crate) fn from_backup_stats(stats: &BackupStats) -> BandTotals {
+        let index = &stats.index_builder_stats;
+        BandTotals {
+            index_hunk_count: index.index_hunks as u64,
+            index_entry_count: index.index_entries,
+            total_file_bytes: index.file_bytes,
+            block_count_written: stats.written_blocks as u64,
+        }
+    }
+}
+
+/// Contents of the band usage cache file.
+#[derive(Debug, Serialize, Deserialize)]
+struct UsageCache {
+    /// All the bands in the archive when the usage was measured.
+    band_ids: Vec<String>,
+    usage: BandUsage,
 }
 
 /// Readonly summary info about a band, from `Band::get_info`.
+#[derive(Clone, Debug)]
 pub struct Info {
     pub id: BandId,
     pub is_closed: bool,
@@ -100,6 +236,18 @@ pub struct Info {
 
     /// Number of hunks present in the index, if that is known.
     pub index_hunk_count: Option<u64>,
+
+    /// Number of entries in the index, if that is known.
+    pub index_entry_count: Option<u64>,
+
+    /// Total size of the files in the band, if that is known.
+    pub total_file_bytes: Option<u64>,
+
+    /// Number of new blocks written while making the band, if that is known.
+    pub block_count_written: Option<u64>,
+
+    /// User-assigned tags, in the order they were added.
+    pub tags: Vec<String>,
 }
 
 // TODO: Maybe merge Band with StoredTree and/or with the Index classes? The distinction seems
@@ -109,6 +257,14 @@ impl Band {
     ///
     /// The Band gets the next id after those that already exist.
     pub fn create(archive: &Archive) -> Result<Band> {
+        Band::create_with_options(archive, &BandOptions::default())
+    }
+
+    /// Make a new band with the given tags and index format.
+    pub fn create_with_options(archive: &Archive, options: &BandOptions) -> Result<Band> {
+        for tag in &options.tags {
+            validate_tag(tag)?;
+        }
         let band_id = archive
             .last_band_id()?
             .map_or_else(BandId::zero, |b| b.next_sibling());
@@ -117,12 +273,32 @@ impl Band {
             .create_dir("")
             .and_then(|()| transport.create_dir(INDEX_DIR))
             .map_err(|source| Error::CreateBand { source })?;
+        let (mut band_format_version, index_format) = match options.index_format {
+            IndexFormat::Json => (BAND_FORMAT_VERSION, None),
+            IndexFormat::Cbor => (CBOR_BAND_FORMAT_VERSION, Some(IndexFormat::Cbor)),
+        };
+        if options.index_hunks_per_pack.is_some() {
+            band_format_version = PACKED_INDEX_BAND_FORMAT_VERSION;
+        }
         let head = Head {
             start_time: Utc::now().timestamp(),
-            band_format_version: Some(BAND_FORMAT_VERSION.to_owned()),
+            band_format_version: Some(band_format_version.to_owned()),
+            tags: dedup_tags(options.tags.iter().cloned()),
+            index_format,
+            chunking: options.chunking,
+            index_hunks_per_pack: options.index_hunks_per_pack,
+            escaped_names: true,
         };
         write_json(&transport, BAND_HEAD_FILENAME, &head)?;
-        Ok(Band { band_id, transport })
+        let index_transport = archive.content_transport(&index_relpath(&band_id));
+        Ok(Band {
+            band_id,
+            transport,
+            index_transport,
+            index_format: options.index_format,
+            index_hunks_per_pack: options.index_hunks_per_pack,
+            escaped_names: true,
+        })
     }
 
     /// Mark this band closed: no more blocks should be written after this.
@@ -133,6 +309,68 @@ impl Band {
             &Tail {
                 end_time: Utc::now().timestamp(),
                 index_hunk_count: Some(index_hunk_count),
+                index_entry_count: None,
+                total_file_bytes: None,
+                block_count_written: None,
+            },
+        )
+    }
+
+    /// Mark this band closed, recording totals of what it contains.
+    pub fn close_with_totals(&self, totals: &BandTotals) -> Result<()> {
+        write_json(
+            &self.transport,
+            BAND_TAIL_FILENAME,
+            &Tail {
+                end_time: Utc::now().timestamp(),
+                index_hunk_count: Some(totals.index_hunk_count),
+                index_entry_count: Some(totals.index_entry_count),
+                total_file_bytes: Some(totals.total_file_bytes),
+                block_count_written: Some(totals.block_count_written),
+            },
+        )
+    }
+
+    /// Record the stats of the backup that wrote this band.
+    ///
+    /// This should be called just before the band is closed.
+    pub(crate) fn write_stats(&self, stats: &BackupStats) -> Result<()> {
+        write_json(&self.transport, BAND_STATS_FILENAME, stats)
+    }
+
+    /// Read the stats of the backup that wrote this band, or None if they
+    /// weren't recorded, as for bands written before 0.6.11 or still
+    /// incomplete.
+    pub fn read_stats(&self) -> Result<Option<BackupStats>> {
+        if self.transport.exists(BAND_STATS_FILENAME)? {
+            Ok(Some(read_json(&self.transport, BAND_STATS_FILENAME)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read this band's cached usage, if it was measured when the archive
+    /// held exactly `band_ids`.
+    ///
+    /// A missing, stale, or unreadable cache gives None.
+    pub(crate) fn read_usage(&self, band_ids: &[String]) -> Result<Option<BandUsage>> {
+        if !self.transport.exists(BAND_USAGE_FILENAME)? {
+            return Ok(None);
+        }
+        let cache: Option<UsageCache> = read_json(&self.transport, BAND_USAGE_FILENAME).ok();
+        Ok(cache
+            .filter(|cache| cache.band_ids == band_ids)
+            .map(|cache| cache.usage))
+    }
+
+    /// Cache this band's usage, measured when the archive held `band_ids`.
+    pub(crate) fn write_usage(&self, band_ids: &[String], usage: &BandUsage) -> Result<()> {
+        write_json(
+            &self.transport,
+            BAND_USAGE_FILENAME,
+            &UsageCache {
+                band_ids: band_ids.to_vec(),
+                usage: usage.clone(),
             },
         )
     }
@@ -140,11 +378,18 @@ impl Band {
     /// Open the band with the given id.
     pub fn open(archive: &Archive, band_id: &BandId) -> Result<Band> {
         let transport: Box<dyn Transport> = archive.transport().sub_transport(&band_id.to_string());
-        let new = Band {
+        let mut new = Band {
             band_id: band_id.to_owned(),
             transport,
+            index_transport: archive.content_transport(&index_relpath(band_id)),
+            index_format: IndexFormat::Json,
+            index_hunks_per_pack: None,
+            escaped_names: false,
         };
         let head = new.read_head()?;
+        new.index_format = head.index_format.unwrap_or_default();
+        new.index_hunks_per_pack = head.index_hunks_per_pack;
+        new.escaped_names = head.escaped_names;
         if let Some(version) = head.band_format_version {
             if !band_version_supported(&version) {
                 return Err(Error::UnsupportedBandVersion {
@@ -153,8 +398,9 @@ impl Band {
                 });
             }
         } else {
-            // Unmarked, old bands, are accepted for now. In the next archive
-            // version, band version markers ought to become mandatory.
+            // Unmarked, old bands, are accepted for now, and `conserve migrate`
+            // can mark them. In the next archive version, band version markers
+            // ought to become mandatory.
         }
         Ok(new)
     }
@@ -182,12 +428,17 @@ impl Band {
     }
 
     pub fn index_builder(&self) -> IndexWriter {
-        IndexWriter::new(self.transport.sub_transport(INDEX_DIR))
+        IndexWriter::new(self.index_transport.clone())
+            .with_format(self.index_format)
+            .with_hunks_per_pack(self.index_hunks_per_pack)
     }
 
     /// Get read-only access to the index of this band.
     pub fn index(&self) -> IndexRead {
-        IndexRead::open(self.transport.sub_transport(INDEX_DIR))
+        IndexRead::open(self.index_transport.clone())
+            .with_format(self.index_format)
+            .with_hunks_per_pack(self.index_hunks_per_pack)
+            .with_escaped_names(self.escaped_names)
     }
 
     /// Return an iterator through entries in this band.
@@ -195,6 +446,141 @@ impl Band {
         self.index().iter_entries()
     }
 
+    /// Copy the head and index of this band, verbatim, into a new band
+    /// with the same id in another archive.
+    ///
+    /// The tail is not copied: the caller should call `copy_tail_to` once the
+    /// blocks referenced by the index are also present in the destination,
+    /// so that the destination band is only complete once it's usable.
+    ///
+    /// Returns the number of index hunks copied.
+    pub(crate) fn copy_head_and_index_to(&self, dest: &Archive) -> Result<usize> {
+        let dest_transport = dest.transport().sub_transport(&self.band_id.to_string());
+        dest_transport
+            .create_dir("")
+            .and_then(|()| dest_transport.create_dir(INDEX_DIR))
+            .map_err(|source| Error::CreateBand { source })?;
+        copy_file(
+            self.transport.as_ref(),
+            dest_transport.as_ref(),
+            BAND_HEAD_FILENAME,
+        )?;
+        // Index hunks are copied through the content transports, so they're
+        // re-encrypted if either archive is encrypted.
+        let dest_index_transport = dest.content_transport(&index_relpath(&self.band_id));
+        let mut hunks = 0;
+        let mut subdirs = self.index_transport.list_dir_names("")?.dirs;
+        subdirs.sort();
+        for subdir in subdirs {
+            dest_index_transport.create_dir(&subdir)?;
+            let mut names = self.index_transport.list_dir_names(&subdir)?.files;
+            names.retain(|name| !name.starts_with(TMP_PREFIX));
+            names.sort();
+            for name in names {
+                copy_file(
+                    self.index_transport.as_ref(),
+                    dest_index_transport.as_ref(),
+                    &format!("{}/{}", subdir, name),
+                )?;
+                hunks += 1;
+            }
+        }
+        if self.index_hunks_per_pack.is_some() {
+            // Each file copied was a pack of hunks.
+            hunks = self.index().count_hunks()? as usize;
+        }
+        if self.index_transport.exists(index::HUNK_MANIFEST_FILENAME)? {
+            copy_file(
+                self.index_transport.as_ref(),
+                dest_index_transport.as_ref(),
+                index::HUNK_MANIFEST_FILENAME,
+            )?;
+        }
+        Ok(hunks)
+    }
+
+    /// Copy the stats (if any) and tail of this band, marking the
+    /// corresponding band in the destination archive complete.
+    pub(crate) fn copy_tail_to(&self, dest: &Archive) -> Result<()> {
+        let dest_transport = dest.transport().sub_transport(&self.band_id.to_string());
+        if self.transport.exists(BAND_STATS_FILENAME)? {
+            copy_file(
+                self.transport.as_ref(),
+                dest_transport.as_ref(),
+                BAND_STATS_FILENAME,
+            )?;
+        }
+        copy_file(
+            self.transport.as_ref(),
+            dest_transport.as_ref(),
+            BAND_TAIL_FILENAME,
+        )
+    }
+
+    /// Add a format version to the head of a band written before versions were
+    /// recorded.
+    ///
+    /// Returns true if the head was (or, in a dry run, would be) changed.
+    pub(crate) fn upgrade_head(&self, dry_run: bool) -> Result<bool> {
+        let mut head = self.read_head()?;
+        if head.band_format_version.is_some() {
+            return Ok(false);
+        }
+        head.band_format_version = Some(UNMARKED_BAND_FORMAT_VERSION.to_owned());
+        if !dry_run {
+            write_json(&self.transport, BAND_HEAD_FILENAME, &head)?;
+        }
+        Ok(true)
+    }
+
+    /// Add the index hunk count to the tail of a closed band written before
+    /// 0.6.4.
+    ///
+    /// Returns true if the tail was (or, in a dry run, would be) changed.
+    pub(crate) fn upgrade_tail(&self, dry_run: bool) -> Result<bool> {
+        let mut tail = match self.read_tail()? {
+            Some(tail) if tail.index_hunk_count.is_none() => tail,
+            _ => return Ok(false),
+        };
+        tail.index_hunk_count = Some(self.index().count_hunks()?.into());
+        if !dry_run {
+            write_json(&self.transport, BAND_TAIL_FILENAME, &tail)?;
+        }
+        Ok(true)
+    }
+
+    /// Return the tags on this band.
+    pub fn tags(&self) -> Result<Vec<String>> {
+        Ok(self.read_head()?.tags)
+    }
+
+    /// Return how files in this band were split into blocks.
+    pub fn chunking(&self) -> Result<Chunking> {
+        Ok(self.read_head()?.chunking)
+    }
+
+    /// Add and remove tags on this band, rewriting its head.
+    ///
+    /// Returns the resulting tags.
+    pub(crate) fn edit_tags(&self, add: &[String], remove: &[String]) -> Result<Vec<String>> {
+        for tag in add {
+            validate_tag(tag)?;
+        }
+        let mut head = self.read_head()?;
+        let tags = dedup_tags(
+            head.tags
+                .iter()
+                .chain(add)
+                .filter(|tag| !remove.contains(tag))
+                .cloned(),
+        );
+        if tags != head.tags {
+            head.tags = tags.clone();
+            write_json(&self.transport, BAND_HEAD_FILENAME, &head)?;
+        }
+        Ok(tags)
+    }
+
     fn read_head(&self) -> Result<Head> {
         read_json(&self.transport, BAND_HEAD_FILENAME)
     }
@@ -224,43 +610,97 @@ impl Band {
                 .as_ref()
                 .map(|tail| Utc.timestamp(tail.end_time, 0)),
             index_hunk_count: tail_option.as_ref().and_then(|tail| tail.index_hunk_count),
+            index_entry_count: tail_option.as_ref().and_then(|tail| tail.index_entry_count),
+            total_file_bytes: tail_option.as_ref().and_then(|tail| tail.total_file_bytes),
+            block_count_written: tail_option
+                .as_ref()
+                .and_then(|tail| tail.block_count_written),
+            tags: head.tags,
         })
     }
 
-    pub fn validate(&self, stats: &mut ValidateStats) -> Result<()> {
+    pub fn validate(&self, stats: &mut ValidateStats, problems: &dyn ProblemSink) -> Result<()> {
         let ListDirNames { mut files, dirs } =
             self.transport.list_dir_names("").map_err(Error::from)?;
         if !files.contains(&BAND_HEAD_FILENAME.to_string()) {
-            ui::problem(&format!("No band head file in {:?}", self.transport));
+            problems.report(Problem::new(
+                ProblemKind::ArchiveStructure,
+                None,
+                format!("No band head file in {:?}", self.transport),
+            ));
             stats.missing_band_heads += 1;
         }
         remove_item(&mut files, &BAND_HEAD_FILENAME);
         remove_item(&mut files, &BAND_TAIL_FILENAME);
+        remove_item(&mut files, &BAND_STATS_FILENAME);
+        remove_item(&mut files, &BAND_USAGE_FILENAME);
 
         if !files.is_empty() {
-            ui::problem(&format!(
-                "Unexpected files in band directory {:?}: {:?}",
-                self.transport, files
+            problems.report(Problem::new(
+                ProblemKind::ArchiveStructure,
+                None,
+                format!(
+                    "Unexpected files in band directory {:?}: {:?}",
+                    self.transport, files
+                ),
             ));
             stats.unexpected_files += 1;
         }
 
         if dirs != [INDEX_DIR.to_string()] {
-            ui::problem(&format!(
-                "Incongruous directories in band directory {:?}: {:?}",
-                self.transport, dirs
+            problems.report(Problem::new(
+                ProblemKind::ArchiveStructure,
+                None,
+                format!(
+                    "Incongruous directories in band directory {:?}: {:?}",
+                    self.transport, dirs
+                ),
             ));
             stats.unexpected_files += 1;
         }
 
+        self.index().validate_hunks(&self.band_id, stats)
+    }
+}
+
+/// Return the archive-relative path of the index directory for a band.
+fn index_relpath(band_id: &BandId) -> String {
+    format!("{}/{}", band_id, INDEX_DIR)
+}
+
+/// Check that a tag can be used to select a band: it must be non-empty, and
+/// not look like a band id.
+fn validate_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || tag.trim() != tag || tag.parse::<BandId>().is_ok() {
+        Err(Error::InvalidTag {
+            tag: tag.to_owned(),
+        })
+    } else {
         Ok(())
     }
 }
 
+/// Remove repeated tags, keeping the first occurrence of each.
+fn dedup_tags<I: Iterator<Item = String>>(tags: I) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for tag in tags {
+        if !result.contains(&tag) {
+            result.push(tag);
+        }
+    }
+    result
+}
+
+/// Copy one file verbatim between transports.
+fn copy_file(from: &dyn Transport, to: &dyn Transport, relpath: &str) -> Result<()> {
+    let mut buf = Vec::new();
+    from.read_file(relpath, &mut buf)?;
+    to.write_file(relpath, &buf).map_err(Error::from)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
-    use std::str::FromStr;
 
     use chrono::Duration;
     use serde_json::json;
@@ -302,6 +742,38 @@ mod tests {
         assert!(dur < Duration::seconds(5));
     }
 
+    #[test]
+    fn write_and_read_stats() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        assert_eq!(band.read_stats().unwrap(), None);
+
+        let stats = BackupStats {
+            files: 3,
+            new_files: 2,
+            written_blocks: 1,
+            compressed_bytes: 1234,
+            ..BackupStats::default()
+        };
+        band.write_stats(&stats).unwrap();
+        assert!(af.path().join("b0000").join("stats.json").is_file());
+        assert_eq!(band.read_stats().unwrap(), Some(stats));
+    }
+
+    #[test]
+    fn read_stats_with_missing_and_unknown_keys() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        fs::write(
+            af.path().join("b0000").join("stats.json"),
+            r#"{"files": 7, "some_future_count": 1}"#,
+        )
+        .unwrap();
+        let stats = band.read_stats().unwrap().unwrap();
+        assert_eq!(stats.files, 7);
+        assert_eq!(stats.written_blocks, 0);
+    }
+
     #[test]
     fn delete_band() {
         let af = ScratchArchive::new();
@@ -333,4 +805,114 @@ mod tests {
             e_str
         );
     }
+
+    #[test]
+    fn tags_recorded_at_creation() {
+        let af = ScratchArchive::new();
+        let tags = vec!["first".to_owned(), "weekly".to_owned(), "first".to_owned()];
+        let options = BandOptions {
+            tags,
+            ..Default::default()
+        };
+        let band = Band::create_with_options(&af, &options).unwrap();
+        assert_eq!(band.tags().unwrap(), ["first", "weekly"]);
+        band.close(0).unwrap();
+        let info = Band::open(&af, &BandId::zero())
+            .unwrap()
+            .get_info()
+            .unwrap();
+        assert_eq!(info.tags, ["first", "weekly"]);
+    }
+
+    #[test]
+    fn untagged_head_has_no_tags_key() {
+        let af = ScratchArchive::new();
+        Band::create(&af).unwrap();
+        let head = fs::read_to_string(af.path().join("b0000").join(BAND_HEAD_FILENAME)).unwrap();
+        assert!(!head.contains("tags"));
+    }
+
+    #[test]
+    fn edit_tags() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        band.close(0).unwrap();
+        let tags = af
+            .edit_band_tags(
+                &BandId::zero(),
+                &["a".to_owned(), "b".to_owned()],
+                &[],
+                false,
+            )
+            .unwrap();
+        assert_eq!(tags, ["a", "b"]);
+        let tags = af
+            .edit_band_tags(&BandId::zero(), &[], &["a".to_owned()], false)
+            .unwrap();
+        assert_eq!(tags, ["b"]);
+        assert_eq!(band.tags().unwrap(), ["b"]);
+        assert!(band.is_closed().unwrap());
+        assert!(!af
+            .validate(&ValidateOptions::default())
+            .unwrap()
+            .has_problems());
+    }
+
+    #[test]
+    fn invalid_tags() {
+        let af = ScratchArchive::new();
+        for tag in &["", "b0001", " padded"] {
+            let options = BandOptions {
+                tags: vec![tag.to_string()],
+                ..Default::default()
+            };
+            assert!(matches!(
+                Band::create_with_options(&af, &options),
+                Err(Error::InvalidTag { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_band_selection_policy() {
+        assert_eq!(
+            "b0012".parse::<BandSelectionPolicy>().unwrap(),
+            BandSelectionPolicy::Specified(BandId::new(&[12]))
+        );
+        assert_eq!(
+            "pre-upgrade".parse::<BandSelectionPolicy>().unwrap(),
+            BandSelectionPolicy::Tagged("pre-upgrade".to_owned())
+        );
+        assert!("".parse::<BandSelectionPolicy>().is_err());
+    }
+
+    #[test]
+    fn select_newest_tagged_band() {
+        let af = ScratchArchive::new();
+        let tagged = BandOptions {
+            tags: vec!["keep".to_owned()],
+            ..Default::default()
+        };
+        Band::create_with_options(&af, &tagged)
+            .unwrap()
+            .close(0)
+            .unwrap();
+        Band::create_with_options(&af, &tagged)
+            .unwrap()
+            .close(0)
+            .unwrap();
+        Band::create(&af).unwrap().close(0).unwrap();
+        assert_eq!(
+            af.resolve_band(&BandSelectionPolicy::Tagged("keep".to_owned()))
+                .unwrap(),
+            BandId::new(&[1])
+        );
+        match af.resolve_band(&BandSelectionPolicy::Tagged("missing".to_owned())) {
+            Err(Error::TagNotFound { tag, available }) => {
+                assert_eq!(tag, "missing");
+                assert_eq!(available, ["keep"]);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
@@ -16,6 +16,8 @@
 use std::fmt;
 use std::str::FromStr;
 
+use serde::{Serialize, Serializer};
+
 use crate::errors::Error;
 
 /// Identifier for a band within an archive, eg 'b0001' or 'b0001-0020'.
@@ -118,6 +120,13 @@ impl fmt::Display for BandId {
     }
 }
 
+impl Serialize for BandId {
+    /// Serialize as the string form, like `"b0001"`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -13,9 +13,11 @@
 
 //! Command-line entry point for Conserve backups.
 
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
 use structopt::StructOpt;
 
 use conserve::backup::BackupOptions;
@@ -29,18 +31,127 @@ use conserve::*;
     about = "A robust backup tool <https://github.com/sourcefrog/conserve/>",
     author
 )]
+struct Args {
+    /// Read the secret for an encrypted archive from this file, rather than
+    /// from $CONSERVE_KEY_FILE or $CONSERVE_PASSPHRASE or a prompt.
+    #[structopt(long, global = true)]
+    key_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
 enum Command {
     /// Copy source directory into an archive.
     Backup {
         /// Path of an existing archive.
         archive: PathBuf,
-        /// Source directory to copy from.
-        source: PathBuf,
+        /// Source directories to copy from.
+        ///
+        /// One source is stored as the root of the backup. Several sources
+        /// are each stored under their absolute path, or under the apaths
+        /// given by `--as`.
+        #[structopt(required = true)]
+        source: Vec<PathBuf>,
+        /// Store the corresponding source under this apath, such as `/etc`.
+        #[structopt(long = "as", number_of_values = 1)]
+        as_apath: Vec<Apath>,
         /// Print copied file names.
         #[structopt(long, short)]
         verbose: bool,
+        /// Exclude files matching this glob pattern, in addition to those
+        /// configured in the archive.
         #[structopt(long, short, number_of_values = 1)]
         exclude: Vec<String>,
+        /// Skip the contents of directories marked by a CACHEDIR.TAG file.
+        #[structopt(long)]
+        exclude_caches: bool,
+        /// Back up the contents of cache directories, even if the archive is
+        /// configured to skip them.
+        #[structopt(long, conflicts_with = "exclude-caches")]
+        no_exclude_caches: bool,
+        /// Skip the contents of directories holding other Conserve archives.
+        ///
+        /// The archive being written is always skipped.
+        #[structopt(long)]
+        exclude_other_archives: bool,
+        /// Break a lock left behind by a previous interrupted backup or gc.
+        #[structopt(long)]
+        break_lock: bool,
+        /// Record a tag on the new backup, so that it can be selected by name.
+        #[structopt(long, number_of_values = 1)]
+        tag: Vec<String>,
+        /// Serialization of the new backup's index: "json" or "cbor".
+        ///
+        /// CBOR indexes are smaller and faster to read, but can't be read by
+        /// Conserve before 0.6.11. By default, the format set when the archive
+        /// was created, or otherwise json.
+        #[structopt(long)]
+        index_format: Option<IndexFormat>,
+        /// Store the new backup's index hunks together in pack files, rather
+        /// than each in its own file.
+        ///
+        /// Packs need many fewer files and writes, but can't be read by
+        /// Conserve before 0.6.11.
+        #[structopt(long)]
+        pack_index: bool,
+        /// Read a file again, up to this many times, if it changes while
+        /// it's being read. Files still changing after that are marked in the
+        /// index.
+        #[structopt(long, default_value = "0")]
+        retry_changed: usize,
+        /// Recognize files moved or renamed since the last backup by their
+        /// inode number, and reuse their stored content without reading them.
+        #[structopt(long)]
+        detect_moves: bool,
+        /// Refuse to back up if the clock reads earlier than the start of the previous backup.
+        #[structopt(long)]
+        strict_time: bool,
+        /// Don't descend into directories on other filesystems, such as
+        /// mount points.
+        #[structopt(long, short = "x")]
+        one_file_system: bool,
+        /// Skip files larger than this many bytes.
+        #[structopt(long)]
+        max_file_size: Option<u64>,
+        /// Split large files into blocks at boundaries chosen by their
+        /// content, so that inserting bytes only changes nearby blocks.
+        #[structopt(long)]
+        cdc: bool,
+        /// Smallest block, in bytes, with --cdc: by default 65536.
+        #[structopt(long, requires = "cdc")]
+        cdc_min_size: Option<usize>,
+        /// Typical block size, in bytes, with --cdc: by default 262144.
+        #[structopt(long, requires = "cdc")]
+        cdc_avg_size: Option<usize>,
+        /// Largest block, in bytes, with --cdc: by default 1048576.
+        #[structopt(long, requires = "cdc")]
+        cdc_max_size: Option<usize>,
+        /// Report what would be stored, without writing anything to the
+        /// archive.
+        ///
+        /// By default, new and modified files are found by comparing their
+        /// metadata to the previous backup, without reading them. With
+        /// `--dry-run=hash` they're also read and hashed, to count how many of
+        /// their blocks are already stored.
+        #[structopt(
+            long,
+            min_values = 0,
+            max_values = 1,
+            require_equals = true,
+            possible_values = &["hash"]
+        )]
+        dry_run: Option<Option<String>>,
+        /// Print the stats as json.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Show the default options configured in an archive.
+    Config {
+        /// Path of the archive.
+        archive: PathBuf,
     },
 
     Debug(Debug),
@@ -55,7 +166,7 @@ enum Command {
         /// Don't actually delete, just check what could be deleted.
         #[structopt(long)]
         dry_run: bool,
-        /// Break a lock left behind by a previous interrupted gc operation, and then gc.
+        /// Break a lock left behind by a previous interrupted backup or gc, and then delete.
         #[structopt(long)]
         break_lock: bool,
         /// Delete indexes but don't garbage-collect blocks.
@@ -69,16 +180,108 @@ enum Command {
     Diff {
         archive: PathBuf,
         source: PathBuf,
+        /// Backup version number or tag.
+        #[structopt(long, short)]
+        backup: Option<BandSelectionPolicy>,
+        /// Use the latest complete backup started no later than this time: a date,
+        /// date and time, or relative time like "3 days ago".
+        #[structopt(long, visible_alias = "as-of", conflicts_with = "backup", parse(try_from_str = parse_timestamp))]
+        backup_before: Option<DateTime<Utc>>,
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Compare the contents of files that have the same size.
+        #[structopt(long)]
+        bytes: bool,
+        /// Show each difference as json, one entry per line.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Compare two source directories.
+    DiffTrees {
+        left: PathBuf,
+        right: PathBuf,
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Compare the contents of files that have the same size.
+        #[structopt(long)]
+        bytes: bool,
+        /// Show each difference as json, one entry per line.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Write a backup version as a tar file, or to stdout.
+    ExportTar {
+        archive: PathBuf,
+        /// Write to this file, rather than stdout.
+        #[structopt(long, short)]
+        output: Option<PathBuf>,
+        /// Backup version number or tag.
         #[structopt(long, short)]
-        backup: Option<BandId>,
+        backup: Option<BandSelectionPolicy>,
+        /// Use the latest complete backup started no later than this time: a date,
+        /// date and time, or relative time like "3 days ago".
+        #[structopt(long, visible_alias = "as-of", conflicts_with = "backup", parse(try_from_str = parse_timestamp))]
+        backup_before: Option<DateTime<Utc>>,
+        /// Compress the tar stream with gzip.
+        #[structopt(long)]
+        gz: bool,
+        #[structopt(long, short)]
+        verbose: bool,
         #[structopt(long, short, number_of_values = 1)]
         exclude: Vec<String>,
+        #[structopt(flatten)]
+        filter: EntryFilterArgs,
+        #[structopt(long = "only", short = "i", number_of_values = 1)]
+        only_subtree: Option<Apath>,
+    },
+
+    /// Store the contents of a tar file as a new backup version.
+    ImportTar {
+        /// Path of an existing archive.
+        archive: PathBuf,
+        /// Tar file to read, which may be gzipped, or "-" for stdin.
+        tar_file: PathBuf,
+        /// Print imported file names.
+        #[structopt(long, short)]
+        verbose: bool,
+        /// Exclude files matching this glob pattern, in addition to those
+        /// configured in the archive.
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Break a lock left behind by a previous interrupted backup or gc.
+        #[structopt(long)]
+        break_lock: bool,
+        /// Record a tag on the new backup, so that it can be selected by name.
+        #[structopt(long, number_of_values = 1)]
+        tag: Vec<String>,
+        /// Serialization of the new backup's index: "json" or "cbor".
+        #[structopt(long)]
+        index_format: Option<IndexFormat>,
     },
 
     /// Create a new archive.
     Init {
         /// Path for new archive.
         archive: PathBuf,
+        /// Encrypt blocks and indexes, with a key derived from a passphrase or key file.
+        ///
+        /// The key file is taken from --key-file or $CONSERVE_KEY_FILE, or the
+        /// passphrase from $CONSERVE_PASSPHRASE, or otherwise the passphrase is
+        /// prompted for.
+        #[structopt(long)]
+        encrypt: bool,
+        /// Exclude files matching this glob pattern from all backups into this archive.
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Skip the contents of cache directories in all backups into this archive.
+        #[structopt(long)]
+        exclude_caches: bool,
+        /// Serialization of the index of backups into this archive, unless
+        /// given for one backup: "json" or "cbor".
+        #[structopt(long)]
+        index_format: Option<IndexFormat>,
     },
 
     /// Delete blocks unreferenced by any index.
@@ -102,22 +305,90 @@ enum Command {
 
         #[structopt(long, short, number_of_values = 1)]
         exclude: Vec<String>,
+
+        #[structopt(flatten)]
+        filter: EntryFilterArgs,
+
+        /// Show the kind, size, and modification time of each entry.
+        #[structopt(long, short)]
+        long: bool,
+
+        /// Show the metadata of each entry as json, one entry per line.
+        #[structopt(long, conflicts_with = "long")]
+        json: bool,
+    },
+
+    /// Mount an archive as a read-only filesystem, with a directory for each
+    /// backup version, until interrupted.
+    #[cfg(all(unix, feature = "fuse"))]
+    Mount {
+        archive: PathBuf,
+        /// Existing directory to mount on.
+        mountpoint: PathBuf,
+    },
+
+    /// Upgrade archive metadata in place to the current format.
+    Migrate {
+        /// Archive to upgrade.
+        archive: PathBuf,
+        /// Don't actually change anything, just check what would be upgraded.
+        #[structopt(long)]
+        dry_run: bool,
+        /// Break a lock left behind by a previous interrupted operation, and then migrate.
+        #[structopt(long)]
+        break_lock: bool,
+    },
+
+    /// Check quickly that an archive can be opened and written, and has
+    /// space for another backup.
+    ///
+    /// Exits with code 4 if not, so that scheduled backups can alert early.
+    #[structopt(alias = "check-remote")]
+    Preflight {
+        /// Archive to check.
+        archive: PathBuf,
+        /// Write the report as json.
+        #[structopt(long)]
+        json: bool,
     },
 
     /// Copy a stored tree to a restore directory.
     Restore {
         archive: PathBuf,
-        destination: PathBuf,
+        #[structopt(required_unless = "to-stdout-tar")]
+        destination: Option<PathBuf>,
+        /// Write the restored tree as a tar stream to stdout, rather than to a directory.
+        #[structopt(long, conflicts_with_all = &["destination", "force-overwrite"])]
+        to_stdout_tar: bool,
+        /// Backup version number or tag.
         #[structopt(long, short)]
-        backup: Option<BandId>,
+        backup: Option<BandSelectionPolicy>,
+        /// Use the latest complete backup started no later than this time: a date,
+        /// date and time, or relative time like "3 days ago".
+        #[structopt(long, visible_alias = "as-of", conflicts_with = "backup", parse(try_from_str = parse_timestamp))]
+        backup_before: Option<DateTime<Utc>>,
         #[structopt(long, short)]
         force_overwrite: bool,
+        /// With --force-overwrite, delete existing directories where the backup has a file or symlink.
+        #[structopt(long, requires = "force-overwrite")]
+        replace_dirs: bool,
+        /// With --force-overwrite, refuse to write through symlinks already in the destination.
+        #[structopt(long, requires = "force-overwrite")]
+        secure_overwrite: bool,
         #[structopt(long, short)]
         verbose: bool,
         #[structopt(long, short, number_of_values = 1)]
         exclude: Vec<String>,
+        #[structopt(flatten)]
+        filter: EntryFilterArgs,
         #[structopt(long = "only", short = "i", number_of_values = 1)]
         only_subtree: Option<Apath>,
+        /// Don't restore extended attributes.
+        #[structopt(long)]
+        no_xattrs: bool,
+        /// Fail if --only selects no entries.
+        #[structopt(long, requires = "only-subtree")]
+        require_matches: bool,
     },
 
     /// Show the total size of files in a stored tree or source directory, with exclusions.
@@ -133,10 +404,74 @@ enum Command {
         exclude: Vec<String>,
     },
 
+    /// Show the stats recorded by a backup, and a summary of the archive's storage.
+    Stats {
+        archive: PathBuf,
+        /// Backup version number or tag.
+        #[structopt(long, short)]
+        backup: Option<BandSelectionPolicy>,
+        /// Use the latest complete backup started no later than this time: a date,
+        /// date and time, or relative time like "3 days ago".
+        #[structopt(long, visible_alias = "as-of", conflicts_with = "backup", parse(try_from_str = parse_timestamp))]
+        backup_before: Option<DateTime<Utc>>,
+        /// Print the stats as json.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Copy backup versions from one archive to another.
+    ///
+    /// Versions keep the same names, and only data blocks missing from the
+    /// destination are copied.
+    Sync {
+        /// Archive to copy from.
+        source_archive: PathBuf,
+        /// Existing archive to copy into.
+        dest_archive: PathBuf,
+        /// Backup to copy; by default all complete backups are copied.
+        #[structopt(long, short, number_of_values = 1)]
+        backup: Vec<BandId>,
+        /// Break a lock left behind on the destination by a previous interrupted operation.
+        #[structopt(long)]
+        break_lock: bool,
+    },
+
+    /// Add or remove tags on a backup version.
+    Tag {
+        /// Archive holding the backup.
+        archive: PathBuf,
+        /// Backup version to tag.
+        backup: BandId,
+        /// Tag to add.
+        #[structopt(long, number_of_values = 1)]
+        add: Vec<String>,
+        /// Tag to remove.
+        #[structopt(long, number_of_values = 1)]
+        remove: Vec<String>,
+        /// Break a lock left behind by a previous interrupted operation.
+        #[structopt(long)]
+        break_lock: bool,
+    },
+
     /// Check that an archive is internally consistent.
     Validate {
         /// Path of the archive to check.
         archive: PathBuf,
+        /// Use at most this many threads: by default, one per CPU.
+        #[structopt(long)]
+        threads: Option<usize>,
+        /// Check only this backup, and the blocks it references: may be
+        /// given several times. By default the whole archive is checked.
+        #[structopt(long, number_of_values = 1)]
+        bands: Vec<BandId>,
+        /// Don't check that block content matches its hash, only that the
+        /// blocks can be decompressed.
+        #[structopt(long)]
+        skip_block_hashes: bool,
+        /// Don't read blocks, only check that the blocks referenced are
+        /// present.
+        #[structopt(long)]
+        quick: bool,
     },
 
     /// List backup versions in an archive.
@@ -148,9 +483,13 @@ enum Command {
         /// Sort bands to show most recent first.
         #[structopt(long, short = "n")]
         newest: bool,
-        /// Show size of stored trees.
+        /// Show the size of each stored tree, and the compressed size of the
+        /// blocks used only by that version, and shared with other versions.
         #[structopt(long, short = "z", conflicts_with = "short")]
         sizes: bool,
+        /// Print the list as json.
+        #[structopt(long, conflicts_with = "short")]
+        json: bool,
     },
 }
 
@@ -163,8 +502,43 @@ struct StoredTreeOrSource {
     #[structopt(long, short, conflicts_with = "archive", required_unless = "archive")]
     source: Option<PathBuf>,
 
+    /// Backup version number or tag.
     #[structopt(long, short, conflicts_with = "source")]
-    backup: Option<BandId>,
+    backup: Option<BandSelectionPolicy>,
+    /// Use the latest complete backup started no later than this time: a date,
+    /// date and time, or relative time like "3 days ago".
+    #[structopt(long, visible_alias = "as-of", conflicts_with_all = &["backup", "source"], parse(try_from_str = parse_timestamp))]
+    backup_before: Option<DateTime<Utc>>,
+}
+
+/// Select archived entries by kind and size.
+#[derive(Debug, StructOpt)]
+struct EntryFilterArgs {
+    /// Skip symlinks.
+    #[structopt(long)]
+    no_symlinks: bool,
+
+    /// Include only files, and the directories holding them.
+    #[structopt(long)]
+    files_only: bool,
+
+    /// Skip files larger than this many bytes.
+    #[structopt(long)]
+    max_size: Option<u64>,
+}
+
+impl EntryFilterArgs {
+    fn to_filter(&self, exclude: &[String]) -> Result<EntryFilter> {
+        let mut filter = EntryFilter::from(excludes::from_strings(exclude)?);
+        if self.no_symlinks {
+            filter = filter.no_symlinks();
+        }
+        if self.files_only {
+            filter = filter.files_only();
+        }
+        filter.max_size = self.max_size;
+        Ok(filter)
+    }
 }
 
 /// Show debugging information.
@@ -175,13 +549,59 @@ enum Debug {
         /// Path of the archive to read.
         archive: PathBuf,
 
-        /// Backup version number.
+        /// Backup version number or tag.
+        #[structopt(long, short)]
+        backup: Option<BandSelectionPolicy>,
+        /// Use the latest complete backup started no later than this time: a date,
+        /// date and time, or relative time like "3 days ago".
+        #[structopt(long, visible_alias = "as-of", conflicts_with = "backup", parse(try_from_str = parse_timestamp))]
+        backup_before: Option<DateTime<Utc>>,
+
+        /// Show the entries in each index hunk separately.
+        #[structopt(long)]
+        hunks: bool,
+
+        /// Show each hunk as it's stored, without decoding it into entries.
+        #[structopt(long)]
+        raw: bool,
+    },
+
+    /// Show everything the index records about one entry, as json.
+    Entry {
+        /// Path of the archive to read.
+        archive: PathBuf,
+
+        /// Apath of the entry, like /dir/file.
+        apath: Apath,
+
+        /// Backup version number or tag.
         #[structopt(long, short)]
-        backup: Option<BandId>,
+        backup: Option<BandSelectionPolicy>,
+        /// Use the latest complete backup started no later than this time: a date,
+        /// date and time, or relative time like "3 days ago".
+        #[structopt(long, visible_alias = "as-of", conflicts_with = "backup", parse(try_from_str = parse_timestamp))]
+        backup_before: Option<DateTime<Utc>>,
+    },
+
+    /// List all blocks, or report how they're used.
+    Blocks {
+        archive: PathBuf,
+        /// Instead of listing blocks, report unreferenced blocks, how many
+        /// bands reference each block, and unexpected files in the blockdir.
+        #[structopt(long)]
+        report: bool,
+        /// Print the report as json.
+        #[structopt(long, requires = "report")]
+        json: bool,
     },
 
-    /// List all blocks.
-    Blocks { archive: PathBuf },
+    /// Count the blocks and summarize their sizes.
+    BlockdirStats {
+        archive: PathBuf,
+        /// Print the stats as json.
+        #[structopt(long)]
+        json: bool,
+    },
 
     /// List all blocks referenced by any band.
     Referenced { archive: PathBuf },
@@ -194,47 +614,201 @@ enum ExitCode {
     Ok = 0,
     Failed = 1,
     PartialCorruption = 2,
+    /// The command finished, but reported some problems along the way, other
+    /// than warnings.
+    Problems = 3,
+    /// `preflight` found the archive can't be backed up to.
+    PreflightFailed = 4,
+    /// Interrupted by Ctrl-C, following the shell convention of 128 + SIGINT.
+    Cancelled = 130,
 }
 
 impl Command {
-    fn run(&self) -> Result<ExitCode> {
+    /// True if this command writes data such as json or a tar stream to
+    /// stdout, so that messages must go to stderr instead.
+    fn writes_data_to_stdout(&self) -> bool {
+        match self {
+            Command::Backup { json, .. }
+            | Command::Diff { json, .. }
+            | Command::DiffTrees { json, .. }
+            | Command::Ls { json, .. }
+            | Command::Preflight { json, .. }
+            | Command::Stats { json, .. }
+            | Command::Versions { json, .. }
+            | Command::Debug(Debug::Blocks { json, .. })
+            | Command::Debug(Debug::BlockdirStats { json, .. }) => *json,
+            Command::ExportTar { output, .. } => output.is_none(),
+            Command::Restore { to_stdout_tar, .. } => *to_stdout_tar,
+            Command::Config { .. }
+            | Command::Debug(Debug::Index { .. })
+            | Command::Debug(Debug::Entry { .. }) => true,
+            _ => false,
+        }
+    }
+
+    fn run(&self, key_file: Option<&Path>) -> Result<ExitCode> {
         let mut stdout = std::io::stdout();
         match self {
             Command::Backup {
                 archive,
                 source,
+                as_apath,
                 verbose,
                 exclude,
+                exclude_caches,
+                no_exclude_caches,
+                exclude_other_archives,
+                break_lock,
+                tag,
+                index_format,
+                pack_index,
+                retry_changed,
+                detect_moves,
+                strict_time,
+                one_file_system,
+                max_file_size,
+                cdc,
+                cdc_min_size,
+                cdc_avg_size,
+                cdc_max_size,
+                dry_run,
+                json,
             } => {
-                let excludes = excludes::from_strings(exclude)?;
-                let source = &LiveTree::open(source)?;
-                let options = BackupOptions {
-                    print_filenames: *verbose,
-                    excludes,
-                    ..Default::default()
+                let chunking = if *cdc {
+                    Chunking::content_defined_with_sizes(
+                        cdc_min_size.unwrap_or(chunking::DEFAULT_CDC_MIN_SIZE),
+                        cdc_avg_size.unwrap_or(chunking::DEFAULT_CDC_AVG_SIZE),
+                        cdc_max_size.unwrap_or(chunking::DEFAULT_CDC_MAX_SIZE),
+                    )?
+                } else {
+                    Chunking::Fixed
+                };
+                let archive = open_archive(archive, key_file)?;
+                let config = archive.config();
+                let options = BackupOptions::default()
+                    .print_filenames(*verbose)
+                    .excludes(excludes::from_strings(config.excludes_with(exclude))?)
+                    .exclude_caches(
+                        !*no_exclude_caches && (*exclude_caches || config.exclude_caches),
+                    )
+                    .exclude_other_archives(*exclude_other_archives)
+                    .one_file_system(*one_file_system)
+                    .max_file_size(*max_file_size)
+                    .break_lock(*break_lock)
+                    .tags(tag.clone())
+                    .index_format(index_format.or(config.index_format).unwrap_or_default())
+                    .index_hunks_per_pack(pack_index.then(|| index::HUNKS_PER_PACK))
+                    .chunking(chunking)
+                    .retry_changed(*retry_changed)
+                    .detect_moves(*detect_moves)
+                    .strict_time(*strict_time)
+                    .cancel(cancel_on_interrupt())
+                    .dry_run(dry_run.as_ref().map(|mode| match mode {
+                        Some(_) => DryRun::Hash,
+                        None => DryRun::Metadata,
+                    }));
+                let stats = match backup_source_roots(source, as_apath)? {
+                    None => archive.backup(&source[0], &options),
+                    Some(roots) => archive.backup_roots(roots, &options),
                 };
-                let stats = backup(&Archive::open_path(archive)?, &source, &options)?;
-                ui::println(&format!("Backup complete.\n{}", stats));
+                let stats = match stats {
+                    Err(Error::BackupCancelled { stats }) => {
+                        stats.problems.show();
+                        ui::println(&format!(
+                            "Backup cancelled; the new version is incomplete.\n{}",
+                            stats
+                        ));
+                        return Ok(ExitCode::Cancelled);
+                    }
+                    result => result?,
+                };
+                stats.problems.show();
+                stats.pattern_matches.warn_unmatched();
+                if *json {
+                    writeln!(stdout, "{}", serde_json::to_string_pretty(&stats).unwrap())?;
+                } else if dry_run.is_some() {
+                    ui::println(&format!(
+                        "Dry run complete; nothing was written.\n{}",
+                        stats
+                    ));
+                } else {
+                    ui::println(&format!("Backup complete.\n{}", stats));
+                }
             }
-            Command::Debug(Debug::Blocks { archive }) => {
+            Command::Config { archive } => {
+                let config = open_archive(archive, key_file)?.config().clone();
+                writeln!(stdout, "{}", serde_json::to_string_pretty(&config).unwrap())?;
+            }
+            Command::Debug(Debug::Blocks {
+                archive,
+                report: false,
+                ..
+            }) => {
                 let mut bw = BufWriter::new(stdout);
-                for hash in Archive::open_path(archive)?.block_dir().block_names()? {
+                for hash in open_archive(archive, key_file)?.block_dir().block_names()? {
                     writeln!(bw, "{}", hash)?;
                 }
             }
-            Command::Debug(Debug::Index { archive, backup }) => {
-                let st = stored_tree_from_opt(archive, &backup)?;
-                output::show_index_json(&st.band(), &mut stdout)?;
+            Command::Debug(Debug::Blocks {
+                archive,
+                report: true,
+                json,
+            }) => {
+                let report = open_archive(archive, key_file)?.block_report()?;
+                if *json {
+                    writeln!(stdout, "{}", serde_json::to_string_pretty(&report).unwrap())?;
+                } else {
+                    write!(stdout, "{}", report)?;
+                }
+            }
+            Command::Debug(Debug::BlockdirStats { archive, json }) => {
+                let stats = open_archive(archive, key_file)?.block_dir().stats()?;
+                if *json {
+                    writeln!(stdout, "{}", serde_json::to_string_pretty(&stats).unwrap())?;
+                } else {
+                    write!(stdout, "{}", stats)?;
+                }
+            }
+            Command::Debug(Debug::Index {
+                archive,
+                backup,
+                backup_before,
+                hunks,
+                raw,
+            }) => {
+                let st = stored_tree_from_opt(archive, key_file, backup, backup_before)?;
+                if *hunks || *raw {
+                    output::show_index_hunks_json(st.band(), *raw, &mut stdout)?;
+                } else {
+                    output::show_index_json(&st.band(), &mut stdout)?;
+                }
+            }
+            Command::Debug(Debug::Entry {
+                archive,
+                apath,
+                backup,
+                backup_before,
+            }) => {
+                let st = stored_tree_from_opt(archive, key_file, backup, backup_before)?;
+                match st.entry(apath)? {
+                    Some(entry) => output::show_entry_json(&entry, &mut stdout)?,
+                    None => {
+                        return Err(Error::EntryNotFound {
+                            apath: apath.clone(),
+                            band_id: st.band().id().clone(),
+                        })
+                    }
+                }
             }
             Command::Debug(Debug::Referenced { archive }) => {
                 let mut bw = BufWriter::new(stdout);
-                for hash in Archive::open_path(archive)?.referenced_blocks()? {
+                for hash in open_archive(archive, key_file)?.referenced_blocks()? {
                     writeln!(bw, "{}", hash)?;
                 }
             }
             Command::Debug(Debug::Unreferenced { archive }) => {
                 let mut bw = BufWriter::new(stdout);
-                for hash in Archive::open_path(archive)?.unreferenced_blocks()? {
+                for hash in open_archive(archive, key_file)?.unreferenced_blocks()? {
                     writeln!(bw, "{}", hash)?;
                 }
             }
@@ -245,7 +819,7 @@ impl Command {
                 no_gc,
                 break_lock,
             } => {
-                let stats = Archive::open_path(archive)?.delete_bands(
+                let stats = open_archive(archive, key_file)?.delete_bands(
                     &backup,
                     &DeleteOptions {
                         dry_run: *dry_run,
@@ -259,19 +833,87 @@ impl Command {
                 archive,
                 source,
                 backup,
+                backup_before,
                 exclude,
+                bytes,
+                json,
             } => {
-                let excludes = excludes::from_strings(exclude)?;
-                let st = stored_tree_from_opt(archive, backup)?;
+                let options = DiffOptions {
+                    excludes: excludes::from_strings(exclude)?,
+                    compare_content: *bytes,
+                    json: *json,
+                    ..DiffOptions::default()
+                };
+                let st = stored_tree_from_opt(archive, key_file, backup, backup_before)?;
                 let lt = LiveTree::open(source)?;
-                diff(&st, &lt, &DiffOptions { excludes })?;
+                diff(&st, &lt, &options)?;
+            }
+            Command::DiffTrees {
+                left,
+                right,
+                exclude,
+                bytes,
+                json,
+            } => {
+                let options = DiffOptions {
+                    excludes: excludes::from_strings(exclude)?,
+                    compare_content: *bytes,
+                    json: *json,
+                    ..DiffOptions::default()
+                };
+                diff_trees(
+                    &LiveTree::open(left)?,
+                    &LiveTree::open(right)?,
+                    &options,
+                    &mut stdout,
+                )?;
+            }
+            Command::ExportTar {
+                archive,
+                output,
+                backup,
+                backup_before,
+                gz,
+                verbose,
+                exclude,
+                filter,
+                only_subtree,
+            } => {
+                let archive = open_archive(archive, key_file)?;
+                let options = ExportTarOptions {
+                    band_selection: band_selection_policy_from_opt(backup, backup_before),
+                    only_subtree: only_subtree.clone(),
+                    filter: filter.to_filter(exclude)?,
+                    print_filenames: *verbose,
+                };
+                let out: Box<dyn Write> = match output {
+                    Some(path) => Box::new(BufWriter::new(
+                        std::fs::File::create(path).map_err(|source| Error::WriteTar { source })?,
+                    )),
+                    None => Box::new(BufWriter::new(std::io::stdout())),
+                };
+                let stats = if *gz {
+                    let mut encoder =
+                        flate2::write::GzEncoder::new(out, flate2::Compression::default());
+                    let stats = export_tar(&archive, &mut encoder, &options)?;
+                    encoder
+                        .finish()
+                        .and_then(|mut out| out.flush())
+                        .map_err(|source| Error::WriteTar { source })?;
+                    stats
+                } else {
+                    let mut out = out;
+                    export_tar(&archive, &mut out, &options)?
+                };
+                stats.problems.show();
+                ui::println(&format!("Export complete.\n{}", stats.summary()));
             }
             Command::Gc {
                 archive,
                 dry_run,
                 break_lock,
             } => {
-                let archive = Archive::open_path(archive)?;
+                let archive = open_archive(archive, key_file)?;
                 let stats = archive.delete_unreferenced(&DeleteOptions {
                     dry_run: *dry_run,
                     break_lock: *break_lock,
@@ -279,49 +921,211 @@ impl Command {
                 })?;
                 ui::println(&format!("{}", stats));
             }
-            Command::Init { archive } => {
-                Archive::create_path(&archive)?;
+            Command::ImportTar {
+                archive,
+                tar_file,
+                verbose,
+                exclude,
+                break_lock,
+                tag,
+                index_format,
+            } => {
+                let archive = open_archive(archive, key_file)?;
+                let config = archive.config();
+                let options = BackupOptions {
+                    print_filenames: *verbose,
+                    excludes: excludes::from_strings(config.excludes_with(exclude))?,
+                    break_lock: *break_lock,
+                    tags: tag.clone(),
+                    index_format: index_format.or(config.index_format).unwrap_or_default(),
+                    ..Default::default()
+                };
+                let input: Box<dyn Read> = if tar_file == Path::new("-") {
+                    Box::new(std::io::stdin())
+                } else {
+                    Box::new(
+                        std::fs::File::open(tar_file)
+                            .map_err(|source| Error::ReadTar { source })?,
+                    )
+                };
+                let mut input = BufReader::new(input);
+                let is_gzip = input
+                    .fill_buf()
+                    .map_err(|source| Error::ReadTar { source })?
+                    .starts_with(&[0x1f, 0x8b]);
+                let stats = if is_gzip {
+                    import_tar(
+                        &archive,
+                        &mut flate2::bufread::GzDecoder::new(input),
+                        &options,
+                    )?
+                } else {
+                    import_tar(&archive, &mut input, &options)?
+                };
+                stats.problems.show();
+                ui::println(&format!("Import complete.\n{}", stats));
+            }
+            Command::Init {
+                archive,
+                encrypt,
+                exclude,
+                exclude_caches,
+                index_format,
+            } => {
+                // Check the patterns are valid before creating the archive.
+                excludes::from_strings(exclude)?;
+                let secret = if *encrypt {
+                    Some(given_secret(key_file)?.map_or_else(prompt_new_passphrase, Ok)?)
+                } else if key_file.is_some() {
+                    return Err(Error::KeyFileWithoutEncryption);
+                } else {
+                    None
+                };
+                let options = InitOptions {
+                    excludes: exclude.clone(),
+                    exclude_caches: *exclude_caches,
+                    index_format: *index_format,
+                };
+                Archive::create_path_with_options(archive, secret.as_ref(), &options)?;
                 ui::println(&format!("Created new archive in {:?}", &archive));
             }
-            Command::Ls { stos, exclude } => {
-                let excludes = excludes::from_strings(exclude)?;
+            Command::Ls {
+                stos,
+                exclude,
+                filter,
+                long,
+                json,
+            } => {
+                let filter = filter.to_filter(exclude)?;
+                let format = if *json {
+                    ListFormat::Json
+                } else if *long {
+                    ListFormat::Long
+                } else {
+                    ListFormat::Names
+                };
                 if let Some(archive) = &stos.archive {
-                    output::show_entry_names(
-                        stored_tree_from_opt(archive, &stos.backup)?
-                            .iter_filtered(None, excludes)?,
+                    show_entries(
+                        format,
+                        stored_tree_from_opt(archive, key_file, &stos.backup, &stos.backup_before)?
+                            .iter_filtered(None, None)?
+                            .filter(|entry| filter.matches(entry)),
                         &mut stdout,
                     )?;
                 } else {
-                    output::show_entry_names(
+                    show_entries(
+                        format,
                         LiveTree::open(stos.source.clone().unwrap())?
-                            .iter_filtered(None, excludes)?,
+                            .iter_filtered(None, None)?
+                            .filter(|entry| filter.matches(entry)),
                         &mut stdout,
                     )?;
                 }
             }
+            #[cfg(all(unix, feature = "fuse"))]
+            Command::Mount {
+                archive,
+                mountpoint,
+            } => {
+                let mount =
+                    conserve::mount::Mount::new(&open_archive(archive, key_file)?, mountpoint)?;
+                ui::println(&format!(
+                    "Mounted on {:?}; interrupt to unmount.",
+                    mountpoint
+                ));
+                wait_for_interrupt_or_unmount(&mount);
+                mount.unmount();
+            }
+            Command::Migrate {
+                archive,
+                dry_run,
+                break_lock,
+            } => {
+                let stats = migrate(
+                    &open_archive(archive, key_file)?,
+                    &MigrateOptions {
+                        dry_run: *dry_run,
+                        break_lock: *break_lock,
+                    },
+                )?;
+                ui::println(&format!("{}", stats));
+            }
+            Command::Preflight { archive, json } => {
+                match open_archive(archive, key_file).and_then(|archive| preflight(&archive)) {
+                    Ok(report) if *json => {
+                        writeln!(stdout, "{}", serde_json::to_string_pretty(&report).unwrap())?;
+                    }
+                    Ok(report) => ui::println(&format!("Archive is ready for backup.\n{}", report)),
+                    Err(err) => {
+                        ui::show_error(&err);
+                        return Ok(ExitCode::PreflightFailed);
+                    }
+                }
+            }
             Command::Restore {
                 archive,
                 destination,
+                to_stdout_tar,
                 backup,
+                backup_before,
                 verbose,
                 force_overwrite,
+                replace_dirs,
+                secure_overwrite,
                 exclude,
+                filter,
                 only_subtree,
+                no_xattrs,
+                require_matches,
             } => {
-                let band_selection = band_selection_policy_from_opt(backup);
-                let archive = Archive::open_path(archive)?;
+                let band_selection = band_selection_policy_from_opt(backup, backup_before);
+                let archive = open_archive(archive, key_file)?;
 
                 let options = RestoreOptions {
                     print_filenames: *verbose,
-                    excludes: excludes::from_strings(exclude)?,
+                    filter: filter.to_filter(exclude)?,
                     only_subtree: only_subtree.clone(),
                     band_selection,
                     overwrite: *force_overwrite,
+                    replace_dirs: *replace_dirs,
+                    secure_overwrite: *secure_overwrite,
+                    restore_xattrs: !*no_xattrs,
+                    cancel: cancel_on_interrupt(),
+                    ..RestoreOptions::default()
                 };
 
-                let copy_stats = restore(&archive, &destination, &options)?;
-                ui::println("Restore complete.");
-                copy_stats.summarize_restore(&mut stdout)?;
+                let result = if *to_stdout_tar {
+                    let mut tree = TarWriteTree::new(BufWriter::new(std::io::stdout()));
+                    restore_into(&archive, &mut tree, &options)
+                } else {
+                    restore(&archive, destination.as_ref().unwrap(), &options)
+                };
+                let copy_stats = match result {
+                    Err(Error::RestoreCancelled { stats }) => {
+                        stats.problems.show();
+                        ui::println(&format!(
+                            "Restore cancelled; the destination is incomplete.\n{}",
+                            stats.summary()
+                        ));
+                        return Ok(ExitCode::Cancelled);
+                    }
+                    result => result?,
+                };
+                copy_stats.problems.show();
+                copy_stats.pattern_matches.warn_unmatched();
+                ui::println(&format!("Restore complete.\n{}", copy_stats.summary()));
+                if let Some(only_subtree) = only_subtree {
+                    if copy_stats.files + copy_stats.directories + copy_stats.symlinks == 0 {
+                        ui::report_problem(Problem::new(
+                            ProblemKind::UnmatchedPattern,
+                            None,
+                            format!("--only {} matched nothing", only_subtree),
+                        ));
+                        if *require_matches {
+                            return Ok(ExitCode::Failed);
+                        }
+                    }
+                }
             }
             Command::Size {
                 ref stos,
@@ -330,7 +1134,7 @@ impl Command {
             } => {
                 let excludes = excludes::from_strings(exclude)?;
                 let size = if let Some(archive) = &stos.archive {
-                    stored_tree_from_opt(archive, &stos.backup)?
+                    stored_tree_from_opt(archive, key_file, &stos.backup, &stos.backup_before)?
                         .size(excludes)?
                         .file_bytes
                 } else {
@@ -344,11 +1148,83 @@ impl Command {
                     ui::println(&conserve::bytes_to_human_mb(size));
                 }
             }
-            Command::Validate { archive } => {
-                let stats = Archive::open_path(archive)?.validate()?;
+            Command::Stats {
+                archive,
+                backup,
+                backup_before,
+                json,
+            } => {
+                let archive = open_archive(archive, key_file)?;
+                let band_id =
+                    archive.resolve_band(&band_selection_policy_from_opt(backup, backup_before))?;
+                let backup_stats = Band::open(&archive, &band_id)?.read_stats()?;
+                let summary = archive.summary()?;
+                if *json {
+                    let value = serde_json::json!({
+                        "band_id": band_id,
+                        "backup_stats": backup_stats,
+                        "archive": summary,
+                    });
+                    writeln!(stdout, "{}", serde_json::to_string_pretty(&value).unwrap())?;
+                } else {
+                    match backup_stats {
+                        Some(stats) => writeln!(stdout, "Backup {}:\n{}", band_id, stats)?,
+                        None => writeln!(stdout, "Backup {}: stats unknown\n", band_id)?,
+                    }
+                    writeln!(stdout, "Archive:\n{}", summary)?;
+                }
+            }
+            Command::Sync {
+                source_archive,
+                dest_archive,
+                backup,
+                break_lock,
+            } => {
+                let stats = sync(
+                    &open_archive(source_archive, key_file)?,
+                    &open_archive(dest_archive, key_file)?,
+                    &SyncOptions {
+                        band_ids: backup.clone(),
+                        break_lock: *break_lock,
+                    },
+                )?;
+                ui::println(&format!("Sync complete.\n{}", stats));
+            }
+            Command::Tag {
+                archive,
+                backup,
+                add,
+                remove,
+                break_lock,
+            } => {
+                let tags = open_archive(archive, key_file)?.edit_band_tags(
+                    backup,
+                    add,
+                    remove,
+                    *break_lock,
+                )?;
+                for tag in tags {
+                    writeln!(stdout, "{}", tag)?;
+                }
+            }
+            Command::Validate {
+                archive,
+                threads,
+                bands,
+                skip_block_hashes,
+                quick,
+            } => {
+                let options = ValidateOptions {
+                    threads: *threads,
+                    bands: (!bands.is_empty()).then(|| bands.clone()),
+                    check_block_hashes: !*skip_block_hashes,
+                    quick: *quick,
+                    ..ValidateOptions::default()
+                };
+                let stats = open_archive(archive, key_file)?.validate(&options)?;
                 stats.summarize(&mut stdout)?;
                 if stats.has_problems() {
-                    ui::problem("Archive has some problems.");
+                    ui::println("Archive has some problems.");
                     return Ok(ExitCode::PartialCorruption);
                 } else {
                     ui::println("Archive is OK.");
@@ -359,10 +1235,13 @@ impl Command {
                 short,
                 newest,
                 sizes,
+                json,
             } => {
                 ui::enable_progress(false);
-                let archive = Archive::open_path(archive)?;
-                if *short {
+                let archive = open_archive(archive, key_file)?;
+                if *json {
+                    output::show_version_list_json(&archive, *newest, *sizes, &mut stdout)?;
+                } else if *short {
                     output::show_brief_version_list(&archive, *newest, &mut stdout)?;
                 } else {
                     output::show_verbose_version_list(&archive, *newest,*sizes, &mut stdout)?;
@@ -373,23 +1252,172 @@ impl Command {
     }
 }
 
-fn stored_tree_from_opt(archive: &Path, backup: &Option<BandId>) -> Result<StoredTree> {
-    let archive = Archive::open_path(archive)?;
-    let policy = band_selection_policy_from_opt(backup);
+/// Environment variable holding the passphrase for encrypted archives.
+const PASSPHRASE_ENV: &str = "CONSERVE_PASSPHRASE";
+
+/// Environment variable naming a key file for encrypted archives.
+const KEY_FILE_ENV: &str = "CONSERVE_KEY_FILE";
+
+/// Get the secret for an encrypted archive from `--key-file` or the
+/// environment, if it's given there.
+fn given_secret(key_file: Option<&Path>) -> Result<Option<Secret>> {
+    if let Some(key_file) = key_file {
+        Secret::from_key_file(key_file).map(Some)
+    } else if let Some(key_file) = std::env::var_os(KEY_FILE_ENV) {
+        Secret::from_key_file(Path::new(&key_file)).map(Some)
+    } else if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        Ok(Some(Secret::from_passphrase(&passphrase)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn prompt_new_passphrase() -> Result<Secret> {
+    let passphrase = rpassword::read_password_from_tty(Some("New passphrase: "))?;
+    let confirm = rpassword::read_password_from_tty(Some("Repeat passphrase: "))?;
+    if passphrase != confirm {
+        return Err(Error::PassphraseMismatch);
+    }
+    Ok(Secret::from_passphrase(&passphrase))
+}
+
+/// Open an archive, getting the secret from `--key-file`, the environment, or
+/// by prompting if it's encrypted.
+fn open_archive(path: &Path, key_file: Option<&Path>) -> Result<Archive> {
+    if let Some(secret) = given_secret(key_file)? {
+        return Archive::open_path_with_secret(path, Some(&secret));
+    }
+    match Archive::open_path(path) {
+        Err(Error::ArchiveEncrypted) => {
+            let passphrase = rpassword::read_password_from_tty(Some("Passphrase: "))
+                .map_err(|_| Error::ArchiveEncrypted)?;
+            Archive::open_path_with_secret(path, Some(&Secret::from_passphrase(&passphrase)))
+        }
+        other => other,
+    }
+}
+
+fn stored_tree_from_opt(
+    archive: &Path,
+    key_file: Option<&Path>,
+    backup: &Option<BandSelectionPolicy>,
+    backup_before: &Option<DateTime<Utc>>,
+) -> Result<StoredTree> {
+    let archive = open_archive(archive, key_file)?;
+    let policy = band_selection_policy_from_opt(backup, backup_before);
     archive.open_stored_tree(policy)
 }
 
-fn band_selection_policy_from_opt(backup: &Option<BandId>) -> BandSelectionPolicy {
-    if let Some(band_id) = backup {
-        BandSelectionPolicy::Specified(band_id.clone())
+/// How `ls` shows each entry.
+#[derive(Clone, Copy)]
+enum ListFormat {
+    Names,
+    Long,
+    Json,
+}
+
+/// List entries either by name or with more details.
+fn show_entries<E: Entry, I: Iterator<Item = E>>(
+    format: ListFormat,
+    entries: I,
+    w: &mut dyn Write,
+) -> Result<()> {
+    match format {
+        ListFormat::Names => output::show_entry_names(entries, w),
+        ListFormat::Long => output::show_entry_details(entries, w),
+        ListFormat::Json => output::show_entries_json(entries, w),
+    }
+}
+
+/// Cancel the returned token when the user presses Ctrl-C, so that the
+/// operation can stop cleanly. A second Ctrl-C exits immediately.
+fn cancel_on_interrupt() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let handler_cancel = cancel.clone();
+    ctrlc::set_handler(move || {
+        if handler_cancel.is_cancelled() {
+            std::process::exit(ExitCode::Cancelled as i32);
+        }
+        ui::println("Interrupted; stopping cleanly (press Ctrl-C again to quit now)");
+        handler_cancel.cancel();
+    })
+    .expect("Failed to set interrupt handler");
+    cancel
+}
+
+/// Block until the process is interrupted, or the filesystem is unmounted
+/// from outside.
+#[cfg(all(unix, feature = "fuse"))]
+fn wait_for_interrupt_or_unmount(mount: &conserve::mount::Mount) {
+    let (tx, rx) = std::sync::mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = tx.send(());
+    })
+    .expect("Failed to set interrupt handler");
+    while !mount.is_finished() {
+        if rx
+            .recv_timeout(std::time::Duration::from_millis(200))
+            .is_ok()
+        {
+            break;
+        }
+    }
+}
+
+/// Map backup sources to the roots of the stored tree, or return None to
+/// back up a single source as the whole tree.
+fn backup_source_roots(sources: &[PathBuf], apaths: &[Apath]) -> Result<Option<Vec<SourceRoot>>> {
+    if apaths.is_empty() {
+        if let [_] = sources {
+            Ok(None)
+        } else {
+            sources
+                .iter()
+                .map(SourceRoot::at_absolute_path)
+                .collect::<Result<_>>()
+                .map(Some)
+        }
+    } else if apaths.len() == sources.len() {
+        Ok(Some(
+            sources
+                .iter()
+                .zip(apaths)
+                .map(|(source, apath)| SourceRoot::new(source, apath.clone()))
+                .collect(),
+        ))
     } else {
-        BandSelectionPolicy::Latest
+        Err(Error::SourceMappingCount {
+            sources: sources.len(),
+            mappings: apaths.len(),
+        })
+    }
+}
+
+fn band_selection_policy_from_opt(
+    backup: &Option<BandSelectionPolicy>,
+    backup_before: &Option<DateTime<Utc>>,
+) -> BandSelectionPolicy {
+    if let Some(cutoff) = backup_before {
+        BandSelectionPolicy::LatestClosedBefore(*cutoff)
+    } else {
+        backup.clone().unwrap_or(BandSelectionPolicy::Latest)
     }
 }
 
 fn main() {
     ui::enable_progress(true);
-    let result = Command::from_args().run();
+    let problems = Arc::new(ui::PrintProblems::default());
+    ui::set_problem_sink(problems.clone());
+    let args = Args::from_args();
+    if args.command.writes_data_to_stdout() {
+        ui::messages_to_stderr(true);
+    }
+    let result = args.command.run(args.key_file.as_deref());
+    match problems.count() {
+        0 => (),
+        1 => ui::println("1 problem encountered"),
+        n => ui::println(&format!("{} problems encountered", n)),
+    }
     match result {
         Err(ref e) => {
             ui::show_error(e);
@@ -402,6 +1430,7 @@ fn main() {
             // Avoid Rust redundantly printing the error.
             std::process::exit(ExitCode::Failed as i32)
         }
+        Ok(ExitCode::Ok) if problems.count() > 0 => std::process::exit(ExitCode::Problems as i32),
         Ok(code) => std::process::exit(code as i32),
     }
 }
@@ -21,11 +21,11 @@
 //!
 //! The structure is: archive > blockdir > subdir > file.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::io;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use blake2_rfc::blake2b;
 use blake2_rfc::blake2b::Blake2b;
@@ -36,7 +36,7 @@ use thousands::Separable;
 use crate::blockhash::BlockHash;
 use crate::compress::snappy::{Compressor, Decompressor};
 use crate::kind::Kind;
-use crate::stats::{BackupStats, Sizes, ValidateStats};
+use crate::stats::{BackupStats, BlockDirStats, Sizes, ValidateStats};
 use crate::transport::local::LocalTransport;
 use crate::transport::{DirEntry, ListDirNames, Transport};
 use crate::*;
@@ -68,6 +68,11 @@ pub struct Address {
 #[derive(Clone, Debug)]
 pub struct BlockDir {
     transport: Box<dyn Transport>,
+
+    /// In a dry run, blocks aren't written, but their hashes are remembered
+    /// here, shared between clones, so that later identical blocks are
+    /// counted as deduplicated just as they would be in a real backup.
+    dry_run: Option<Arc<Mutex<HashSet<BlockHash>>>>,
 }
 
 /// Returns the transport-relative subdirectory name.
@@ -87,7 +92,10 @@ impl BlockDir {
     }
 
     pub fn open(transport: Box<dyn Transport>) -> BlockDir {
-        BlockDir { transport }
+        BlockDir {
+            transport,
+            dry_run: None,
+        }
     }
 
     /// Create a BlockDir directory and return an object accessing it.
@@ -99,7 +107,7 @@ impl BlockDir {
         transport
             .create_dir("")
             .map_err(|source| Error::CreateBlockDir { source })?;
-        Ok(BlockDir { transport })
+        Ok(BlockDir::open(transport))
     }
 
     /// Returns the number of compressed bytes.
@@ -116,9 +124,10 @@ impl BlockDir {
             .or_else(|io_err| {
                 if io_err.kind() == io::ErrorKind::AlreadyExists {
                     // Perhaps it was simultaneously created by another thread or process.
-                    ui::problem(&format!(
-                        "Unexpected late detection of existing block {:?}",
-                        hex_hash
+                    ui::report_problem(Problem::new(
+                        ProblemKind::Block,
+                        None,
+                        format!("Unexpected late detection of existing block {:?}", hex_hash),
                     ));
                     Ok(())
                 } else {
@@ -131,17 +140,34 @@ impl BlockDir {
         Ok(comp_len)
     }
 
+    /// Return a copy of this block dir for a dry run, which counts blocks as
+    /// if they were stored, but doesn't write them.
+    pub(crate) fn dry_run(&self) -> BlockDir {
+        BlockDir {
+            transport: self.transport.clone(),
+            dry_run: Some(Arc::new(Mutex::new(HashSet::new()))),
+        }
+    }
+
     pub(crate) fn store_or_deduplicate(
         &mut self,
         block_data: &[u8],
         stats: &mut BackupStats,
     ) -> Result<BlockHash> {
         let hash = self.hash_bytes(block_data);
-        if self.contains(&hash)? {
+        let already_counted = match &self.dry_run {
+            Some(written) => !written.lock().unwrap().insert(hash.clone()),
+            None => false,
+        };
+        if already_counted || self.contains(&hash)? {
             stats.deduplicated_blocks += 1;
             stats.deduplicated_bytes += block_data.len() as u64;
         } else {
-            let comp_len = self.compress_and_store(block_data, &hash)?;
+            let comp_len = if self.dry_run.is_some() {
+                Compressor::new().compress(block_data)?.len() as u64
+            } else {
+                self.compress_and_store(block_data, &hash)?
+            };
             stats.written_blocks += 1;
             stats.uncompressed_bytes += block_data.len() as u64;
             stats.compressed_bytes += comp_len;
@@ -156,6 +182,33 @@ impl BlockDir {
             .map_err(Error::from)
     }
 
+    /// Copy a block, still compressed, into another block directory.
+    ///
+    /// The block is not decompressed or checked, so this is cheap, but
+    /// corruption in the source will be copied across. If either archive is
+    /// encrypted the block is decrypted and re-encrypted by its transport.
+    ///
+    /// Returns the number of compressed bytes copied.
+    pub(crate) fn copy_block_to(&self, hash: &BlockHash, dest: &BlockDir) -> Result<u64> {
+        let hex_hash = hash.to_string();
+        let relpath = block_relpath(hash);
+        let mut compressed = Vec::new();
+        self.transport
+            .read_file(&relpath, &mut compressed)
+            .map_err(|source| Error::ReadBlock {
+                source,
+                hash: hex_hash.clone(),
+            })?;
+        dest.transport.create_dir(subdir_relpath(&hex_hash))?;
+        dest.transport
+            .write_file(&relpath, &compressed)
+            .map_err(|source| Error::WriteBlock {
+                hash: hex_hash,
+                source,
+            })?;
+        Ok(compressed.len() as u64)
+    }
+
     /// Returns the compressed on-disk size of a block.
     pub fn compressed_size(&self, hash: &BlockHash) -> Result<u64> {
         Ok(self.transport.metadata(&block_relpath(hash))?.len)
@@ -199,9 +252,10 @@ impl BlockDir {
             if dirname.len() == SUBDIR_NAME_CHARS {
                 true
             } else {
-                ui::problem(&format!(
-                    "Unexpected subdirectory in blockdir: {:?}",
-                    dirname
+                ui::report_problem(Problem::new(
+                    ProblemKind::ArchiveStructure,
+                    None,
+                    format!("Unexpected subdirectory in blockdir: {:?}", dirname),
                 ));
                 false
             }
@@ -217,14 +271,22 @@ impl BlockDir {
             .map(move |subdir_name| transport.iter_dir_entries(&subdir_name))
             .filter_map(|iter_or| {
                 if let Err(ref err) = iter_or {
-                    ui::problem(&format!("Error listing block directory: {:?}", &err));
+                    ui::report_problem(Problem::new(
+                        ProblemKind::Block,
+                        None,
+                        format!("Error listing block directory: {:?}", &err),
+                    ));
                 }
                 iter_or.ok()
             })
             .flatten()
             .filter_map(|iter_or| {
                 if let Err(ref err) = iter_or {
-                    ui::problem(&format!("Error listing block subdirectory: {:?}", &err));
+                    ui::report_problem(Problem::new(
+                        ProblemKind::Block,
+                        None,
+                        format!("Error listing block subdirectory: {:?}", &err),
+                    ));
                 }
                 iter_or.ok()
             })
@@ -243,38 +305,91 @@ impl BlockDir {
             .filter_map(|de| de.name.parse().ok()))
     }
 
+    /// Count the blocks and summarize their compressed sizes.
+    ///
+    /// Blocks are listed by the same iterator as [BlockDir::block_names],
+    /// one subdirectory at a time, so the names of all the blocks are never
+    /// held at once.
+    pub fn stats(&self) -> Result<BlockDirStats> {
+        let mut stats = BlockDirStats::default();
+        for hash in self.block_names()? {
+            match self.compressed_size(&hash) {
+                Ok(bytes) => stats.add_block(bytes),
+                Err(err) => ui::report_problem(Problem::new(
+                    ProblemKind::Block,
+                    None,
+                    format!("Can't get size of block {}: {}", hash, err),
+                )),
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Return the paths, relative to the blockdir, of files that aren't
+    /// blocks, such as temporary files left behind by an interrupted write.
+    ///
+    /// Unexpected directories are included with a trailing slash, but their
+    /// contents aren't listed.
+    pub fn unexpected_files(&self) -> Result<Vec<String>> {
+        let ListDirNames { files, dirs } = self.transport.list_dir_names("")?;
+        let mut unexpected = files;
+        for subdir in dirs {
+            if subdir.len() != SUBDIR_NAME_CHARS {
+                unexpected.push(format!("{}/", subdir));
+                continue;
+            }
+            for entry in self.transport.iter_dir_entries(&subdir)? {
+                let DirEntry { name, kind } = entry?;
+                let is_block = kind == Kind::File
+                    && name.starts_with(&subdir)
+                    && name.len() == BLOCKDIR_FILE_NAME_LEN
+                    && name.parse::<BlockHash>().is_ok();
+                if !is_block {
+                    unexpected.push(format!("{}/{}", subdir, name));
+                }
+            }
+        }
+        unexpected.sort();
+        Ok(unexpected)
+    }
+
     /// Check format invariants of the BlockDir.
     ///
     /// Return a dict describing which blocks are present, and the length of their uncompressed
     /// data.
-    pub fn validate(&self, stats: &mut ValidateStats) -> Result<HashMap<BlockHash, usize>> {
+    ///
+    /// Each block counts as one unit of work on the progress bar, which may be
+    /// shared with other concurrent checks.
+    pub fn validate(
+        &self,
+        stats: &mut ValidateStats,
+        progress_bar_mutex: &Mutex<ProgressBar>,
+    ) -> Result<HashMap<BlockHash, usize>> {
         // TODO: In the top-level directory, no files or directories other than prefix
         // directories of the right length.
         // TODO: Test having a block with the right compression but the wrong contents.
-        ui::println("Count blocks...");
-        let mut progress_bar = ProgressBar::new();
-        progress_bar.set_phase("Count blocks".to_owned());
-        let blocks: Vec<BlockHash> = self
-            .block_names()?
-            .enumerate()
-            .inspect(|(i, _hash)| {
-                if i % 100 == 0 {
-                    progress_bar.set_work_done(*i)
-                }
-            })
-            .map(|(_i, hash)| hash)
-            .collect();
+        let blocks: Vec<BlockHash> = self.block_names()?.collect();
+        self.validate_blocks(blocks, true, stats, progress_bar_mutex)
+    }
+
+    /// Read each of `blocks`, checking it matches its hash if `check_hashes`
+    /// is set, and return the length of the uncompressed data of those that
+    /// could be read.
+    pub(crate) fn validate_blocks(
+        &self,
+        blocks: Vec<BlockHash>,
+        check_hashes: bool,
+        stats: &mut ValidateStats,
+        progress_bar_mutex: &Mutex<ProgressBar>,
+    ) -> Result<HashMap<BlockHash, usize>> {
         crate::ui::println(&format!(
             "Check {} blocks...",
             blocks.len().separate_with_commas()
         ));
-        progress_bar.set_total_work(blocks.len());
         stats.block_read_count = blocks.len().try_into().unwrap();
-        let block_count = blocks.len();
-        progress_bar.set_phase("Check block hashes".to_owned());
-        progress_bar.set_total_work(block_count);
-        progress_bar.set_work_done(0);
-        let progress_bar_mutex = Mutex::new(progress_bar);
+        if let Ok(mut progress_bar) = progress_bar_mutex.lock() {
+            progress_bar.increment_total_work(blocks.len());
+        }
         // Make a vec of Some(usize) if the block could be read, or None if it
         // failed, where the usize gives the uncompressed data size.
         let mut results: Vec<Option<(BlockHash, usize)>> = Vec::new();
@@ -282,10 +397,12 @@ impl BlockDir {
             .into_par_iter()
             .map(|hash| {
                 let r = self
-                    .get_block_content(&hash)
+                    .read_block(&hash, check_hashes)
                     .map(|(bytes, _sizes)| (hash, bytes.len()))
                     .ok();
-                progress_bar_mutex.lock().unwrap().increment_work_done(1);
+                if let Ok(mut progress_bar) = progress_bar_mutex.lock() {
+                    progress_bar.increment_work_done(1);
+                }
                 r
             })
             .collect_into_vec(&mut results);
@@ -301,6 +418,11 @@ impl BlockDir {
     ///
     /// Checks that the hash is correct with the contents.
     pub fn get_block_content(&self, hash: &BlockHash) -> Result<(Vec<u8>, Sizes)> {
+        self.read_block(hash, true)
+    }
+
+    /// Read and decompress a block, checking its hash if `check_hash` is set.
+    fn read_block(&self, hash: &BlockHash, check_hash: bool) -> Result<(Vec<u8>, Sizes)> {
         // TODO: Reuse decompressor buffer.
         // TODO: Reuse read buffer.
         let mut decompressor = Decompressor::new();
@@ -313,20 +435,26 @@ impl BlockDir {
                 hash: hash.to_string(),
             })?;
         let decompressed_bytes = decompressor.decompress(&compressed_bytes)?;
-        let actual_hash = BlockHash::from(blake2b::blake2b(
-            BLAKE_HASH_SIZE_BYTES,
-            &[],
-            &decompressed_bytes,
-        ));
-        if actual_hash != *hash {
-            ui::problem(&format!(
-                "Block file {:?} has actual decompressed hash {}",
-                &block_relpath, actual_hash
+        if check_hash {
+            let actual_hash = BlockHash::from(blake2b::blake2b(
+                BLAKE_HASH_SIZE_BYTES,
+                &[],
+                &decompressed_bytes,
             ));
-            return Err(Error::BlockCorrupt {
-                hash: hash.to_string(),
-                actual_hash: actual_hash.to_string(),
-            });
+            if actual_hash != *hash {
+                ui::report_problem(Problem::new(
+                    ProblemKind::Block,
+                    None,
+                    format!(
+                        "Block file {:?} has actual decompressed hash {}",
+                        &block_relpath, actual_hash
+                    ),
+                ));
+                return Err(Error::BlockCorrupt {
+                    hash: hash.to_string(),
+                    actual_hash: actual_hash.to_string(),
+                });
+            }
         }
         let sizes = Sizes {
             uncompressed: decompressed_bytes.len() as u64,
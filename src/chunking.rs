@@ -0,0 +1,277 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Split the content of large files into blocks.
+//!
+//! By default files are cut into blocks of `MAX_BLOCK_SIZE` bytes. Inserting
+//! or removing bytes near the start of a file then shifts every later block,
+//! so none of them deduplicate against the previous backup.
+//!
+//! Content-defined chunking instead cuts where a rolling hash of the last
+//! few dozen bytes matches a pattern, as in FastCDC, so that after an edit
+//! the boundaries fall in the same places relative to the content, and only
+//! the blocks around the edit are new.
+//!
+//! Addresses are a hash, offset and length whichever way the blocks were
+//! cut, so reading doesn't depend on the chunking.
+
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+/// Default smallest block cut by content-defined chunking, except at the
+/// end of a file.
+pub const DEFAULT_CDC_MIN_SIZE: usize = 64 << 10;
+
+/// Default typical size of blocks cut by content-defined chunking.
+pub const DEFAULT_CDC_AVG_SIZE: usize = 256 << 10;
+
+/// Default largest block cut by content-defined chunking.
+pub const DEFAULT_CDC_MAX_SIZE: usize = MAX_BLOCK_SIZE;
+
+/// The rolling hash looks at about this many bytes, so smaller minimum
+/// block sizes aren't useful.
+const MIN_CDC_MIN_SIZE: usize = 64;
+
+/// How to split files into blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Chunking {
+    /// Blocks of `MAX_BLOCK_SIZE` bytes, except the last in each file.
+    #[default]
+    Fixed,
+
+    /// Cut at boundaries chosen by the content.
+    ContentDefined {
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    },
+}
+
+impl Chunking {
+    /// Content-defined chunking with the default sizes.
+    pub fn content_defined() -> Chunking {
+        Chunking::ContentDefined {
+            min_size: DEFAULT_CDC_MIN_SIZE,
+            avg_size: DEFAULT_CDC_AVG_SIZE,
+            max_size: DEFAULT_CDC_MAX_SIZE,
+        }
+    }
+
+    /// Content-defined chunking with the given block sizes.
+    ///
+    /// The sizes must increase, the smallest must be at least 64 bytes, and
+    /// the largest no more than the fixed block size, 1MiB. The average is
+    /// rounded down to a power of two.
+    pub fn content_defined_with_sizes(
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    ) -> Result<Chunking> {
+        if min_size < MIN_CDC_MIN_SIZE
+            || min_size >= avg_size
+            || avg_size >= max_size
+            || max_size > MAX_BLOCK_SIZE
+        {
+            return Err(Error::InvalidChunkSizes {
+                min_size,
+                avg_size,
+                max_size,
+            });
+        }
+        Ok(Chunking::ContentDefined {
+            min_size,
+            avg_size,
+            max_size,
+        })
+    }
+
+    pub fn is_fixed(&self) -> bool {
+        *self == Chunking::Fixed
+    }
+
+    /// The most bytes that go into one block.
+    pub(crate) fn max_size(&self) -> usize {
+        match *self {
+            Chunking::Fixed => MAX_BLOCK_SIZE,
+            Chunking::ContentDefined { max_size, .. } => max_size,
+        }
+    }
+
+    /// Return the length of the next block at the start of `data`.
+    ///
+    /// `data` should hold `max_size` bytes, or else everything remaining in
+    /// the file.
+    pub(crate) fn cut(&self, data: &[u8]) -> usize {
+        match *self {
+            Chunking::Fixed => data.len().min(MAX_BLOCK_SIZE),
+            Chunking::ContentDefined {
+                min_size,
+                avg_size,
+                max_size,
+            } => cdc_cut(data, min_size, avg_size, max_size),
+        }
+    }
+}
+
+/// Find a content-defined boundary, using FastCDC's normalized chunking:
+/// before the average size a boundary needs more hash bits to be zero, and
+/// after it fewer, which pulls block sizes towards the average.
+fn cdc_cut(data: &[u8], min_size: usize, avg_size: usize, max_size: usize) -> usize {
+    if data.len() <= min_size {
+        return data.len();
+    }
+    let end = data.len().min(max_size);
+    let normal_end = end.min(avg_size);
+    let bits = usize::BITS - 1 - avg_size.leading_zeros();
+    let mask_small = high_bits_mask(bits + 1);
+    let mask_large = high_bits_mask(bits - 1);
+    let mut hash: u64 = 0;
+    let mut i = min_size;
+    while i < normal_end {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_small == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    while i < end {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_large == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    end
+}
+
+/// A mask of the `bits` most significant bits, which in the gear hash
+/// depend on the most recent bytes.
+fn high_bits_mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+/// Random values added to the rolling hash for each byte value.
+///
+/// Changing these would move the boundaries, so that blocks written by
+/// earlier backups no longer deduplicate.
+static GEAR: [u64; 256] = gear_table();
+
+/// Fill the gear table from a splitmix64 sequence.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Deterministic incompressible bytes.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn cut_all(chunking: &Chunking, mut data: &[u8]) -> Vec<usize> {
+        let mut lens = Vec::new();
+        while !data.is_empty() {
+            let len = chunking.cut(&data[..data.len().min(chunking.max_size())]);
+            lens.push(len);
+            data = &data[len..];
+        }
+        lens
+    }
+
+    #[test]
+    fn fixed_cuts_at_max_block_size() {
+        let data = vec![0u8; MAX_BLOCK_SIZE * 2 + 10];
+        assert_eq!(
+            cut_all(&Chunking::Fixed, &data),
+            [MAX_BLOCK_SIZE, MAX_BLOCK_SIZE, 10]
+        );
+    }
+
+    #[test]
+    fn content_defined_blocks_are_within_limits() {
+        let chunking = Chunking::content_defined_with_sizes(1024, 4096, 16384).unwrap();
+        let data = noise(1 << 20, 1);
+        let lens = cut_all(&chunking, &data);
+        assert_eq!(lens.iter().sum::<usize>(), data.len());
+        let (last, rest) = lens.split_last().unwrap();
+        assert!(*last <= 16384);
+        assert!(rest.iter().all(|len| (1024..=16384).contains(len)));
+        let avg = data.len() / lens.len();
+        assert!((2048..=8192).contains(&avg), "average {}", avg);
+    }
+
+    #[test]
+    fn content_defined_boundaries_resynchronize_after_insertion() {
+        let chunking = Chunking::content_defined_with_sizes(1024, 4096, 16384).unwrap();
+        let data = noise(1 << 20, 2);
+        let mut edited = data[..100].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&data[100..]);
+        let lens = cut_all(&chunking, &data);
+        let edited_lens = cut_all(&chunking, &edited);
+        // After the first few blocks, the rest are the same.
+        let common = lens
+            .iter()
+            .rev()
+            .zip(edited_lens.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert!(common + 3 >= lens.len(), "{} of {}", common, lens.len());
+    }
+
+    #[test]
+    fn invalid_sizes_are_refused() {
+        for &(min, avg, max) in &[
+            (10, 4096, 16384),
+            (4096, 4096, 16384),
+            (1024, 16384, 4096),
+            (1024, 4096, MAX_BLOCK_SIZE * 2),
+        ] {
+            assert!(matches!(
+                Chunking::content_defined_with_sizes(min, avg, max),
+                Err(Error::InvalidChunkSizes { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn serialized_form() {
+        assert_eq!(
+            serde_json::to_string(&Chunking::content_defined()).unwrap(),
+            r#"{"content_defined":{"min_size":65536,"avg_size":262144,"max_size":1048576}}"#
+        );
+    }
+}
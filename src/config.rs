@@ -0,0 +1,152 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Per-archive configuration of default options.
+//!
+//! An archive can optionally contain a `config.json` file holding defaults for
+//! operations on that archive, so that they don't need to be repeated on every
+//! command line. Excludes given on the command line are added to the configured
+//! ones, and other options given on the command line override them.
+//!
+//! Unknown keys produce a warning but are otherwise ignored, so that archives
+//! configured by newer versions can still be used.
+
+use serde::{Deserialize, Serialize};
+
+use crate::jsonio::{read_json, write_json};
+use crate::*;
+
+pub(crate) const CONFIG_FILENAME: &str = "config.json";
+
+/// Default options stored in an archive.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// Glob patterns to exclude from every backup into this archive.
+    pub excludes: Vec<String>,
+
+    /// Skip the contents of directories marked as caches by a `CACHEDIR.TAG` file.
+    pub exclude_caches: bool,
+
+    /// Serialization of the index of new backups, unless given for one backup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_format: Option<IndexFormat>,
+}
+
+/// Keys understood in the config file.
+const KNOWN_KEYS: &[&str] = &["excludes", "exclude_caches", "index_format"];
+
+impl ArchiveConfig {
+    /// Read the config from an archive, or return the defaults if there is no
+    /// config file.
+    pub(crate) fn read<TR: AsRef<dyn Transport>>(transport: &TR) -> Result<ArchiveConfig> {
+        if !transport.as_ref().exists(CONFIG_FILENAME)? {
+            return Ok(ArchiveConfig::default());
+        }
+        let value: serde_json::Value = read_json(transport, CONFIG_FILENAME)?;
+        if let Some(map) = value.as_object() {
+            for key in map.keys() {
+                if !KNOWN_KEYS.contains(&key.as_str()) {
+                    ui::report_problem(Problem::new(
+                        ProblemKind::Config,
+                        None,
+                        format!(
+                            "Unknown key {:?} in archive {} ignored",
+                            key, CONFIG_FILENAME
+                        ),
+                    ));
+                }
+            }
+        }
+        serde_json::from_value(value).map_err(|source| Error::DeserializeJson {
+            path: CONFIG_FILENAME.into(),
+            source,
+        })
+    }
+
+    pub(crate) fn write<TR: AsRef<dyn Transport>>(&self, transport: &TR) -> Result<()> {
+        write_json(transport, CONFIG_FILENAME, self)
+    }
+
+    /// Return the configured excludes followed by any additional patterns,
+    /// typically from the command line.
+    pub fn excludes_with(&self, additional: &[String]) -> Vec<String> {
+        let mut excludes = self.excludes.clone();
+        excludes.extend(additional.iter().cloned());
+        excludes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn default_without_config_file() {
+        let archive = ScratchArchive::new();
+        assert_eq!(*archive.config(), ArchiveConfig::default());
+        assert!(!archive.path().join(CONFIG_FILENAME).exists());
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut archive = ScratchArchive::new();
+        let config = ArchiveConfig {
+            excludes: vec!["*.o".to_owned()],
+            exclude_caches: true,
+            index_format: Some(IndexFormat::Cbor),
+        };
+        archive.set_config(config.clone()).unwrap();
+        let reopened = Archive::open_path(archive.path()).unwrap();
+        assert_eq!(*reopened.config(), config);
+        assert!(!reopened
+            .validate(&ValidateOptions::default())
+            .unwrap()
+            .has_problems());
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored() {
+        let archive = ScratchArchive::new();
+        fs::write(
+            archive.path().join(CONFIG_FILENAME),
+            r#"{"excludes": ["/junk"], "rate_limit": 1000}"#,
+        )
+        .unwrap();
+        let reopened = Archive::open_path(archive.path()).unwrap();
+        assert_eq!(reopened.config().excludes, vec!["/junk".to_owned()]);
+    }
+
+    #[test]
+    fn configured_excludes_apply_to_backup() {
+        let mut archive = ScratchArchive::new();
+        archive
+            .set_config(ArchiveConfig {
+                excludes: vec!["*.tmp".to_owned()],
+                ..Default::default()
+            })
+            .unwrap();
+        let source = TreeFixture::new();
+        source.create_file("keep");
+        source.create_file("scratch.tmp");
+        let options = BackupOptions {
+            excludes: excludes::from_strings(archive.config().excludes_with(&[])).unwrap(),
+            ..Default::default()
+        };
+        let stats = archive.backup(source.path(), &options).unwrap();
+        assert_eq!(stats.files, 1);
+    }
+}
@@ -13,6 +13,8 @@
 
 //! Copy tree contents.
 
+use std::time::Instant;
+
 use crate::kind::Kind;
 use crate::stats::CopyStats;
 use crate::*;
@@ -23,36 +25,69 @@ pub struct CopyOptions {
     pub measure_first: bool,
     /// Copy only this subtree from the source.
     pub only_subtree: Option<Apath>,
-    pub excludes: Option<GlobSet>,
+    /// Copy only entries that match this filter, and the directories holding them.
+    pub filter: EntryFilter,
+    /// The total size of the files to be copied, if it's already known,
+    /// to show progress without measuring the source first.
+    pub expected_bytes: Option<u64>,
+    /// Stop between entries when this is cancelled.
+    pub cancel: CancellationToken,
 }
 
 /// Copy files and other entries from one tree to another.
 ///
 /// NOTE: Although this is public, it's suggested to use `Archive::backup` or `Archive::restore` if
 /// possible, as they're higher-level APIs.
-pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
+///
+/// If `options.cancel` is cancelled, the copy stops before the next entry and
+/// returns [Error::RestoreCancelled] with the stats so far. The destination
+/// is only finished if every entry was reached.
+pub fn copy_tree<ST: ReadTree<Entry = IndexEntry>>(
     source: &ST,
-    mut dest: DT,
+    dest: &mut dyn WriteTree,
     options: &CopyOptions,
 ) -> Result<CopyStats> {
+    let start = Instant::now();
     let mut stats = CopyStats::default();
     let mut progress_bar = ProgressBar::new();
     // This causes us to walk the source tree twice, which is probably an acceptable option
     // since it's nice to see realistic overall progress. We could keep all the entries
     // in memory, and maybe we should, but it might get unreasonably big.
-    if options.measure_first {
+    if let Some(expected_bytes) = options.expected_bytes {
+        progress_bar.set_bytes_total(expected_bytes);
+    } else if options.measure_first {
         progress_bar.set_phase("Measure source tree".to_owned());
         // TODO: Maybe read all entries for the source tree in to memory now, rather than walking it
         // again a second time? But, that'll potentially use memory proportional to tree size, which
         // I'd like to avoid, and also perhaps make it more likely we grumble about files that were
         // deleted or changed while this is running.
-        progress_bar.set_bytes_total(source.size(options.excludes.clone())?.file_bytes as u64);
+        progress_bar
+            .set_bytes_total(source.size(options.filter.excludes.clone())?.file_bytes as u64);
     }
 
     progress_bar.set_phase("Copying".to_owned());
     let entry_iter: Box<dyn Iterator<Item = ST::Entry>> =
-        source.iter_filtered(options.only_subtree.clone(), options.excludes.clone())?;
-    for entry in entry_iter {
+        source.iter_filtered(options.only_subtree.clone(), None)?;
+    let excludes = options
+        .filter
+        .excludes
+        .as_ref()
+        .map(Exclude::with_new_counts);
+    let filter = EntryFilter {
+        excludes: excludes.clone(),
+        ..options.filter.clone()
+    };
+    for entry in filter.filter_keeping_parents(entry_iter) {
+        if options.cancel.is_cancelled() {
+            stats.pattern_matches = excludes
+                .as_ref()
+                .map(Exclude::pattern_stats)
+                .unwrap_or_default();
+            stats.elapsed = start.elapsed();
+            return Err(Error::RestoreCancelled {
+                stats: Box::new(stats),
+            });
+        }
         if options.print_filenames {
             crate::ui::println(entry.apath());
         }
@@ -64,7 +99,10 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
             }
             Kind::File => {
                 stats.files += 1;
-                let result = dest.copy_file(&entry, source).map(|s| stats += s);
+                let result = source
+                    .file_contents(&entry)
+                    .and_then(|mut content| dest.copy_file(&entry, &mut content))
+                    .map(|s| stats += s);
                 if let Some(bytes) = entry.size() {
                     progress_bar.increment_bytes_done(bytes);
                 }
@@ -82,12 +120,20 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
                 continue;
             }
         } {
-            ui::show_error(&e);
+            stats.problems.push_error(Some(entry.apath()), &e);
             stats.errors += 1;
             continue;
         }
     }
+    if let Some(err) = source.take_iter_error() {
+        return Err(err);
+    }
+    stats.pattern_matches = excludes
+        .as_ref()
+        .map(Exclude::pattern_stats)
+        .unwrap_or_default();
     stats += dest.finish()?;
     // TODO: Merge in stats from the tree iter and maybe the source tree?
+    stats.elapsed = start.elapsed();
     Ok(stats)
 }
@@ -0,0 +1,449 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Optional encryption of archive contents at rest.
+//!
+//! An encrypted archive has a random 256-bit master key. The master key is
+//! stored in the archive header, encrypted ("wrapped") by a key derived with
+//! Argon2id from the user's `Secret`: either a passphrase or the contents of a
+//! key file.
+//!
+//! Data blocks and index hunks are encrypted with XChaCha20-Poly1305 under the
+//! master key, after compression, each with a fresh random nonce. The file's
+//! path within the archive is authenticated along with it, so that files can't
+//! be swapped or moved without detection. The archive
+//! header and the band head and tail files, which hold only versions, times and
+//! counts, are not encrypted.
+//!
+//! Block hashes are still computed over the plaintext, so that identical
+//! content is deduplicated within the archive. This is a deliberate trade-off:
+//! someone who can read the archive, and who has a guess at the content of a
+//! file, can confirm whether that content is stored, and can see the sizes and
+//! structure of the stored data.
+//!
+//! Encryption is implemented by `EncryptedTransport`, which wraps the transports
+//! used by the block directory and band indexes, so the code above it is
+//! unchanged.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+use crate::transport::{Capacity, DirEntry, ListDirNames, Metadata, Transport};
+use crate::*;
+
+const CIPHER_NAME: &str = "xchacha20poly1305";
+const KDF_NAME: &str = "argon2id";
+
+/// Memory used by the key derivation function, in KiB.
+///
+/// This and the iteration count are also the minimum accepted from an
+/// archive header, so that a tampered header can't make the passphrase cheap
+/// to guess.
+const KDF_MEMORY_KIB: u32 = 19 * 1024;
+const KDF_ITERATIONS: u32 = 2;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// A passphrase or key file contents, from which the archive key is derived.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn from_passphrase(passphrase: &str) -> Secret {
+        Secret(passphrase.as_bytes().to_vec())
+    }
+
+    /// Use the whole contents of a file as the secret.
+    pub fn from_key_file(path: &Path) -> Result<Secret> {
+        std::fs::read(path)
+            .map(Secret)
+            .map_err(|source| Error::ReadKeyFile {
+                path: path.to_owned(),
+                source,
+            })
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Description of the encryption, stored in the archive header.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct EncryptionHeader {
+    cipher: String,
+    kdf: String,
+    kdf_memory_kib: u32,
+    kdf_iterations: u32,
+    /// Hex salt for the key derivation function.
+    salt: String,
+    /// Hex nonce and encrypted master key.
+    wrapped_key: String,
+}
+
+/// The master key for an encrypted archive.
+pub(crate) struct ArchiveKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl ArchiveKey {
+    /// Make a new random master key, wrapped by a key derived from `secret`.
+    pub(crate) fn generate(secret: &Secret) -> Result<(ArchiveKey, EncryptionHeader)> {
+        let master_key = random_bytes(KEY_LEN)?;
+        let salt = random_bytes(SALT_LEN)?;
+        let wrapping_key = derive_key(secret, &salt, KDF_MEMORY_KIB, KDF_ITERATIONS)?;
+        let wrapped_key = seal(&wrapping_key, &master_key)?;
+        let header = EncryptionHeader {
+            cipher: CIPHER_NAME.to_owned(),
+            kdf: KDF_NAME.to_owned(),
+            kdf_memory_kib: KDF_MEMORY_KIB,
+            kdf_iterations: KDF_ITERATIONS,
+            salt: hex::encode(&salt),
+            wrapped_key: hex::encode(&wrapped_key),
+        };
+        Ok((ArchiveKey::new(&master_key), header))
+    }
+
+    /// Recover the master key described by an archive header.
+    ///
+    /// Returns `Error::WrongPassphrase` if the secret doesn't match.
+    pub(crate) fn unwrap(header: &EncryptionHeader, secret: &Secret) -> Result<ArchiveKey> {
+        if header.cipher != CIPHER_NAME || header.kdf != KDF_NAME {
+            return Err(Error::UnsupportedEncryption {
+                cipher: header.cipher.clone(),
+                kdf: header.kdf.clone(),
+            });
+        }
+        let unsupported = || Error::UnsupportedEncryption {
+            cipher: header.cipher.clone(),
+            kdf: header.kdf.clone(),
+        };
+        if header.kdf_memory_kib < KDF_MEMORY_KIB || header.kdf_iterations < KDF_ITERATIONS {
+            return Err(Error::WeakKeyDerivation {
+                memory_kib: header.kdf_memory_kib,
+                iterations: header.kdf_iterations,
+            });
+        }
+        let salt = hex::decode(&header.salt).map_err(|_| unsupported())?;
+        let wrapped_key = hex::decode(&header.wrapped_key).map_err(|_| unsupported())?;
+        let wrapping_key = derive_key(secret, &salt, header.kdf_memory_kib, header.kdf_iterations)?;
+        let master_key = open(&wrapping_key, &wrapped_key).ok_or(Error::WrongPassphrase)?;
+        if master_key.len() != KEY_LEN {
+            return Err(unsupported());
+        }
+        Ok(ArchiveKey::new(&master_key))
+    }
+
+    fn new(key: &[u8]) -> ArchiveKey {
+        ArchiveKey {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+}
+
+impl fmt::Debug for ArchiveKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ArchiveKey(..)")
+    }
+}
+
+/// Derive a key from a secret with Argon2id.
+fn derive_key(secret: &Secret, salt: &[u8], memory_kib: u32, iterations: u32) -> Result<Vec<u8>> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: memory_kib,
+        time_cost: iterations,
+        lanes: 1,
+        hash_length: KEY_LEN as u32,
+        ..argon2::Config::default()
+    };
+    argon2::hash_raw(&secret.0, salt, &config).map_err(|err| Error::UnsupportedEncryption {
+        cipher: CIPHER_NAME.to_owned(),
+        kdf: format!("{} ({})", KDF_NAME, err),
+    })
+}
+
+fn random_bytes(len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    getrandom::getrandom(&mut buf)?;
+    Ok(buf)
+}
+
+/// Encrypt with a fresh random nonce, returning the nonce followed by the ciphertext.
+///
+/// `aad` is authenticated but not encrypted, and must be given again to open it.
+fn seal_with(cipher: &XChaCha20Poly1305, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut sealed = random_bytes(NONCE_LEN)?;
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&sealed),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("XChaCha20Poly1305 encryption failed");
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypt the output of `seal_with`, returning None if it's not authentic.
+fn open_with(cipher: &XChaCha20Poly1305, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+fn seal(key: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    seal_with(
+        &XChaCha20Poly1305::new(Key::from_slice(key)),
+        b"",
+        plaintext,
+    )
+}
+
+fn open(key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    open_with(&XChaCha20Poly1305::new(Key::from_slice(key)), b"", sealed)
+}
+
+/// Join relative paths within the archive.
+fn join_relpath(dir: &str, relpath: &str) -> String {
+    if dir.is_empty() {
+        relpath.to_owned()
+    } else {
+        format!("{}/{}", dir, relpath)
+    }
+}
+
+/// A Transport that encrypts the contents of files written through it, and
+/// decrypts them when read.
+///
+/// File names, directories, and existence are passed through unchanged, but
+/// each file's path from the archive directory is authenticated with its
+/// content.
+#[derive(Clone, Debug)]
+pub(crate) struct EncryptedTransport {
+    inner: Box<dyn Transport>,
+    key: Arc<ArchiveKey>,
+    /// Path of this transport's directory, relative to the archive directory.
+    relpath: String,
+}
+
+impl EncryptedTransport {
+    /// Encrypt files in `inner`, which is the directory `relpath` of the archive.
+    pub(crate) fn new(
+        inner: Box<dyn Transport>,
+        key: Arc<ArchiveKey>,
+        relpath: &str,
+    ) -> EncryptedTransport {
+        EncryptedTransport {
+            inner,
+            key,
+            relpath: relpath.to_owned(),
+        }
+    }
+
+    /// The associated data for a file: its path from the archive directory.
+    fn aad(&self, relpath: &str) -> Vec<u8> {
+        join_relpath(&self.relpath, relpath).into_bytes()
+    }
+}
+
+impl Transport for EncryptedTransport {
+    fn iter_dir_entries(
+        &self,
+        path: &str,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<DirEntry>>>> {
+        self.inner.iter_dir_entries(path)
+    }
+
+    fn list_dir_names(&self, relpath: &str) -> io::Result<ListDirNames> {
+        self.inner.list_dir_names(relpath)
+    }
+
+    fn read_file(&self, path: &str, out_buf: &mut Vec<u8>) -> io::Result<()> {
+        let mut sealed = Vec::new();
+        self.inner.read_file(path, &mut sealed)?;
+        let plaintext = open_with(&self.key.cipher, &self.aad(path), &sealed).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to decrypt {:?}: wrong key or corrupt data", path),
+            )
+        })?;
+        out_buf.clear();
+        out_buf.extend_from_slice(&plaintext);
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> io::Result<bool> {
+        self.inner.exists(path)
+    }
+
+    fn create_dir(&self, relpath: &str) -> io::Result<()> {
+        self.inner.create_dir(relpath)
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        self.inner.write_file(
+            relpath,
+            &seal_with(&self.key.cipher, &self.aad(relpath), content)?,
+        )
+    }
+
+    fn write_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        self.inner.write_new_file(
+            relpath,
+            &seal_with(&self.key.cipher, &self.aad(relpath), content)?,
+        )
+    }
+
+    /// Returns the metadata of the encrypted file, so the length includes the
+    /// encryption overhead.
+    fn metadata(&self, relpath: &str) -> io::Result<Metadata> {
+        self.inner.metadata(relpath)
+    }
+
+    fn remove_file(&self, relpath: &str) -> io::Result<()> {
+        self.inner.remove_file(relpath)
+    }
+
+    fn remove_dir(&self, relpath: &str) -> io::Result<()> {
+        self.inner.remove_dir(relpath)
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> io::Result<()> {
+        self.inner.remove_dir_all(relpath)
+    }
+
+    fn capacity(&self) -> io::Result<Option<Capacity>> {
+        self.inner.capacity()
+    }
+
+    fn local_path(&self) -> Option<PathBuf> {
+        self.inner.local_path()
+    }
+
+    fn sub_transport(&self, relpath: &str) -> Box<dyn Transport> {
+        Box::new(EncryptedTransport {
+            inner: self.inner.sub_transport(relpath),
+            key: self.key.clone(),
+            relpath: join_relpath(&self.relpath, relpath),
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn Transport> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::local::LocalTransport;
+
+    #[test]
+    fn wrap_and_unwrap_key() {
+        let secret = Secret::from_passphrase("correct horse");
+        let (key, header) = ArchiveKey::generate(&secret).unwrap();
+        let sealed = seal_with(&key.cipher, b"f", b"hello").unwrap();
+
+        let key2 = ArchiveKey::unwrap(&header, &Secret::from_passphrase("correct horse")).unwrap();
+        assert_eq!(open_with(&key2.cipher, b"f", &sealed).unwrap(), b"hello");
+        assert!(open_with(&key2.cipher, b"g", &sealed).is_none());
+
+        assert!(matches!(
+            ArchiveKey::unwrap(&header, &Secret::from_passphrase("battery staple")),
+            Err(Error::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn weak_key_derivation_is_refused() {
+        let secret = Secret::from_passphrase("correct horse");
+        let (_key, header) = ArchiveKey::generate(&secret).unwrap();
+        for (memory_kib, iterations) in [(8, KDF_ITERATIONS), (KDF_MEMORY_KIB, 1)] {
+            let weak_header = EncryptionHeader {
+                kdf_memory_kib: memory_kib,
+                kdf_iterations: iterations,
+                ..header.clone()
+            };
+            assert!(matches!(
+                ArchiveKey::unwrap(&weak_header, &secret),
+                Err(Error::WeakKeyDerivation { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn encrypted_file_moved_to_another_path_is_refused() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let (key, _header) = ArchiveKey::generate(&Secret::from_passphrase("pw")).unwrap();
+        let transport = EncryptedTransport::new(
+            Box::new(LocalTransport::new(temp.path())),
+            Arc::new(key),
+            "d",
+        );
+        transport.create_dir("sub").unwrap();
+        transport.write_file("sub/a", b"content of a").unwrap();
+        let mut buf = Vec::new();
+        transport
+            .sub_transport("sub")
+            .read_file("a", &mut buf)
+            .unwrap();
+        assert_eq!(buf, b"content of a");
+
+        std::fs::copy(temp.path().join("sub/a"), temp.path().join("sub/b")).unwrap();
+        let err = transport.read_file("sub/b", &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encrypted_transport_round_trip() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let (key, _header) = ArchiveKey::generate(&Secret::from_passphrase("pw")).unwrap();
+        let transport = EncryptedTransport::new(
+            Box::new(LocalTransport::new(temp.path())),
+            Arc::new(key),
+            "",
+        );
+        transport.write_file("f", b"some content").unwrap();
+
+        let raw = std::fs::read(temp.path().join("f")).unwrap();
+        assert_eq!(raw.len(), NONCE_LEN + b"some content".len() + 16);
+        assert!(!raw.windows(4).any(|w| w == b"some"));
+
+        let mut buf = Vec::new();
+        transport.read_file("f", &mut buf).unwrap();
+        assert_eq!(buf, b"some content");
+    }
+}
@@ -11,41 +11,162 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
+//! Compare two trees: a stored tree and a live tree, or two live trees.
+
 use std::io::prelude::*;
 use std::io::{stdout, BufWriter};
+use std::sync::Arc;
+
+use blake2_rfc::blake2b::Blake2b;
+use serde::Serialize;
 
 use crate::*;
 
+/// Size of the buffer used to read file contents while hashing them.
+const HASH_BUF_SIZE: usize = 1 << 20;
+
+#[derive(Clone, Debug, Default)]
 pub struct DiffOptions {
-    pub excludes: Option<GlobSet>,
+    pub excludes: Option<Exclude>,
+    /// Hash the contents of files that have the same size on both sides, to
+    /// find files whose content changed without changing their size.
+    pub compare_content: bool,
+    /// Write one json object per entry, rather than a line of text.
+    pub json: bool,
+    /// Told about files whose contents can't be compared, or by default the
+    /// UI's problem sink.
+    pub problems: Option<Arc<dyn ProblemSink>>,
 }
 
-pub fn diff(st: &StoredTree, lt: &LiveTree, options: &DiffOptions) -> Result<()> {
-    // TODO: Consider whether the actual files have changed.
-    // TODO: Summarize diff.
-    // TODO: Optionally include unchanged files.
+/// How an entry differs between the left and right trees.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffKind {
+    /// Present only in the left tree.
+    Left,
+    /// Present only in the right tree.
+    Right,
+    /// Present in both trees, and not seen to differ.
+    Both,
+    /// Present in both trees, with a different kind, size, symlink target,
+    /// or (if compared) content.
+    Changed,
+}
 
-    show_tree_diff(
-        &mut MergeTrees::new(
-            st.iter_filtered(None, options.excludes.clone())?,
-            lt.iter_filtered(None, options.excludes.clone())?,
-        ),
-        &mut stdout(),
-    )
+impl DiffKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DiffKind::Left => "left",
+            DiffKind::Right => "right",
+            DiffKind::Both => "both",
+            DiffKind::Changed => "changed",
+        }
+    }
 }
 
-fn show_tree_diff(
-    iter: &mut dyn Iterator<Item = crate::merge::MergedEntry>,
+/// One entry in the comparison of two trees.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DiffEntry {
+    pub apath: Apath,
+    pub change: DiffKind,
+}
+
+/// Show the differences between a stored tree and a live tree on stdout.
+pub fn diff(st: &StoredTree, lt: &LiveTree, options: &DiffOptions) -> Result<()> {
+    diff_trees(st, lt, options, &mut stdout())
+}
+
+/// Write the differences between any two trees to `w`, as text or json.
+pub fn diff_trees<A: ReadTree, B: ReadTree>(
+    a: &A,
+    b: &B,
+    options: &DiffOptions,
     w: &mut dyn Write,
 ) -> Result<()> {
+    // TODO: Summarize diff.
     let mut bw = BufWriter::new(w);
-    for e in iter {
-        let ks = match e.kind {
-            MergedEntryKind::LeftOnly => "left",
-            MergedEntryKind::RightOnly => "right",
-            MergedEntryKind::Both => "both",
-        };
-        writeln!(bw, "{:<8} {}", ks, e.apath)?;
+    for de in iter_diff(a, b, options)? {
+        if options.json {
+            writeln!(bw, "{}", serde_json::to_string(&de).unwrap())?;
+        } else {
+            writeln!(bw, "{:<8} {}", de.change.name(), de.apath)?;
+        }
+    }
+    if let Some(err) = a.take_iter_error().or_else(|| b.take_iter_error()) {
+        return Err(err);
     }
     Ok(())
 }
+
+/// Iterate, in apath order, how each entry differs between two trees.
+///
+/// Errors reading file contents to compare them are reported as problems, and
+/// the entry is then compared only by its metadata. If either tree stops
+/// early, the error can then be taken with [ReadTree::take_iter_error].
+pub fn iter_diff<'a, A: ReadTree, B: ReadTree>(
+    a: &'a A,
+    b: &'a B,
+    options: &'a DiffOptions,
+) -> Result<impl Iterator<Item = DiffEntry> + 'a> {
+    let merge = MergeTrees::new(
+        a.iter_filtered(None, options.excludes.clone())?,
+        b.iter_filtered(None, options.excludes.clone())?,
+    );
+    Ok(merge.map(move |me| {
+        let change = match (me.left, me.right) {
+            (Some(ae), Some(be)) => compare_entries(a, b, &ae, &be, options),
+            (Some(_), None) => DiffKind::Left,
+            _ => DiffKind::Right,
+        };
+        DiffEntry {
+            apath: me.apath,
+            change,
+        }
+    }))
+}
+
+fn compare_entries<A: ReadTree, B: ReadTree>(
+    a: &A,
+    b: &B,
+    ae: &A::Entry,
+    be: &B::Entry,
+    options: &DiffOptions,
+) -> DiffKind {
+    if ae.kind() != be.kind()
+        || ae.size() != be.size()
+        || ae.symlink_target() != be.symlink_target()
+    {
+        return DiffKind::Changed;
+    }
+    if options.compare_content && ae.kind() == Kind::File && ae.size() != Some(0) {
+        match (hash_file_contents(a, ae), hash_file_contents(b, be)) {
+            (Ok(ahash), Ok(bhash)) => {
+                if ahash != bhash {
+                    return DiffKind::Changed;
+                }
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                ui::problem_sink_or(&options.problems).report(Problem::new(
+                    ProblemKind::Compare,
+                    Some(ae.apath()),
+                    format!("Failed to compare contents: {}", err),
+                ))
+            }
+        }
+    }
+    DiffKind::Both
+}
+
+/// Hash the whole contents of a file, in the same way as blocks are hashed.
+fn hash_file_contents<T: ReadTree>(tree: &T, entry: &T::Entry) -> Result<BlockHash> {
+    let mut r = tree.file_contents(entry)?;
+    let mut hasher = Blake2b::new(BLAKE_HASH_SIZE_BYTES);
+    let mut buf = vec![0; HASH_BUF_SIZE];
+    loop {
+        let len = r.read(&mut buf)?;
+        if len == 0 {
+            return Ok(BlockHash::from(hasher.finalize()));
+        }
+        hasher.update(&buf[..len]);
+    }
+}
@@ -20,12 +20,35 @@ use crate::kind::Kind;
 use crate::unix_time::UnixTime;
 use crate::*;
 
+/// Identifies a file on a Unix filesystem, across renames, by its device and
+/// inode numbers.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
 pub trait Entry: Debug + Eq + PartialEq {
     fn apath(&self) -> &Apath;
     fn kind(&self) -> Kind;
     fn mtime(&self) -> UnixTime;
     fn size(&self) -> Option<u64>;
     fn symlink_target(&self) -> &Option<String>;
+    /// Extended attributes, or None if there are none.
+    fn xattrs(&self) -> Option<&Xattrs>;
+    /// Unix permission bits, if known.
+    fn unix_mode(&self) -> Option<u32>;
+
+    /// Whether the file was seen to change while it was being backed up, or
+    /// None if that's not recorded, as for live files.
+    fn changed_during_backup(&self) -> Option<bool> {
+        None
+    }
+
+    /// The device and inode numbers of a file, if known.
+    fn file_id(&self) -> Option<FileId> {
+        None
+    }
 
     /// True if the metadata supports an assumption the file contents have
     /// not changed.
@@ -0,0 +1,252 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Select which archived entries are restored, listed, or exported.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+
+use crate::*;
+
+/// Select entries by name, kind, and size.
+///
+/// An entry is included only if it passes every condition.
+#[derive(Clone, Debug, Default)]
+pub struct EntryFilter {
+    /// Skip entries excluded by these patterns.
+    pub excludes: Option<Exclude>,
+    /// Skip entries of these kinds.
+    pub skip_kinds: Vec<Kind>,
+    /// Skip files larger than this many bytes.
+    pub max_size: Option<u64>,
+}
+
+impl EntryFilter {
+    /// A filter that includes everything.
+    pub fn new() -> EntryFilter {
+        EntryFilter::default()
+    }
+
+    /// Skip symlinks.
+    pub fn no_symlinks(mut self) -> EntryFilter {
+        self.skip_kinds.push(Kind::Symlink);
+        self
+    }
+
+    /// Include only files: not directories, symlinks, or other kinds.
+    pub fn files_only(mut self) -> EntryFilter {
+        self.skip_kinds
+            .extend(&[Kind::Dir, Kind::Symlink, Kind::Unknown]);
+        self
+    }
+
+    /// True if this filter doesn't exclude anything.
+    pub fn includes_everything(&self) -> bool {
+        self.excludes.is_none() && self.skip_kinds.is_empty() && self.max_size.is_none()
+    }
+
+    /// True if the entry should be included.
+    pub fn matches<E: Entry>(&self, entry: &E) -> bool {
+        if let Some(excludes) = &self.excludes {
+            if excludes.is_excluded(entry.apath(), entry.kind()) {
+                return false;
+            }
+        }
+        if self.skip_kinds.contains(&entry.kind()) {
+            return false;
+        }
+        match (entry.kind(), self.max_size) {
+            (Kind::File, Some(max_size)) => entry.size().unwrap_or_default() <= max_size,
+            _ => true,
+        }
+    }
+
+    /// Filter entries from a tree iterator, but still return directories
+    /// that were filtered out if they're needed as parents of included
+    /// entries.
+    ///
+    /// Those directories are returned just before their first included
+    /// descendant, so they still come before their contents, although not
+    /// always in apath order relative to their siblings.
+    pub fn filter_keeping_parents<E, I>(self, entries: I) -> FilterKeepingParents<E, I>
+    where
+        E: Entry,
+        I: Iterator<Item = E>,
+    {
+        FilterKeepingParents {
+            filter: self,
+            entries,
+            held_dirs: BTreeMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+}
+
+impl From<Exclude> for EntryFilter {
+    fn from(excludes: Exclude) -> EntryFilter {
+        EntryFilter {
+            excludes: Some(excludes),
+            ..EntryFilter::default()
+        }
+    }
+}
+
+impl From<Option<Exclude>> for EntryFilter {
+    fn from(excludes: Option<Exclude>) -> EntryFilter {
+        EntryFilter {
+            excludes,
+            ..EntryFilter::default()
+        }
+    }
+}
+
+/// Iterator returned by [EntryFilter::filter_keeping_parents].
+pub struct FilterKeepingParents<E, I> {
+    filter: EntryFilter,
+    entries: I,
+    /// Directories that were filtered out, keyed by their path components, in
+    /// case something inside them is included. They're dropped once the
+    /// iteration has passed their subtree.
+    held_dirs: BTreeMap<Vec<String>, E>,
+    /// Entries to return before reading any more.
+    ready: VecDeque<E>,
+}
+
+impl<E: Entry, I: Iterator<Item = E>> Iterator for FilterKeepingParents<E, I> {
+    type Item = E;
+
+    fn next(&mut self) -> Option<E> {
+        loop {
+            if let Some(entry) = self.ready.pop_front() {
+                return Some(entry);
+            }
+            let entry = self.entries.next()?;
+            let mut components: Vec<String> = entry
+                .apath()
+                .split('/')
+                .filter(|c| !c.is_empty())
+                .map(str::to_owned)
+                .collect();
+            let name = components.pop();
+            if !self.held_dirs.is_empty() {
+                self.drop_passed_dirs(&components);
+            }
+            if !self.filter.matches(&entry) {
+                if entry.kind() == Kind::Dir {
+                    components.extend(name);
+                    self.held_dirs.insert(components, entry);
+                }
+                continue;
+            }
+            for len in 0..=components.len() {
+                if let Some(dir) = self.held_dirs.remove(&components[..len]) {
+                    self.ready.push_back(dir);
+                }
+            }
+            self.ready.push_back(entry);
+        }
+    }
+}
+
+impl<E, I> FilterKeepingParents<E, I> {
+    /// Drop held directories whose subtrees are entirely before entries in
+    /// the directory `parent`.
+    ///
+    /// Entries come in apath order, which lists each directory's children
+    /// after those of every directory whose components sort before its own.
+    /// So any held directory sorting before `parent` is finished, except for
+    /// the ancestors of `parent`.
+    fn drop_passed_dirs(&mut self, parent: &[String]) {
+        let passed: Vec<Vec<String>> = self
+            .held_dirs
+            .range::<[String], _>((Bound::Unbounded, Bound::Excluded(parent)))
+            .map(|(dir, _)| dir)
+            .filter(|dir| !parent.starts_with(dir))
+            .cloned()
+            .collect();
+        for dir in passed {
+            self.held_dirs.remove(&dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    fn names<I: Iterator<Item = LiveEntry>>(entries: I) -> Vec<String> {
+        entries.map(|entry| entry.apath().to_string()).collect()
+    }
+
+    #[test]
+    fn files_only_keeps_needed_parents() {
+        let tf = TreeFixture::new();
+        tf.create_dir("empty");
+        tf.create_dir("full");
+        tf.create_dir("full/deeper");
+        tf.create_file("full/deeper/file");
+        tf.create_file("top");
+        let entries = tf.live_tree().iter_entries().unwrap();
+
+        let filter = EntryFilter::new().files_only();
+        assert_eq!(
+            names(filter.clone().filter_keeping_parents(entries)),
+            ["/", "/top", "/full", "/full/deeper", "/full/deeper/file"]
+        );
+        let entries = tf.live_tree().iter_entries().unwrap();
+        assert_eq!(
+            names(entries.filter(|entry| filter.matches(entry))),
+            ["/top", "/full/deeper/file"]
+        );
+    }
+
+    #[test]
+    fn held_dirs_are_dropped_after_their_subtree() {
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_dir("a/sub");
+        tf.create_file("a/sub/file");
+        tf.create_file("a/y");
+        tf.create_dir("b");
+        tf.create_dir("b/empty");
+        tf.create_dir("c");
+        tf.create_file("c/file");
+        let entries = tf.live_tree().iter_entries().unwrap();
+
+        let mut filtered = EntryFilter::new()
+            .files_only()
+            .filter_keeping_parents(entries);
+        assert_eq!(
+            names(filtered.by_ref()),
+            ["/", "/a", "/a/y", "/a/sub", "/a/sub/file", "/c", "/c/file"]
+        );
+        assert!(filtered.held_dirs.is_empty());
+    }
+
+    #[test]
+    fn max_size_only_applies_to_files() {
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents("small", b"12");
+        tf.create_file_with_contents("large", b"123456");
+        let filter = EntryFilter {
+            max_size: Some(4),
+            ..EntryFilter::default()
+        };
+        let entries = tf.live_tree().iter_entries().unwrap();
+        assert_eq!(
+            names(entries.filter(|entry| filter.matches(entry))),
+            ["/", "/small"]
+        );
+    }
+}
@@ -13,8 +13,13 @@
 
 //! Conserve error types.
 
+use std::fmt;
+use std::io;
+use std::ops::{Add, AddAssign};
 use std::path::PathBuf;
+use std::sync::Mutex;
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::blockdir::Address;
@@ -46,12 +51,23 @@ pub enum Error {
     #[error("Failed to read archive header")]
     ReadArchiveHeader { source: std::io::Error },
 
+    #[error("Failed to write, read back, and delete probe file {path:?} in the archive")]
+    PreflightProbe { path: String, source: IOError },
+
     #[error(
-        "Archive version {:?} is not supported by Conserve {}",
-        version,
-        crate::version()
+        "Only {} available for the archive, but the last backup wrote {}",
+        crate::bytes_to_human(*.available),
+        crate::bytes_to_human(*.needed)
     )]
-    UnsupportedArchiveVersion { version: String },
+    InsufficientSpace { available: u64, needed: u64 },
+
+    #[error(
+        "Archive version {:?} is not supported by Conserve {}, which supports {}",
+        found,
+        crate::version(),
+        supported
+    )]
+    UnsupportedArchiveVersion { found: String, supported: String },
 
     #[error(
         "Band version {version:?} in {band_id} is not supported by Conserve {}",
@@ -59,15 +75,57 @@ pub enum Error {
     )]
     UnsupportedBandVersion { band_id: BandId, version: String },
 
+    #[error("Archive is encrypted, and no passphrase or key file was given")]
+    ArchiveEncrypted,
+
+    #[error("Wrong passphrase or key file for encrypted archive")]
+    WrongPassphrase,
+
+    #[error("Passphrases do not match")]
+    PassphraseMismatch,
+
+    #[error("Unsupported encryption {cipher:?} with key derivation {kdf:?}")]
+    UnsupportedEncryption { cipher: String, kdf: String },
+
+    #[error(
+        "Key derivation in the archive header is weaker than allowed: \
+        {memory_kib} KiB and {iterations} iterations"
+    )]
+    WeakKeyDerivation { memory_kib: u32, iterations: u32 },
+
+    #[error("A key file was given, but the new archive is not encrypted")]
+    KeyFileWithoutEncryption,
+
+    #[error("Failed to read key file {:?}", path)]
+    ReadKeyFile { path: PathBuf, source: IOError },
+
     #[error("Destination directory not empty: {:?}", path)]
     DestinationNotEmpty { path: PathBuf },
 
     #[error("Archive has no bands")]
     ArchiveEmpty,
 
+    #[error(
+        "Archive has no complete backups; the newest, {newest}, is incomplete \
+        and may be partial, but can be read with `--backup {newest}`"
+    )]
+    NoCompleteBands { newest: BandId },
+
+    #[error(
+        "No backup {band_id} in archive; available backups: {}",
+        band_list(available)
+    )]
+    BandNotFound {
+        band_id: BandId,
+        available: Vec<BandId>,
+    },
+
     #[error("Directory for new archive is not empty")]
     NewArchiveDirectoryNotEmpty,
 
+    #[error("Directory for new archive already holds an archive")]
+    ArchiveAlreadyExists,
+
     #[error("Invalid backup version number {:?}", version)]
     InvalidVersion { version: String },
 
@@ -83,6 +141,12 @@ pub enum Error {
     #[error("Band {} is incomplete", band_id)]
     BandIncomplete { band_id: BandId },
 
+    #[error(
+        "Band {} already exists in the destination archive with different contents",
+        band_id
+    )]
+    BandConflict { band_id: BandId },
+
     #[error(
         "Can't delete blocks because the last band ({}) is incomplete and may be in use",
         band_id
@@ -95,6 +159,35 @@ pub enum Error {
     #[error("Archive is locked for garbage collection")]
     GarbageCollectionLockHeld,
 
+    #[error("Invalid tag {tag:?}: tags must be non-empty and not look like a backup id")]
+    InvalidTag { tag: String },
+
+    #[error("No backup is tagged {tag:?}; available tags: {available:?}")]
+    TagNotFound { tag: String, available: Vec<String> },
+
+    #[error("Invalid time {timestamp:?}; expected a date, a date and time, or something like \"3 days ago\"")]
+    InvalidTimestamp { timestamp: String },
+
+    #[error("No complete backup started at or before {cutoff}")]
+    NoBandBefore {
+        cutoff: chrono::DateTime<chrono::Utc>,
+    },
+
+    #[error(
+        "The clock reads {now}, earlier than the start of the previous backup {previous_band} at {previous_start}"
+    )]
+    ClockSkew {
+        previous_band: BandId,
+        previous_start: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+    },
+
+    #[error("No entry {apath} in {band_id}")]
+    EntryNotFound { apath: Apath, band_id: BandId },
+
+    #[error("Archive is locked by process {pid} on {hostname:?}")]
+    ArchiveLocked { hostname: String, pid: u32 },
+
     #[error(transparent)]
     ParseGlob {
         #[from]
@@ -110,12 +203,46 @@ pub enum Error {
     #[error("Failed to serialize index")]
     SerializeIndex { source: serde_json::Error },
 
+    #[error("Index hunk {path:?} is listed in the manifest but missing")]
+    MissingIndexHunk { path: String },
+
+    #[error("Index hunk {path:?} doesn't match the hash in the manifest")]
+    IndexHunkHashMismatch { path: String },
+
+    #[error("Index hunk {path:?} is beyond the hunks listed in the manifest")]
+    UnexpectedIndexHunk { path: String },
+
     #[error("Failed to deserialize index hunk {:?}", path)]
     DeserializeIndex {
         path: String,
         source: serde_json::Error,
     },
 
+    #[error("Failed to serialize index")]
+    SerializeIndexCbor { source: serde_cbor::Error },
+
+    #[error("Failed to deserialize index hunk {:?}", path)]
+    DeserializeIndexCbor {
+        path: String,
+        source: serde_cbor::Error,
+    },
+
+    #[error("Invalid apath {apath:?} in index")]
+    InvalidApath { apath: String },
+
+    #[error("Unsupported index format {format:?}; expected \"json\" or \"cbor\"")]
+    UnsupportedIndexFormat { format: String },
+
+    #[error(
+        "Invalid chunk sizes: minimum {min_size}, average {avg_size}, maximum {max_size}; \
+         they must increase, from at least 64 bytes to at most 1MiB"
+    )]
+    InvalidChunkSizes {
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    },
+
     #[error("Failed to write metadata file {:?}", path)]
     WriteMetadata {
         path: String,
@@ -146,18 +273,59 @@ pub enum Error {
     #[error("Failed to read source tree {:?}", path)]
     ListSourceTree { path: PathBuf, source: IOError },
 
+    #[error("Can't map source {:?} to an apath; give it one with --as", path)]
+    UnmappableSourcePath { path: PathBuf },
+
+    #[error("Source roots {first} and {second} overlap")]
+    OverlappingSourceRoots { first: Apath, second: Apath },
+
+    #[error(
+        "Given {mappings} --as mappings for {sources} sources; give one for each source, or none"
+    )]
+    SourceMappingCount { sources: usize, mappings: usize },
+
     #[error("Failed to store file {:?}", apath)]
     StoreFile { apath: Apath, source: IOError },
 
+    #[error("Failed to start worker threads")]
+    StartThreads { source: rayon::ThreadPoolBuildError },
+
     #[error("Failed to restore {:?}", path)]
     Restore { path: PathBuf, source: IOError },
 
+    #[error("Not replacing existing directory {:?} without replace_dirs", path)]
+    DestinationIsDirectory { path: PathBuf },
+
+    #[error("Not restoring through symlink or non-directory {:?}", path)]
+    SymlinkInDestination { path: PathBuf },
+
+    #[error("Failed to write {:?} to tar stream", apath)]
+    WriteTarEntry { apath: Apath, source: IOError },
+
+    #[error("Failed to write tar stream")]
+    WriteTar { source: IOError },
+
+    #[error("Failed to read tar stream")]
+    ReadTar { source: IOError },
+
     #[error("Failed to restore modification time on {:?}", path)]
     RestoreModificationTime { path: PathBuf, source: IOError },
 
+    #[error("Failed to mount archive on {:?}", path)]
+    Mount { path: PathBuf, source: IOError },
+
     #[error("Failed to delete band {}", band_id)]
     BandDeletion { band_id: BandId, source: IOError },
 
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("Backup cancelled; the new version is incomplete")]
+    BackupCancelled { stats: Box<BackupStats> },
+
+    #[error("Restore cancelled; the destination is incomplete")]
+    RestoreCancelled { stats: Box<CopyStats> },
+
     /// Generic IO error.
     #[error(transparent)]
     IOError {
@@ -171,3 +339,380 @@ pub enum Error {
         source: snap::Error,
     },
 }
+
+/// How an error affects the operation that encountered it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum ErrorCategory {
+    /// The operation can't usefully continue: for example the archive is
+    /// missing, locked, or has corrupt metadata.
+    Fatal,
+    /// A condition that might clear up if the operation is retried, such as an
+    /// interrupted call, a timeout, or a full disk.
+    Transient,
+    /// A problem with one entry, such as an unreadable source file, that
+    /// doesn't prevent the rest of the tree being processed.
+    PerEntry,
+}
+
+impl Error {
+    /// Classify this error as fatal, transient, or affecting only one entry.
+    pub fn category(&self) -> ErrorCategory {
+        if self.io_source().is_some_and(is_transient_io_error) {
+            return ErrorCategory::Transient;
+        }
+        match self {
+            Error::ReadSourceFile { .. }
+            | Error::ListSourceTree { .. }
+            | Error::StoreFile { .. }
+            | Error::Restore { .. }
+            | Error::DestinationIsDirectory { .. }
+            | Error::SymlinkInDestination { .. }
+            | Error::RestoreModificationTime { .. }
+            | Error::WriteTarEntry { .. }
+            | Error::InvalidApath { .. }
+            | Error::BlockCorrupt { .. }
+            | Error::AddressTooLong { .. }
+            | Error::ReadBlock { .. } => ErrorCategory::PerEntry,
+            _ => ErrorCategory::Fatal,
+        }
+    }
+
+    /// The underlying IO error, if this error was caused by one.
+    fn io_source(&self) -> Option<&IOError> {
+        match self {
+            Error::IOError { source } => Some(source),
+            _ => std::error::Error::source(self)?.downcast_ref::<IOError>(),
+        }
+    }
+}
+
+fn is_transient_io_error(err: &IOError) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::StorageFull
+    ) || crate::io::is_out_of_file_handles(err)
+}
+
+/// Format band ids for an error message, like "b0000, b0001".
+fn band_list(band_ids: &[BandId]) -> String {
+    if band_ids.is_empty() {
+        "none".to_owned()
+    } else {
+        band_ids
+            .iter()
+            .map(BandId::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// What sort of thing a [Problem] is about, for callers that handle problems
+/// programmatically rather than by showing their message.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// Any other error from an operation that continued.
+    #[default]
+    Error,
+    /// A source directory, file, or name that couldn't be read.
+    SourceTree,
+    /// Extended attributes that couldn't be read or restored.
+    Xattr,
+    /// A symlink that couldn't be restored, or has no target.
+    UnrestorableSymlink,
+    /// An entry restored under a different name.
+    RenamedEntry,
+    /// A band whose start time is out of order with the clock or other bands.
+    ClockSkew,
+    /// An incomplete band read as if it were a complete tree.
+    IncompleteBand,
+    /// A band that couldn't be read.
+    Band,
+    /// Unexpected or missing files or directories in the archive.
+    ArchiveStructure,
+    /// An index hunk or manifest that couldn't be read or was damaged.
+    Index,
+    /// A block that is missing, damaged, or unexpectedly present.
+    Block,
+    /// An unknown key in the archive config.
+    Config,
+    /// A lock left by a process that no longer exists.
+    StaleLock,
+    /// A lock that couldn't be released, or that another process took over.
+    Lock,
+    /// A tar entry that couldn't be imported.
+    TarEntry,
+    /// An exclude or subtree pattern that matched nothing.
+    UnmatchedPattern,
+    /// A file whose contents couldn't be read to compare them.
+    Compare,
+}
+
+impl ProblemKind {
+    /// How serious problems of this kind are.
+    pub fn severity(self) -> Severity {
+        use ProblemKind::*;
+        match self {
+            ClockSkew | IncompleteBand | UnmatchedPattern | StaleLock | Config => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+/// Whether a [Problem] means that something went wrong, or is only advice.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Worth knowing, but nothing was lost or left undone.
+    Warning,
+    /// Something couldn't be read, written, or checked.
+    Error,
+}
+
+/// A non-fatal error encountered during an operation, which continued.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(default)]
+    pub kind: ProblemKind,
+    /// The entry affected, if the problem concerns one entry.
+    #[serde(default)]
+    pub apath: Option<Apath>,
+    pub category: ErrorCategory,
+    /// Description of the error, including its causes.
+    pub message: String,
+}
+
+impl Problem {
+    /// Describe a problem that didn't stop the operation or the entry.
+    pub fn new<S: Into<String>>(kind: ProblemKind, apath: Option<&Apath>, message: S) -> Problem {
+        Problem {
+            kind,
+            apath: apath.cloned(),
+            category: ErrorCategory::PerEntry,
+            message: message.into(),
+        }
+    }
+
+    pub fn from_error(apath: Option<&Apath>, err: &Error) -> Problem {
+        let mut message = err.to_string();
+        let mut cause: &dyn std::error::Error = err;
+        while let Some(c) = cause.source() {
+            message.push_str(&format!("\n  caused by: {}", c));
+            cause = c;
+        }
+        Problem {
+            kind: ProblemKind::Error,
+            apath: apath.cloned(),
+            category: err.category(),
+            message,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.kind.severity()
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.apath {
+            Some(apath) => write!(f, "{}: {}", apath, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Non-fatal problems accumulated by backup, restore, or validate, and
+/// returned to the caller in their stats.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Problems(Vec<Problem>);
+
+impl Problems {
+    pub fn new() -> Problems {
+        Problems::default()
+    }
+
+    pub fn push(&mut self, problem: Problem) {
+        self.0.push(problem)
+    }
+
+    /// Record an error affecting `apath`, or the operation generally.
+    pub fn push_error(&mut self, apath: Option<&Apath>, err: &Error) {
+        self.push(Problem::from_error(apath, err))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Problem> {
+        self.0.iter()
+    }
+
+    /// Report each problem to the UI's problem sink.
+    pub fn show(&self) {
+        for problem in &self.0 {
+            crate::ui::report_problem(problem.clone());
+        }
+    }
+}
+
+/// Receives problems as an operation encounters them.
+///
+/// The command line prints and counts them: see [crate::ui::PrintProblems].
+/// Library callers can collect them, for example in a `Mutex<Problems>`.
+pub trait ProblemSink: fmt::Debug + Send + Sync {
+    fn report(&self, problem: Problem);
+}
+
+impl ProblemSink for Mutex<Problems> {
+    fn report(&self, problem: Problem) {
+        self.lock().unwrap().push(problem)
+    }
+}
+
+impl Add for Problems {
+    type Output = Problems;
+
+    fn add(mut self, other: Problems) -> Problems {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for Problems {
+    fn add_assign(&mut self, other: Problems) {
+        self.0.extend(other.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn categories() {
+        let io_error = |kind| IOError::new(kind, "test");
+        assert_eq!(Error::ArchiveEmpty.category(), ErrorCategory::Fatal);
+        assert_eq!(
+            Error::DeserializeJson {
+                path: "BANDHEAD".into(),
+                source: serde_json::from_str::<u32>("{").unwrap_err(),
+            }
+            .category(),
+            ErrorCategory::Fatal
+        );
+        assert_eq!(
+            Error::ReadSourceFile {
+                path: "/src/a".into(),
+                source: io_error(io::ErrorKind::PermissionDenied),
+            }
+            .category(),
+            ErrorCategory::PerEntry
+        );
+        assert_eq!(
+            Error::InvalidApath {
+                apath: "/../a".to_owned()
+            }
+            .category(),
+            ErrorCategory::PerEntry
+        );
+        assert_eq!(
+            Error::ReadSourceFile {
+                path: "/src/a".into(),
+                source: io_error(io::ErrorKind::Interrupted),
+            }
+            .category(),
+            ErrorCategory::Transient
+        );
+        assert_eq!(
+            Error::WriteBlock {
+                hash: "00".to_owned(),
+                source: io_error(io::ErrorKind::StorageFull),
+            }
+            .category(),
+            ErrorCategory::Transient
+        );
+        assert_eq!(
+            Error::WriteBlock {
+                hash: "00".to_owned(),
+                source: io_error(io::ErrorKind::PermissionDenied),
+            }
+            .category(),
+            ErrorCategory::Fatal
+        );
+        assert_eq!(
+            Error::from(io_error(io::ErrorKind::TimedOut)).category(),
+            ErrorCategory::Transient
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            Error::ListSourceTree {
+                path: "/src".into(),
+                source: IOError::from_raw_os_error(libc::EMFILE),
+            }
+            .category(),
+            ErrorCategory::Transient
+        );
+    }
+
+    #[test]
+    fn problems_accumulate() {
+        let mut problems = Problems::new();
+        assert!(problems.is_empty());
+        problems.push_error(
+            Some(&Apath::from("/a")),
+            &Error::ReadSourceFile {
+                path: "/src/a".into(),
+                source: IOError::new(io::ErrorKind::PermissionDenied, "denied"),
+            },
+        );
+        let mut other = Problems::new();
+        other.push_error(None, &Error::ArchiveEmpty);
+        let problems = problems + other;
+        assert_eq!(problems.len(), 2);
+        let first = problems.iter().next().unwrap();
+        assert_eq!(first.apath, Some(Apath::from("/a")));
+        assert_eq!(first.category, ErrorCategory::PerEntry);
+        assert_eq!(
+            first.to_string(),
+            "/a: Failed to read source file \"/src/a\"\n  caused by: denied"
+        );
+        assert_eq!(first.kind, ProblemKind::Error);
+    }
+
+    #[test]
+    fn problems_sink_collects_kinds() {
+        let sink = Mutex::new(Problems::new());
+        sink.report(Problem::new(
+            ProblemKind::UnrestorableSymlink,
+            Some(&Apath::from("/link")),
+            "No target in symlink entry",
+        ));
+        sink.report(Problem::new(ProblemKind::ClockSkew, None, "Clock skew"));
+        let problems = sink.into_inner().unwrap();
+        let kinds: Vec<ProblemKind> = problems.iter().map(|p| p.kind).collect();
+        assert_eq!(
+            kinds,
+            [ProblemKind::UnrestorableSymlink, ProblemKind::ClockSkew]
+        );
+        let severities: Vec<Severity> = problems.iter().map(Problem::severity).collect();
+        assert_eq!(severities, [Severity::Error, Severity::Warning]);
+        let json = serde_json::to_string(&problems).unwrap();
+        assert!(
+            json.contains(r#""kind":"unrestorable_symlink""#),
+            "{}",
+            json
+        );
+    }
+}
@@ -1,5 +1,5 @@
 // Copyright 2017 Julian Raufelder.
-// Copyright 2020 Martin Pool.
+// Copyright 2020, 2021 Martin Pool.
 
 // This program is free software; you can redistribute it and/or modify
 // it under the terms of the GNU General Public License as published by
@@ -11,68 +11,405 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
-//! Create GlobSet from a list of strings
+//! Exclude entries by patterns with gitignore-like rules.
+//!
+//! Patterns are matched against apaths:
+//!
+//! * A pattern starting with `/` is anchored at the root of the tree, so
+//!   `/target` matches only `/target`. Other patterns match at any depth, so
+//!   `target` matches `/target` and `/src/target`.
+//! * `*` and `?` don't match `/`, but `**` matches any number of directories.
+//! * A pattern ending in `/` matches only directories.
+//! * A pattern starting with `!` re-includes paths excluded by earlier
+//!   patterns. The last matching pattern wins.
+//! * When a directory is excluded, so is everything inside it, and its
+//!   contents can't be re-included.
+//!
+//! Before Conserve 0.6.11, excludes were plain globs matched against the
+//! whole apath, in which `*` also matched `/`. Those can still be used through
+//! [from_globs].
 
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
 
 use super::*;
+use crate::stats::PatternStats;
+
+/// A set of patterns selecting entries to exclude.
+///
+/// Each pattern counts how many entries it matched. Clones share the counts.
+#[derive(Clone, Debug)]
+pub struct Exclude {
+    globset: GlobSet,
+    /// One for each glob in `globset`, in the same order.
+    rules: Vec<Rule>,
+    /// Number of entries matched by each rule.
+    match_counts: Arc<Vec<AtomicUsize>>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    /// The pattern as given, if known.
+    pattern: Option<String>,
+    /// True for `!` patterns, which re-include matching paths.
+    negated: bool,
+    /// True for patterns ending in `/`, which match only directories.
+    dir_only: bool,
+}
+
+impl Exclude {
+    fn new(globset: GlobSet, rules: Vec<Rule>) -> Exclude {
+        let match_counts = Arc::new(rules.iter().map(|_| AtomicUsize::new(0)).collect());
+        Exclude {
+            globset,
+            rules,
+            match_counts,
+        }
+    }
+
+    /// An Exclude that excludes nothing.
+    pub fn nothing() -> Exclude {
+        Exclude::new(GlobSet::empty(), Vec::new())
+    }
+
+    /// True if there are no patterns.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Return a copy of this Exclude with its own match counts, starting
+    /// from zero.
+    pub fn with_new_counts(&self) -> Exclude {
+        Exclude::new(self.globset.clone(), self.rules.clone())
+    }
 
+    /// Return how many entries each pattern has matched.
+    ///
+    /// Patterns made from a [GlobSet], whose text isn't known, are left out.
+    pub fn pattern_stats(&self) -> PatternStats {
+        let mut stats = PatternStats::default();
+        for (rule, count) in self.rules.iter().zip(self.match_counts.iter()) {
+            if let Some(pattern) = &rule.pattern {
+                stats.push(pattern, count.load(Ordering::Relaxed));
+            }
+        }
+        stats
+    }
+
+    /// True if the patterns exclude an entry of this kind at this apath.
+    ///
+    /// This doesn't look at whether any parent directory is excluded: it's
+    /// meant for tree walks that don't descend into excluded directories.
+    ///
+    /// The root directory is never excluded.
+    pub fn matches(&self, apath: &str, kind: Kind) -> bool {
+        self.check(apath, kind, true)
+    }
+
+    /// True if an entry of this kind at this apath is excluded, either
+    /// itself or because a parent directory is excluded.
+    ///
+    /// Only the patterns matching the entry itself are counted, since the
+    /// parents are typically checked as entries in their own right.
+    pub fn is_excluded(&self, apath: &str, kind: Kind) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let mut end = 0;
+        while let Some(pos) = apath[end + 1..].find('/') {
+            end += 1 + pos;
+            if self.check(&apath[..end], Kind::Dir, false) {
+                return true;
+            }
+        }
+        self.check(apath, kind, true)
+    }
+
+    fn check(&self, apath: &str, kind: Kind, count: bool) -> bool {
+        if apath == "/" {
+            return false;
+        }
+        let mut last = None;
+        for i in self.globset.matches(apath) {
+            if kind == Kind::Dir || !self.rules[i].dir_only {
+                if count {
+                    self.match_counts[i].fetch_add(1, Ordering::Relaxed);
+                }
+                last = last.max(Some(i));
+            }
+        }
+        last.is_some_and(|i| !self.rules[i].negated)
+    }
+}
+
+impl From<GlobSet> for Exclude {
+    /// Exclude paths matching any of the globs, without any gitignore-like
+    /// rules.
+    fn from(globset: GlobSet) -> Exclude {
+        let rules = (0..globset.len())
+            .map(|_| Rule {
+                pattern: None,
+                negated: false,
+                dir_only: false,
+            })
+            .collect();
+        Exclude::new(globset, rules)
+    }
+}
+
+/// Make an Exclude from patterns with gitignore-like rules.
+///
+/// Returns None if there are no patterns.
 pub fn from_strings<I: IntoIterator<Item = S>, S: AsRef<str>>(
     excludes: I,
-) -> Result<Option<GlobSet>> {
+) -> Result<Option<Exclude>> {
     let mut builder = GlobSetBuilder::new();
-    let mut empty = true;
+    let mut rules = Vec::new();
+    for original in excludes {
+        let original = original.as_ref();
+        let mut pattern = original;
+        let negated = pattern.starts_with('!');
+        if negated {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.len() > 1 && pattern.ends_with('/');
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+        let glob = if pattern.starts_with('/') {
+            pattern.to_owned()
+        } else {
+            format!("/**/{}", pattern)
+        };
+        builder.add(
+            GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|source| Error::ParseGlob { source })?,
+        );
+        rules.push(Rule {
+            pattern: Some(original.to_owned()),
+            negated,
+            dir_only,
+        });
+    }
+    if rules.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Exclude::new(builder.build()?, rules)))
+}
+
+/// Make an Exclude from plain globs, matched against the whole apath, as
+/// excludes were interpreted before Conserve 0.6.11.
+///
+/// Returns None if there are no globs.
+pub fn from_globs<I: IntoIterator<Item = S>, S: AsRef<str>>(
+    excludes: I,
+) -> Result<Option<Exclude>> {
+    let mut builder = GlobSetBuilder::new();
+    let mut rules = Vec::new();
     for i in excludes {
         builder.add(Glob::new(i.as_ref()).map_err(|source| Error::ParseGlob { source })?);
-        empty = false;
+        rules.push(Rule {
+            pattern: Some(i.as_ref().to_owned()),
+            negated: false,
+            dir_only: false,
+        });
     }
-    if empty {
+    if rules.is_empty() {
         return Ok(None);
     }
-    builder.build().map_err(Into::into).map(Some)
+    Ok(Some(Exclude::new(builder.build()?, rules)))
 }
 
-pub fn excludes_nothing() -> GlobSet {
-    GlobSetBuilder::new().build().unwrap()
+pub fn excludes_nothing() -> Exclude {
+    Exclude::nothing()
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::*;
 
+    /// Patterns, an apath, its kind, and whether it's excluded.
+    const CASES: &[(&[&str], &str, Kind, bool)] = &[
+        // Unanchored names match at any depth.
+        (&["subfile"], "/subfile", Kind::File, true),
+        (&["subfile"], "/subdir/subfile", Kind::File, true),
+        (&["subfile"], "/a/b/c/subfile", Kind::File, true),
+        (&["subfile"], "/subfile2", Kind::File, false),
+        (&["subfile"], "/mysubfile", Kind::File, false),
+        // Anchored patterns match only from the root.
+        (&["/subfile"], "/subfile", Kind::File, true),
+        (&["/subfile"], "/subdir/subfile", Kind::File, false),
+        (&["/subdir/subfile"], "/subdir/subfile", Kind::File, true),
+        (&["/subdir/subfile"], "/a/subdir/subfile", Kind::File, false),
+        // Unanchored patterns with a slash also match at any depth.
+        (&["subdir/subfile"], "/subdir/subfile", Kind::File, true),
+        (&["subdir/subfile"], "/a/subdir/subfile", Kind::File, true),
+        (&["subdir/subfile"], "/subdir/a/subfile", Kind::File, false),
+        // `*` and `?` don't cross directories; `**` does.
+        (&["*.o"], "/main.o", Kind::File, true),
+        (&["*.o"], "/src/main.o", Kind::File, true),
+        (&["*.o"], "/src/main.c", Kind::File, false),
+        (&["/*.o"], "/main.o", Kind::File, true),
+        (&["/*.o"], "/src/main.o", Kind::File, false),
+        (&["/src/*"], "/src/main.c", Kind::File, true),
+        (&["/src/*"], "/src/sub/main.c", Kind::File, true),
+        (&["/src/**/*.c"], "/src/main.c", Kind::File, true),
+        (&["/src/**/*.c"], "/src/a/b/main.c", Kind::File, true),
+        (&["/**/subfile"], "/a/subfile", Kind::File, true),
+        (&["fo?"], "/foo", Kind::File, true),
+        (&["fo?"], "/fo", Kind::File, false),
+        (&["ba[abc]"], "/x/baa", Kind::File, true),
+        (&["ba[abc]"], "/x/bad", Kind::File, false),
+        // Excluding a directory excludes its contents.
+        (&["target"], "/target", Kind::Dir, true),
+        (&["target"], "/target/debug/conserve", Kind::File, true),
+        (&["/target"], "/src/target/x", Kind::File, false),
+        (&["/target"], "/target/x", Kind::File, true),
+        // A trailing slash matches only directories.
+        (&["build/"], "/build", Kind::Dir, true),
+        (&["build/"], "/build", Kind::File, false),
+        (&["build/"], "/build", Kind::Symlink, false),
+        (&["build/"], "/src/build", Kind::Dir, true),
+        (&["build/"], "/build/out.o", Kind::File, true),
+        (&["/build/"], "/src/build", Kind::Dir, false),
+        // Negation re-includes, and the last matching pattern wins.
+        (&["*.log", "!keep.log"], "/a.log", Kind::File, true),
+        (&["*.log", "!keep.log"], "/keep.log", Kind::File, false),
+        (&["*.log", "!keep.log"], "/x/keep.log", Kind::File, false),
+        (&["!keep.log", "*.log"], "/keep.log", Kind::File, true),
+        (&["*", "!/src"], "/src", Kind::Dir, false),
+        (&["*", "!/src"], "/doc", Kind::Dir, true),
+        (&["!other"], "/thing", Kind::File, false),
+        // Contents of an excluded directory can't be re-included.
+        (&["/build", "!/build/keep"], "/build/keep", Kind::File, true),
+        (
+            &["/build/*", "!/build/keep"],
+            "/build/keep",
+            Kind::File,
+            false,
+        ),
+        (
+            &["/build/*", "!/build/keep"],
+            "/build/other",
+            Kind::File,
+            true,
+        ),
+        // Negated directory-only patterns.
+        (&["*", "!*/"], "/dir", Kind::Dir, false),
+        (&["*", "!*/"], "/dir/file", Kind::File, true),
+        // The root is never matched by name.
+        (&["*"], "/", Kind::Dir, false),
+    ];
+
+    #[test]
+    fn table_of_patterns() {
+        for (patterns, apath, kind, expected) in CASES {
+            let exclude = excludes::from_strings(patterns.iter()).unwrap().unwrap();
+            assert_eq!(
+                exclude.is_excluded(apath, *kind),
+                *expected,
+                "patterns {:?} on {} {:?}",
+                patterns,
+                apath,
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn matches_ignores_parents() {
+        let exclude = excludes::from_strings(&["/build"]).unwrap().unwrap();
+        assert!(exclude.matches("/build", Kind::Dir));
+        assert!(!exclude.matches("/build/out", Kind::File));
+        assert!(exclude.is_excluded("/build/out", Kind::File));
+    }
+
+    #[test]
+    fn no_patterns() {
+        assert!(excludes::from_strings(&[] as &[&str]).unwrap().is_none());
+        assert!(excludes::from_globs(&[] as &[&str]).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_pattern() {
+        assert!(matches!(
+            excludes::from_strings(&["a[b"]),
+            Err(Error::ParseGlob { .. })
+        ));
+    }
+
     #[test]
     pub fn simple_parse() {
         let vec = vec!["fo*", "foo", "bar*"];
-        let excludes = excludes::from_strings(&vec).expect("ok").expect("some");
-        assert_eq!(excludes.matches("foo").len(), 2);
-        assert_eq!(excludes.matches("foobar").len(), 1);
-        assert_eq!(excludes.matches("barBaz").len(), 1);
-        assert_eq!(excludes.matches("bazBar").len(), 0);
+        let excludes = excludes::from_globs(&vec).expect("ok").expect("some");
+        assert!(excludes.matches("foo", Kind::File));
+        assert!(excludes.matches("foobar", Kind::File));
+        assert!(excludes.matches("barBaz", Kind::File));
+        assert!(!excludes.matches("bazBar", Kind::File));
     }
 
     #[test]
     pub fn path_parse() {
-        let excludes = excludes::from_strings(&["fo*/bar/baz*"])
+        let excludes = excludes::from_globs(&["fo*/bar/baz*"])
             .expect("ok")
             .expect("some");
-        assert_eq!(excludes.matches("foo/bar/baz.rs").len(), 1);
+        assert!(excludes.matches("foo/bar/baz.rs", Kind::File));
     }
 
     #[test]
     pub fn extendend_pattern_parse() {
-        let excludes = excludes::from_strings(&["fo?", "ba[abc]", "[!a-z]"])
+        let excludes = excludes::from_globs(&["fo?", "ba[abc]", "[!a-z]"])
             .expect("ok")
             .expect("some");
-        assert_eq!(excludes.matches("foo").len(), 1);
-        assert_eq!(excludes.matches("fo").len(), 0);
-        assert_eq!(excludes.matches("baa").len(), 1);
-        assert_eq!(excludes.matches("1").len(), 1);
-        assert_eq!(excludes.matches("a").len(), 0);
+        assert!(excludes.matches("foo", Kind::File));
+        assert!(!excludes.matches("fo", Kind::File));
+        assert!(excludes.matches("baa", Kind::File));
+        assert!(excludes.matches("1", Kind::File));
+        assert!(!excludes.matches("a", Kind::File));
+    }
+
+    #[test]
+    pub fn old_globs_match_across_directories() {
+        let excludes = excludes::from_globs(&["/*.o"]).unwrap().unwrap();
+        assert!(excludes.is_excluded("/src/main.o", Kind::File));
+    }
+
+    #[test]
+    fn counts_matches_of_each_pattern() {
+        let exclude = excludes::from_strings(&["*.o", "/build/", "typo"])
+            .unwrap()
+            .unwrap();
+        assert!(exclude.matches("/a.o", Kind::File));
+        assert!(exclude.matches("/b.o", Kind::File));
+        assert!(exclude.matches("/build", Kind::Dir));
+        assert!(!exclude.matches("/main.c", Kind::File));
+        // Parents aren't counted again for each entry inside them.
+        assert!(exclude.is_excluded("/build/x", Kind::File));
+        let stats = exclude.pattern_stats();
+        let counts: Vec<(&str, usize)> = stats
+            .iter()
+            .map(|pc| (pc.pattern.as_str(), pc.matches))
+            .collect();
+        assert_eq!(counts, [("*.o", 2), ("/build/", 1), ("typo", 0)]);
+        assert_eq!(stats.unmatched().collect::<Vec<_>>(), ["typo"]);
+        assert_eq!(
+            exclude
+                .with_new_counts()
+                .pattern_stats()
+                .unmatched()
+                .count(),
+            3
+        );
     }
 
     #[test]
     pub fn nothing_parse() {
         let excludes = excludes::excludes_nothing();
-        assert!(excludes.matches("a").is_empty());
+        assert!(!excludes.matches("a", Kind::File));
+        assert!(!excludes.is_excluded("/a", Kind::File));
     }
 }
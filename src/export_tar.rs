@@ -0,0 +1,376 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Export a stored tree as a tar stream.
+//!
+//! Entries are written in apath order, with paths relative to the root of the
+//! tree. File contents are streamed from the archive one block at a time.
+//!
+//! Headers are POSIX ustar. A path or symlink target too long for the ustar
+//! fields, or a file of 8GiB or more, is described by a PAX extended header
+//! before the entry.
+//!
+//! Entries get the Unix permissions recorded in the index. Entries from older
+//! indexes, or backed up on platforms without Unix permissions, are given
+//! mode 0755 for directories and 0644 for files. Symlinks are always 0777.
+
+use std::io;
+use std::io::{Read, Write};
+
+use tar::{EntryType, Header};
+
+use crate::entry::Entry;
+use crate::stats::CopyStats;
+use crate::*;
+
+/// Options for `export_tar`.
+#[derive(Debug)]
+pub struct ExportTarOptions {
+    /// The band to export, or by default the last complete one.
+    pub band_selection: BandSelectionPolicy,
+    /// Export only this subdirectory.
+    pub only_subtree: Option<Apath>,
+    /// Export only entries that match this filter, and the directories
+    /// holding them.
+    pub filter: EntryFilter,
+    pub print_filenames: bool,
+}
+
+impl Default for ExportTarOptions {
+    fn default() -> Self {
+        ExportTarOptions {
+            band_selection: BandSelectionPolicy::LatestClosed,
+            only_subtree: None,
+            filter: EntryFilter::default(),
+            print_filenames: false,
+        }
+    }
+}
+
+/// Write a tar stream of one stored tree to `out`.
+///
+/// This is [restore_into] a [TarWriteTree]. `out` is flushed but not
+/// otherwise finished, so a compressing writer can be finished by the caller.
+///
+/// A file whose content can't be read from the archive is skipped and
+/// counted as an error, as in a restore. Since a tar stream can't be patched
+/// up after a partly-written entry, any error once an entry has been started,
+/// or writing the stream, is fatal.
+pub fn export_tar(
+    archive: &Archive,
+    out: &mut dyn Write,
+    options: &ExportTarOptions,
+) -> Result<CopyStats> {
+    let mut tree = TarWriteTree::new(out);
+    let stats = restore_into(
+        archive,
+        &mut tree,
+        &RestoreOptions {
+            band_selection: options.band_selection.clone(),
+            only_subtree: options.only_subtree.clone(),
+            filter: options.filter.clone(),
+            print_filenames: options.print_filenames,
+            ..RestoreOptions::default()
+        },
+    )?;
+    tree.into_inner()?;
+    Ok(stats)
+}
+
+/// A [WriteTree] that writes entries into a tar stream, so that a restore can
+/// be sent to another program or machine rather than written to disk.
+///
+/// The root directory is not written, and the paths of other entries are
+/// relative to it.
+///
+/// Once writing an entry has failed the stream may be left inside that
+/// entry, so every later entry fails too, and so does `finish`.
+///
+/// If the tree is dropped without being finished, for example because the
+/// copy was cancelled, the stream is left without an end-of-archive marker,
+/// so that readers can tell it's incomplete.
+pub struct TarWriteTree<W: Write> {
+    /// The builder, until it's taken back by [TarWriteTree::into_inner].
+    builder: Option<tar::Builder<AbandonableWriter<W>>>,
+    broken: bool,
+    finished: bool,
+}
+
+impl<W: Write> TarWriteTree<W> {
+    /// Start a tar stream written to `out`.
+    pub fn new(out: W) -> TarWriteTree<W> {
+        TarWriteTree {
+            builder: Some(tar::Builder::new(AbandonableWriter {
+                inner: out,
+                abandoned: false,
+            })),
+            broken: false,
+            finished: false,
+        }
+    }
+
+    /// Write the end-of-archive marker, flush, and return the underlying
+    /// writer.
+    pub fn into_inner(mut self) -> Result<W> {
+        if !self.finished {
+            self.finish_stream()?;
+        }
+        self.builder
+            .take()
+            .unwrap()
+            .into_inner()
+            .map(|out| out.inner)
+            .map_err(|source| Error::WriteTar { source })
+    }
+
+    /// Write the end-of-archive marker and flush.
+    fn finish_stream(&mut self) -> Result<()> {
+        if self.broken {
+            return Err(Error::WriteTar {
+                source: broken_stream_error(),
+            });
+        }
+        let builder = self.builder.as_mut().unwrap();
+        builder
+            .finish()
+            .and_then(|()| builder.get_mut().flush())
+            .map_err(|source| {
+                self.broken = true;
+                Error::WriteTar { source }
+            })?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Write one entry, or fail if the stream is already broken.
+    fn append<F>(&mut self, apath: &Apath, append_fn: F) -> Result<()>
+    where
+        F: FnOnce(&mut tar::Builder<AbandonableWriter<W>>) -> io::Result<()>,
+    {
+        let result = if self.broken {
+            Err(broken_stream_error())
+        } else {
+            append_fn(self.builder.as_mut().unwrap())
+        };
+        result.map_err(|source| {
+            self.broken = true;
+            Error::WriteTarEntry {
+                apath: apath.clone(),
+                source,
+            }
+        })
+    }
+}
+
+impl<W: Write> Drop for TarWriteTree<W> {
+    fn drop(&mut self) {
+        // tar::Builder writes the end-of-archive marker when it's dropped, so
+        // stop it reaching an unfinished stream.
+        if !self.finished {
+            if let Some(builder) = self.builder.as_mut() {
+                builder.get_mut().abandoned = true;
+            }
+        }
+    }
+}
+
+impl<W: Write> WriteTree for TarWriteTree<W> {
+    fn finish(&mut self) -> Result<CopyStats> {
+        self.finish_stream()?;
+        Ok(CopyStats::default())
+    }
+
+    fn copy_dir(&mut self, entry: &IndexEntry) -> Result<()> {
+        let name = tar_name(entry.apath());
+        if name.is_empty() {
+            return Ok(());
+        }
+        let mut header = new_header(entry);
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(entry.unix_mode().unwrap_or(0o755));
+        header.set_size(0);
+        self.append(entry.apath(), |builder| {
+            append_ustar(
+                builder,
+                &mut header,
+                &format!("{}/", name),
+                None,
+                &mut io::empty(),
+            )
+        })
+    }
+
+    fn copy_symlink(&mut self, entry: &IndexEntry) -> Result<()> {
+        let mut header = new_header(entry);
+        header.set_entry_type(EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        let target = entry.symlink_target().clone().unwrap_or_default();
+        self.append(entry.apath(), |builder| {
+            append_ustar(
+                builder,
+                &mut header,
+                tar_name(entry.apath()),
+                Some(&target),
+                &mut io::empty(),
+            )
+        })
+    }
+
+    fn copy_file(&mut self, entry: &IndexEntry, content: &mut dyn Read) -> Result<CopyStats> {
+        let mut stats = CopyStats::default();
+        let len = entry.size().unwrap_or_default();
+        let mut header = new_header(entry);
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(entry.unix_mode().unwrap_or(0o644));
+        header.set_size(len);
+        self.append(entry.apath(), |builder| {
+            append_ustar(builder, &mut header, tar_name(entry.apath()), None, content)
+        })?;
+        stats.uncompressed_bytes = len;
+        if len == 0 {
+            stats.empty_files = 1;
+        }
+        Ok(stats)
+    }
+}
+
+/// Passes writes through to the underlying stream until it's abandoned, and
+/// then discards them.
+struct AbandonableWriter<W> {
+    inner: W,
+    abandoned: bool,
+}
+
+impl<W: Write> Write for AbandonableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.abandoned {
+            Ok(buf.len())
+        } else {
+            self.inner.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.abandoned {
+            Ok(())
+        } else {
+            self.inner.flush()
+        }
+    }
+}
+
+fn broken_stream_error() -> io::Error {
+    io::Error::other("tar stream is incomplete after an earlier error")
+}
+
+/// The largest size that fits in the octal size field of a ustar header.
+const USTAR_MAX_SIZE: u64 = 0o777_7777_7777;
+
+fn new_header<E: Entry>(entry: &E) -> Header {
+    let mut header = Header::new_ustar();
+    header.set_mtime(entry.mtime().secs.max(0) as u64);
+    header
+}
+
+/// Write one entry with a ustar header, preceded by a PAX extended header if
+/// the name, symlink target, or size doesn't fit.
+///
+/// `name` and `link_name` are in the escaped form used in the index; the
+/// original bytes are written to the tar.
+///
+/// This doesn't use `tar::Builder::append_data`, because that falls back to
+/// GNU extensions for long names.
+fn append_ustar<W: Write>(
+    builder: &mut tar::Builder<W>,
+    header: &mut Header,
+    name: &str,
+    link_name: Option<&str>,
+    content: &mut dyn Read,
+) -> io::Result<()> {
+    let mut pax = Vec::new();
+    if header.set_path(&*names::to_os_str(name)).is_err() {
+        let bytes = names::unescape_bytes(name);
+        add_pax_record(&mut pax, "path", &bytes);
+        let ustar = header.as_ustar_mut().unwrap();
+        ustar.prefix = [0; 155];
+        set_truncated(&mut ustar.name, &bytes);
+    }
+    if let Some(link_name) = link_name {
+        if header.set_link_name(&*names::to_os_str(link_name)).is_err() {
+            let bytes = names::unescape_bytes(link_name);
+            add_pax_record(&mut pax, "linkpath", &bytes);
+            set_truncated(&mut header.as_ustar_mut().unwrap().linkname, &bytes);
+        }
+    }
+    let size = header.size()?;
+    if size > USTAR_MAX_SIZE {
+        add_pax_record(&mut pax, "size", size.to_string().as_bytes());
+        header.set_size(0);
+    }
+    if !pax.is_empty() {
+        let mut pax_header = Header::new_ustar();
+        pax_header.set_entry_type(EntryType::XHeader);
+        pax_header.set_path("PaxHeader")?;
+        pax_header.set_mode(0o644);
+        pax_header.set_mtime(header.mtime()?);
+        pax_header.set_size(pax.len() as u64);
+        pax_header.set_cksum();
+        builder.append(&pax_header, pax.as_slice())?;
+    }
+    header.set_cksum();
+    builder.append(header, content)
+}
+
+/// Append a PAX record, `"<length> <key>=<value>\n"`, where the length
+/// counts the whole record including its own digits.
+fn add_pax_record(pax: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    pax.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    pax.extend_from_slice(value);
+    pax.push(b'\n');
+}
+
+/// Fill a ustar header field with as much of `value` as fits.
+fn set_truncated(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+    field[len..].fill(0);
+}
+
+/// The relative path of an entry within the tar file.
+fn tar_name(apath: &Apath) -> &str {
+    apath.trim_start_matches('/')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pax_record_length_counts_its_own_digits() {
+        let mut pax = Vec::new();
+        add_pax_record(&mut pax, "path", b"a/b");
+        assert_eq!(pax, b"12 path=a/b\n");
+
+        // The digits of the length push this record from 99 to 101 bytes.
+        let mut pax = Vec::new();
+        add_pax_record(&mut pax, "path", &[b'x'; 91]);
+        assert_eq!(pax.len(), 101);
+        assert!(pax.starts_with(b"101 path=x"));
+    }
+}
@@ -126,7 +126,9 @@ mod test {
     fn completed_backup_ok() {
         let archive = ScratchArchive::new();
         let source = TreeFixture::new();
-        backup(&archive, &source.live_tree(), &BackupOptions::default()).unwrap();
+        archive
+            .backup(source.path(), &BackupOptions::default())
+            .unwrap();
         let delete_guard = GarbageCollectionLock::new(&archive).unwrap();
         delete_guard.check().unwrap();
     }
@@ -136,7 +138,7 @@ mod test {
         let archive = ScratchArchive::new();
         let source = TreeFixture::new();
         let _delete_guard = GarbageCollectionLock::new(&archive).unwrap();
-        let backup_result = backup(&archive, &source.live_tree(), &BackupOptions::default());
+        let backup_result = archive.backup(source.path(), &BackupOptions::default());
         assert_eq!(
             backup_result.err().expect("backup fails").to_string(),
             "Archive is locked for garbage collection"
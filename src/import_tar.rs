@@ -0,0 +1,442 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Import a tar stream as a new backup version.
+//!
+//! Tar entries can come in any order, but the index must be written in apath
+//! order. File contents are stored into the blockdir as they're read from the
+//! stream, and deduplicated as usual, but the index entries for the whole tar
+//! are kept in memory and sorted before the index is written. This needs
+//! roughly a few hundred bytes per entry, but nothing proportional to the size
+//! of the files.
+//!
+//! Directories that are implied by the paths of other entries but not present
+//! in the tar are added, with the time of the import as their mtime.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::time::SystemTime;
+
+use itertools::Itertools;
+use tar::EntryType;
+
+use crate::backup::{store_file_content, FileCombiner};
+use crate::stats::BackupStats;
+use crate::unix_time::UnixTime;
+use crate::*;
+
+/// Read a tar stream from `source` and store it as a new band.
+///
+/// The band is written as if the tar contents were a source tree, using the
+/// excludes, tags, and other settings from `options`.
+///
+/// Devices, fifos and other entries that can't be stored are skipped,
+/// counted as an unsupported kind, and reported in the stats. Entries whose
+/// names can't be mapped to an apath, for example because they contain `..`,
+/// are reported and counted as errors. If the same path occurs more than
+/// once, the last entry wins, as it would when unpacking the tar.
+pub fn import_tar(
+    archive: &Archive,
+    source: &mut dyn Read,
+    options: &BackupOptions,
+) -> Result<BackupStats> {
+    if gc_lock::GarbageCollectionLock::is_locked(archive)? {
+        return Err(Error::GarbageCollectionLockHeld);
+    }
+    let mut lock = archive.lock(options.break_lock)?;
+    let band = Band::create_with_options(
+        archive,
+        &BandOptions {
+            tags: options.tags.clone(),
+            index_format: options.index_format,
+            chunking: options.chunking,
+            index_hunks_per_pack: options.index_hunks_per_pack,
+        },
+    )?;
+    let mut block_dir = archive.block_dir().clone();
+    let mut file_combiner = FileCombiner::new(block_dir.clone());
+    // Apaths of small files waiting in the combiner.
+    let mut combining: BTreeSet<Apath> = BTreeSet::new();
+    let mut stats = BackupStats::default();
+    let mut entries: BTreeMap<Apath, IndexEntry> = BTreeMap::new();
+    let mut progress_bar = ProgressBar::new();
+    progress_bar.set_phase("Importing".to_owned());
+
+    let mut tar = tar::Archive::new(source);
+    for tar_entry in tar.entries().map_err(read_tar_error)? {
+        let mut tar_entry = tar_entry.map_err(read_tar_error)?;
+        let header = tar_entry.header();
+        let path = tar_entry.path_bytes().into_owned();
+        let apath = match tar_path_to_apath(&path) {
+            Some(apath) => apath,
+            None => {
+                stats.problems.push(Problem {
+                    kind: ProblemKind::TarEntry,
+                    apath: None,
+                    category: ErrorCategory::PerEntry,
+                    message: format!(
+                        "Can't import tar entry with path {:?}",
+                        String::from_utf8_lossy(&path)
+                    ),
+                });
+                stats.errors += 1;
+                continue;
+            }
+        };
+        let kind = match header.entry_type() {
+            EntryType::Directory => Kind::Dir,
+            EntryType::Regular | EntryType::Continuous => Kind::File,
+            EntryType::Symlink => Kind::Symlink,
+            _ => Kind::Unknown,
+        };
+        if options
+            .excludes
+            .as_ref()
+            .is_some_and(|excludes| excludes.is_excluded(&apath, kind))
+        {
+            continue;
+        }
+        if combining.contains(&apath) {
+            // Store the earlier small file now, so that it can't replace this
+            // later entry when the combiner is drained at the end.
+            collect_finished(&mut file_combiner, &mut combining, &mut entries, &mut stats)?;
+        }
+        progress_bar.set_filename(apath.to_string());
+        let mtime = header.mtime().map_err(read_tar_error)? as i64;
+        let metadata = IndexEntry {
+            apath: apath.clone(),
+            kind: Kind::Unknown,
+            mtime,
+            mtime_nanos: 0,
+            addrs: Vec::new(),
+            target: None,
+            xattrs: Xattrs::new(),
+            // Some writers leave the mode blank, for example on hard links.
+            unix_mode: header.mode().ok().map(|mode| mode & 0o7777),
+            changed_during_backup: false,
+            dev: None,
+            ino: None,
+            unknown_fields: UnknownFields::default(),
+        };
+        match header.entry_type() {
+            EntryType::Directory => {
+                if options.print_filenames {
+                    ui::println(&format!("{}/", apath));
+                }
+                stats.directories += 1;
+                entries.insert(
+                    apath,
+                    IndexEntry {
+                        kind: Kind::Dir,
+                        ..metadata
+                    },
+                );
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                if options.print_filenames {
+                    ui::println(&apath);
+                }
+                stats.files += 1;
+                stats.new_files += 1;
+                let size = header.size().map_err(read_tar_error)?;
+                let entry = IndexEntry {
+                    kind: Kind::File,
+                    ..metadata
+                };
+                if size == 0 {
+                    stats.empty_files += 1;
+                    entries.insert(apath, entry);
+                } else if size <= SMALL_FILE_CAP {
+                    // Finished entries are collected from the combiner at the end.
+                    entries.remove(&apath);
+                    combining.insert(apath);
+                    file_combiner.push_file(entry, size, &mut tar_entry)?;
+                } else {
+                    let addrs = store_file_content(
+                        &apath,
+                        &mut tar_entry,
+                        &mut block_dir,
+                        &mut stats,
+                        &options.chunking,
+                        &CancellationToken::new(),
+                    )?;
+                    entries.insert(apath, IndexEntry { addrs, ..entry });
+                }
+                progress_bar.increment_bytes_done(size);
+            }
+            EntryType::Symlink => {
+                let target = match tar_entry.link_name_bytes() {
+                    Some(target) => names::escape_bytes(&target).into_owned(),
+                    None => {
+                        stats.problems.push(Problem {
+                            kind: ProblemKind::TarEntry,
+                            apath: Some(apath),
+                            category: ErrorCategory::PerEntry,
+                            message: "Tar symlink has no target".to_owned(),
+                        });
+                        stats.errors += 1;
+                        continue;
+                    }
+                };
+                if options.print_filenames {
+                    ui::println(&format!("{} -> {}", apath, target));
+                }
+                stats.symlinks += 1;
+                entries.insert(
+                    apath,
+                    IndexEntry {
+                        kind: Kind::Symlink,
+                        target: Some(target),
+                        unix_mode: None,
+                        ..metadata
+                    },
+                );
+            }
+            EntryType::Link => {
+                // A hard link to a file earlier in the tar, which can be stored
+                // as another file with the same addresses.
+                let target = tar_entry
+                    .link_name_bytes()
+                    .and_then(|name| tar_path_to_apath(&name));
+                let mut addrs = target.as_ref().and_then(|t| linked_file_addrs(t, &entries));
+                if addrs.is_none() {
+                    // The target might be a small file waiting to be combined.
+                    collect_finished(&mut file_combiner, &mut combining, &mut entries, &mut stats)?;
+                    addrs = target.as_ref().and_then(|t| linked_file_addrs(t, &entries));
+                }
+                let addrs = match addrs {
+                    Some(addrs) => addrs,
+                    None => {
+                        stats.problems.push(Problem {
+                            kind: ProblemKind::TarEntry,
+                            apath: Some(apath),
+                            category: ErrorCategory::PerEntry,
+                            message: "Can't find the target of this hard link in the tar"
+                                .to_owned(),
+                        });
+                        stats.errors += 1;
+                        continue;
+                    }
+                };
+                if options.print_filenames {
+                    ui::println(&apath);
+                }
+                stats.files += 1;
+                stats.new_files += 1;
+                entries.insert(
+                    apath,
+                    IndexEntry {
+                        kind: Kind::File,
+                        addrs,
+                        ..metadata
+                    },
+                );
+            }
+            EntryType::XGlobalHeader | EntryType::XHeader => (),
+            other => {
+                stats.problems.push(Problem {
+                    kind: ProblemKind::TarEntry,
+                    apath: Some(apath),
+                    category: ErrorCategory::PerEntry,
+                    message: format!("Skipping tar entry of unsupported type {:?}", other),
+                });
+                stats.unknown_kind += 1;
+            }
+        }
+    }
+
+    collect_finished(&mut file_combiner, &mut combining, &mut entries, &mut stats)?;
+    add_missing_dirs(&mut entries, &mut stats);
+    let mut index_builder = band.index_builder();
+    for hunk in entries
+        .into_values()
+        .chunks(options.max_entries_per_hunk)
+        .into_iter()
+    {
+        for entry in hunk {
+            index_builder.push_entry(entry);
+        }
+        index_builder.finish_hunk()?;
+        lock.refresh()?;
+    }
+    let stats = BackupStats {
+        index_builder_stats: index_builder.finish()?,
+        ..stats
+    };
+    band.write_stats(&stats)?;
+    band.close_with_totals(&BandTotals::from_backup_stats(&stats))?;
+    Ok(stats)
+}
+
+/// Store any small files waiting in the combiner, and move their entries into
+/// the map of entries.
+fn collect_finished(
+    file_combiner: &mut FileCombiner,
+    combining: &mut BTreeSet<Apath>,
+    entries: &mut BTreeMap<Apath, IndexEntry>,
+    stats: &mut BackupStats,
+) -> Result<()> {
+    let (combiner_stats, finished) = file_combiner.drain()?;
+    combining.clear();
+    *stats += combiner_stats;
+    for entry in finished {
+        entries.insert(entry.apath.clone(), entry);
+    }
+    Ok(())
+}
+
+/// Find the addresses of a file already imported, as the target of a hard link.
+fn linked_file_addrs(
+    target: &Apath,
+    entries: &BTreeMap<Apath, IndexEntry>,
+) -> Option<Vec<Address>> {
+    match entries.get(target) {
+        Some(entry) if entry.kind == Kind::File => Some(entry.addrs.clone()),
+        _ => None,
+    }
+}
+
+/// Add entries for the root and any other parent directories that aren't in
+/// the tar.
+fn add_missing_dirs(entries: &mut BTreeMap<Apath, IndexEntry>, stats: &mut BackupStats) {
+    let now = UnixTime::from(SystemTime::now());
+    let mut missing: BTreeSet<Apath> = BTreeSet::new();
+    let root = Apath::from("/");
+    if !entries.contains_key(&root) {
+        missing.insert(root);
+    }
+    for apath in entries.keys() {
+        let mut parent = apath.to_string();
+        while let Some(slash) = parent.rfind('/') {
+            parent.truncate(slash);
+            if parent.is_empty() {
+                break;
+            }
+            let parent_apath = Apath::from(parent.as_str());
+            if entries.contains_key(&parent_apath) || missing.contains(&parent_apath) {
+                break;
+            }
+            missing.insert(parent_apath);
+        }
+    }
+    for apath in missing {
+        stats.directories += 1;
+        entries.insert(
+            apath.clone(),
+            IndexEntry {
+                apath,
+                kind: Kind::Dir,
+                mtime: now.secs,
+                mtime_nanos: now.nanosecs,
+                addrs: Vec::new(),
+                target: None,
+                xattrs: Xattrs::new(),
+                unix_mode: None,
+                changed_during_backup: false,
+                dev: None,
+                ino: None,
+                unknown_fields: UnknownFields::default(),
+            },
+        );
+    }
+}
+
+/// Convert a path from a tar header into an apath.
+///
+/// Leading `/` and `./` are removed, as is a trailing `/` on directories, so
+/// that `./a/b/`, `/a/b` and `a/b` are all `/a/b`. Paths with `..` components
+/// return None. Bytes that aren't UTF-8 are escaped as in
+/// [names::escape_bytes].
+fn tar_path_to_apath(path: &[u8]) -> Option<Apath> {
+    let path = names::escape_bytes(path);
+    let mut apath = String::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => return None,
+            name => {
+                apath.push('/');
+                apath.push_str(name);
+            }
+        }
+    }
+    if apath.is_empty() {
+        apath.push('/');
+    }
+    debug_assert!(Apath::is_valid(&apath), "{:?}", apath);
+    Some(Apath::from(apath.as_str()))
+}
+
+fn read_tar_error(source: std::io::Error) -> Error {
+    Error::ReadTar { source }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tar_paths_to_apaths() {
+        let check = |path: &str, expected: Option<&str>| {
+            assert_eq!(
+                tar_path_to_apath(path.as_bytes()),
+                expected.map(Apath::from),
+                "{:?}",
+                path
+            );
+        };
+        check("a", Some("/a"));
+        check("./a/b", Some("/a/b"));
+        check("/a/b/", Some("/a/b"));
+        check("a//b/./c", Some("/a/b/c"));
+        check("./", Some("/"));
+        check(".", Some("/"));
+        check("a/../b", None);
+        check("..", None);
+        assert_eq!(
+            tar_path_to_apath(b"caf\xe9/x"),
+            Some(Apath::from("/caf\u{F7E9}/x"))
+        );
+    }
+
+    #[test]
+    fn missing_dirs_are_added() {
+        let mut entries = BTreeMap::new();
+        for apath in &["/a/b/c", "/a/b/d", "/e"] {
+            let apath = Apath::from(*apath);
+            entries.insert(
+                apath.clone(),
+                IndexEntry {
+                    apath,
+                    kind: Kind::File,
+                    mtime: 0,
+                    mtime_nanos: 0,
+                    addrs: Vec::new(),
+                    target: None,
+                    xattrs: Xattrs::new(),
+                    unix_mode: None,
+                    changed_during_backup: false,
+                    dev: None,
+                    ino: None,
+                    unknown_fields: UnknownFields::default(),
+                },
+            );
+        }
+        let mut stats = BackupStats::default();
+        add_missing_dirs(&mut entries, &mut stats);
+        assert_eq!(stats.directories, 3);
+        let apaths: Vec<&str> = entries.keys().map(|a| a.as_ref()).collect();
+        assert_eq!(apaths, ["/", "/a", "/e", "/a/b", "/a/b/c", "/a/b/d"]);
+        assert_eq!(entries[&Apath::from("/a")].kind, Kind::Dir);
+    }
+}
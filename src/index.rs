@@ -13,15 +13,27 @@
 
 //! Index lists the files in a band in the archive.
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
 use std::io;
 use std::iter::Peekable;
 use std::path::Path;
+use std::str::FromStr;
 use std::vec;
 
+use blake2_rfc::blake2b;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+
 use crate::compress::snappy::{Compressor, Decompressor};
+use crate::jsonio::{read_json, write_json};
 use crate::kind::Kind;
-use crate::stats::{IndexReadStats, IndexWriterStats};
+use crate::misc;
+use crate::stats::{IndexReadStats, IndexWriterStats, ValidateStats};
 use crate::transport::local::LocalTransport;
 use crate::transport::Transport;
 use crate::unix_time::UnixTime;
@@ -29,14 +41,135 @@ use crate::*;
 
 pub const MAX_ENTRIES_PER_HUNK: usize = 1000;
 
+/// Finish an index hunk once its entries are estimated to serialize to about
+/// this many bytes, even if it has fewer than `MAX_ENTRIES_PER_HUNK` entries.
+pub const MAX_HUNK_BYTES: usize = 4 << 20;
+
+/// Roughly the serialized size of one block address: a hash in hex, and its
+/// start and length.
+pub(crate) const ESTIMATED_ADDRESS_LEN: usize = 180;
+
 pub const HUNKS_PER_SUBDIR: u32 = 10_000;
 
+/// Number of hunks written into each pack file, in an index whose hunks are
+/// packed.
+///
+/// This divides `HUNKS_PER_SUBDIR`, so that packs don't straddle
+/// subdirectories.
+pub const HUNKS_PER_PACK: u32 = 100;
+
+/// Serialization of entries within index hunks.
+///
+/// The format is chosen when a band is created, and recorded through the band's
+/// format version, so that each band is read with the format it was written in.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    /// A json list of entries; the only format before 0.6.11.
+    #[default]
+    Json,
+    /// A CBOR array of entries, with struct fields identified by number.
+    Cbor,
+}
+
+impl FromStr for IndexFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<IndexFormat> {
+        match s {
+            "json" => Ok(IndexFormat::Json),
+            "cbor" => Ok(IndexFormat::Cbor),
+            _ => Err(Error::UnsupportedIndexFormat {
+                format: s.to_owned(),
+            }),
+        }
+    }
+}
+
+impl IndexFormat {
+    /// Serialize entries into `buf`, replacing its contents, so that the
+    /// buffer's allocation can be reused for each hunk.
+    fn serialize_into(self, entries: &[IndexEntry], buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        match self {
+            IndexFormat::Json => serde_json::to_writer(&mut *buf, entries)
+                .map_err(|source| Error::SerializeIndex { source }),
+            IndexFormat::Cbor => entries
+                .serialize(&mut serde_cbor::Serializer::new(buf).packed_format())
+                .map_err(|source| Error::SerializeIndexCbor { source }),
+        }
+    }
+
+    fn deserialize(self, bytes: &[u8], path: &str) -> Result<Vec<IndexEntry>> {
+        match self {
+            IndexFormat::Json => {
+                serde_json::from_slice(bytes).map_err(|source| Error::DeserializeIndex {
+                    path: path.to_owned(),
+                    source,
+                })
+            }
+            IndexFormat::Cbor => {
+                serde_cbor::from_slice(bytes).map_err(|source| Error::DeserializeIndexCbor {
+                    path: path.to_owned(),
+                    source,
+                })
+            }
+        }
+    }
+
+    /// Decode a hunk into a generic json value, without interpreting it as
+    /// index entries, to show exactly what was stored.
+    fn deserialize_raw(self, bytes: &[u8], path: &str) -> Result<serde_json::Value> {
+        match self {
+            IndexFormat::Json => {
+                serde_json::from_slice(bytes).map_err(|source| Error::DeserializeIndex {
+                    path: path.to_owned(),
+                    source,
+                })
+            }
+            IndexFormat::Cbor => serde_cbor::from_slice::<serde_cbor::Value>(bytes)
+                .map_err(|source| Error::DeserializeIndexCbor {
+                    path: path.to_owned(),
+                    source,
+                })
+                .and_then(|value| {
+                    serde_json::to_value(value).map_err(|source| Error::SerializeIndex { source })
+                }),
+        }
+    }
+}
+
+/// Name of the file in the index directory listing the range of apaths in
+/// each hunk.
+pub(crate) const HUNK_MANIFEST_FILENAME: &str = "MANIFEST";
+
+/// The first and last apaths in one index hunk, and a hash of the hunk, as
+/// recorded in the hunk manifest.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HunkRange {
+    pub first: Apath,
+    pub last: Apath,
+    /// BLAKE2b hash of the hunk as stored, after compression; absent in
+    /// manifests written before hunks were hashed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<BlockHash>,
+}
+
+/// Hash the stored bytes of a hunk, to detect damage.
+fn hunk_hash(stored_bytes: &[u8]) -> BlockHash {
+    blake2b::blake2b(BLAKE_HASH_SIZE_BYTES, &[], stored_bytes).into()
+}
+
 /// Description of one archived file.
 ///
-/// This struct is directly encoded/decoded to the json index file, and also can be constructed by
-/// stat-ing (but not reading) a live file.
+/// This struct is encoded to and decoded from index hunks, and also can be
+/// constructed by stat-ing (but not reading) a live file. Fields it doesn't
+/// know, written by later versions, are kept in `unknown_fields`.
+///
+/// The stable API for reading entries is the accessor methods of the [Entry]
+/// trait, plus [IndexEntry::addrs]; the fields may change.
 // GRCOV_EXCLUDE_START
-#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct IndexEntry {
     /// Path of this entry relative to the base of the backup, in `apath` form.
     pub apath: Apath,
@@ -45,7 +178,6 @@ pub struct IndexEntry {
     pub kind: Kind,
 
     /// File modification time, in whole seconds past the Unix epoch.
-    #[serde(default)]
     pub mtime: i64,
 
     /// Fractional nanoseconds for modification time.
@@ -57,22 +189,383 @@ pub struct IndexEntry {
     /// It seems moderately common that the nanos are zero, probably because
     /// the time was set by something that didn't preserve them. In that case,
     /// skip serializing.
-    #[serde(default)]
-    #[serde(skip_serializing_if = "crate::misc::zero_u32")]
     pub mtime_nanos: u32,
 
     /// For stored files, the blocks holding the file contents.
-    #[serde(default)]
-    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub addrs: Vec<blockdir::Address>,
 
     /// For symlinks only, the target of the symlink.
-    #[serde(default)]
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub target: Option<String>,
+
+    /// Extended attributes, with values base64-encoded in the index.
+    ///
+    /// Absent in indexes written before 0.6.11, and for entries with no xattrs.
+    pub xattrs: Xattrs,
+
+    /// Unix permission bits, including the setuid, setgid and sticky bits.
+    ///
+    /// Absent in indexes written before 0.6.11, for symlinks, and for entries
+    /// backed up on platforms without Unix permissions.
+    pub unix_mode: Option<u32>,
+
+    /// True if the file's size or mtime changed while it was being read, so
+    /// the stored content may not match any single version of the file.
+    ///
+    /// Absent in indexes written before 0.6.11, and when false.
+    pub changed_during_backup: bool,
+
+    /// For files, the device number of the filesystem holding the source file.
+    ///
+    /// Absent in indexes written before 0.6.11, and on platforms other than Unix.
+    pub dev: Option<u64>,
+
+    /// For files, the inode number of the source file.
+    ///
+    /// Used with `dev` to recognize files that were moved or renamed. Absent
+    /// in indexes written before 0.6.11, and on platforms other than Unix.
+    pub ino: Option<u64>,
+
+    /// Fields written by a later version that this version doesn't know.
+    pub unknown_fields: UnknownFields,
 }
 // GRCOV_EXCLUDE_STOP
 
+/// Names of the fields of [IndexEntry], in the order that numbers them in
+/// CBOR indexes.
+///
+/// `apath` and `kind` are required; the others may be absent, and then take
+/// their default values. New fields must be added at the end, and be
+/// optional, so that earlier versions can still read the entry.
+const ENTRY_FIELDS: &[&str] = &[
+    "apath",
+    "kind",
+    "mtime",
+    "mtime_nanos",
+    "addrs",
+    "target",
+    "xattrs",
+    "unix_mode",
+    "changed_during_backup",
+    "dev",
+    "ino",
+];
+
+/// Fields of a stored index entry that this version doesn't know, kept so
+/// that they're written again when the entry is copied into a new index.
+///
+/// This also keeps the stored value of a kind this version doesn't know,
+/// which is read as [Kind::Unknown].
+///
+/// Fields and kinds are named in json indexes and numbered in CBOR indexes,
+/// so each is only written back into an index of the format it was read from.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct UnknownFields {
+    named: BTreeMap<String, serde_json::Value>,
+    numbered: BTreeMap<u64, serde_cbor::Value>,
+    kind_name: Option<String>,
+    kind_number: Option<u64>,
+}
+
+impl UnknownFields {
+    pub fn is_empty(&self) -> bool {
+        self.named.is_empty()
+            && self.numbered.is_empty()
+            && self.kind_name.is_none()
+            && self.kind_number.is_none()
+    }
+
+    /// The name of an unknown kind read from a json index.
+    pub fn kind_name(&self) -> Option<&str> {
+        self.kind_name.as_deref()
+    }
+
+    /// The number of an unknown kind read from a CBOR index.
+    pub fn kind_number(&self) -> Option<u64> {
+        self.kind_number
+    }
+
+    /// The names of the unknown fields read from a json index.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.named.keys().map(String::as_str)
+    }
+
+    /// The numbers of the unknown fields read from a CBOR index.
+    pub fn numbers(&self) -> impl Iterator<Item = u64> + '_ {
+        self.numbered.keys().copied()
+    }
+}
+
+impl Serialize for IndexEntry {
+    /// Entries are maps keyed by field name in human-readable formats, and
+    /// otherwise by field number.
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let numbered = !serializer.is_human_readable();
+        let has_mtime_nanos = !misc::zero_u32(&self.mtime_nanos);
+        let has_addrs = !self.addrs.is_empty();
+        let has_xattrs = !self.xattrs.is_empty();
+        let has_changed = !misc::is_false(&self.changed_during_backup);
+        let unknown_len = if numbered {
+            self.unknown_fields.numbered.len()
+        } else {
+            self.unknown_fields.named.len()
+        };
+        let len = [
+            has_mtime_nanos,
+            has_addrs,
+            self.target.is_some(),
+            has_xattrs,
+            self.unix_mode.is_some(),
+            has_changed,
+            self.dev.is_some(),
+            self.ino.is_some(),
+        ]
+        .iter()
+        .filter(|present| **present)
+        .count()
+            + 3
+            + unknown_len;
+        let mut map = serializer.serialize_map(Some(len))?;
+        serialize_field(&mut map, numbered, 0, &self.apath)?;
+        // Write back the name or number of a kind read from a later version.
+        if let (Kind::Unknown, true, Some(number)) =
+            (self.kind, numbered, &self.unknown_fields.kind_number)
+        {
+            serialize_field(&mut map, numbered, 1, number)?;
+        } else if let (Kind::Unknown, false, Some(name)) =
+            (self.kind, numbered, &self.unknown_fields.kind_name)
+        {
+            serialize_field(&mut map, numbered, 1, name)?;
+        } else {
+            serialize_field(&mut map, numbered, 1, &self.kind)?;
+        }
+        serialize_field(&mut map, numbered, 2, &self.mtime)?;
+        if has_mtime_nanos {
+            serialize_field(&mut map, numbered, 3, &self.mtime_nanos)?;
+        }
+        if has_addrs {
+            serialize_field(&mut map, numbered, 4, &self.addrs)?;
+        }
+        if let Some(target) = &self.target {
+            serialize_field(&mut map, numbered, 5, target)?;
+        }
+        if has_xattrs {
+            serialize_field(&mut map, numbered, 6, &Base64Xattrs(&self.xattrs))?;
+        }
+        if let Some(unix_mode) = &self.unix_mode {
+            serialize_field(&mut map, numbered, 7, unix_mode)?;
+        }
+        if has_changed {
+            serialize_field(&mut map, numbered, 8, &self.changed_during_backup)?;
+        }
+        if let Some(dev) = &self.dev {
+            serialize_field(&mut map, numbered, 9, dev)?;
+        }
+        if let Some(ino) = &self.ino {
+            serialize_field(&mut map, numbered, 10, ino)?;
+        }
+        if numbered {
+            for (number, value) in &self.unknown_fields.numbered {
+                map.serialize_entry(number, value)?;
+            }
+        } else {
+            for (name, value) in &self.unknown_fields.named {
+                map.serialize_entry(name, value)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Serialize one known field of an entry, keyed by its number or name.
+fn serialize_field<M: SerializeMap, T: Serialize + ?Sized>(
+    map: &mut M,
+    numbered: bool,
+    index: usize,
+    value: &T,
+) -> std::result::Result<(), M::Error> {
+    if numbered {
+        map.serialize_entry(&(index as u64), value)
+    } else {
+        map.serialize_entry(ENTRY_FIELDS[index], value)
+    }
+}
+
+impl<'de> Deserialize<'de> for IndexEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_map(IndexEntryVisitor)
+    }
+}
+
+struct IndexEntryVisitor;
+
+impl<'de> Visitor<'de> for IndexEntryVisitor {
+    type Value = IndexEntry;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an index entry")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<IndexEntry, A::Error> {
+        let mut apath = None;
+        let mut kind = None;
+        let mut entry = IndexEntry {
+            apath: "/".into(),
+            kind: Kind::Unknown,
+            mtime: 0,
+            mtime_nanos: 0,
+            addrs: Vec::new(),
+            target: None,
+            xattrs: Xattrs::new(),
+            unix_mode: None,
+            changed_during_backup: false,
+            dev: None,
+            ino: None,
+            unknown_fields: UnknownFields::default(),
+        };
+        while let Some(key) = map.next_key::<FieldKey>()? {
+            match key {
+                FieldKey::Known(0) => apath = Some(map.next_value()?),
+                FieldKey::Known(1) => {
+                    kind = Some(match map.next_value()? {
+                        StoredKind::Known(kind) => kind,
+                        StoredKind::Named(name) => {
+                            entry.unknown_fields.kind_name = Some(name);
+                            Kind::Unknown
+                        }
+                        StoredKind::Numbered(number) => {
+                            entry.unknown_fields.kind_number = Some(number);
+                            Kind::Unknown
+                        }
+                    })
+                }
+                FieldKey::Known(2) => entry.mtime = map.next_value()?,
+                FieldKey::Known(3) => entry.mtime_nanos = map.next_value()?,
+                FieldKey::Known(4) => entry.addrs = map.next_value()?,
+                FieldKey::Known(5) => entry.target = map.next_value()?,
+                FieldKey::Known(6) => entry.xattrs = map.next_value::<Base64XattrsOwned>()?.0,
+                FieldKey::Known(7) => entry.unix_mode = map.next_value()?,
+                FieldKey::Known(8) => entry.changed_during_backup = map.next_value()?,
+                FieldKey::Known(9) => entry.dev = map.next_value()?,
+                FieldKey::Known(10) => entry.ino = map.next_value()?,
+                FieldKey::Known(_) => unreachable!(),
+                FieldKey::Named(name) => {
+                    let value = map.next_value()?;
+                    entry.unknown_fields.named.insert(name, value);
+                }
+                FieldKey::Numbered(number) => {
+                    let value = map.next_value()?;
+                    entry.unknown_fields.numbered.insert(number, value);
+                }
+            }
+        }
+        entry.apath = apath.ok_or_else(|| de::Error::missing_field("apath"))?;
+        entry.kind = kind.ok_or_else(|| de::Error::missing_field("kind"))?;
+        Ok(entry)
+    }
+}
+
+/// The key of a field in a stored entry: either the index of a known field
+/// in `ENTRY_FIELDS`, or the name or number of an unknown field.
+enum FieldKey {
+    Known(usize),
+    Named(String),
+    Numbered(u64),
+}
+
+impl<'de> Deserialize<'de> for FieldKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct FieldKeyVisitor;
+
+        impl<'de> Visitor<'de> for FieldKeyVisitor {
+            type Value = FieldKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a field name or number")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> std::result::Result<FieldKey, E> {
+                Ok(match ENTRY_FIELDS.iter().position(|field| *field == name) {
+                    Some(index) => FieldKey::Known(index),
+                    None => FieldKey::Named(name.to_owned()),
+                })
+            }
+
+            fn visit_u64<E: de::Error>(self, number: u64) -> std::result::Result<FieldKey, E> {
+                Ok(if number < ENTRY_FIELDS.len() as u64 {
+                    FieldKey::Known(number as usize)
+                } else {
+                    FieldKey::Numbered(number)
+                })
+            }
+        }
+
+        deserializer.deserialize_any(FieldKeyVisitor)
+    }
+}
+
+/// A kind as stored: by name in json, or by number in CBOR.
+///
+/// Kinds added by later versions are read as [Kind::Unknown], and so are
+/// skipped when the tree is restored, but their name or number is kept in
+/// [UnknownFields] so that it can be written again.
+enum StoredKind {
+    Known(Kind),
+    Named(String),
+    Numbered(u64),
+}
+
+impl<'de> Deserialize<'de> for StoredKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct StoredKindVisitor;
+
+        impl<'de> Visitor<'de> for StoredKindVisitor {
+            type Value = StoredKind;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a kind name or number")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> std::result::Result<StoredKind, E> {
+                Ok(match name {
+                    "File" => StoredKind::Known(Kind::File),
+                    "Dir" => StoredKind::Known(Kind::Dir),
+                    "Symlink" => StoredKind::Known(Kind::Symlink),
+                    _ => StoredKind::Named(name.to_owned()),
+                })
+            }
+
+            fn visit_u64<E: de::Error>(self, number: u64) -> std::result::Result<StoredKind, E> {
+                Ok(match number {
+                    0 => StoredKind::Known(Kind::File),
+                    1 => StoredKind::Known(Kind::Dir),
+                    2 => StoredKind::Known(Kind::Symlink),
+                    _ => StoredKind::Numbered(number),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(StoredKindVisitor)
+    }
+}
+
+/// Serializes xattrs with base64-encoded values.
+struct Base64Xattrs<'a>(&'a Xattrs);
+
+impl Serialize for Base64Xattrs<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        crate::xattrs::base64_values::serialize(self.0, serializer)
+    }
+}
+
+/// Deserializes xattrs with base64-encoded values.
+struct Base64XattrsOwned(Xattrs);
+
+impl<'de> Deserialize<'de> for Base64XattrsOwned {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        crate::xattrs::base64_values::deserialize(deserializer).map(Base64XattrsOwned)
+    }
+}
+
 impl Entry for IndexEntry {
     /// Return apath relative to the top of the tree.
     fn apath(&self) -> &Apath {
@@ -94,7 +587,11 @@ impl Entry for IndexEntry {
 
     /// Size of the file, if it is a file. None for directories and symlinks.
     fn size(&self) -> Option<u64> {
-        Some(self.addrs.iter().map(|a| a.len).sum())
+        if self.kind == Kind::File {
+            Some(self.addrs.iter().map(|a| a.len).sum())
+        } else {
+            None
+        }
     }
 
     /// Target of the symlink, if this is a symlink.
@@ -102,9 +599,49 @@ impl Entry for IndexEntry {
     fn symlink_target(&self) -> &Option<String> {
         &self.target
     }
+
+    fn xattrs(&self) -> Option<&Xattrs> {
+        Some(&self.xattrs).filter(|xattrs| !xattrs.is_empty())
+    }
+
+    fn unix_mode(&self) -> Option<u32> {
+        self.unix_mode
+    }
+
+    fn changed_during_backup(&self) -> Option<bool> {
+        Some(self.changed_during_backup)
+    }
+
+    fn file_id(&self) -> Option<FileId> {
+        match (self.dev, self.ino) {
+            (Some(dev), Some(ino)) => Some(FileId { dev, ino }),
+            _ => None,
+        }
+    }
 }
 
 impl IndexEntry {
+    /// The addresses of the blocks holding the file's content, in order.
+    ///
+    /// This is part of the stable API.
+    pub fn addrs(&self) -> &[blockdir::Address] {
+        &self.addrs
+    }
+
+    /// Roughly how many bytes this entry takes when serialized, erring on the
+    /// high side, without actually serializing it.
+    pub(crate) fn estimated_serialized_len(&self) -> usize {
+        // Allow for the field names and numbers.
+        100 + self.apath.len()
+            + self.target.as_ref().map_or(0, String::len)
+            + self.addrs.len() * ESTIMATED_ADDRESS_LEN
+            + self
+                .xattrs
+                .iter()
+                .map(|(name, value)| 8 + name.len() + value.len() * 4 / 3)
+                .sum::<usize>()
+    }
+
     /// Copy the metadata, but not the body content, from another entry.
     pub(crate) fn metadata_from<E: Entry>(source: &E) -> IndexEntry {
         let mtime = source.mtime();
@@ -119,6 +656,12 @@ impl IndexEntry {
             target: source.symlink_target().clone(),
             mtime: mtime.secs,
             mtime_nanos: mtime.nanosecs,
+            xattrs: source.xattrs().cloned().unwrap_or_default(),
+            unix_mode: source.unix_mode(),
+            changed_during_backup: false,
+            dev: source.file_id().map(|id| id.dev),
+            ino: source.file_id().map(|id| id.ino),
+            unknown_fields: UnknownFields::default(),
         }
     }
 }
@@ -128,12 +671,19 @@ impl IndexEntry {
 /// This class is responsible for: remembering the hunk number, and checking that the
 /// hunks preserve apath order.
 pub struct IndexWriter {
-    /// The `i` directory within the band where all files for this index are written.
-    transport: Box<dyn Transport>,
+    /// The `i` directory within the band where all files for this index are
+    /// written, or None in a dry run, when hunks are measured but not written.
+    transport: Option<Box<dyn Transport>>,
 
     /// Currently queued entries to be written out, in arbitrary order.
     entries: Vec<IndexEntry>,
 
+    /// Estimated serialized size of the queued entries.
+    buffered_bytes: usize,
+
+    /// Buffer for serializing each hunk, reused to avoid reallocating it.
+    serialize_buf: Vec<u8>,
+
     /// Index hunk number, starting at 0.
     sequence: u32,
 
@@ -146,25 +696,76 @@ pub struct IndexWriter {
     pub stats: IndexWriterStats,
 
     compressor: Compressor,
+
+    /// Serialization of the hunks.
+    format: IndexFormat,
+
+    /// Apath ranges of the hunks written so far, to be written as the
+    /// manifest when the index is finished.
+    hunk_ranges: Vec<HunkRange>,
+
+    /// If set, hunks are written together into packs of this many, rather
+    /// than each into its own file.
+    hunks_per_pack: Option<u32>,
+
+    /// Stored form of hunks waiting to be written in the next pack.
+    packed_hunks: Vec<Vec<u8>>,
 }
 
 /// Accumulate and write out index entries into files in an index directory.
 impl IndexWriter {
     /// Make a new builder that will write files into the given directory.
     pub fn new(transport: Box<dyn Transport>) -> IndexWriter {
+        IndexWriter::with_transport(Some(transport))
+    }
+
+    /// Make a builder for a dry run, which serializes and compresses hunks to
+    /// count their size, but doesn't write anything.
+    pub(crate) fn dry_run() -> IndexWriter {
+        IndexWriter::with_transport(None)
+    }
+
+    fn with_transport(transport: Option<Box<dyn Transport>>) -> IndexWriter {
         IndexWriter {
             transport,
             entries: Vec::<IndexEntry>::with_capacity(MAX_ENTRIES_PER_HUNK),
+            buffered_bytes: 0,
+            serialize_buf: Vec::new(),
             sequence: 0,
             check_order: apath::DebugCheckOrder::new(),
             stats: IndexWriterStats::default(),
             compressor: Compressor::new(),
+            format: IndexFormat::default(),
+            hunk_ranges: Vec::new(),
+            hunks_per_pack: None,
+            packed_hunks: Vec::new(),
         }
     }
 
-    /// Finish the last hunk of this index, and return the stats.
+    /// Write hunks in the given format.
+    pub fn with_format(self, format: IndexFormat) -> IndexWriter {
+        IndexWriter { format, ..self }
+    }
+
+    /// Write hunks into packs of this many, if set, rather than each into
+    /// its own file.
+    pub fn with_hunks_per_pack(self, hunks_per_pack: Option<u32>) -> IndexWriter {
+        assert_ne!(hunks_per_pack, Some(0));
+        IndexWriter {
+            hunks_per_pack,
+            packed_hunks: Vec::with_capacity(hunks_per_pack.unwrap_or(0) as usize),
+            ..self
+        }
+    }
+
+    /// Finish the last hunk of this index, write the hunk manifest, and
+    /// return the stats.
     pub fn finish(mut self) -> Result<IndexWriterStats> {
         self.finish_hunk()?;
+        self.flush()?;
+        if let Some(transport) = &self.transport {
+            write_json(transport, HUNK_MANIFEST_FILENAME, &self.hunk_ranges)?;
+        }
         Ok(self.stats)
     }
 
@@ -175,18 +776,65 @@ impl IndexWriter {
     ///
     /// The new entry must sort after everything already written to the index.
     pub(crate) fn push_entry(&mut self, entry: IndexEntry) {
+        self.buffered_bytes += entry.estimated_serialized_len();
         self.entries.push(entry);
     }
 
     pub(crate) fn append_entries(&mut self, entries: &mut Vec<IndexEntry>) {
+        self.buffered_bytes += entries
+            .iter()
+            .map(IndexEntry::estimated_serialized_len)
+            .sum::<usize>();
         self.entries.append(entries);
     }
 
+    /// The number of entries queued for the next hunk.
+    pub(crate) fn buffered_entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The estimated serialized size of the entries queued for the next hunk.
+    pub(crate) fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Write out any finished hunks still waiting to fill a pack.
+    ///
+    /// The pack is written again, with more hunks, once they're finished.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if self.packed_hunks.is_empty() {
+            return Ok(());
+        }
+        let hunks_per_pack = self.hunks_per_pack.expect("index is packed");
+        let first_hunk = self.sequence - self.packed_hunks.len() as u32;
+        let relpath = pack_relpath(first_hunk);
+        let write_error = |source| Error::WriteIndex {
+            path: relpath.clone(),
+            source,
+        };
+        if let Some(transport) = &self.transport {
+            if first_hunk == 0
+                || (first_hunk - hunks_per_pack) / HUNKS_PER_SUBDIR != first_hunk / HUNKS_PER_SUBDIR
+            {
+                transport
+                    .create_dir(&subdir_relpath(first_hunk))
+                    .map_err(write_error)?;
+            }
+            transport
+                .write_file(&relpath, &encode_pack(&self.packed_hunks))
+                .map_err(write_error)?;
+        }
+        if self.packed_hunks.len() == hunks_per_pack as usize {
+            self.packed_hunks.clear();
+        }
+        Ok(())
+    }
+
     /// Finish this hunk of the index.
     ///
     /// This writes all the currently queued entries into a new index file
-    /// in the band directory, and then clears the buffer to start receiving
-    /// entries for the next hunk.
+    /// in the band directory, or adds them to the next pack, and then clears
+    /// the buffer to start receiving entries for the next hunk.
     pub fn finish_hunk(&mut self) -> Result<()> {
         if self.entries.is_empty() {
             return Ok(());
@@ -204,23 +852,43 @@ impl IndexWriter {
             path: relpath.clone(),
             source,
         };
-        let json =
-            serde_json::to_vec(&self.entries).map_err(|source| Error::SerializeIndex { source })?;
-        if (self.sequence % HUNKS_PER_SUBDIR) == 0 {
-            self.transport
-                .create_dir(&subdir_relpath(self.sequence))
+        self.format
+            .serialize_into(&self.entries, &mut self.serialize_buf)?;
+        let serialized = &self.serialize_buf;
+        let compressed_bytes = self.compressor.compress(serialized)?;
+        if self.hunks_per_pack.is_some() {
+            self.packed_hunks.push(compressed_bytes.to_vec());
+        } else if let Some(transport) = &self.transport {
+            if (self.sequence % HUNKS_PER_SUBDIR) == 0 {
+                transport
+                    .create_dir(&subdir_relpath(self.sequence))
+                    .map_err(write_error)?;
+            }
+            transport
+                .write_file(&relpath, compressed_bytes)
                 .map_err(write_error)?;
         }
-        let compressed_bytes = self.compressor.compress(&json)?;
-        self.transport
-            .write_file(&relpath, compressed_bytes)
-            .map_err(write_error)?;
 
         self.stats.index_hunks += 1;
         self.stats.compressed_index_bytes += compressed_bytes.len() as u64;
-        self.stats.uncompressed_index_bytes += json.len() as u64;
+        self.stats.uncompressed_index_bytes += serialized.len() as u64;
+        self.stats.index_entries += self.entries.len() as u64;
+        self.stats.file_bytes += self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.size())
+            .sum::<u64>();
+        self.hunk_ranges.push(HunkRange {
+            first: self.entries[0].apath.clone(),
+            last: self.entries.last().unwrap().apath.clone(),
+            hash: Some(hunk_hash(compressed_bytes)),
+        });
         self.entries.clear(); // Ready for the next hunk.
+        self.buffered_bytes = 0;
         self.sequence += 1;
+        if Some(self.packed_hunks.len() as u32) == self.hunks_per_pack {
+            self.flush()?;
+        }
         Ok(())
     }
 }
@@ -235,10 +903,206 @@ fn hunk_relpath(hunk_number: u32) -> String {
     format!("{:05}/{:09}", hunk_number / HUNKS_PER_SUBDIR, hunk_number)
 }
 
+/// Return the relative path for the pack starting with `first_hunk`.
+fn pack_relpath(first_hunk: u32) -> String {
+    format!(
+        "{:05}/{:09}.pack",
+        first_hunk / HUNKS_PER_SUBDIR,
+        first_hunk
+    )
+}
+
+/// Combine the stored form of several hunks into a pack.
+///
+/// A pack starts with the number of hunks as a little-endian u32, then the
+/// end offset of each hunk as a little-endian u64, counted from the end of
+/// this table, and then the hunks one after another.
+fn encode_pack(hunks: &[Vec<u8>]) -> Vec<u8> {
+    let table_len = 4 + 8 * hunks.len();
+    let mut pack = Vec::with_capacity(table_len + hunks.iter().map(Vec::len).sum::<usize>());
+    pack.extend_from_slice(&(hunks.len() as u32).to_le_bytes());
+    let mut end = 0u64;
+    for hunk in hunks {
+        end += hunk.len() as u64;
+        pack.extend_from_slice(&end.to_le_bytes());
+    }
+    for hunk in hunks {
+        pack.extend_from_slice(hunk);
+    }
+    pack
+}
+
+/// A pack of several index hunks, read from the archive.
+#[derive(Debug, Clone)]
+struct Pack {
+    /// Number of the first hunk in the pack.
+    first_hunk: u32,
+    bytes: Vec<u8>,
+    /// The range of `bytes` holding each hunk.
+    ranges: Vec<std::ops::Range<usize>>,
+}
+
+impl Pack {
+    fn parse(first_hunk: u32, bytes: Vec<u8>) -> io::Result<Pack> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Index pack is damaged");
+        let count = bytes
+            .get(..4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(invalid)?;
+        let table_len = count
+            .checked_mul(8)
+            .and_then(|len| len.checked_add(4))
+            .ok_or_else(invalid)?;
+        let table = bytes.get(4..table_len).ok_or_else(invalid)?;
+        let mut ranges = Vec::with_capacity(count);
+        let mut start = table_len;
+        for end in table.chunks_exact(8) {
+            let end = u64::from_le_bytes(end.try_into().unwrap())
+                .try_into()
+                .ok()
+                .and_then(|end: usize| end.checked_add(table_len))
+                .filter(|end| *end >= start && *end <= bytes.len())
+                .ok_or_else(invalid)?;
+            ranges.push(start..end);
+            start = end;
+        }
+        Ok(Pack {
+            first_hunk,
+            bytes,
+            ranges,
+        })
+    }
+
+    fn len(&self) -> u32 {
+        self.ranges.len() as u32
+    }
+
+    /// The stored form of a hunk, or None if it's not in this pack.
+    fn hunk(&self, hunk_number: u32) -> Option<&[u8]> {
+        let range = self
+            .ranges
+            .get(hunk_number.checked_sub(self.first_hunk)? as usize)?;
+        Some(&self.bytes[range.clone()])
+    }
+}
+
+/// Reads the stored form of hunks, each from its own file or from packs.
+///
+/// The most recently read pack is kept, so that reading its hunks in order
+/// reads the pack once.
+#[derive(Debug, Clone)]
+struct HunkReader {
+    transport: Box<dyn Transport>,
+    hunks_per_pack: Option<u32>,
+    pack: Option<Pack>,
+}
+
+impl HunkReader {
+    fn new(transport: Box<dyn Transport>, hunks_per_pack: Option<u32>) -> HunkReader {
+        HunkReader {
+            transport,
+            hunks_per_pack,
+            pack: None,
+        }
+    }
+
+    /// The path to describe a hunk in messages.
+    fn path(&self, hunk_number: u32) -> String {
+        match self.hunks_per_pack {
+            None => hunk_relpath(hunk_number),
+            Some(n) => format!(
+                "{}#{}",
+                pack_relpath(hunk_number - hunk_number % n),
+                hunk_number
+            ),
+        }
+    }
+
+    /// Read the stored (compressed) form of a hunk into `buf`.
+    ///
+    /// A hunk that's not present, whether or not its pack is, gives a
+    /// `NotFound` error.
+    fn read(&mut self, hunk_number: u32, buf: &mut Vec<u8>) -> io::Result<()> {
+        let hunks_per_pack = match self.hunks_per_pack {
+            None => return self.transport.read_file(&hunk_relpath(hunk_number), buf),
+            Some(n) => n,
+        };
+        let first_hunk = hunk_number - hunk_number % hunks_per_pack;
+        if self.pack.as_ref().map(|pack| pack.first_hunk) != Some(first_hunk) {
+            self.pack = None;
+            let mut bytes = Vec::new();
+            self.transport
+                .read_file(&pack_relpath(first_hunk), &mut bytes)?;
+            self.pack = Some(Pack::parse(first_hunk, bytes)?);
+        }
+        let hunk = self
+            .pack
+            .as_ref()
+            .and_then(|pack| pack.hunk(hunk_number))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Hunk is not in its pack"))?;
+        buf.clear();
+        buf.extend_from_slice(hunk);
+        Ok(())
+    }
+
+    /// True if the file holding the hunk exists.
+    fn exists(&mut self, hunk_number: u32) -> io::Result<bool> {
+        if self.hunks_per_pack.is_none() {
+            return self.transport.exists(&hunk_relpath(hunk_number));
+        }
+        match self.read(hunk_number, &mut Vec::new()) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Count the hunks, up to the first one that's missing.
+    fn count(&mut self) -> Result<u32> {
+        let hunks_per_pack = self.hunks_per_pack.unwrap_or(1);
+        let mut first_hunk = 0;
+        loop {
+            let path = match self.hunks_per_pack {
+                None => hunk_relpath(first_hunk),
+                Some(_) => pack_relpath(first_hunk),
+            };
+            if !self
+                .transport
+                .exists(&path)
+                .map_err(|source| Error::ReadIndex { source, path })?
+            {
+                break;
+            }
+            first_hunk += hunks_per_pack;
+        }
+        if self.hunks_per_pack.is_none() || first_hunk == 0 {
+            return Ok(first_hunk);
+        }
+        // Only the last pack can hold fewer hunks.
+        let last_pack = first_hunk - hunks_per_pack;
+        self.read(last_pack, &mut Vec::new())
+            .map_err(|source| Error::ReadIndex {
+                path: pack_relpath(last_pack),
+                source,
+            })?;
+        Ok(last_pack + self.pack.as_ref().map_or(0, Pack::len))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IndexRead {
     /// Transport pointing to this index directory.
     transport: Box<dyn Transport>,
+
+    /// Serialization of the hunks.
+    format: IndexFormat,
+
+    /// If set, hunks are stored in packs of this many.
+    hunks_per_pack: Option<u32>,
+
+    /// True if names in the entries are escaped as described in
+    /// [crate::names].
+    escaped_names: bool,
 }
 
 impl IndexRead {
@@ -248,7 +1112,40 @@ impl IndexRead {
     }
 
     pub(crate) fn open(transport: Box<dyn Transport>) -> IndexRead {
-        IndexRead { transport }
+        IndexRead {
+            transport,
+            format: IndexFormat::default(),
+            hunks_per_pack: None,
+            escaped_names: true,
+        }
+    }
+
+    /// Read hunks in the given format.
+    pub(crate) fn with_format(self, format: IndexFormat) -> IndexRead {
+        IndexRead { format, ..self }
+    }
+
+    /// Read hunks stored in packs of this many, if set, rather than each in
+    /// its own file.
+    pub(crate) fn with_hunks_per_pack(self, hunks_per_pack: Option<u32>) -> IndexRead {
+        IndexRead {
+            hunks_per_pack,
+            ..self
+        }
+    }
+
+    /// Set whether the names in the index are escaped, as they are in bands
+    /// written by 0.6.11 and later. Names that aren't are escaped as they're
+    /// read, so that all entries can be read the same way.
+    pub(crate) fn with_escaped_names(self, escaped_names: bool) -> IndexRead {
+        IndexRead {
+            escaped_names,
+            ..self
+        }
+    }
+
+    fn hunk_reader(&self) -> HunkReader {
+        HunkReader::new(self.transport.box_clone(), self.hunks_per_pack)
     }
 
     /// Return the (1-based) number of index hunks in an index directory.
@@ -256,18 +1153,7 @@ impl IndexRead {
         // TODO: Might be faster to list the directory than to probe for all of them.
         // TODO: Perhaps, list the directories and cope cleanly with
         // one hunk being missing.
-        for i in 0.. {
-            let path = hunk_relpath(i);
-            if !self
-                .transport
-                .exists(&path)
-                .map_err(|source| Error::ReadIndex { source, path })?
-            {
-                // If hunk 1 is missing, 1 hunks exists.
-                return Ok(i);
-            }
-        }
-        unreachable!();
+        self.hunk_reader().count()
     }
 
     pub fn estimate_entry_count(&self) -> Result<u64> {
@@ -275,20 +1161,152 @@ impl IndexRead {
     }
 
     /// Make an iterator that will return all entries in this band.
+    ///
+    /// Iteration stops at a hunk that can't be read.
     pub fn iter_entries(self) -> IndexEntryIter<IndexHunkIter> {
         IndexEntryIter::new(self.iter_hunks())
     }
 
     /// Make an iterator that returns hunks of entries from this index.
+    ///
+    /// If the index has a hunk manifest, each hunk is checked against the
+    /// hash recorded there. Iteration stops at a hunk that's missing or
+    /// damaged, and the error can be taken from the iterator, unless it's
+    /// made [lenient](IndexHunkIter::lenient).
     pub fn iter_hunks(&self) -> IndexHunkIter {
+        let hunk_ranges = match self.read_hunk_manifest() {
+            Ok(hunk_ranges) => hunk_ranges,
+            Err(err) => {
+                ui::report_problem(Problem::new(
+                    ProblemKind::Index,
+                    None,
+                    format!("Error reading index hunk manifest: {:?}", err),
+                ));
+                None
+            }
+        };
         IndexHunkIter {
             next_hunk_number: 0,
-            transport: self.transport.box_clone(),
+            hunk_reader: self.hunk_reader(),
+            format: self.format,
             decompressor: Decompressor::new(),
             compressed_buf: Vec::new(),
             stats: IndexReadStats::default(),
             after: None,
+            subtree: None,
+            hunk_ranges,
+            last_apath: None,
+            past_subtree: false,
+            lenient: false,
+            failed: false,
+            error: None,
+            escaped_names: self.escaped_names,
+        }
+    }
+
+    /// Read and decode one hunk, or return None if there's no hunk with this
+    /// number.
+    pub fn read_hunk(&self, hunk_number: u32) -> Result<Option<Vec<IndexEntry>>> {
+        let mut hunk_reader = self.hunk_reader();
+        let path = hunk_reader.path(hunk_number);
+        let mut entries = read_hunk_bytes(&mut hunk_reader, hunk_number)?
+            .map(|bytes| self.format.deserialize(&bytes, &path))
+            .transpose()?;
+        if let (Some(entries), false) = (&mut entries, self.escaped_names) {
+            escape_old_names(entries);
+        }
+        Ok(entries)
+    }
+
+    /// Read one hunk as the generic structure stored in it, before it's
+    /// interpreted as index entries, or return None if there's no hunk with
+    /// this number.
+    pub fn read_raw_hunk(&self, hunk_number: u32) -> Result<Option<serde_json::Value>> {
+        let mut hunk_reader = self.hunk_reader();
+        let path = hunk_reader.path(hunk_number);
+        read_hunk_bytes(&mut hunk_reader, hunk_number)?
+            .map(|bytes| self.format.deserialize_raw(&bytes, &path))
+            .transpose()
+    }
+
+    /// Check that every hunk listed in the manifest is present and matches
+    /// its hash, and that there are no hunks beyond those listed.
+    ///
+    /// Indexes without a manifest, or whose manifest has no hashes, aren't
+    /// checked here. Problems are described in `stats`, naming `band_id`.
+    pub(crate) fn validate_hunks(&self, band_id: &BandId, stats: &mut ValidateStats) -> Result<()> {
+        let hunk_ranges = match self.read_hunk_manifest()? {
+            Some(hunk_ranges) => hunk_ranges,
+            None => return Ok(()),
+        };
+        let mut report = |err: Error| {
+            stats.problems.push(Problem {
+                kind: ProblemKind::Index,
+                apath: None,
+                category: err.category(),
+                message: format!("{} in {}", err, band_id),
+            });
+            stats.index_hunk_problems += 1;
+        };
+        let mut hunk_reader = self.hunk_reader();
+        let mut stored = Vec::new();
+        for (hunk_number, range) in hunk_ranges.iter().enumerate() {
+            let expected = match &range.hash {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let path = hunk_reader.path(hunk_number as u32);
+            match hunk_reader.read(hunk_number as u32, &mut stored) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    report(Error::MissingIndexHunk { path })
+                }
+                Err(source) => report(Error::ReadIndex { path, source }),
+                Ok(()) if hunk_hash(&stored) != *expected => {
+                    report(Error::IndexHunkHashMismatch { path })
+                }
+                Ok(()) => (),
+            }
+        }
+        let path = hunk_reader.path(hunk_ranges.len() as u32);
+        if hunk_reader
+            .exists(hunk_ranges.len() as u32)
+            .map_err(|source| Error::ReadIndex {
+                path: path.clone(),
+                source,
+            })?
+        {
+            report(Error::UnexpectedIndexHunk { path });
         }
+        Ok(())
+    }
+
+    /// Read the apath ranges of each hunk, if this index has a manifest.
+    ///
+    /// Indexes written before 0.6.11, and indexes of incomplete bands, have no
+    /// manifest.
+    pub fn read_hunk_manifest(&self) -> Result<Option<Vec<HunkRange>>> {
+        read_hunk_manifest(&self.transport)
+    }
+}
+
+/// Read and decompress one hunk, or return None if it's not present.
+fn read_hunk_bytes(hunk_reader: &mut HunkReader, hunk_number: u32) -> Result<Option<Vec<u8>>> {
+    let mut compressed = Vec::new();
+    match hunk_reader.read(hunk_number, &mut compressed) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(Error::ReadIndex {
+            path: hunk_reader.path(hunk_number),
+            source,
+        }),
+        Ok(()) => Ok(Some(Decompressor::new().decompress(&compressed)?.to_vec())),
+    }
+}
+
+fn read_hunk_manifest<TR: AsRef<dyn Transport>>(transport: &TR) -> Result<Option<Vec<HunkRange>>> {
+    if transport.as_ref().exists(HUNK_MANIFEST_FILENAME)? {
+        read_json(transport, HUNK_MANIFEST_FILENAME).map(Some)
+    } else {
+        Ok(None)
     }
 }
 
@@ -297,13 +1315,32 @@ impl IndexRead {
 /// Each returned item is a vec of (typically up to a thousand) index entries.
 pub struct IndexHunkIter {
     next_hunk_number: u32,
-    /// The `i` directory within the band where all files for this index are written.
-    transport: Box<dyn Transport>,
+    /// Reads hunks from the `i` directory within the band.
+    hunk_reader: HunkReader,
+    format: IndexFormat,
     decompressor: Decompressor,
     compressed_buf: Vec<u8>,
     pub stats: IndexReadStats,
     /// If set, yield only entries ordered after this apath.
     after: Option<Apath>,
+    /// If set, yield only entries within this subtree.
+    subtree: Option<Apath>,
+    /// Apath ranges and hashes of the hunks, from the manifest, used to skip
+    /// hunks outside the subtree without reading them, and to check the
+    /// hunks that are read.
+    hunk_ranges: Option<Vec<HunkRange>>,
+    /// The last apath in the last hunk read, before filtering by subtree.
+    last_apath: Option<Apath>,
+    /// True if the iterator has passed the end of the subtree.
+    past_subtree: bool,
+    /// If true, report hunks that can't be read and continue past them.
+    lenient: bool,
+    /// True if iteration stopped at a hunk that couldn't be read.
+    failed: bool,
+    /// The error that stopped iteration, until it's taken.
+    error: Option<Error>,
+    /// True if names in the index are already escaped.
+    escaped_names: bool,
 }
 
 impl Iterator for IndexHunkIter {
@@ -311,15 +1348,28 @@ impl Iterator for IndexHunkIter {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
+            if self.past_subtree || self.failed {
+                return None;
+            }
+            if self.skip_hunk_outside_subtree() {
+                continue;
+            }
             let hunk_number = self.next_hunk_number;
-            let entries = match self.read_next_hunk() {
+            let mut entries = match self.read_next_hunk() {
                 Ok(None) => return None,
                 Ok(Some(entries)) => entries,
+                Err(err) if !self.lenient => {
+                    self.stats.errors += 1;
+                    self.failed = true;
+                    self.error = Some(err);
+                    return None;
+                }
                 Err(err) => {
                     self.stats.errors += 1;
-                    ui::problem(&format!(
-                        "Error reading index hunk {:?}: {:?} ",
-                        hunk_number, err
+                    ui::report_problem(Problem::new(
+                        ProblemKind::Index,
+                        None,
+                        format!("Error reading index hunk {:?}: {:?} ", hunk_number, err),
                     ));
                     continue;
                 }
@@ -333,14 +1383,23 @@ impl Iterator for IndexHunkIter {
                 if let Some(first) = entries.first() {
                     if first.apath > *after {
                         self.after = None; // don't need to look again
-                        return Some(entries);
+                    } else {
+                        let idx = match entries.binary_search_by_key(&after, |entry| &entry.apath) {
+                            Ok(idx) => idx + 1, // after the point it was found
+                            Err(idx) => idx,    // from the point it would have been
+                        };
+                        entries.drain(..idx);
                     }
                 }
-                let idx = match entries.binary_search_by_key(&after, |entry| &entry.apath) {
-                    Ok(idx) => idx + 1, // after the point it was found
-                    Err(idx) => idx,    // from the point it would have been
-                };
-                return Some(Vec::from(&entries[idx..]));
+            }
+            if let Some(last) = entries.last() {
+                self.last_apath = Some(last.apath.clone());
+            }
+            if let Some(ref subtree) = self.subtree {
+                if let Some(last) = entries.last() {
+                    self.past_subtree = last.apath.is_after_subtree(subtree);
+                }
+                entries.retain(|entry| subtree.is_prefix_of(&entry.apath));
             }
             if !entries.is_empty() {
                 return Some(entries);
@@ -358,16 +1417,97 @@ impl IndexHunkIter {
         }
     }
 
+    /// Return only entries within `subtree`.
+    ///
+    /// If the index has a hunk manifest, hunks wholly outside the subtree are
+    /// not read.
+    pub fn subtree(self, subtree: &Apath) -> Self {
+        IndexHunkIter {
+            subtree: Some(subtree.clone()),
+            ..self
+        }
+    }
+
+    /// Report hunks that are missing or can't be read, and continue with the
+    /// following hunks, rather than stopping.
+    ///
+    /// This is meant for validation and other diagnostics, which want to see
+    /// as much of a damaged index as possible.
+    pub fn lenient(self) -> Self {
+        IndexHunkIter {
+            lenient: true,
+            ..self
+        }
+    }
+
+    /// Take the error that stopped iteration, if any.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// True if iteration stopped at a hunk that couldn't be read.
+    pub(crate) fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// The last apath in the most recently read hunk, including entries
+    /// outside the subtree.
+    pub(crate) fn last_apath(&self) -> Option<&Apath> {
+        self.last_apath.as_ref()
+    }
+
+    /// True if iteration stopped because the index continued past the end of
+    /// the subtree.
+    pub(crate) fn is_past_subtree(&self) -> bool {
+        self.past_subtree
+    }
+
+    /// Use the hunk manifest to skip over the next hunk if it's wholly before
+    /// the subtree, or to stop if it's after.
+    ///
+    /// Returns true if the next hunk should not be read.
+    fn skip_hunk_outside_subtree(&mut self) -> bool {
+        let (subtree, hunk_ranges) = match (&self.subtree, &self.hunk_ranges) {
+            (Some(subtree), Some(hunk_ranges)) => (subtree, hunk_ranges),
+            _ => return false,
+        };
+        if let Some(range) = hunk_ranges.get(self.next_hunk_number as usize) {
+            // The subtree's top directory sorts among its siblings, and its
+            // contents sort contiguously, later, so a hunk can be skipped if
+            // it doesn't hold the top directory and ends before the contents.
+            let holds_top = range.first <= *subtree && *subtree <= range.last;
+            if !holds_top
+                && !subtree.is_prefix_of(&range.last)
+                && !range.last.is_after_subtree(subtree)
+            {
+                self.next_hunk_number += 1;
+                self.stats.index_hunks_skipped += 1;
+                return true;
+            } else if range.first.is_after_subtree(subtree) {
+                self.past_subtree = true;
+                return true;
+            }
+        }
+        false
+    }
+
     fn read_next_hunk(&mut self) -> Result<Option<Vec<IndexEntry>>> {
-        let path = &hunk_relpath(self.next_hunk_number);
+        let hunk_number = self.next_hunk_number;
+        let path = &self.hunk_reader.path(hunk_number);
         // Whether we succeed or fail, don't try to read this hunk again.
         self.next_hunk_number += 1;
-        if let Err(err) = self.transport.read_file(&path, &mut self.compressed_buf) {
+        let range = self
+            .hunk_ranges
+            .as_ref()
+            .and_then(|ranges| ranges.get(hunk_number as usize));
+        if let Err(err) = self.hunk_reader.read(hunk_number, &mut self.compressed_buf) {
             if err.kind() == io::ErrorKind::NotFound {
-                // TODO: Cope with one hunk being missing, while there are still
-                // later-numbered hunks. This would require reading the whole
-                // list of hunks first.
-                return Ok(None);
+                // Without a manifest, the index ends at the first missing
+                // hunk. With one, later hunks can still be read.
+                return match range {
+                    Some(_) => Err(Error::MissingIndexHunk { path: path.clone() }),
+                    None => Ok(None),
+                };
             } else {
                 return Err(Error::ReadIndex {
                     path: path.clone(),
@@ -375,15 +1515,19 @@ impl IndexHunkIter {
                 });
             }
         }
+        if let Some(expected) = range.and_then(|range| range.hash.as_ref()) {
+            if hunk_hash(&self.compressed_buf) != *expected {
+                return Err(Error::IndexHunkHashMismatch { path: path.clone() });
+            }
+        }
         self.stats.index_hunks += 1;
         self.stats.compressed_index_bytes += self.compressed_buf.len() as u64;
         let index_bytes = self.decompressor.decompress(&self.compressed_buf)?;
         self.stats.uncompressed_index_bytes += index_bytes.len() as u64;
-        let entries: Vec<IndexEntry> =
-            serde_json::from_slice(&index_bytes).map_err(|source| Error::DeserializeIndex {
-                path: path.clone(),
-                source,
-            })?;
+        let mut entries = self.format.deserialize(&index_bytes, path)?;
+        if !self.escaped_names {
+            escape_old_names(&mut entries);
+        }
         if entries.is_empty() {
             // It's legal, it's just weird - and it can be produced by some old Conserve versions.
         }
@@ -391,6 +1535,22 @@ impl IndexHunkIter {
     }
 }
 
+/// Escape the apaths and symlink targets of entries from a band written
+/// before names were escaped, so that any characters in them that are used as
+/// escapes are read back as themselves.
+fn escape_old_names(entries: &mut [IndexEntry]) {
+    for entry in entries {
+        if let Cow::Owned(apath) = names::escape_bytes(entry.apath.as_bytes()) {
+            entry.apath = apath.into();
+        }
+        if let Some(target) = &mut entry.target {
+            if let Cow::Owned(escaped) = names::escape_bytes(target.as_bytes()) {
+                *target = escaped;
+            }
+        }
+    }
+}
+
 /// Read out all the entries from a stored index, in apath order.
 pub struct IndexEntryIter<HI: Iterator<Item = Vec<IndexEntry>>> {
     /// Temporarily buffered entries, read from the index files but not yet
@@ -477,6 +1637,7 @@ mod tests {
 
     use super::transport::local::LocalTransport;
     use super::*;
+    use crate::test_fixtures::TestTransport;
 
     fn setup() -> (TempDir, IndexWriter) {
         let testdir = TempDir::new().unwrap();
@@ -492,6 +1653,12 @@ mod tests {
             kind: Kind::File,
             addrs: vec![],
             target: None,
+            xattrs: Xattrs::new(),
+            unix_mode: None,
+            changed_during_backup: false,
+            dev: None,
+            ino: None,
+            unknown_fields: UnknownFields::default(),
         }
     }
 
@@ -504,6 +1671,12 @@ mod tests {
             kind: Kind::File,
             addrs: vec![],
             target: None,
+            xattrs: Xattrs::new(),
+            unix_mode: None,
+            changed_during_backup: false,
+            dev: None,
+            ino: None,
+            unknown_fields: UnknownFields::default(),
         }];
         let index_json = serde_json::to_string(&entries).unwrap();
         println!("{}", index_json);
@@ -515,6 +1688,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn serialize_xattrs_as_base64() {
+        let mut entry = sample_entry("/a");
+        entry
+            .xattrs
+            .insert("user.comment".to_owned(), b"hello".to_vec());
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            json,
+            "{\"apath\":\"/a\",\
+             \"kind\":\"File\",\
+             \"mtime\":1461736377,\
+             \"xattrs\":{\"user.comment\":\"aGVsbG8=\"}}"
+        );
+        assert_eq!(serde_json::from_str::<IndexEntry>(&json).unwrap(), entry);
+    }
+
+    #[test]
+    fn index_writer_counts_buffered_entries_and_reuses_buffer() {
+        let (_testdir, mut ib) = setup();
+        assert_eq!(ib.buffered_entries(), 0);
+        assert_eq!(ib.buffered_bytes(), 0);
+        ib.push_entry(sample_entry("/aaa"));
+        ib.push_entry(sample_entry("/bbb"));
+        assert_eq!(ib.buffered_entries(), 2);
+        let actual_len = serde_json::to_vec(&ib.entries).unwrap().len();
+        assert!(ib.buffered_bytes() >= actual_len);
+        ib.finish_hunk().unwrap();
+        assert_eq!(ib.buffered_entries(), 0);
+        assert_eq!(ib.buffered_bytes(), 0);
+        let buf_ptr = ib.serialize_buf.as_ptr();
+
+        ib.push_entry(sample_entry("/ccc"));
+        ib.finish_hunk().unwrap();
+        assert_eq!(ib.serialize_buf.as_ptr(), buf_ptr);
+    }
+
     #[test]
     fn index_builder_sorts_entries() {
         let (_testdir, mut ib) = setup();
@@ -757,4 +1967,492 @@ mod tests {
         assert_eq!(read_index.count_hunks()?, 1);
         Ok(())
     }
+
+    /// Write an index with one hunk for each of the directories `/d00` to
+    /// `/d19`, each containing ten files.
+    fn write_many_hunks(ib: &mut IndexWriter) {
+        for i in 0..20 {
+            if i == 0 {
+                for j in 0..20 {
+                    ib.push_entry(sample_entry(&format!("/d{:02}", j)));
+                }
+            }
+            for j in 0..10 {
+                ib.push_entry(sample_entry(&format!("/d{:02}/f{}", i, j)));
+            }
+            ib.finish_hunk().unwrap();
+        }
+    }
+
+    #[test]
+    fn manifest_records_hunk_ranges() {
+        let (testdir, mut ib) = setup();
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+        let ranges = IndexRead::open_path(testdir.path())
+            .read_hunk_manifest()
+            .unwrap()
+            .unwrap();
+        assert_eq!(ranges.len(), 20);
+        assert_eq!(ranges[0].first, "/d00");
+        assert_eq!(ranges[0].last, "/d00/f9");
+        assert_eq!(ranges[7].first, "/d07/f0");
+        assert_eq!(ranges[7].last, "/d07/f9");
+        let stored = std::fs::read(testdir.path().join(hunk_relpath(7))).unwrap();
+        assert_eq!(ranges[7].hash, Some(hunk_hash(&stored)));
+    }
+
+    /// Flip one bit in the stored form of a hunk.
+    fn damage_hunk(index_dir: &Path, hunk_number: u32) {
+        let path = index_dir.join(hunk_relpath(hunk_number));
+        let mut stored = std::fs::read(&path).unwrap();
+        let last = stored.len() - 1;
+        stored[last] ^= 0x01;
+        std::fs::write(&path, stored).unwrap();
+    }
+
+    #[test]
+    fn damaged_hunk_stops_iteration_unless_lenient() {
+        let (testdir, mut ib) = setup();
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+        damage_hunk(testdir.path(), 3);
+
+        let index = IndexRead::open_path(testdir.path());
+        let mut hunks = index.iter_hunks();
+        hunks.next_hunk_number = 3;
+        match hunks.read_next_hunk() {
+            Err(Error::IndexHunkHashMismatch { path }) => assert_eq!(path, "00000/000000003"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut hunks = index.iter_hunks();
+        assert_eq!(hunks.by_ref().count(), 3);
+        assert!(matches!(
+            hunks.take_error(),
+            Some(Error::IndexHunkHashMismatch { .. })
+        ));
+        assert_eq!(hunks.next(), None);
+
+        let mut hunks = index.iter_hunks().lenient();
+        let apaths: Vec<Apath> = hunks.by_ref().flatten().map(|entry| entry.apath).collect();
+        assert!(hunks.take_error().is_none());
+        assert_eq!(hunks.stats.errors, 1);
+        assert_eq!(hunks.stats.index_hunks, 19);
+        assert!(apaths.contains(&"/d02/f9".into()));
+        assert!(!apaths.contains(&"/d03/f0".into()));
+        assert!(apaths.contains(&"/d04/f0".into()));
+        assert!(apaths.contains(&"/d19/f9".into()));
+
+        let mut stats = ValidateStats::default();
+        index.validate_hunks(&BandId::zero(), &mut stats).unwrap();
+        assert_eq!(stats.index_hunk_problems, 1);
+        assert!(stats.has_problems());
+        assert_eq!(
+            stats.problems.iter().next().unwrap().message,
+            "Index hunk \"00000/000000003\" doesn't match the hash in the manifest in b0000"
+        );
+    }
+
+    #[test]
+    fn missing_hunk_listed_in_manifest_is_reported() {
+        let (testdir, mut ib) = setup();
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+        std::fs::remove_file(testdir.path().join(hunk_relpath(5))).unwrap();
+
+        let index = IndexRead::open_path(testdir.path());
+        let mut hunks = index.iter_hunks();
+        assert_eq!(hunks.by_ref().count(), 5);
+        assert!(matches!(
+            hunks.take_error(),
+            Some(Error::MissingIndexHunk { .. })
+        ));
+        let mut hunks = index.iter_hunks().lenient();
+        assert_eq!(hunks.by_ref().count(), 19);
+        assert_eq!(hunks.stats.errors, 1);
+
+        let mut stats = ValidateStats::default();
+        index.validate_hunks(&BandId::zero(), &mut stats).unwrap();
+        assert_eq!(stats.index_hunk_problems, 1);
+    }
+
+    #[test]
+    fn manifest_without_hashes_is_not_checked() {
+        let (testdir, mut ib) = setup();
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+        let index = IndexRead::open_path(testdir.path());
+        let old_manifest: Vec<HunkRange> = index
+            .read_hunk_manifest()
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|range| HunkRange {
+                hash: None,
+                ..range
+            })
+            .collect();
+        write_json(&index.transport, HUNK_MANIFEST_FILENAME, &old_manifest).unwrap();
+        let manifest =
+            std::fs::read_to_string(testdir.path().join(HUNK_MANIFEST_FILENAME)).unwrap();
+        assert!(!manifest.contains("hash"));
+        damage_hunk(testdir.path(), 3);
+
+        let mut stats = ValidateStats::default();
+        index.validate_hunks(&BandId::zero(), &mut stats).unwrap();
+        assert_eq!(stats.index_hunk_problems, 0);
+    }
+
+    #[test]
+    fn extra_hunk_beyond_manifest_is_reported() {
+        let (testdir, mut ib) = setup();
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+        std::fs::copy(
+            testdir.path().join(hunk_relpath(0)),
+            testdir.path().join(hunk_relpath(20)),
+        )
+        .unwrap();
+
+        let mut stats = ValidateStats::default();
+        IndexRead::open_path(testdir.path())
+            .validate_hunks(&BandId::zero(), &mut stats)
+            .unwrap();
+        assert_eq!(stats.index_hunk_problems, 1);
+    }
+
+    #[test]
+    fn subtree_skips_hunks_outside_range() {
+        let (testdir, mut ib) = setup();
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+
+        let transport = TestTransport::new(testdir.path());
+        let mut hunks = IndexRead::open(Box::new(transport.clone()))
+            .iter_hunks()
+            .subtree(&"/d07".into());
+        let apaths: Vec<String> = hunks
+            .by_ref()
+            .flatten()
+            .map(|entry| entry.apath.to_string())
+            .collect();
+        assert_eq!(apaths.len(), 11);
+        assert_eq!(apaths[0], "/d07");
+        assert_eq!(apaths[1], "/d07/f0");
+        assert_eq!(apaths[10], "/d07/f9");
+        // The manifest, hunk 0 holding /d07, and hunk 7.
+        assert_eq!(
+            transport.reads(),
+            ["MANIFEST", "00000/000000000", "00000/000000007"]
+        );
+        assert_eq!(hunks.stats.index_hunks, 2);
+        assert_eq!(hunks.stats.index_hunks_skipped, 6);
+    }
+
+    #[test]
+    fn subtree_without_manifest_reads_all_hunks() {
+        let (testdir, mut ib) = setup();
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+        std::fs::remove_file(testdir.path().join(HUNK_MANIFEST_FILENAME)).unwrap();
+
+        let mut hunks = IndexRead::open_path(testdir.path())
+            .iter_hunks()
+            .subtree(&"/d07".into());
+        assert_eq!(hunks.by_ref().flatten().count(), 11);
+        // Reading stops after the first hunk past the subtree.
+        assert_eq!(hunks.stats.index_hunks, 9);
+        assert_eq!(hunks.stats.index_hunks_skipped, 0);
+    }
+
+    #[test]
+    fn round_trip_in_each_format() {
+        let hash: BlockHash = "8de0f4f9".repeat(16).parse().unwrap();
+        let entries = vec![
+            IndexEntry {
+                kind: Kind::Dir,
+                ..sample_entry("/")
+            },
+            IndexEntry {
+                mtime_nanos: 123_456,
+                addrs: vec![
+                    blockdir::Address {
+                        hash: hash.clone(),
+                        start: 0,
+                        len: 10,
+                    },
+                    blockdir::Address {
+                        hash,
+                        start: 1000,
+                        len: 20,
+                    },
+                ],
+                ..sample_entry("/a file")
+            },
+            IndexEntry {
+                kind: Kind::Symlink,
+                target: Some("../elsewhere".to_owned()),
+                ..sample_entry("/link")
+            },
+            IndexEntry {
+                kind: Kind::Dir,
+                xattrs: vec![("user.color".to_owned(), b"blue".to_vec())]
+                    .into_iter()
+                    .collect(),
+                ..sample_entry("/sub")
+            },
+            sample_entry("/sub/f\u{00e9}"),
+        ];
+        for format in &[IndexFormat::Json, IndexFormat::Cbor] {
+            let testdir = TempDir::new().unwrap();
+            let transport: Box<dyn Transport> = Box::new(LocalTransport::new(testdir.path()));
+            let mut ib = IndexWriter::new(transport.clone()).with_format(*format);
+            ib.push_entry(entries[0].clone());
+            ib.push_entry(entries[1].clone());
+            ib.push_entry(entries[2].clone());
+            ib.finish_hunk().unwrap();
+            ib.push_entry(entries[3].clone());
+            ib.push_entry(entries[4].clone());
+            let stats = ib.finish().unwrap();
+            assert_eq!(stats.index_hunks, 2);
+
+            let read_back: Vec<IndexEntry> = IndexRead::open(transport)
+                .with_format(*format)
+                .iter_entries()
+                .collect();
+            assert_eq!(read_back, entries, "format {:?}", format);
+        }
+    }
+
+    /// Write the hunks from `write_many_hunks` in packs of eight, through a
+    /// transport that counts writes.
+    fn write_packed_hunks() -> (TempDir, TestTransport) {
+        let testdir = TempDir::new().unwrap();
+        let transport = TestTransport::new(testdir.path());
+        let mut ib = IndexWriter::new(Box::new(transport.clone())).with_hunks_per_pack(Some(8));
+        write_many_hunks(&mut ib);
+        ib.finish().unwrap();
+        (testdir, transport)
+    }
+
+    #[test]
+    fn packed_hunks_are_read_back() {
+        let (testdir, transport) = write_packed_hunks();
+        // Three packs and the manifest, rather than a file for each of the
+        // twenty hunks.
+        assert_eq!(
+            transport.writes(),
+            [
+                "00000/000000000.pack",
+                "00000/000000008.pack",
+                "00000/000000016.pack",
+                "MANIFEST"
+            ]
+        );
+        let mut names: Vec<String> = std::fs::read_dir(testdir.path().join("00000"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["000000000.pack", "000000008.pack", "000000016.pack"]
+        );
+
+        let index = IndexRead::open_path(testdir.path()).with_hunks_per_pack(Some(8));
+        assert_eq!(index.count_hunks().unwrap(), 20);
+        assert_eq!(index.read_hunk(17).unwrap().unwrap()[0].apath, "/d17/f0");
+        assert_eq!(index.read_hunk(20).unwrap(), None);
+        let mut stats = ValidateStats::default();
+        index.validate_hunks(&BandId::zero(), &mut stats).unwrap();
+        assert!(!stats.has_problems());
+
+        let transport = TestTransport::new(testdir.path());
+        let mut hunks = IndexRead::open(Box::new(transport.clone()))
+            .with_hunks_per_pack(Some(8))
+            .iter_hunks();
+        let apaths: Vec<Apath> = hunks.by_ref().flatten().map(|entry| entry.apath).collect();
+        assert_eq!(apaths.len(), 220);
+        assert_eq!(apaths[0], "/d00");
+        assert_eq!(apaths[219], "/d19/f9");
+        assert_eq!(hunks.stats.index_hunks, 20);
+        // The manifest and each pack.
+        assert_eq!(
+            transport.reads(),
+            [
+                "MANIFEST",
+                "00000/000000000.pack",
+                "00000/000000008.pack",
+                "00000/000000016.pack"
+            ]
+        );
+    }
+
+    #[test]
+    fn damaged_hunk_in_pack_is_reported() {
+        let (testdir, _transport) = write_packed_hunks();
+        let path = testdir.path().join(pack_relpath(8));
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+
+        let index = IndexRead::open_path(testdir.path()).with_hunks_per_pack(Some(8));
+        let mut hunks = index.iter_hunks().lenient();
+        assert_eq!(hunks.by_ref().flatten().count(), 210);
+        assert_eq!(hunks.stats.errors, 1);
+        let mut stats = ValidateStats::default();
+        index.validate_hunks(&BandId::zero(), &mut stats).unwrap();
+        assert_eq!(
+            stats.problems.iter().next().unwrap().message,
+            "Index hunk \"00000/000000008.pack#15\" doesn't match the hash in the manifest in b0000"
+        );
+    }
+
+    #[test]
+    fn flushed_pack_is_rewritten_with_later_hunks() {
+        let (testdir, ib) = setup();
+        let mut ib = ib.with_hunks_per_pack(Some(8));
+        for i in 0..3 {
+            ib.push_entry(sample_entry(&format!("/{}", i)));
+            ib.finish_hunk().unwrap();
+        }
+        ib.flush().unwrap();
+        let index = IndexRead::open_path(testdir.path()).with_hunks_per_pack(Some(8));
+        assert_eq!(index.count_hunks().unwrap(), 3);
+        ib.push_entry(sample_entry("/3"));
+        ib.finish().unwrap();
+        assert_eq!(index.count_hunks().unwrap(), 4);
+        assert_eq!(index.iter_entries().count(), 4);
+    }
+
+    #[test]
+    fn truncated_pack_is_invalid() {
+        let pack = encode_pack(&[b"one".to_vec(), b"three".to_vec()]);
+        let parsed = Pack::parse(8, pack.clone()).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed.hunk(9), Some(&b"three"[..]));
+        assert_eq!(parsed.hunk(10), None);
+        for len in [0, 3, 12, pack.len() - 1] {
+            let err = Pack::parse(8, pack[..len].to_vec()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn cbor_is_not_read_as_json() {
+        let (testdir, ib) = setup();
+        let mut ib = ib.with_format(IndexFormat::Cbor);
+        ib.push_entry(sample_entry("/a"));
+        ib.finish().unwrap();
+        let mut hunks = IndexRead::open_path(testdir.path()).iter_hunks();
+        assert_eq!(hunks.next(), None);
+        assert_eq!(hunks.stats.errors, 1);
+    }
+
+    #[test]
+    fn parse_index_format() {
+        assert_eq!("json".parse::<IndexFormat>().unwrap(), IndexFormat::Json);
+        assert_eq!("cbor".parse::<IndexFormat>().unwrap(), IndexFormat::Cbor);
+        assert!("xml".parse::<IndexFormat>().is_err());
+    }
+
+    #[test]
+    fn cbor_fields_are_numbered() {
+        let entry = IndexEntry {
+            unix_mode: Some(0o644),
+            ..sample_entry("/a")
+        };
+        let mut buf = Vec::new();
+        IndexFormat::Cbor
+            .serialize_into(&[entry], &mut buf)
+            .unwrap();
+        let value: serde_cbor::Value = serde_cbor::from_slice(&buf).unwrap();
+        let expected: serde_cbor::Value = serde_cbor::from_slice(
+            &serde_cbor::to_vec(&vec![vec![
+                (0, serde_cbor::Value::Text("/a".to_owned())),
+                (1, serde_cbor::Value::Integer(0)),
+                (2, serde_cbor::Value::Integer(1_461_736_377)),
+                (7, serde_cbor::Value::Integer(0o644)),
+            ]
+            .into_iter()
+            .collect::<BTreeMap<u64, serde_cbor::Value>>()])
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn unknown_json_fields_are_kept() {
+        let json = r#"[{"apath":"/a","kind":"File","mtime":1461736377,"blake3":"abcd","flags":{"x":[1,2]}},
+            {"apath":"/b","kind":"Fifo"}]"#;
+        let entries = IndexFormat::Json
+            .deserialize(json.as_bytes(), "hunk")
+            .unwrap();
+        assert_eq!(entries[0].apath, "/a");
+        assert_eq!(
+            entries[0].unknown_fields.names().collect::<Vec<_>>(),
+            ["blake3", "flags"]
+        );
+        assert_eq!(entries[1].kind, Kind::Unknown);
+        assert_eq!(entries[1].unknown_fields.kind_name(), Some("Fifo"));
+        assert_eq!(entries[1].mtime, 0);
+
+        let mut buf = Vec::new();
+        IndexFormat::Json
+            .serialize_into(&entries, &mut buf)
+            .unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(written[0]["blake3"], "abcd");
+        assert_eq!(written[0]["flags"]["x"][1], 2);
+        assert_eq!(written[1]["kind"], "Fifo");
+        assert_eq!(
+            IndexFormat::Json.deserialize(&buf, "hunk").unwrap(),
+            entries
+        );
+        // Unknown fields aren't written into the other format.
+        IndexFormat::Cbor
+            .serialize_into(&entries[..1], &mut buf)
+            .unwrap();
+        let cbor_entries = IndexFormat::Cbor.deserialize(&buf, "hunk").unwrap();
+        assert!(cbor_entries[0].unknown_fields.is_empty());
+    }
+
+    #[test]
+    fn unknown_cbor_fields_are_kept() {
+        let entry: BTreeMap<u64, serde_cbor::Value> = vec![
+            (0, serde_cbor::Value::Text("/a".to_owned())),
+            (1, serde_cbor::Value::Integer(7)),
+            (12, serde_cbor::Value::Bytes(vec![1, 2, 3])),
+        ]
+        .into_iter()
+        .collect();
+        let bytes = serde_cbor::to_vec(&vec![entry]).unwrap();
+        let entries = IndexFormat::Cbor.deserialize(&bytes, "hunk").unwrap();
+        assert_eq!(entries[0].kind, Kind::Unknown);
+        assert_eq!(entries[0].unknown_fields.kind_number(), Some(7));
+        assert_eq!(
+            entries[0].unknown_fields.numbers().collect::<Vec<_>>(),
+            [12]
+        );
+
+        let mut buf = Vec::new();
+        IndexFormat::Cbor
+            .serialize_into(&entries, &mut buf)
+            .unwrap();
+        assert_eq!(
+            IndexFormat::Cbor.deserialize(&buf, "hunk").unwrap(),
+            entries
+        );
+    }
+
+    #[test]
+    fn entry_without_apath_is_refused() {
+        let json = r#"[{"kind":"File","mtime":1461736377}]"#;
+        assert!(matches!(
+            IndexFormat::Json.deserialize(json.as_bytes(), "hunk"),
+            Err(Error::DeserializeIndex { .. })
+        ));
+    }
 }
@@ -13,10 +13,20 @@
 
 //! IO utilities.
 
-use std::fs;
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How many times to retry an operation that failed because no more files
+/// could be opened.
+const OUT_OF_HANDLES_RETRIES: usize = 5;
+
+/// Delay before the first retry when out of file handles, doubling for each
+/// later retry.
+const OUT_OF_HANDLES_DELAY: Duration = Duration::from_millis(10);
 
 pub(crate) fn ensure_dir_exists(path: &Path) -> std::io::Result<()> {
     fs::create_dir(path).or_else(|e| {
@@ -33,15 +43,16 @@ pub(crate) fn directory_is_empty(path: &Path) -> std::io::Result<bool> {
     Ok(std::fs::read_dir(path)?.next().is_none())
 }
 
-/// Read up to `len` bytes into a buffer, and resize the vec to the bytes read.
+/// Read into a buffer until it holds `len` bytes or the source is exhausted,
+/// keeping what it already holds, and resize the vec to the bytes read.
 pub(crate) fn read_with_retries(
     buf: &mut Vec<u8>,
     len: usize,
     from_file: &mut dyn Read,
 ) -> std::io::Result<()> {
     // TODO: This could safely resize the buf without initializing, since it will be overwritten.
+    let mut bytes_read = buf.len();
     buf.resize(len, 0);
-    let mut bytes_read = 0;
     while bytes_read < len {
         let read_len = from_file.read(&mut buf[bytes_read..])?;
         if read_len == 0 {
@@ -52,3 +63,112 @@ pub(crate) fn read_with_retries(
     buf.truncate(bytes_read);
     Ok(())
 }
+
+/// Create a new file with a unique name starting with `prefix` in `dir`,
+/// returning the file and its path.
+///
+/// The file is created exclusively, so an existing file is never
+/// overwritten. Opening it is retried if the process is out of file handles;
+/// the error is returned as it came from the OS, so that case can be seen.
+pub(crate) fn create_temp_file_in(dir: &Path, prefix: &str) -> io::Result<(File, PathBuf)> {
+    loop {
+        let mut random = [0u8; 8];
+        getrandom::getrandom(&mut random)?;
+        let path = dir.join(format!("{}{}", prefix, hex::encode(random)));
+        match retry_out_of_handles(|| OpenOptions::new().write(true).create_new(true).open(&path)) {
+            Ok(file) => return Ok((file, path)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// True if the error means no more files can be opened until some are closed:
+/// `EMFILE` or `ENFILE` on Unix, or `ERROR_TOO_MANY_OPEN_FILES` on Windows.
+pub(crate) fn is_out_of_file_handles(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+    }
+    #[cfg(windows)]
+    {
+        const ERROR_TOO_MANY_OPEN_FILES: i32 = 4;
+        err.raw_os_error() == Some(ERROR_TOO_MANY_OPEN_FILES)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+/// Run `f`, which opens a file, retrying after a short and increasing delay
+/// if it fails because the process or system is out of file handles, in the
+/// hope that other threads or processes close some.
+pub(crate) fn retry_out_of_handles<T, F>(mut f: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut delay = OUT_OF_HANDLES_DELAY;
+    for _ in 0..OUT_OF_HANDLES_RETRIES {
+        match f() {
+            Err(err) if is_out_of_file_handles(&err) => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    f()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_out_of_handles_until_success() {
+        let mut attempts = 0;
+        let result = retry_out_of_handles(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(out_of_handles_error())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_out_of_handles(|| {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn retries_are_limited() {
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_out_of_handles(|| {
+            attempts += 1;
+            Err(out_of_handles_error())
+        });
+        assert!(is_out_of_file_handles(&result.unwrap_err()));
+        assert_eq!(attempts, OUT_OF_HANDLES_RETRIES + 1);
+    }
+
+    #[cfg(unix)]
+    fn out_of_handles_error() -> io::Error {
+        io::Error::from_raw_os_error(libc::EMFILE)
+    }
+
+    #[cfg(windows)]
+    fn out_of_handles_error() -> io::Error {
+        io::Error::from_raw_os_error(4)
+    }
+}
@@ -11,6 +11,21 @@
 // GNU General Public License for more details.
 
 //! Conserve backup system.
+//!
+//! ## Stable API
+//!
+//! Most of this library is still changing along with the command-line tool,
+//! but the following are supported for programs that read archives, and
+//! are covered by `tests/api.rs`:
+//!
+//! * [Archive::open_path] and [Archive::band_ids].
+//! * [Archive::open_stored_tree], selecting a version by [BandSelectionPolicy].
+//! * [StoredTree::iter_entries], yielding [IndexEntry] values, optionally
+//!   filtered by an [Exclude] from [excludes::from_strings].
+//! * The [Entry] accessors on `IndexEntry` ([Entry::apath], [Entry::kind],
+//!   [Entry::size], [Entry::mtime], [Entry::symlink_target]) and
+//!   [IndexEntry::addrs].
+//! * Reading file contents through [ReadTree::file_contents].
 
 // Conserve implementation modules.
 pub mod apath;
@@ -20,57 +35,99 @@ mod band;
 pub mod bandid;
 mod blockdir;
 pub mod blockhash;
+pub mod cancel;
+pub mod chunking;
 pub mod compress;
+pub mod config;
 pub mod copy_tree;
+pub mod crypt;
 mod diff;
 mod entry;
+pub mod entry_filter;
 pub mod errors;
 pub mod excludes;
+pub mod export_tar;
 mod gc_lock;
+pub mod import_tar;
 pub mod index;
 mod io;
 mod jsonio;
 pub mod kind;
 pub mod live_tree;
+pub mod lock;
 mod merge;
+pub mod migrate;
 pub(crate) mod misc;
+#[cfg(all(unix, feature = "fuse"))]
+pub mod mount;
+pub mod names;
+mod nofollow;
 pub mod output;
+pub mod preflight;
 mod progress;
+pub mod referenced_blocks;
 pub mod restore;
 pub mod stats;
 mod stitch;
 mod stored_file;
 mod stored_tree;
+pub mod sync;
 pub mod test_fixtures;
+pub mod timespec;
 pub mod transport;
 mod tree;
 pub mod ui;
 pub mod unix_time;
+pub mod xattrs;
 
 pub use crate::apath::Apath;
 pub use crate::archive::Archive;
 pub use crate::archive::DeleteOptions;
-pub use crate::backup::{backup, BackupOptions};
-pub use crate::band::Band;
+pub use crate::archive::InitOptions;
+pub use crate::archive::ValidateOptions;
+#[allow(deprecated)]
+pub use crate::backup::{backup, BackupMonitor, BackupOptions, DryRun};
 pub use crate::band::BandSelectionPolicy;
+pub use crate::band::{Band, BandOptions, BandTotals};
 pub use crate::bandid::BandId;
-pub use crate::blockdir::BlockDir;
+pub use crate::blockdir::{Address, BlockDir};
 pub use crate::blockhash::BlockHash;
-pub use crate::diff::{diff, DiffOptions};
-pub use crate::entry::Entry;
-pub use crate::errors::Error;
+pub use crate::cancel::CancellationToken;
+pub use crate::chunking::Chunking;
+pub use crate::config::ArchiveConfig;
+pub use crate::crypt::Secret;
+pub use crate::diff::{diff, diff_trees, iter_diff, DiffEntry, DiffKind, DiffOptions};
+pub use crate::entry::{Entry, FileId};
+pub use crate::entry_filter::EntryFilter;
+pub use crate::errors::{
+    Error, ErrorCategory, Problem, ProblemKind, ProblemSink, Problems, Severity,
+};
+pub use crate::excludes::Exclude;
+pub use crate::export_tar::{export_tar, ExportTarOptions, TarWriteTree};
 pub use crate::gc_lock::GarbageCollectionLock;
-pub use crate::index::{IndexEntry, IndexRead, IndexWriter};
+pub use crate::import_tar::import_tar;
+pub use crate::index::{IndexEntry, IndexFormat, IndexRead, IndexWriter, UnknownFields};
 pub use crate::kind::Kind;
-pub use crate::live_tree::{LiveEntry, LiveTree};
+pub use crate::live_tree::{LiveEntry, LiveTree, SourceRoot};
+pub use crate::lock::ArchiveLock;
 pub use crate::merge::{MergeTrees, MergedEntryKind};
-pub use crate::misc::bytes_to_human_mb;
+pub use crate::migrate::{migrate, MigrateOptions};
+pub use crate::misc::{bytes_to_human, bytes_to_human_mb};
+pub use crate::preflight::preflight;
 pub use crate::progress::ProgressBar;
-pub use crate::restore::{restore, RestoreOptions, RestoreTree};
-pub use crate::stats::{BackupStats, CopyStats, DeleteStats, ValidateStats};
+pub use crate::referenced_blocks::ReferencedBlocks;
+pub use crate::restore::{restore, restore_into, RestoreOptions, RestoreTree};
+pub use crate::stats::{
+    ArchiveSummary, BackupStats, BandUsage, BlockDirStats, BlockReport, CopyStats, DeleteStats,
+    MigrateStats, PreflightReport, SyncStats, ValidateStats,
+};
+pub use crate::stored_file::ReadStoredFile;
 pub use crate::stored_tree::StoredTree;
+pub use crate::sync::{sync, SyncOptions};
+pub use crate::timespec::parse_timestamp;
 pub use crate::transport::Transport;
 pub use crate::tree::{ReadBlocks, ReadTree, TreeSize, WriteTree};
+pub use crate::xattrs::Xattrs;
 
 // Commonly-used external types.
 pub use globset::GlobSet;
@@ -88,6 +145,12 @@ pub fn version() -> &'static str {
 /// (This might be older than the program version.)
 pub const ARCHIVE_VERSION: &str = "0.6";
 
+/// Oldest archive format version that can be read and written by this program.
+///
+/// Archives between this and `ARCHIVE_VERSION` are opened normally, and their
+/// header can be upgraded by `conserve migrate`.
+pub const MIN_ARCHIVE_VERSION: &str = "0.6";
+
 pub const SYMLINKS_SUPPORTED: bool = cfg!(target_family = "unix");
 
 /// Break blocks at this many uncompressed bytes.
@@ -111,5 +174,13 @@ static BAND_HEAD_FILENAME: &str = "BANDHEAD";
 /// Metadata file in the band directory, for closed bands.
 static BAND_TAIL_FILENAME: &str = "BANDTAIL";
 
+/// Metadata file in the band directory, recording the stats of the backup
+/// that wrote it.
+static BAND_STATS_FILENAME: &str = "stats.json";
+
+/// Cache file in the band directory, recording how much block storage the
+/// band uses alone and shares with other bands.
+static BAND_USAGE_FILENAME: &str = "usage.json";
+
 /// Length of the binary content hash.
 pub(crate) const BLAKE_HASH_SIZE_BYTES: usize = 64;
@@ -14,34 +14,306 @@
 //! Find source files within a source directory, in apath order.
 
 use std::collections::vec_deque::VecDeque;
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::ErrorKind;
-use std::path::{Path, PathBuf};
-
-use globset::GlobSet;
+use std::iter::Peekable;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
+use crate::io::retry_out_of_handles;
 use crate::kind::Kind;
 use crate::stats::LiveTreeIterStats;
 use crate::unix_time::UnixTime;
+use crate::xattrs::{read_xattrs, ReadXattrs};
 use crate::Result;
 use crate::*;
 
 /// A real tree on the filesystem, for use as a backup source or restore destination.
+///
+/// The tree is usually one directory, but can be several source directories
+/// each mapped under its own apath, with the directories above them filled
+/// in, so that they're backed up as one tree.
 #[derive(Clone)]
 pub struct LiveTree {
+    /// Source directories, in apath order, none of them inside another.
+    roots: Vec<SourceRoot>,
+
+    /// Skip the contents of directories containing a valid `CACHEDIR.TAG`.
+    exclude_caches: bool,
+
+    /// Don't descend into directories on other filesystems.
+    one_file_system: bool,
+
+    /// Skip the contents of directories holding a Conserve archive.
+    exclude_other_archives: bool,
+
+    /// Skip the contents of these directories, such as the archive being
+    /// written.
+    excluded_dirs: Vec<Apath>,
+
+    /// Told about problems reading the tree, or by default the UI's problem
+    /// sink.
+    problems: Option<Arc<dyn ProblemSink>>,
+}
+
+/// Name of the file marking a cache directory, from
+/// <https://bford.info/cachedir/>.
+const CACHEDIR_TAG_FILENAME: &str = "CACHEDIR.TAG";
+
+/// Required first bytes of a valid `CACHEDIR.TAG`.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// A source directory, and the apath under which its contents appear in a
+/// live tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceRoot {
+    apath: Apath,
     path: PathBuf,
 }
 
+impl SourceRoot {
+    /// Map a source directory under an explicit apath.
+    pub fn new<P: AsRef<Path>>(path: P, apath: Apath) -> SourceRoot {
+        SourceRoot {
+            apath,
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Map a source directory under the apath spelled like its absolute path,
+    /// so that `/var/lib/postgresql` is stored as `/var/lib/postgresql`.
+    ///
+    /// On Windows the drive is dropped from the apath.
+    pub fn at_absolute_path<P: AsRef<Path>>(path: P) -> Result<SourceRoot> {
+        let path = path.as_ref();
+        let unmappable = || Error::UnmappableSourcePath {
+            path: path.to_owned(),
+        };
+        let absolute = fs::canonicalize(path).map_err(|source| Error::ListSourceTree {
+            path: path.to_owned(),
+            source,
+        })?;
+        let mut apath = String::new();
+        for component in absolute.components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir => (),
+                Component::Normal(name) => {
+                    apath.push('/');
+                    apath.push_str(&names::escape_os_str(name).ok_or_else(unmappable)?);
+                }
+                Component::CurDir | Component::ParentDir => return Err(unmappable()),
+            }
+        }
+        if apath.is_empty() {
+            apath.push('/');
+        }
+        if !Apath::is_valid(&apath) {
+            return Err(unmappable());
+        }
+        Ok(SourceRoot {
+            apath: apath.into(),
+            path: absolute,
+        })
+    }
+
+    pub fn apath(&self) -> &Apath {
+        &self.apath
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The filesystem path of an apath within this root.
+    fn path_of(&self, apath: &Apath) -> PathBuf {
+        debug_assert!(self.apath.is_prefix_of(apath));
+        let rest = apath[self.apath.len()..].trim_start_matches('/');
+        let mut path = self.path.clone();
+        if !rest.is_empty() {
+            path.push(names::to_os_str(rest));
+        }
+        path
+    }
+}
+
 impl LiveTree {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<LiveTree> {
         // TODO: Maybe fail here if the root doesn't exist or isn't a directory?
         Ok(LiveTree {
-            path: path.as_ref().to_path_buf(),
+            roots: vec![SourceRoot::new(path, "/".into())],
+            exclude_caches: false,
+            one_file_system: false,
+            exclude_other_archives: false,
+            excluded_dirs: Vec::new(),
+            problems: None,
         })
     }
 
+    /// Open several source directories as one tree.
+    ///
+    /// Each root's contents appear under its apath, and the directories above
+    /// the roots are included with the metadata of the directories the same
+    /// number of levels above the source on disk, or of the root itself if
+    /// there are none. Roots that are the same as, or inside, another root
+    /// are an error.
+    pub fn open_roots(mut roots: Vec<SourceRoot>) -> Result<LiveTree> {
+        roots.sort_by(|a, b| a.apath.cmp(&b.apath));
+        for (i, first) in roots.iter().enumerate() {
+            for second in &roots[i + 1..] {
+                if first.apath.is_prefix_of(&second.apath) {
+                    return Err(Error::OverlappingSourceRoots {
+                        first: first.apath.clone(),
+                        second: second.apath.clone(),
+                    });
+                }
+            }
+        }
+        Ok(LiveTree {
+            roots,
+            exclude_caches: false,
+            one_file_system: false,
+            exclude_other_archives: false,
+            excluded_dirs: Vec::new(),
+            problems: None,
+        })
+    }
+
+    /// The source directories in this tree, in apath order.
+    pub fn roots(&self) -> &[SourceRoot] {
+        &self.roots
+    }
+
+    /// Set whether to skip the contents of cache directories, which are marked by
+    /// a `CACHEDIR.TAG` file.
+    ///
+    /// The cache directory itself is still included.
+    pub fn with_exclude_caches(mut self, exclude_caches: bool) -> LiveTree {
+        self.exclude_caches = exclude_caches;
+        self
+    }
+
+    /// Set whether to skip the contents of directories on a different
+    /// filesystem from the root of the tree, such as mount points.
+    ///
+    /// The mount point directory itself is still included. This has no effect
+    /// on platforms other than Unix.
+    pub fn with_one_file_system(mut self, one_file_system: bool) -> LiveTree {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Set whether to skip the contents of directories that hold a Conserve
+    /// archive, recognized by its header file.
+    ///
+    /// The archive directory itself is still included.
+    pub fn with_exclude_other_archives(mut self, exclude_other_archives: bool) -> LiveTree {
+        self.exclude_other_archives = exclude_other_archives;
+        self
+    }
+
+    /// Skip the contents of the directory with this apath, keeping the
+    /// directory itself.
+    pub(crate) fn exclude_dir(&mut self, apath: Apath) {
+        self.excluded_dirs.push(apath);
+    }
+
+    /// Find the apath of a filesystem path inside one of the roots, or None
+    /// if it's not inside the tree.
+    ///
+    /// Both the path and the roots are canonicalized, so that the comparison
+    /// isn't fooled by symlinks or relative paths.
+    pub(crate) fn apath_of_path(&self, path: &Path) -> Option<Apath> {
+        let path = fs::canonicalize(path).ok()?;
+        for root in &self.roots {
+            let root_path = match fs::canonicalize(&root.path) {
+                Ok(root_path) => root_path,
+                Err(_) => continue,
+            };
+            if let Ok(rest) = path.strip_prefix(&root_path) {
+                let mut apath = root.apath.to_string();
+                for component in rest.components() {
+                    if !apath.ends_with('/') {
+                        apath.push('/');
+                    }
+                    apath.push_str(&names::escape_os_str(component.as_os_str())?);
+                }
+                return Some(apath.into());
+            }
+        }
+        None
+    }
+
+    /// Set a sink to be told about problems reading the tree, such as
+    /// unreadable directories, which are otherwise skipped.
+    pub fn with_problems(mut self, problems: Arc<dyn ProblemSink>) -> LiveTree {
+        self.problems = Some(problems);
+        self
+    }
+
     fn relative_path(&self, apath: &Apath) -> PathBuf {
-        relative_path(&self.path, apath)
+        self.roots
+            .iter()
+            .find(|root| root.apath.is_prefix_of(apath))
+            .expect("apath is not inside any source root")
+            .path_of(apath)
+    }
+
+    /// Make entries for the directories above the roots, in apath order.
+    fn root_parent_entries(&self, problems: &dyn ProblemSink) -> Result<Vec<LiveEntry>> {
+        let mut parents: BTreeMap<Apath, &SourceRoot> = BTreeMap::new();
+        for root in &self.roots {
+            let components: Vec<&str> = root.apath.split('/').filter(|c| !c.is_empty()).collect();
+            for depth in 0..components.len() {
+                let apath: Apath = format!("/{}", components[..depth].join("/")).into();
+                parents.entry(apath).or_insert(root);
+            }
+        }
+        let mut entries = Vec::with_capacity(parents.len());
+        for (apath, root) in parents {
+            let levels = root.apath.split('/').filter(|c| !c.is_empty()).count()
+                - apath.split('/').filter(|c| !c.is_empty()).count();
+            let path = root
+                .path
+                .ancestors()
+                .nth(levels)
+                .filter(|path| !path.as_os_str().is_empty())
+                .unwrap_or(&root.path);
+            let metadata = fs::metadata(path).map_err(|source| Error::ListSourceTree {
+                path: path.to_owned(),
+                source,
+            })?;
+            entries.push(LiveEntry::from_fs_metadata(
+                apath, path, &metadata, None, problems,
+            ));
+        }
+        Ok(entries)
+    }
+
+    /// Stat a file again, after reading it, to see whether it changed since
+    /// `entry` was made.
+    ///
+    /// Returns None if the size and mtime are the same, or otherwise an
+    /// updated entry.
+    pub fn recheck_file(&self, entry: &LiveEntry) -> Result<Option<LiveEntry>> {
+        let path = self.relative_path(&entry.apath);
+        let metadata =
+            fs::symlink_metadata(&path).map_err(|source| Error::ReadSourceFile { path, source })?;
+        let mtime: UnixTime = metadata
+            .modified()
+            .expect("Failed to get file mtime")
+            .into();
+        let size = Some(metadata.len());
+        if mtime == entry.mtime && size == entry.size {
+            Ok(None)
+        } else {
+            Ok(Some(LiveEntry {
+                mtime,
+                size,
+                unix_mode: unix_mode(entry.kind, &metadata),
+                ..entry.clone()
+            }))
+        }
     }
 }
 
@@ -53,12 +325,11 @@ pub struct LiveEntry {
     mtime: UnixTime,
     size: Option<u64>,
     symlink_target: Option<String>,
-}
-
-fn relative_path(root: &Path, apath: &Apath) -> PathBuf {
-    let mut path = root.to_path_buf();
-    path.push(&apath[1..]);
-    path
+    xattrs: Xattrs,
+    /// The number of xattrs that couldn't be read.
+    unreadable_xattrs: usize,
+    unix_mode: Option<u32>,
+    file_id: Option<FileId>,
 }
 
 impl tree::ReadTree for LiveTree {
@@ -72,21 +343,56 @@ impl tree::ReadTree for LiveTree {
     /// child directories, visit them according to a sorted comparison by their UTF-8
     /// name.
     fn iter_entries(&self) -> Result<Box<dyn Iterator<Item = Self::Entry>>> {
-        Ok(Box::new(Iter::new(&self.path, None, None)?))
+        self.iter_filtered(None, None)
     }
 
     fn iter_filtered(
         &self,
         subtree: Option<Apath>,
-        excludes: Option<GlobSet>,
+        excludes: Option<Exclude>,
     ) -> Result<Box<dyn Iterator<Item = LiveEntry>>> {
-        Ok(Box::new(Iter::new(&self.path, subtree, excludes)?))
+        let problems = ui::problem_sink_or(&self.problems);
+        if let [root] = self.roots.as_slice() {
+            if root.apath == "/" {
+                return Ok(Box::new(Iter::new(
+                    root, subtree, excludes, self, problems,
+                )?));
+            }
+        }
+        let subtree = subtree.unwrap_or_else(|| "/".into());
+        let mut iters: Vec<Box<dyn Iterator<Item = LiveEntry>>> = vec![Box::new(
+            self.root_parent_entries(problems.as_ref())?
+                .into_iter()
+                .filter(|entry| subtree.is_prefix_of(&entry.apath))
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )];
+        for root in &self.roots {
+            let root_subtree = if subtree.is_prefix_of(&root.apath) {
+                None
+            } else if root.apath.is_prefix_of(&subtree) {
+                Some(subtree.clone())
+            } else {
+                continue;
+            };
+            iters.push(Box::new(Iter::new(
+                root,
+                root_subtree,
+                excludes.clone(),
+                self,
+                problems.clone(),
+            )?));
+        }
+        Ok(Box::new(MergeRoots {
+            iters: iters.into_iter().map(Iterator::peekable).collect(),
+        }))
     }
 
     fn file_contents(&self, entry: &LiveEntry) -> Result<Self::R> {
         assert_eq!(entry.kind(), Kind::File);
         let path = self.relative_path(&entry.apath);
-        fs::File::open(&path).map_err(|source| Error::ReadSourceFile { path, source })
+        retry_out_of_handles(|| fs::File::open(&path))
+            .map_err(|source| Error::ReadSourceFile { path, source })
     }
 
     fn estimate_count(&self) -> Result<u64> {
@@ -117,13 +423,27 @@ impl Entry for LiveEntry {
     fn symlink_target(&self) -> &Option<String> {
         &self.symlink_target
     }
+
+    fn xattrs(&self) -> Option<&Xattrs> {
+        Some(&self.xattrs).filter(|xattrs| !xattrs.is_empty())
+    }
+
+    fn unix_mode(&self) -> Option<u32> {
+        self.unix_mode
+    }
+
+    fn file_id(&self) -> Option<FileId> {
+        self.file_id
+    }
 }
 
 impl LiveEntry {
     fn from_fs_metadata(
         apath: Apath,
+        path: &Path,
         metadata: &fs::Metadata,
         symlink_target: Option<String>,
+        problems: &dyn ProblemSink,
     ) -> LiveEntry {
         // TODO: Could we read the symlink target here, rather than in the caller?
         let mtime = metadata
@@ -135,21 +455,129 @@ impl LiveEntry {
         } else {
             None
         };
+        let kind: Kind = metadata.file_type().into();
+        // Xattrs are only restored on files and directories, so don't bother
+        // reading them from anything else.
+        let ReadXattrs {
+            xattrs,
+            unreadable: unreadable_xattrs,
+        } = match kind {
+            Kind::File | Kind::Dir => read_xattrs(path, problems),
+            _ => ReadXattrs::default(),
+        };
         LiveEntry {
             apath,
-            kind: metadata.file_type().into(),
+            kind,
             mtime,
             symlink_target,
             size,
+            xattrs,
+            unreadable_xattrs,
+            unix_mode: unix_mode(kind, metadata),
+            file_id: file_id(kind, metadata),
         }
     }
+
+    /// The number of extended attributes on this file that exist but could
+    /// not be read, typically for lack of privilege.
+    pub fn unreadable_xattrs(&self) -> usize {
+        self.unreadable_xattrs
+    }
+}
+
+/// Return the permission bits of a file or directory.
+///
+/// Symlink permissions are not meaningful, so they're not recorded.
+#[cfg(unix)]
+fn unix_mode(kind: Kind, metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    match kind {
+        Kind::Symlink => None,
+        _ => Some(metadata.permissions().mode() & 0o7777),
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_kind: Kind, _metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Return the device and inode numbers of a file.
+///
+/// They're only used to recognize moved files, so aren't recorded for other
+/// kinds.
+#[cfg(unix)]
+fn file_id(kind: Kind, metadata: &fs::Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    match kind {
+        Kind::File => Some(FileId {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn file_id(_kind: Kind, _metadata: &fs::Metadata) -> Option<FileId> {
+    None
+}
+
+/// Return the device number of the filesystem holding a file.
+#[cfg(unix)]
+fn device(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// True if this directory contains a valid cache directory tag.
+fn is_cache_dir(dir_path: &Path) -> bool {
+    let mut buf = [0u8; CACHEDIR_TAG_SIGNATURE.len()];
+    fs::File::open(dir_path.join(CACHEDIR_TAG_FILENAME))
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut buf))
+        .map(|()| buf == CACHEDIR_TAG_SIGNATURE)
+        .unwrap_or(false)
+}
+
+/// True if this directory holds a Conserve archive.
+fn is_archive_dir(dir_path: &Path) -> bool {
+    dir_path.join(crate::archive::HEADER_FILENAME).is_file()
+}
+
+/// Merge entries from the roots of a live tree, each in apath order, into one
+/// iterator in apath order.
+struct MergeRoots {
+    iters: Vec<Peekable<Box<dyn Iterator<Item = LiveEntry>>>>,
+}
+
+impl Iterator for MergeRoots {
+    type Item = LiveEntry;
+
+    fn next(&mut self) -> Option<LiveEntry> {
+        // There are only a few roots, so just look at the next entry from each.
+        let mut first: Option<(usize, &Apath)> = None;
+        for (i, it) in self.iters.iter_mut().enumerate() {
+            if let Some(entry) = it.peek() {
+                if first.is_none_or(|(_, apath)| entry.apath < *apath) {
+                    first = Some((i, &entry.apath));
+                }
+            }
+        }
+        let i = first?.0;
+        self.iters[i].next()
+    }
 }
 
 /// Recursive iterator of the contents of a live tree.
 #[derive(Debug)]
 pub struct Iter {
     /// Root of the source tree.
-    root_path: PathBuf,
+    root: SourceRoot,
 
     /// Directories yet to be visited.
     dir_deque: VecDeque<Apath>,
@@ -162,35 +590,68 @@ pub struct Iter {
     check_order: apath::DebugCheckOrder,
 
     /// glob pattern to skip in iterator
-    excludes: Option<GlobSet>,
+    excludes: Option<Exclude>,
+
+    /// Skip the contents of cache directories.
+    exclude_caches: bool,
+
+    /// Skip the contents of directories holding an archive.
+    exclude_other_archives: bool,
+
+    /// Skip the contents of these directories.
+    excluded_dirs: Vec<Apath>,
+
+    /// If set, skip the contents of directories not on this device.
+    root_device: Option<u64>,
+
+    /// Told about directories and entries that can't be read.
+    problems: Arc<dyn ProblemSink>,
 
     stats: LiveTreeIterStats,
 }
 
 impl Iter {
     /// Construct a new iter that will visit everything below this root path,
-    /// subject to some exclusions
-    fn new(root_path: &Path, subtree: Option<Apath>, excludes: Option<GlobSet>) -> Result<Iter> {
-        let subtree = subtree.unwrap_or_else(|| "/".into());
-        let start_path = relative_path(root_path, &subtree);
+    /// subject to some exclusions, and other options from `tree`.
+    fn new(
+        root: &SourceRoot,
+        subtree: Option<Apath>,
+        excludes: Option<Exclude>,
+        tree: &LiveTree,
+        problems: Arc<dyn ProblemSink>,
+    ) -> Result<Iter> {
+        let subtree = subtree.unwrap_or_else(|| root.apath.clone());
+        let start_path = root.path_of(&subtree);
         let start_metadata = fs::symlink_metadata(&start_path).map_err(Error::from)?;
+        let root_device = if tree.one_file_system {
+            device(&start_metadata)
+        } else {
+            None
+        };
         // Preload iter to return the root and then recurse into it.
         let mut entry_deque = VecDeque::<LiveEntry>::new();
         entry_deque.push_back(LiveEntry::from_fs_metadata(
             subtree.clone(),
+            &start_path,
             &start_metadata,
             None,
+            problems.as_ref(),
         ));
         // TODO: Consider the case where the root is not actually a directory?
         // Should that be supported?
         let mut dir_deque = VecDeque::<Apath>::new();
         dir_deque.push_back(subtree);
         Ok(Iter {
-            root_path: root_path.to_path_buf(),
+            root: root.clone(),
             entry_deque,
             dir_deque,
             check_order: apath::DebugCheckOrder::new(),
             excludes,
+            exclude_caches: tree.exclude_caches,
+            exclude_other_archives: tree.exclude_other_archives,
+            excluded_dirs: tree.excluded_dirs.clone(),
+            root_device,
+            problems,
             stats: LiveTreeIterStats::default(),
         })
     }
@@ -208,11 +669,31 @@ impl Iter {
         // reverse order from which we pop would work well.
         self.stats.directories_visited += 1;
         let mut children = Vec::<(String, LiveEntry)>::new();
-        let dir_path = relative_path(&self.root_path, parent_apath);
-        let dir_iter = match fs::read_dir(&dir_path) {
+        let dir_path = self.root.path_of(parent_apath);
+        if (self.exclude_caches && is_cache_dir(&dir_path))
+            || (self.exclude_other_archives && is_archive_dir(&dir_path))
+            || self.excluded_dirs.contains(parent_apath)
+        {
+            self.stats.exclusions += 1;
+            return;
+        }
+        if let Some(root_device) = self.root_device {
+            if fs::symlink_metadata(&dir_path).map_or(None, |m| device(&m)) != Some(root_device) {
+                self.stats.exclusions += 1;
+                return;
+            }
+        }
+        // The directory is read completely and its handle closed before any
+        // children are visited, so the walk holds only one directory open
+        // however wide or deep the tree.
+        let dir_iter = match retry_out_of_handles(|| fs::read_dir(&dir_path)) {
             Ok(i) => i,
             Err(e) => {
-                ui::problem(&format!("Error reading directory {:?}: {}", &dir_path, e));
+                self.problems.report(Problem::new(
+                    ProblemKind::SourceTree,
+                    Some(parent_apath),
+                    format!("Error reading directory {:?}: {}", &dir_path, e),
+                ));
                 return;
             }
         };
@@ -220,9 +701,13 @@ impl Iter {
             let dir_entry = match dir_entry {
                 Ok(dir_entry) => dir_entry,
                 Err(e) => {
-                    ui::problem(&format!(
-                        "Error reading next entry from directory {:?}: {}",
-                        &dir_path, e
+                    self.problems.report(Problem::new(
+                        ProblemKind::SourceTree,
+                        Some(parent_apath),
+                        format!(
+                            "Error reading next entry from directory {:?}: {}",
+                            &dir_path, e
+                        ),
                     ));
                     continue;
                 }
@@ -233,30 +718,32 @@ impl Iter {
                 child_apath_str.push('/');
             }
             let child_osstr = &dir_entry.file_name();
-            let child_name = match child_osstr.to_str() {
+            let child_name = match names::escape_os_str(child_osstr) {
                 Some(c) => c,
                 None => {
-                    ui::problem(&format!(
-                        "Can't decode filename {:?} in {:?}",
-                        child_osstr, dir_path,
+                    self.problems.report(Problem::new(
+                        ProblemKind::SourceTree,
+                        Some(parent_apath),
+                        format!("Can't decode filename {:?} in {:?}", child_osstr, dir_path),
                     ));
                     continue;
                 }
             };
-            child_apath_str.push_str(child_name);
+            child_apath_str.push_str(&child_name);
             let ft = match dir_entry.file_type() {
                 Ok(ft) => ft,
                 Err(e) => {
-                    ui::problem(&format!(
-                        "Error getting type of {:?} during iteration: {}",
-                        child_apath_str, e
+                    self.problems.report(Problem::new(
+                        ProblemKind::SourceTree,
+                        Some(&child_apath_str.as_str().into()),
+                        format!("Error getting type during iteration: {}", e),
                     ));
                     continue;
                 }
             };
 
             if let Some(ref excludes) = self.excludes {
-                if excludes.is_match(&child_apath_str) {
+                if excludes.matches(&child_apath_str, Kind::from(ft)) {
                     self.stats.exclusions += 1;
                     continue;
                 }
@@ -268,15 +755,17 @@ impl Iter {
                         ErrorKind::NotFound => {
                             // Fairly harmless, and maybe not even worth logging. Just a race
                             // between listing the directory and looking at the contents.
-                            ui::problem(&format!(
-                                "File disappeared during iteration: {:?}: {}",
-                                child_apath_str, e
+                            self.problems.report(Problem::new(
+                                ProblemKind::SourceTree,
+                                Some(&child_apath_str.as_str().into()),
+                                format!("File disappeared during iteration: {}", e),
                             ));
                         }
                         _ => {
-                            ui::problem(&format!(
-                                "Failed to read source metadata from {:?}: {}",
-                                child_apath_str, e
+                            self.problems.report(Problem::new(
+                                ProblemKind::SourceTree,
+                                Some(&child_apath_str.as_str().into()),
+                                format!("Failed to read source metadata: {}", e),
                             ));
                             self.stats.metadata_error += 1;
                         }
@@ -291,19 +780,21 @@ impl Iter {
                 let t = match dir_path.join(dir_entry.file_name()).read_link() {
                     Ok(t) => t,
                     Err(e) => {
-                        ui::problem(&format!(
-                            "Failed to read target of symlink {:?}: {}",
-                            child_apath_str, e
+                        self.problems.report(Problem::new(
+                            ProblemKind::SourceTree,
+                            Some(&child_apath_str.as_str().into()),
+                            format!("Failed to read target of symlink: {}", e),
                         ));
                         continue;
                     }
                 };
-                match t.into_os_string().into_string() {
-                    Ok(t) => Some(t),
-                    Err(e) => {
-                        ui::problem(&format!(
-                            "Failed to decode target of symlink {:?}: {:?}",
-                            child_apath_str, e
+                match names::escape_os_str(t.as_os_str()) {
+                    Some(target) => Some(target.into_owned()),
+                    None => {
+                        self.problems.report(Problem::new(
+                            ProblemKind::SourceTree,
+                            Some(&child_apath_str.as_str().into()),
+                            format!("Failed to decode target of symlink: {:?}", t),
                         ));
                         continue;
                     }
@@ -312,8 +803,14 @@ impl Iter {
                 None
             };
             children.push((
-                child_name.to_string(),
-                LiveEntry::from_fs_metadata(child_apath_str.into(), &metadata, target),
+                child_name.into_owned(),
+                LiveEntry::from_fs_metadata(
+                    child_apath_str.into(),
+                    &dir_entry.path(),
+                    &metadata,
+                    target,
+                    self.problems.as_ref(),
+                ),
             ));
         }
         children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
@@ -370,7 +867,7 @@ mod tests {
     fn open_tree() {
         let tf = TreeFixture::new();
         let lt = LiveTree::open(tf.path()).unwrap();
-        assert_eq!(lt.path, tf.path());
+        assert_eq!(lt.roots, [SourceRoot::new(tf.path(), "/".into())]);
     }
 
     #[test]
@@ -396,7 +893,7 @@ mod tests {
         assert_eq!(result.len(), 7);
 
         let repr = format!("{:?}", &result[6]);
-        let re = Regex::new(r#"LiveEntry \{ apath: Apath\("/jam/apricot"\), kind: File, mtime: UnixTime \{ [^)]* \}, size: Some\(8\), symlink_target: None \}"#).unwrap();
+        let re = Regex::new(r#"LiveEntry \{ apath: Apath\("/jam/apricot"\), kind: File, mtime: UnixTime \{ [^)]* \}, size: Some\(8\), symlink_target: None, xattrs: \{\}, unreadable_xattrs: 0, unix_mode: (Some\(\d+\)|None), file_id: (Some\(FileId \{ dev: \d+, ino: \d+ \}\)|None) \}"#).unwrap();
         assert!(re.is_match(&repr));
 
         // TODO: Somehow get the stats out of the iterator.
@@ -433,6 +930,70 @@ mod tests {
         // assert_eq!(source_iter.stats.exclusions, 5);
     }
 
+    #[test]
+    fn exclude_caches() {
+        let tf = TreeFixture::new();
+        tf.create_file("a");
+        tf.create_dir("cache");
+        tf.create_file_with_contents(
+            "cache/CACHEDIR.TAG",
+            b"Signature: 8a477f597d28d172789f06886806bc55\n# a cache\n",
+        );
+        tf.create_file("cache/junk");
+        tf.create_dir("notcache");
+        tf.create_file_with_contents("notcache/CACHEDIR.TAG", b"not a real tag");
+
+        let names = |lt: LiveTree| -> Vec<String> {
+            lt.iter_entries()
+                .unwrap()
+                .map(|entry| entry.apath.into())
+                .collect()
+        };
+        let lt = LiveTree::open(tf.path()).unwrap();
+        assert_eq!(names(lt.clone()).len(), 7);
+        assert_eq!(
+            names(lt.with_exclude_caches(true)),
+            ["/", "/a", "/cache", "/notcache", "/notcache/CACHEDIR.TAG"]
+        );
+    }
+
+    #[test]
+    fn exclude_other_archives() {
+        let tf = TreeFixture::new();
+        tf.create_file("a");
+        Archive::create_path(&tf.path().join("arch")).unwrap();
+        tf.create_dir("notarch");
+        tf.create_dir("notarch/CONSERVE");
+
+        let names = |lt: LiveTree| -> Vec<String> {
+            lt.iter_entries()
+                .unwrap()
+                .map(|entry| entry.apath.into())
+                .collect()
+        };
+        let lt = LiveTree::open(tf.path()).unwrap();
+        assert!(names(lt.clone()).contains(&"/arch/CONSERVE".to_owned()));
+        assert_eq!(
+            names(lt.with_exclude_other_archives(true)),
+            ["/", "/a", "/arch", "/notarch", "/notarch/CONSERVE"]
+        );
+    }
+
+    #[test]
+    fn apath_of_path() {
+        let tf = TreeFixture::new();
+        tf.create_dir("sub");
+        tf.create_dir("sub/dir");
+        let lt = LiveTree::open(tf.path()).unwrap();
+        assert_eq!(
+            lt.apath_of_path(&tf.path().join("sub").join("..").join("sub").join("dir")),
+            Some("/sub/dir".into())
+        );
+        assert_eq!(lt.apath_of_path(tf.path()), Some("/".into()));
+        assert_eq!(lt.apath_of_path(&tf.path().join("missing")), None);
+        assert_eq!(lt.apath_of_path(&std::env::temp_dir()), None);
+    }
+
     #[cfg(unix)]
     #[test]
     fn symlinks() {
@@ -465,4 +1026,89 @@ mod tests {
 
         assert_eq!(names.as_slice(), ["/subdir", "/subdir/a", "/subdir/b"]);
     }
+
+    #[test]
+    fn merge_several_roots() {
+        let etc = TreeFixture::new();
+        etc.create_file("a");
+        etc.create_dir("sub");
+        etc.create_file("sub/x");
+        let pg = TreeFixture::new();
+        pg.create_file("b");
+
+        let lt = LiveTree::open_roots(vec![
+            SourceRoot::new(pg.path(), "/var/lib/pg".into()),
+            SourceRoot::new(etc.path(), "/etc".into()),
+        ])
+        .unwrap();
+        let names = |subtree: Option<Apath>| -> Vec<String> {
+            lt.iter_filtered(subtree, None)
+                .unwrap()
+                .map(|entry| entry.apath.into())
+                .collect()
+        };
+        assert_eq!(
+            names(None),
+            [
+                "/",
+                "/etc",
+                "/var",
+                "/etc/a",
+                "/etc/sub",
+                "/etc/sub/x",
+                "/var/lib",
+                "/var/lib/pg",
+                "/var/lib/pg/b",
+            ]
+        );
+        assert_eq!(
+            names(Some("/var".into())),
+            ["/var", "/var/lib", "/var/lib/pg", "/var/lib/pg/b"]
+        );
+        assert_eq!(names(Some("/etc/sub".into())), ["/etc/sub", "/etc/sub/x"]);
+
+        let entries: Vec<LiveEntry> = lt.iter_entries().unwrap().collect();
+        assert!(entries
+            .iter()
+            .take(3)
+            .all(|entry| entry.kind() == Kind::Dir));
+        let b = entries.last().unwrap();
+        assert_eq!(b.size(), Some(8));
+        assert!(lt.file_contents(b).is_ok());
+    }
+
+    #[test]
+    fn overlapping_roots_are_an_error() {
+        let tf = TreeFixture::new();
+        for (first, second) in &[("/var", "/var/lib"), ("/home", "/home"), ("/", "/etc")] {
+            match LiveTree::open_roots(vec![
+                SourceRoot::new(tf.path(), (*second).into()),
+                SourceRoot::new(tf.path(), (*first).into()),
+            ]) {
+                Err(Error::OverlappingSourceRoots {
+                    first: a,
+                    second: b,
+                }) => {
+                    assert_eq!(a, *first);
+                    assert_eq!(b, *second);
+                }
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("overlapping roots {} and {} were accepted", first, second),
+            }
+        }
+    }
+
+    #[test]
+    fn source_root_at_absolute_path() {
+        let tf = TreeFixture::new();
+        let root = SourceRoot::at_absolute_path(tf.path()).unwrap();
+        let absolute = tf.path().canonicalize().unwrap();
+        assert_eq!(root.path(), absolute);
+        assert!(root.apath().len() > 1);
+        assert!(absolute
+            .to_str()
+            .unwrap()
+            .replace('\\', "/")
+            .ends_with(root.apath().as_ref() as &str));
+    }
 }
@@ -0,0 +1,342 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! An `ArchiveLock` excludes other writers from an archive.
+//!
+//! Operations that change the archive (backup, delete, gc) take the lock
+//! before they start and release it when they're dropped. Operations that only
+//! read the archive don't need the lock.
+//!
+//! The lock is a `LOCK` file in the top of the archive, written through the
+//! Transport, containing the hostname, pid, and time it was taken. The file is
+//! created only if it doesn't already exist, so of several processes racing to
+//! take the lock, only one succeeds. Because it's just a file written through the
+//! Transport it works the same way for local and remote archives, but it is only
+//! advisory: a process that doesn't check the lock is not stopped.
+//!
+//! A lock that has not been refreshed for `STALE_LOCK_AGE` is assumed to have been
+//! left behind by a process that died, and is replaced with a warning. Long-running
+//! writers should call `ArchiveLock::refresh` occasionally to keep their lock fresh.
+//! Any lock can be explicitly broken with `ArchiveLock::break_lock`.
+//!
+//! When a lock is dropped, the file is removed only if it still names this
+//! process, so that a lock broken and retaken by another process is left alone.
+
+use std::io;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::jsonio::{read_json, write_json};
+use crate::*;
+
+pub(crate) const LOCK_FILENAME: &str = "LOCK";
+
+/// Locks older than this are assumed to be left over from a process that died.
+pub const STALE_LOCK_AGE: Duration = Duration::from_secs(12 * 3600);
+
+/// `ArchiveLock::refresh` rewrites the lock only if it's older than this.
+const LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Description of the process holding a lock, as stored in the lock file.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LockHolder {
+    /// Name of the host where the lock was taken.
+    pub hostname: String,
+
+    /// Process id on that host.
+    pub pid: u32,
+
+    /// Seconds since the Unix epoch when the lock was taken or last refreshed.
+    pub acquired_time: i64,
+}
+
+impl LockHolder {
+    fn current() -> LockHolder {
+        LockHolder {
+            hostname: hostname::get()
+                .map(|h| h.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            pid: std::process::id(),
+            acquired_time: Utc::now().timestamp(),
+        }
+    }
+
+    /// True if this lock is old enough that it's assumed to be abandoned.
+    pub fn is_stale(&self) -> bool {
+        Utc::now().timestamp() - self.acquired_time > STALE_LOCK_AGE.as_secs() as i64
+    }
+}
+
+/// Exclusive lock on an archive for writing.
+///
+/// The lock is released when the object is dropped.
+#[derive(Debug)]
+pub struct ArchiveLock {
+    /// Transport for the archive directory.
+    transport: Box<dyn Transport>,
+    holder: LockHolder,
+}
+
+impl ArchiveLock {
+    /// Lock this archive for writing.
+    ///
+    /// Returns `Err(Error::ArchiveLocked)` if some other process holds a lock
+    /// that isn't stale.
+    pub fn acquire(archive: &Archive) -> Result<ArchiveLock> {
+        let transport = archive.transport().box_clone();
+        let holder = LockHolder::current();
+        if create_lock_file(transport.as_ref(), &holder)? {
+            return Ok(ArchiveLock { transport, holder });
+        }
+        match ArchiveLock::holder(archive)? {
+            Some(existing) if existing.is_stale() => {
+                ui::report_problem(Problem::new(
+                    ProblemKind::StaleLock,
+                    None,
+                    format!(
+                        "Replacing stale lock held by process {} on {:?}",
+                        existing.pid, existing.hostname
+                    ),
+                ));
+                match transport.remove_file(LOCK_FILENAME) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => (),
+                }
+            }
+            Some(existing) => {
+                return Err(Error::ArchiveLocked {
+                    hostname: existing.hostname,
+                    pid: existing.pid,
+                })
+            }
+            // Released since we tried to create it.
+            None => (),
+        }
+        if create_lock_file(transport.as_ref(), &holder)? {
+            return Ok(ArchiveLock { transport, holder });
+        }
+        // Another process took the lock in the meantime.
+        let existing = ArchiveLock::holder(archive)?;
+        Err(Error::ArchiveLocked {
+            hostname: existing
+                .as_ref()
+                .map(|h| h.hostname.clone())
+                .unwrap_or_default(),
+            pid: existing.map_or(0, |h| h.pid),
+        })
+    }
+
+    /// Take a lock on an archive, breaking any existing lock.
+    ///
+    /// Use this only if you're confident that the process owning the lock
+    /// has terminated.
+    pub fn break_lock(archive: &Archive) -> Result<ArchiveLock> {
+        ArchiveLock::remove(archive)?;
+        ArchiveLock::acquire(archive)
+    }
+
+    /// Remove any lock on the archive, without taking a new one.
+    pub fn remove(archive: &Archive) -> Result<()> {
+        if ArchiveLock::is_locked(archive)? {
+            archive.transport().remove_file(LOCK_FILENAME)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the archive is currently locked, whether or not the lock is stale.
+    pub fn is_locked(archive: &Archive) -> Result<bool> {
+        archive
+            .transport()
+            .exists(LOCK_FILENAME)
+            .map_err(Error::from)
+    }
+
+    /// Return a description of the process holding the lock, if the archive is locked.
+    pub fn holder(archive: &Archive) -> Result<Option<LockHolder>> {
+        if ArchiveLock::is_locked(archive)? {
+            read_json(&archive.transport().box_clone(), LOCK_FILENAME).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Update the time in the lock, so that it's not considered stale while
+    /// a long operation continues.
+    ///
+    /// This is cheap to call often: the lock file is only rewritten if it's
+    /// more than a few minutes old.
+    pub fn refresh(&mut self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        if now - self.holder.acquired_time < LOCK_REFRESH_INTERVAL.as_secs() as i64 {
+            return Ok(());
+        }
+        self.holder.acquired_time = now;
+        write_json(&self.transport, LOCK_FILENAME, &self.holder)
+    }
+}
+
+impl Drop for ArchiveLock {
+    fn drop(&mut self) {
+        let problem = match read_json::<LockHolder, _>(&self.transport, LOCK_FILENAME) {
+            Ok(current)
+                if current.hostname == self.holder.hostname && current.pid == self.holder.pid =>
+            {
+                match self.transport.remove_file(LOCK_FILENAME) {
+                    Ok(()) => return,
+                    Err(err) => format!("Failed to delete {}: {}", LOCK_FILENAME, err),
+                }
+            }
+            Ok(current) => format!(
+                "Lock was broken and taken by process {} on {:?}; leaving it in place",
+                current.pid, current.hostname
+            ),
+            Err(err) => format!("Failed to read {} to release it: {}", LOCK_FILENAME, err),
+        };
+        // The UI may be in a bad state while unwinding from a panic, and the
+        // panic will be reported anyhow.
+        if !std::thread::panicking() {
+            ui::report_problem(Problem::new(ProblemKind::Lock, None, problem));
+        }
+    }
+}
+
+/// Create the lock file naming `holder`, or return false if the archive is
+/// already locked.
+fn create_lock_file(transport: &dyn Transport, holder: &LockHolder) -> Result<bool> {
+    let mut json = serde_json::to_string(holder).map_err(|source| Error::SerializeJson {
+        path: LOCK_FILENAME.to_owned(),
+        source,
+    })?;
+    json.push('\n');
+    match transport.write_new_file(LOCK_FILENAME, json.as_bytes()) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(source) => Err(Error::WriteMetadata {
+            path: LOCK_FILENAME.to_owned(),
+            source,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn lock_removed_when_dropped() {
+        let archive = ScratchArchive::new();
+        let lock = ArchiveLock::acquire(&archive).unwrap();
+        assert!(archive.transport().exists(LOCK_FILENAME).unwrap());
+        let holder = ArchiveLock::holder(&archive).unwrap().unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert!(!holder.is_stale());
+        drop(lock);
+        assert!(!archive.transport().exists(LOCK_FILENAME).unwrap());
+    }
+
+    #[test]
+    fn second_lock_denied() {
+        let archive = ScratchArchive::new();
+        let _lock1 = ArchiveLock::acquire(&archive).unwrap();
+        match ArchiveLock::acquire(&archive) {
+            Err(Error::ArchiveLocked { pid, .. }) => assert_eq!(pid, std::process::id()),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn backup_while_locked_fails() {
+        let archive = ScratchArchive::new();
+        let source = TreeFixture::new();
+        let _lock = ArchiveLock::acquire(&archive).unwrap();
+        let result = archive.backup(source.path(), &BackupOptions::default());
+        assert!(matches!(result, Err(Error::ArchiveLocked { .. })));
+        assert!(archive.band_ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lock_removed_after_backup() {
+        let archive = ScratchArchive::new();
+        let source = TreeFixture::new();
+        archive
+            .backup(source.path(), &BackupOptions::default())
+            .unwrap();
+        assert!(!ArchiveLock::is_locked(&archive).unwrap());
+    }
+
+    #[test]
+    fn stale_lock_replaced() {
+        let archive = ScratchArchive::new();
+        let stale = LockHolder {
+            hostname: "elsewhere".to_owned(),
+            pid: 1234,
+            acquired_time: Utc::now().timestamp() - STALE_LOCK_AGE.as_secs() as i64 - 60,
+        };
+        write_json(&archive.transport().box_clone(), LOCK_FILENAME, &stale).unwrap();
+        let lock = ArchiveLock::acquire(&archive).unwrap();
+        assert_eq!(
+            ArchiveLock::holder(&archive).unwrap().unwrap().pid,
+            std::process::id()
+        );
+        drop(lock);
+    }
+
+    #[test]
+    fn only_one_concurrent_lock() {
+        let archive = ScratchArchive::new();
+        let barrier = std::sync::Barrier::new(8);
+        let locks: Vec<Result<ArchiveLock>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        ArchiveLock::acquire(&archive)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(locks.iter().filter(|lock| lock.is_ok()).count(), 1);
+        assert!(locks
+            .iter()
+            .filter_map(|lock| lock.as_ref().err())
+            .all(|err| matches!(err, Error::ArchiveLocked { .. })));
+    }
+
+    #[test]
+    fn lock_taken_by_another_process_is_not_removed() {
+        let archive = ScratchArchive::new();
+        let lock = ArchiveLock::acquire(&archive).unwrap();
+        let other = LockHolder {
+            hostname: "elsewhere".to_owned(),
+            pid: 1234,
+            acquired_time: Utc::now().timestamp(),
+        };
+        write_json(&archive.transport().box_clone(), LOCK_FILENAME, &other).unwrap();
+        drop(lock);
+        assert_eq!(ArchiveLock::holder(&archive).unwrap(), Some(other));
+    }
+
+    #[test]
+    fn break_lock() {
+        let archive = ScratchArchive::new();
+        let lock1 = ArchiveLock::acquire(&archive).unwrap();
+        // Pretend the process owning lock1 died, and get a new lock.
+        std::mem::forget(lock1);
+        assert!(ArchiveLock::acquire(&archive).is_err());
+        let _lock2 = ArchiveLock::break_lock(&archive).unwrap();
+    }
+}
@@ -30,11 +30,14 @@ pub enum MergedEntryKind {
 use self::MergedEntryKind::*;
 
 #[derive(Debug, PartialEq, Eq)]
-pub struct MergedEntry {
+pub struct MergedEntry<AE, BE> {
     // TODO: Add accessors rather than making these public?
-    // TODO: Include the original entries from either side?
     pub apath: Apath,
     pub kind: MergedEntryKind,
+    /// The entry from the left tree, if present there.
+    pub left: Option<AE>,
+    /// The entry from the right tree, if present there.
+    pub right: Option<BE>,
 }
 
 /// Zip together entries from two trees, into an iterator of MergedEntryKind.
@@ -77,7 +80,7 @@ where
     AE: Entry,
     BE: Entry,
 {
-    type Item = MergedEntry;
+    type Item = MergedEntry<AE, BE>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // TODO: Stats about the merge.
@@ -102,39 +105,40 @@ where
                 Some(MergedEntry {
                     apath: tb.apath().clone(),
                     kind: RightOnly,
+                    left: None,
+                    right: Some(tb),
                 })
             }
         } else if self.nb.is_none() {
+            let ta = self.na.take().unwrap();
             Some(MergedEntry {
-                apath: self.na.take().unwrap().apath().clone(),
+                apath: ta.apath().clone(),
                 kind: LeftOnly,
+                left: Some(ta),
+                right: None,
             })
         } else {
             let pa = self.na.as_ref().unwrap().apath().clone();
             let pb = self.nb.as_ref().unwrap().apath().clone();
             match pa.cmp(&pb) {
-                Ordering::Equal => {
-                    self.na.take();
-                    self.nb.take();
-                    Some(MergedEntry {
-                        apath: pa,
-                        kind: Both,
-                    })
-                }
-                Ordering::Less => {
-                    self.na.take().unwrap();
-                    Some(MergedEntry {
-                        apath: pa,
-                        kind: LeftOnly,
-                    })
-                }
-                Ordering::Greater => {
-                    self.nb.take().unwrap();
-                    Some(MergedEntry {
-                        apath: pb,
-                        kind: RightOnly,
-                    })
-                }
+                Ordering::Equal => Some(MergedEntry {
+                    apath: pa,
+                    kind: Both,
+                    left: self.na.take(),
+                    right: self.nb.take(),
+                }),
+                Ordering::Less => Some(MergedEntry {
+                    apath: pa,
+                    kind: LeftOnly,
+                    left: self.na.take(),
+                    right: None,
+                }),
+                Ordering::Greater => Some(MergedEntry {
+                    apath: pb,
+                    kind: RightOnly,
+                    left: None,
+                    right: self.nb.take(),
+                }),
             }
         }
     }
@@ -142,7 +146,6 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::MergedEntry;
     use super::MergedEntryKind::*;
     use crate::test_fixtures::*;
     use crate::*;
@@ -157,13 +160,10 @@ mod tests {
         )
         .collect::<Vec<_>>();
         assert_eq!(di.len(), 1);
-        assert_eq!(
-            di[0],
-            MergedEntry {
-                apath: "/".into(),
-                kind: Both,
-            }
-        );
+        assert_eq!(di[0].apath, "/");
+        assert_eq!(di[0].kind, Both);
+        assert!(di[0].left.is_some());
+        assert!(di[0].right.is_some());
     }
 
     // TODO: More tests of various diff situations.
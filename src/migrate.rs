@@ -0,0 +1,67 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Upgrade archive metadata in place to the current format.
+//!
+//! Conserve can read all the formats that `migrate` knows how to upgrade, so
+//! migration is never required, but it fills in metadata that older versions
+//! didn't record, so that it can be relied upon by validation and by future
+//! versions.
+//!
+//! Current migrations:
+//!
+//! * The archive header is marked with the current `ARCHIVE_VERSION`.
+//!
+//! * Band heads written before 0.6.3 are marked with a `band_format_version`.
+//!
+//! * Band tails written before 0.6.4 get an `index_hunk_count`.
+
+use std::time::Instant;
+
+use crate::stats::MigrateStats;
+use crate::*;
+
+/// Options for `migrate`.
+#[derive(Debug, Default, Clone)]
+pub struct MigrateOptions {
+    /// Report what would be changed, without changing anything.
+    pub dry_run: bool,
+
+    /// Break any existing lock on the archive.
+    pub break_lock: bool,
+}
+
+/// Upgrade the header and band metadata of an archive to the current format.
+///
+/// Migration is idempotent: running it again on an upgraded archive changes
+/// nothing.
+pub fn migrate(archive: &Archive, options: &MigrateOptions) -> Result<MigrateStats> {
+    let start = Instant::now();
+    let mut stats = MigrateStats::default();
+    let _lock = archive.lock(options.break_lock)?;
+    if archive.upgrade_header(options.dry_run)? {
+        stats.header_upgraded += 1;
+    }
+    for band_id in archive.band_ids()? {
+        let band = Band::open(archive, &band_id)?;
+        stats.bands_examined += 1;
+        if band.upgrade_head(options.dry_run)? {
+            stats.band_heads_upgraded += 1;
+        }
+        if band.upgrade_tail(options.dry_run)? {
+            stats.band_tails_upgraded += 1;
+        }
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
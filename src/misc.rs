@@ -29,6 +29,22 @@ pub fn bytes_to_human_mb(s: u64) -> String {
     s
 }
 
+/// Describe a number of bytes in the largest decimal unit that keeps at least
+/// one whole digit, like `"999 B"`, `"1.5 kB"`, or `"12.3 GB"`.
+pub fn bytes_to_human(bytes: u64) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB", "TB", "PB", "EB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 999.95 && unit + 1 < UNITS.len() {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
 /// True if `a` is zero.
 ///
 /// This trivial function exists as a predicate for serde.
@@ -44,3 +60,28 @@ pub(crate) fn zero_u32(a: &u32) -> bool {
 pub(crate) fn zero_u64(a: &u64) -> bool {
     *a == 0
 }
+
+/// True if `a` is false.
+///
+/// This trivial function exists as a predicate for serde.
+#[allow(clippy::trivially_copy_pass_by_ref)]
+pub(crate) fn is_false(a: &bool) -> bool {
+    !*a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn human_bytes() {
+        assert_eq!(bytes_to_human(0), "0 B");
+        assert_eq!(bytes_to_human(999), "999 B");
+        assert_eq!(bytes_to_human(1000), "1.0 kB");
+        assert_eq!(bytes_to_human(1_549), "1.5 kB");
+        assert_eq!(bytes_to_human(999_949), "999.9 kB");
+        assert_eq!(bytes_to_human(999_950), "1.0 MB");
+        assert_eq!(bytes_to_human(12_345_678_901), "12.3 GB");
+        assert_eq!(bytes_to_human(u64::MAX), "18.4 EB");
+    }
+}
@@ -0,0 +1,472 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Mount an archive as a read-only FUSE filesystem.
+//!
+//! The root directory holds one directory per band, named by the band id,
+//! and a `latest` symlink to the last complete band. Each directory's entries
+//! are read from the band's index the first time it's looked up or listed,
+//! and then kept in memory, so only the index hunks covering directories that
+//! are visited need to be read. File contents are read through a seekable
+//! [ReadStoredFile], so reads only fetch the blocks they touch.
+//!
+//! Nothing in the archive changes while it's mounted, so the kernel is told
+//! to cache attributes and contents for a long time.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{
+    consts::FOPEN_KEEP_CACHE, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, ReplyOpen, Request, FUSE_ROOT_ID,
+};
+use libc::{EIO, ENOENT, ENOTDIR};
+
+use crate::unix_time::UnixTime;
+use crate::*;
+
+/// How long the kernel may cache attributes and lookups.
+const TTL: Duration = Duration::from_secs(3600);
+
+const LATEST_NAME: &str = "latest";
+
+/// A mounted archive, which is unmounted when this is dropped or
+/// [Mount::unmount] is called.
+pub struct Mount {
+    session: fuser::BackgroundSession,
+}
+
+impl Mount {
+    /// Mount the archive read-only on an existing directory, serving requests
+    /// from a background thread.
+    pub fn new(archive: &Archive, mountpoint: &Path) -> Result<Mount> {
+        let fs = ArchiveFs::new(archive)?;
+        let options = [
+            MountOption::RO,
+            MountOption::FSName("conserve".to_owned()),
+            MountOption::Subtype("conserve".to_owned()),
+        ];
+        let session =
+            fuser::spawn_mount2(fs, mountpoint, &options).map_err(|source| Error::Mount {
+                path: mountpoint.to_owned(),
+                source,
+            })?;
+        Ok(Mount { session })
+    }
+
+    /// True if the filesystem has been unmounted from outside, for example
+    /// by `fusermount -u`.
+    pub fn is_finished(&self) -> bool {
+        self.session.guard.is_finished()
+    }
+
+    /// Unmount the filesystem and wait for the serving thread to stop.
+    pub fn unmount(self) {
+        self.session.join()
+    }
+}
+
+/// One file, directory, or symlink in the mounted filesystem.
+struct Node {
+    parent: u64,
+    kind: FileType,
+    mtime: SystemTime,
+    size: u64,
+    /// The band holding this node, or None for the root and `latest`.
+    band: Option<usize>,
+    /// For directories, the children, or None if the directory hasn't been
+    /// read from the index yet.
+    children: Option<Children>,
+    /// For entries in a band, other than the band directory, the index entry.
+    entry: Option<IndexEntry>,
+    /// For `latest`, the name of the band it points to.
+    link: Option<String>,
+}
+
+/// The children of a directory.
+#[derive(Default)]
+struct Children {
+    /// Names and inodes, in the order they're listed.
+    names: Vec<(String, u64)>,
+    /// Inodes by name as seen through the filesystem, for lookups.
+    by_name: HashMap<OsString, u64>,
+}
+
+impl Children {
+    fn push(&mut self, name: String, ino: u64) {
+        self.by_name
+            .insert(names::to_os_str(&name).into_owned(), ino);
+        self.names.push((name, ino));
+    }
+}
+
+struct ArchiveFs {
+    archive: Archive,
+    band_ids: Vec<BandId>,
+    /// Opened trees, by band index.
+    trees: HashMap<usize, StoredTree>,
+    /// Nodes indexed by inode number minus one.
+    nodes: Vec<Node>,
+    open_files: HashMap<u64, ReadStoredFile>,
+    next_fh: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl ArchiveFs {
+    fn new(archive: &Archive) -> Result<ArchiveFs> {
+        let band_ids = archive.band_ids()?;
+        let mut fs = ArchiveFs {
+            archive: archive.clone(),
+            band_ids: band_ids.clone(),
+            trees: HashMap::new(),
+            nodes: Vec::new(),
+            open_files: HashMap::new(),
+            next_fh: 1,
+            // Safety: these calls can't fail.
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        let root = fs.add_node(Node {
+            parent: FUSE_ROOT_ID,
+            kind: FileType::Directory,
+            mtime: SystemTime::now(),
+            size: 0,
+            band: None,
+            children: Some(Children::default()),
+            entry: None,
+            link: None,
+        });
+        assert_eq!(root, FUSE_ROOT_ID);
+        let mut root_children = Children::default();
+        for (i, band_id) in band_ids.iter().enumerate() {
+            let info = Band::open(archive, band_id)?.get_info()?;
+            let ino = fs.add_node(Node {
+                parent: root,
+                kind: FileType::Directory,
+                mtime: info.start_time.into(),
+                size: 0,
+                band: Some(i),
+                children: None,
+                entry: None,
+                link: None,
+            });
+            root_children.push(band_id.to_string(), ino);
+        }
+        if let Some(band) = archive.last_complete_band()? {
+            let target = band.id().to_string();
+            let ino = fs.add_node(Node {
+                parent: root,
+                kind: FileType::Symlink,
+                mtime: SystemTime::now(),
+                size: target.len() as u64,
+                band: None,
+                children: None,
+                entry: None,
+                link: Some(target),
+            });
+            root_children.push(LATEST_NAME.to_owned(), ino);
+        }
+        fs.nodes[0].children = Some(root_children);
+        Ok(fs)
+    }
+
+    fn add_node(&mut self, node: Node) -> u64 {
+        self.nodes.push(node);
+        self.nodes.len() as u64
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1).and_then(|i| self.nodes.get(i as usize))
+    }
+
+    /// Make sure the children of a directory are known, reading them from
+    /// the band's index if necessary.
+    fn load_children(&mut self, ino: u64) -> std::result::Result<&Children, i32> {
+        let node = self.node(ino).ok_or(ENOENT)?;
+        if node.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+        if node.children.is_none() {
+            let band = node.band.expect("unloaded directory is in a band");
+            if let Err(err) = self.load_dir(ino, band) {
+                ui::report_problem(Problem::from_error(None, &err));
+                return Err(EIO);
+            }
+        }
+        Ok(self.nodes[ino as usize - 1].children.as_ref().unwrap())
+    }
+
+    /// Open the stored tree for a band, if it's not open already.
+    fn tree(&mut self, band: usize) -> Result<&StoredTree> {
+        if !self.trees.contains_key(&band) {
+            let tree = self
+                .archive
+                .open_stored_tree(BandSelectionPolicy::Specified(self.band_ids[band].clone()))?;
+            self.trees.insert(band, tree);
+        }
+        Ok(&self.trees[&band])
+    }
+
+    /// Read the entries directly inside directory `dir_ino` from the band's
+    /// index, and add nodes for them.
+    fn load_dir(&mut self, dir_ino: u64, band: usize) -> Result<()> {
+        let dir_apath = match &self.nodes[dir_ino as usize - 1].entry {
+            Some(entry) => entry.apath.clone(),
+            None => Apath::from("/"),
+        };
+        // Within the directory's subtree, its own children come before
+        // anything in its subdirectories, so stop at the first entry that's
+        // not a direct child.
+        let mut entries = Vec::new();
+        for entry in self
+            .tree(band)?
+            .iter_entries(Some(&dir_apath), &Exclude::nothing())
+        {
+            let entry = entry?;
+            if entry.apath == dir_apath {
+                continue;
+            }
+            let (parent, name) = entry.apath.rsplit_once('/').unwrap();
+            if (if parent.is_empty() { "/" } else { parent }) != &*dir_apath {
+                break;
+            }
+            entries.push((name.to_owned(), entry));
+        }
+        let mut children = Children::default();
+        for (name, entry) in entries {
+            let kind = match entry.kind() {
+                Kind::Dir => FileType::Directory,
+                Kind::File => FileType::RegularFile,
+                Kind::Symlink => FileType::Symlink,
+                Kind::Unknown => continue,
+            };
+            let size = match kind {
+                FileType::Symlink => entry.target.as_ref().map_or(0, |t| t.len() as u64),
+                _ => entry.size().unwrap_or(0),
+            };
+            let ino = self.add_node(Node {
+                parent: dir_ino,
+                kind,
+                mtime: system_time(entry.mtime()),
+                size,
+                band: Some(band),
+                children: None,
+                entry: Some(entry),
+                link: None,
+            });
+            children.push(name, ino);
+        }
+        self.nodes[dir_ino as usize - 1].children = Some(children);
+        Ok(())
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let node = self.node(ino)?;
+        let default_perm = match node.kind {
+            FileType::Directory => 0o555,
+            FileType::Symlink => 0o777,
+            _ => 0o444,
+        };
+        let perm = node
+            .entry
+            .as_ref()
+            .and_then(|entry| entry.unix_mode)
+            .map_or(default_perm, |mode| (mode & 0o7777) as u16);
+        Some(FileAttr {
+            ino,
+            size: node.size,
+            blocks: node.size.div_ceil(512),
+            atime: node.mtime,
+            mtime: node.mtime,
+            ctime: node.mtime,
+            crtime: node.mtime,
+            kind: node.kind,
+            perm,
+            nlink: if node.kind == FileType::Directory {
+                2
+            } else {
+                1
+            },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let children = match self.load_children(parent) {
+            Ok(children) => children,
+            Err(errno) => return reply.error(errno),
+        };
+        let ino = match children.by_name.get(name) {
+            Some(ino) => *ino,
+            None => return reply.error(ENOENT),
+        };
+        match self.attr(ino) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.node(ino) {
+            Some(Node {
+                link: Some(link), ..
+            }) => reply.data(link.as_bytes()),
+            Some(Node {
+                entry: Some(entry), ..
+            }) if entry.kind() == Kind::Symlink => reply.data(&names::unescape_bytes(
+                entry.target.as_deref().unwrap_or(""),
+            )),
+            Some(_) => reply.error(libc::EINVAL),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let (band, entry) = match self.node(ino) {
+            Some(Node {
+                band: Some(band),
+                entry: Some(entry),
+                kind: FileType::RegularFile,
+                ..
+            }) => (*band, entry),
+            Some(_) => return reply.error(libc::EISDIR),
+            None => return reply.error(ENOENT),
+        };
+        match self.trees[&band].file_contents(entry) {
+            Ok(file) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.open_files.insert(fh, file);
+                reply.opened(fh, FOPEN_KEEP_CACHE);
+            }
+            Err(err) => {
+                ui::report_problem(Problem::from_error(None, &err));
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let file = match self.open_files.get_mut(&fh) {
+            Some(file) => file,
+            None => return reply.error(libc::EBADF),
+        };
+        let mut buf = vec![0; size as usize];
+        let mut filled = 0;
+        let result = file.seek(SeekFrom::Start(offset as u64)).and_then(|_| {
+            // Fill the whole buffer unless we reach the end, as FUSE expects.
+            while filled < buf.len() {
+                match file.read(&mut buf[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => reply.data(&buf[..filled]),
+            Err(err) => {
+                ui::report_problem(Problem::new(
+                    ProblemKind::Error,
+                    None,
+                    format!("Failed to read stored file: {}", err),
+                ));
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.open_files.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let parent = match self.node(ino) {
+            Some(node) => node.parent,
+            None => return reply.error(ENOENT),
+        };
+        let children = match self.load_children(ino) {
+            Ok(children) => children.names.clone(),
+            Err(errno) => return reply.error(errno),
+        };
+        let mut listing: Vec<(u64, FileType, &str)> = vec![
+            (ino, FileType::Directory, "."),
+            (parent, FileType::Directory, ".."),
+        ];
+        listing.extend(
+            children
+                .iter()
+                .map(|(name, child)| (*child, self.nodes[*child as usize - 1].kind, name.as_str())),
+        );
+        for (i, (child, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            // The offset passed back to us is that of the next entry.
+            if reply.add(child, (i + 1) as i64, kind, names::to_os_str(name)) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn system_time(time: UnixTime) -> SystemTime {
+    let nanos = Duration::from_nanos(time.nanosecs.into());
+    if time.secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(time.secs as u64) + nanos
+    } else {
+        UNIX_EPOCH - Duration::from_secs(time.secs.unsigned_abs()) + nanos
+    }
+}
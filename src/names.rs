@@ -0,0 +1,244 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Store filenames and symlink targets that aren't UTF-8 as strings.
+//!
+//! On Unix, names are arbitrary bytes, but apaths and symlink targets in the
+//! index are strings. Names that are valid UTF-8 are stored unchanged. Each
+//! byte that isn't part of a valid UTF-8 sequence is stored as the character
+//! `U+F700` plus the byte value, which falls in `U+F780..=U+F7FF` in the
+//! Private Use Area. So that the mapping can be exactly reversed, a name that
+//! really contains characters from that range has each of their UTF-8 bytes
+//! escaped the same way.
+//!
+//! Names are escaped the same way on every platform, so a name holding
+//! characters from the escape range reads back as itself wherever it was
+//! backed up. Bands record that their names are escaped: names in bands
+//! written before 0.6.11 aren't, and are escaped as they're read.
+//!
+//! On Unix, restore maps the escapes back to the original bytes. Other
+//! platforms can't make names from arbitrary bytes, so the escape characters
+//! are kept in the restored name unless they stand for valid UTF-8.
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+
+/// The escape for byte `b` is `ESCAPE_BASE + b`.
+const ESCAPE_BASE: u32 = 0xF700;
+
+/// The range of characters that escape bytes not in valid UTF-8.
+const ESCAPES: std::ops::RangeInclusive<char> = '\u{F780}'..='\u{F7FF}';
+
+fn escape_byte(b: u8) -> char {
+    debug_assert!(b >= 0x80, "ASCII is always valid UTF-8");
+    char::from_u32(ESCAPE_BASE + b as u32).unwrap()
+}
+
+/// True if the name contains escaped bytes.
+pub fn is_escaped(name: &str) -> bool {
+    name.chars().any(|c| ESCAPES.contains(&c))
+}
+
+/// Convert the bytes of a name to a string, escaping any that aren't valid
+/// UTF-8.
+pub fn escape_bytes(bytes: &[u8]) -> Cow<'_, str> {
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        if !is_escaped(s) {
+            return Cow::Borrowed(s);
+        }
+    }
+    let mut escaped = String::with_capacity(bytes.len() + 8);
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if ESCAPES.contains(&c) {
+                let mut buf = [0; 4];
+                escaped.extend(c.encode_utf8(&mut buf).bytes().map(escape_byte));
+            } else {
+                escaped.push(c);
+            }
+        }
+        escaped.extend(chunk.invalid().iter().copied().map(escape_byte));
+    }
+    Cow::Owned(escaped)
+}
+
+/// Convert a name stored by [escape_bytes] back to the original bytes.
+pub fn unescape_bytes(name: &str) -> Cow<'_, [u8]> {
+    if !is_escaped(name) {
+        return Cow::Borrowed(name.as_bytes());
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    for c in name.chars() {
+        if ESCAPES.contains(&c) {
+            bytes.push((c as u32 - ESCAPE_BASE) as u8);
+        } else {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+    Cow::Owned(bytes)
+}
+
+/// True if the original name, before escaping, was Unicode, so it can be
+/// restored exactly on any platform.
+pub fn is_unicode(name: &str) -> bool {
+    std::str::from_utf8(&unescape_bytes(name)).is_ok()
+}
+
+/// Convert a filename or symlink target from the filesystem to a string.
+///
+/// On Unix this always succeeds. Elsewhere it returns None if the name isn't
+/// Unicode.
+pub fn escape_os_str(name: &OsStr) -> Option<Cow<'_, str>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(escape_bytes(name.as_bytes()))
+    }
+    #[cfg(not(unix))]
+    {
+        name.to_str().map(|name| escape_bytes(name.as_bytes()))
+    }
+}
+
+/// Convert a stored name to a name for the local filesystem.
+///
+/// On Unix this is the original name, byte for byte. Elsewhere it's the
+/// original name if that was Unicode, and otherwise escape characters are
+/// kept in the name.
+pub fn to_os_str(name: &str) -> Cow<'_, OsStr> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        match unescape_bytes(name) {
+            Cow::Borrowed(bytes) => Cow::Borrowed(OsStr::from_bytes(bytes)),
+            Cow::Owned(bytes) => Cow::Owned(OsStr::from_bytes(&bytes).to_owned()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        match unescape_bytes(name) {
+            Cow::Borrowed(_) => Cow::Borrowed(OsStr::new(name)),
+            Cow::Owned(bytes) => match String::from_utf8(bytes) {
+                Ok(unescaped) => Cow::Owned(unescaped.into()),
+                Err(_) => Cow::Borrowed(OsStr::new(name)),
+            },
+        }
+    }
+}
+
+/// Make a name safe to print on a terminal.
+///
+/// Escaped bytes are shown as `\xNN` with the original byte value, control
+/// characters as `\xNN` or `\u{NNNN}`, and backslashes are doubled. Other
+/// names are returned unchanged.
+pub fn printable(name: &str) -> Cow<'_, str> {
+    if !name
+        .chars()
+        .any(|c| c == '\\' || c.is_control() || ESCAPES.contains(&c))
+    {
+        return Cow::Borrowed(name);
+    }
+    let mut out = String::with_capacity(name.len() + 8);
+    for c in name.chars() {
+        if c == '\\' {
+            out.push_str("\\\\");
+        } else if ESCAPES.contains(&c) {
+            out.push_str(&format!("\\x{:02x}", c as u32 - ESCAPE_BASE));
+        } else if c.is_control() && (c as u32) < 0x100 {
+            out.push_str(&format!("\\x{:02x}", c as u32));
+        } else if c.is_control() {
+            out.push_str(&format!("\\u{{{:04x}}}", c as u32));
+        } else {
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> String {
+        let escaped = escape_bytes(bytes).into_owned();
+        assert_eq!(unescape_bytes(&escaped).as_ref(), bytes);
+        escaped
+    }
+
+    #[test]
+    fn utf8_is_unchanged() {
+        assert!(matches!(
+            escape_bytes(b"caf\xc3\xa9"),
+            Cow::Borrowed("café")
+        ));
+        assert!(matches!(
+            unescape_bytes("café"),
+            Cow::Borrowed(b"caf\xc3\xa9")
+        ));
+        assert_eq!(round_trip("日本語.txt".as_bytes()), "日本語.txt");
+    }
+
+    #[test]
+    fn invalid_bytes_are_escaped() {
+        assert_eq!(round_trip(b"caf\xe9"), "caf\u{F7E9}");
+        assert_eq!(round_trip(b"\xff\xfe"), "\u{F7FF}\u{F7FE}");
+        // A truncated multibyte sequence at the end.
+        assert_eq!(round_trip(b"a\xe6\x97"), "a\u{F7E6}\u{F797}");
+    }
+
+    #[test]
+    fn escape_characters_in_names_are_themselves_escaped() {
+        let name = "x\u{F7E9}y";
+        let escaped = round_trip(name.as_bytes());
+        assert_eq!(escaped, "x\u{F7EF}\u{F79F}\u{F7A9}y");
+        assert_ne!(escaped, round_trip(b"x\xe9y"));
+        // Private Use characters outside the escape range are unchanged.
+        assert_eq!(round_trip("\u{F700}".as_bytes()), "\u{F700}");
+    }
+
+    #[test]
+    fn printable_names() {
+        assert!(matches!(printable("/a/b c.txt"), Cow::Borrowed(_)));
+        assert_eq!(printable("/caf\u{F7E9}"), "/caf\\xe9");
+        assert_eq!(printable("/a\\b"), "/a\\\\b");
+        assert_eq!(printable("/new\nline\u{7f}"), "/new\\x0aline\\x7f");
+        assert_eq!(printable("/\u{2028}"), "/\u{2028}");
+    }
+
+    #[test]
+    fn unicode_names() {
+        assert!(is_unicode("caf\u{e9}"));
+        assert!(is_unicode(&escape_bytes("x\u{F7E9}".as_bytes())));
+        assert!(!is_unicode(&escape_bytes(b"caf\xe9")));
+    }
+
+    #[test]
+    fn private_use_characters_round_trip_on_every_platform() {
+        let name = OsStr::new("x\u{F7E9}y");
+        let escaped = escape_os_str(name).unwrap();
+        assert_eq!(escaped, "x\u{F7EF}\u{F79F}\u{F7A9}y");
+        assert_eq!(to_os_str(&escaped), name);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn os_str_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"\xe9t\xe9");
+        let escaped = escape_os_str(name).unwrap();
+        assert_eq!(escaped, "\u{F7E9}t\u{F7E9}");
+        assert_eq!(to_os_str(&escaped), name);
+    }
+}
@@ -0,0 +1,398 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Create entries inside a directory without following symlinks on the way.
+//!
+//! When restoring over a directory that someone else can write to, they
+//! could plant a symlink such as `dest/etc -> /etc`, and a naive restore would
+//! write through it. These functions refuse to traverse any symlink below
+//! the root, and report it as `Error::SymlinkInDestination`.
+//!
+//! On Unix, each directory is opened relative to its parent's file descriptor
+//! with `O_NOFOLLOW`, and the new entry is created relative to the open
+//! parent, so a symlink swapped in while the restore is running is still not
+//! followed.
+//!
+//! On other platforms each ancestor is checked with `symlink_metadata` before
+//! the entry is created by path. This still catches symlinks planted in
+//! advance, but a symlink swapped in between the check and the write will be
+//! followed.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::*;
+
+/// Create a new file at `root` joined with `names`, for writing.
+///
+/// If anything, including a symlink, is already there, this fails.
+pub(crate) fn create_new_file(root: &Path, names: &[&OsStr]) -> Result<File> {
+    imp::create_new_file(root, names)
+}
+
+/// Create a new file with a unique name starting with `prefix`, in the
+/// directory at `root` joined with `dir_names`.
+///
+/// The file is created exclusively, so an existing file is never
+/// overwritten. Returns the file and its name.
+pub(crate) fn create_temp_file(
+    root: &Path,
+    dir_names: &[&OsStr],
+    prefix: &str,
+) -> Result<(File, OsString)> {
+    loop {
+        let mut random = [0u8; 8];
+        getrandom::getrandom(&mut random).map_err(|err| Error::Restore {
+            path: joined(root, dir_names),
+            source: err.into(),
+        })?;
+        let name = OsString::from(format!("{}{}", prefix, hex::encode(random)));
+        let mut names = dir_names.to_vec();
+        names.push(&name);
+        match create_new_file(root, &names) {
+            Ok(file) => return Ok((file, name)),
+            Err(Error::Restore { source, .. })
+                if source.kind() == std::io::ErrorKind::AlreadyExists =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Create a directory at `root` joined with `names`, or do nothing if a
+/// directory is already there.
+pub(crate) fn create_dir(root: &Path, names: &[&OsStr]) -> Result<()> {
+    imp::create_dir(root, names)
+}
+
+/// Check that each of `names` below `root` is a directory and not a symlink.
+pub(crate) fn check_dirs(root: &Path, names: &[&OsStr]) -> Result<()> {
+    imp::check_dirs(root, names)
+}
+
+/// Create a symlink at `root` joined with `names`.
+#[cfg(unix)]
+pub(crate) fn create_symlink(root: &Path, names: &[&OsStr], target: &OsStr) -> Result<()> {
+    imp::create_symlink(root, names, target)
+}
+
+/// Rename `from` to `to`, both within the directory at `root` joined with
+/// `dir_names`, replacing any existing entry at `to`.
+pub(crate) fn rename(root: &Path, dir_names: &[&OsStr], from: &OsStr, to: &OsStr) -> Result<()> {
+    imp::rename(root, dir_names, from, to)
+}
+
+fn joined(root: &Path, names: &[&OsStr]) -> PathBuf {
+    names
+        .iter()
+        .fold(root.to_owned(), |path, name| path.join(name))
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::{CString, OsStr};
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::Path;
+
+    use super::joined;
+    use crate::*;
+
+    fn to_cstring(name: &OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    /// Convert a raw file descriptor result into an owned `File`.
+    fn fd_result(fd: libc::c_int) -> io::Result<File> {
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            // Safety: the fd was just returned by the kernel and is owned by nobody else.
+            Ok(unsafe { File::from_raw_fd(fd) })
+        }
+    }
+
+    /// Open the directory that will hold the last of `names`, without
+    /// following symlinks below `root`.
+    fn open_parent(root: &Path, names: &[&OsStr]) -> Result<File> {
+        open_dir(root, &names[..names.len() - 1])
+    }
+
+    pub(super) fn check_dirs(root: &Path, names: &[&OsStr]) -> Result<()> {
+        open_dir(root, names).map(drop)
+    }
+
+    /// Open the directory at `root` joined with `names`, without following
+    /// symlinks below `root`.
+    fn open_dir(root: &Path, names: &[&OsStr]) -> Result<File> {
+        let restore_err = |i: usize, source| Error::Restore {
+            path: joined(root, &names[..i]),
+            source,
+        };
+        let c_root = to_cstring(root.as_os_str()).map_err(|source| restore_err(0, source))?;
+        // The root itself is given by the user, so may be a symlink.
+        let mut dir = fd_result(unsafe {
+            libc::open(
+                c_root.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        })
+        .map_err(|source| restore_err(0, source))?;
+        for (i, name) in names.iter().enumerate() {
+            let c_name = to_cstring(name).map_err(|source| restore_err(i + 1, source))?;
+            dir = fd_result(unsafe {
+                libc::openat(
+                    dir.as_raw_fd(),
+                    c_name.as_ptr(),
+                    libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                )
+            })
+            .map_err(|source| match source.raw_os_error() {
+                // ELOOP means it's a symlink; ENOTDIR means it's some other
+                // non-directory, which might also be a symlink on some systems.
+                Some(libc::ELOOP) | Some(libc::ENOTDIR) => Error::SymlinkInDestination {
+                    path: joined(root, &names[..=i]),
+                },
+                _ => restore_err(i + 1, source),
+            })?;
+        }
+        Ok(dir)
+    }
+
+    pub(super) fn create_new_file(root: &Path, names: &[&OsStr]) -> Result<File> {
+        let parent = open_parent(root, names)?;
+        let restore_err = |source| Error::Restore {
+            path: joined(root, names),
+            source,
+        };
+        let c_name = to_cstring(names[names.len() - 1]).map_err(restore_err)?;
+        fd_result(unsafe {
+            libc::openat(
+                parent.as_raw_fd(),
+                c_name.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                0o666 as libc::c_uint,
+            )
+        })
+        .map_err(|source| match source.raw_os_error() {
+            Some(libc::ELOOP) => Error::SymlinkInDestination {
+                path: joined(root, names),
+            },
+            _ => restore_err(source),
+        })
+    }
+
+    pub(super) fn create_dir(root: &Path, names: &[&OsStr]) -> Result<()> {
+        if names.is_empty() {
+            // The root directory itself.
+            return Ok(());
+        }
+        let parent = open_parent(root, names)?;
+        let restore_err = |source| Error::Restore {
+            path: joined(root, names),
+            source,
+        };
+        let c_name = to_cstring(names[names.len() - 1]).map_err(restore_err)?;
+        if unsafe { libc::mkdirat(parent.as_raw_fd(), c_name.as_ptr(), 0o777) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(restore_err(err));
+        }
+        // Check what's already there, without following it.
+        fd_result(unsafe {
+            libc::openat(
+                parent.as_raw_fd(),
+                c_name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        })
+        .map(|_| ())
+        .map_err(|source| match source.raw_os_error() {
+            Some(libc::ELOOP) | Some(libc::ENOTDIR) => Error::SymlinkInDestination {
+                path: joined(root, names),
+            },
+            _ => restore_err(source),
+        })
+    }
+
+    pub(super) fn create_symlink(root: &Path, names: &[&OsStr], target: &OsStr) -> Result<()> {
+        let parent = open_parent(root, names)?;
+        let restore_err = |source| Error::Restore {
+            path: joined(root, names),
+            source,
+        };
+        let c_name = to_cstring(names[names.len() - 1]).map_err(restore_err)?;
+        let c_target = to_cstring(target).map_err(restore_err)?;
+        if unsafe { libc::symlinkat(c_target.as_ptr(), parent.as_raw_fd(), c_name.as_ptr()) } == 0 {
+            Ok(())
+        } else {
+            Err(restore_err(io::Error::last_os_error()))
+        }
+    }
+
+    pub(super) fn rename(
+        root: &Path,
+        dir_names: &[&OsStr],
+        from: &OsStr,
+        to: &OsStr,
+    ) -> Result<()> {
+        let dir = open_dir(root, dir_names)?;
+        let restore_err = |source| Error::Restore {
+            path: joined(root, dir_names).join(to),
+            source,
+        };
+        let c_from = to_cstring(from).map_err(restore_err)?;
+        let c_to = to_cstring(to).map_err(restore_err)?;
+        if unsafe {
+            libc::renameat(
+                dir.as_raw_fd(),
+                c_from.as_ptr(),
+                dir.as_raw_fd(),
+                c_to.as_ptr(),
+            )
+        } == 0
+        {
+            Ok(())
+        } else {
+            Err(restore_err(io::Error::last_os_error()))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+
+    use super::joined;
+    use crate::*;
+
+    /// Check that no ancestor of the entry below `root` is a symlink.
+    ///
+    /// This is racy: a symlink could be swapped in after the check.
+    fn check_parents(root: &Path, names: &[&OsStr]) -> Result<()> {
+        check_dirs(root, &names[..names.len() - 1])
+    }
+
+    pub(super) fn check_dirs(root: &Path, names: &[&OsStr]) -> Result<()> {
+        for i in 1..=names.len() {
+            let path = joined(root, &names[..i]);
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    return Err(Error::SymlinkInDestination { path })
+                }
+                Ok(_) => (),
+                Err(source) => return Err(Error::Restore { path, source }),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_not_symlink(path: &Path) -> Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => Err(Error::SymlinkInDestination {
+                path: path.to_owned(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn create_new_file(root: &Path, names: &[&OsStr]) -> Result<File> {
+        check_parents(root, names)?;
+        let path = joined(root, names);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|source| Error::Restore { path, source })
+    }
+
+    pub(super) fn create_dir(root: &Path, names: &[&OsStr]) -> Result<()> {
+        if names.is_empty() {
+            return Ok(());
+        }
+        check_parents(root, names)?;
+        let path = joined(root, names);
+        check_not_symlink(&path)?;
+        match fs::create_dir(&path) {
+            Err(source) if source.kind() != io::ErrorKind::AlreadyExists => {
+                Err(Error::Restore { path, source })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn rename(
+        root: &Path,
+        dir_names: &[&OsStr],
+        from: &OsStr,
+        to: &OsStr,
+    ) -> Result<()> {
+        check_dirs(root, dir_names)?;
+        let dir = joined(root, dir_names);
+        let path = dir.join(to);
+        fs::rename(dir.join(from), &path).map_err(|source| Error::Restore { path, source })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn create_within_real_directories() {
+        let root = tempfile::tempdir().unwrap();
+        create_dir(root.path(), &[OsStr::new("a")]).unwrap();
+        create_dir(root.path(), &[OsStr::new("a")]).unwrap();
+        create_new_file(root.path(), &[OsStr::new("a"), OsStr::new("f")]).unwrap();
+        assert!(root.path().join("a/f").is_file());
+        check_dirs(root.path(), &[OsStr::new("a")]).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_symlinked_parent() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        let names = [OsStr::new("link"), OsStr::new("f")];
+
+        assert!(matches!(
+            create_new_file(root.path(), &names),
+            Err(Error::SymlinkInDestination { .. })
+        ));
+        assert!(matches!(
+            create_dir(root.path(), &names),
+            Err(Error::SymlinkInDestination { .. })
+        ));
+        assert!(matches!(
+            create_symlink(root.path(), &names, OsStr::new("target")),
+            Err(Error::SymlinkInDestination { .. })
+        ));
+        assert!(matches!(
+            create_dir(root.path(), &names[..1]),
+            Err(Error::SymlinkInDestination { .. })
+        ));
+        assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+    }
+}
@@ -16,18 +16,20 @@
 //! These are objects that accept iterators of different types of content, and write it to a
 //! file (typically stdout).
 
+use std::collections::BTreeMap;
 use std::io::{BufWriter, Write};
 
-use chrono::Local;
+use chrono::{Local, SecondsFormat, TimeZone, Utc};
 
+use crate::misc::bytes_to_human;
 use crate::*;
 
 pub fn show_brief_version_list(
     archive: &Archive,
     sort_recent_first: bool,
-    w: &mut dyn Write
+    w: &mut dyn Write,
 ) -> Result<()> {
-    let mut band_ids = archive.list_band_ids()?;
+    let mut band_ids = archive.band_ids()?;
     if sort_recent_first {
         band_ids.reverse();
     }
@@ -43,22 +45,40 @@ pub fn show_verbose_version_list(
     show_sizes: bool,
     w: &mut dyn Write,
 ) -> Result<()> {
-    let mut band_ids = archive.list_band_ids()?;
+    let mut band_ids = archive.band_ids()?;
     if sort_recent_first {
         band_ids.reverse();
     }
+    let usage = if show_sizes {
+        writeln!(
+            w,
+            "{:<20} {:<10} {:<19} {:>8} {:>10} {:>10} {:>10}",
+            "version", "status", "started", "took", "size", "unique", "shared",
+        )?;
+        archive.band_usage()?
+    } else {
+        BTreeMap::new()
+    };
     for band_id in band_ids {
         let band = match Band::open(&archive, &band_id) {
             Ok(band) => band,
             Err(e) => {
-                ui::problem(&format!("Failed to open band {:?}: {:?}", band_id, e));
+                ui::report_problem(Problem::new(
+                    ProblemKind::Band,
+                    None,
+                    format!("Failed to open band {:?}: {:?}", band_id, e),
+                ));
                 continue;
             }
         };
         let info = match band.get_info() {
             Ok(info) => info,
             Err(e) => {
-                ui::problem(&format!("Failed to read band tail {:?}: {:?}", band_id, e));
+                ui::report_problem(Problem::new(
+                    ProblemKind::Band,
+                    None,
+                    format!("Failed to read band tail {:?}: {:?}", band_id, e),
+                ));
                 continue;
             }
         };
@@ -76,41 +96,277 @@ pub fn show_verbose_version_list(
             .and_then(|et| (et - info.start_time).to_std().ok())
             .map(crate::ui::duration_to_hms)
             .unwrap_or_default();
+        let tags_str = if info.tags.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", info.tags.join(", "))
+        };
         if show_sizes {
-            let tree_mb = crate::misc::bytes_to_human_mb(
-                archive
-                    .open_stored_tree(BandSelectionPolicy::Specified(band_id.clone()))?
-                    .size(None)?
-                    .file_bytes,
-            );
+            let tree_size = bytes_to_human(tree_bytes(archive, &info)?);
+            let band_usage = usage.get(&band_id).cloned().unwrap_or_default();
             writeln!(
                 w,
-                "{:<20} {:<10} {} {:>8} {:>14}",
-                band_id, is_complete_str, start_time_str, duration_str, tree_mb,
+                "{:<20} {:<10} {} {:>8} {:>10} {:>10} {:>10}{}",
+                band_id,
+                is_complete_str,
+                start_time_str,
+                duration_str,
+                tree_size,
+                bytes_to_human(band_usage.unique_bytes),
+                bytes_to_human(band_usage.shared_bytes),
+                tags_str,
             )?;
         } else {
             writeln!(
                 w,
-                "{:<20} {:<10} {} {:>8}",
-                band_id, is_complete_str, start_time_str, duration_str,
+                "{:<20} {:<10} {} {:>8}{}",
+                band_id, is_complete_str, start_time_str, duration_str, tags_str,
             )?;
         }
     }
     Ok(())
 }
 
+/// The total size of files in a band: recorded in the tail of bands written
+/// by recent versions, or otherwise measured from the stored tree.
+fn tree_bytes(archive: &Archive, info: &band::Info) -> Result<u64> {
+    match info.total_file_bytes {
+        Some(bytes) => Ok(bytes),
+        None => Ok(archive
+            .open_stored_tree(BandSelectionPolicy::Specified(info.id.clone()))?
+            .size(None)?
+            .file_bytes),
+    }
+}
+
+/// Show the list of versions as json, optionally with their sizes and usage.
+pub fn show_version_list_json(
+    archive: &Archive,
+    sort_recent_first: bool,
+    show_sizes: bool,
+    w: &mut dyn Write,
+) -> Result<()> {
+    let mut band_ids = archive.band_ids()?;
+    if sort_recent_first {
+        band_ids.reverse();
+    }
+    let usage = if show_sizes {
+        archive.band_usage()?
+    } else {
+        BTreeMap::new()
+    };
+    let mut versions = Vec::new();
+    for band_id in band_ids {
+        let info = Band::open(archive, &band_id)?.get_info()?;
+        let mut version = serde_json::json!({
+            "band_id": band_id,
+            "is_complete": info.is_closed,
+            "start_time": info.start_time.to_rfc3339(),
+            "end_time": info.end_time.map(|t| t.to_rfc3339()),
+            "tags": info.tags,
+        });
+        if show_sizes {
+            version["tree_bytes"] = tree_bytes(archive, &info)?.into();
+            let band_usage = usage.get(&band_id).cloned().unwrap_or_default();
+            version["unique_bytes"] = band_usage.unique_bytes.into();
+            version["shared_bytes"] = band_usage.shared_bytes.into();
+        }
+        versions.push(version);
+    }
+    writeln!(w, "{}", serde_json::to_string_pretty(&versions).unwrap())?;
+    Ok(())
+}
+
 pub fn show_index_json(band: &Band, w: &mut dyn Write) -> Result<()> {
     // TODO: Maybe use https://docs.serde.rs/serde/ser/trait.Serializer.html#method.collect_seq.
     let bw = BufWriter::new(w);
-    let index_entries: Vec<IndexEntry> = band.iter_entries().collect();
+    let index_entries: Vec<IndexEntry> = band.index().iter_hunks().lenient().flatten().collect();
     serde_json::ser::to_writer_pretty(bw, &index_entries)
         .map_err(|source| Error::SerializeIndex { source })
 }
 
+/// Show each index hunk, either decoded into entries, or if `raw` as the
+/// generic structure that's stored, as a json list.
+pub fn show_index_hunks_json(band: &Band, raw: bool, w: &mut dyn Write) -> Result<()> {
+    let index = band.index();
+    let mut hunks = Vec::new();
+    for hunk_number in 0..index.count_hunks()? {
+        let entries = if raw {
+            index.read_raw_hunk(hunk_number)?
+        } else {
+            index
+                .read_hunk(hunk_number)?
+                .map(|entries| serde_json::to_value(entries).unwrap())
+        };
+        if let Some(entries) = entries {
+            hunks.push(serde_json::json!({
+                "hunk": hunk_number,
+                "entries": entries,
+            }));
+        }
+    }
+    writeln!(w, "{}", serde_json::to_string_pretty(&hunks).unwrap())?;
+    Ok(())
+}
+
+/// Show everything the index records about one entry, including its block
+/// addresses, as pretty json.
+pub fn show_entry_json(entry: &IndexEntry, w: &mut dyn Write) -> Result<()> {
+    writeln!(
+        w,
+        "{}",
+        serde_json::to_string_pretty(&EntryJson::with_addrs(entry)).unwrap()
+    )?;
+    Ok(())
+}
+
+/// Show entries as json, one per line, without block addresses.
+pub fn show_entries_json<E: Entry, I: Iterator<Item = E>>(it: I, w: &mut dyn Write) -> Result<()> {
+    let mut bw = BufWriter::new(w);
+    for entry in it {
+        if let Err(err) = entry.apath().check_valid() {
+            ui::report_problem(Problem::from_error(None, &err));
+            continue;
+        }
+        writeln!(
+            bw,
+            "{}",
+            serde_json::to_string(&EntryJson::new(&entry)).unwrap()
+        )?;
+    }
+    Ok(())
+}
+
+/// The metadata of an entry as shown by `ls --json` and `debug entry`.
+///
+/// Unlike the index format, this always shows the size and block offsets,
+/// the mtime as a timestamp, and the mode in octal.
+#[derive(serde::Serialize)]
+struct EntryJson<'e> {
+    apath: &'e Apath,
+    kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    mtime: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unix_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'e str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    addrs: Option<Vec<AddressJson>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "serialize_xattrs")]
+    xattrs: Option<&'e Xattrs>,
+    #[serde(skip_serializing_if = "crate::misc::is_false")]
+    changed_during_backup: bool,
+}
+
+fn serialize_xattrs<S: serde::Serializer>(
+    xattrs: &Option<&Xattrs>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    crate::xattrs::base64_values::serialize(xattrs.unwrap_or(&Xattrs::new()), serializer)
+}
+
+#[derive(serde::Serialize)]
+struct AddressJson {
+    hash: String,
+    start: u64,
+    len: u64,
+}
+
+impl<'e> EntryJson<'e> {
+    fn new<E: Entry>(entry: &'e E) -> EntryJson<'e> {
+        let mtime = entry.mtime();
+        EntryJson {
+            apath: entry.apath(),
+            kind: entry.kind(),
+            size: entry.size(),
+            mtime: Utc
+                .timestamp_opt(mtime.secs, mtime.nanosecs)
+                .single()
+                .map(|t| t.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                .unwrap_or_default(),
+            unix_mode: entry.unix_mode().map(|mode| format!("{:o}", mode)),
+            target: entry.symlink_target().as_deref(),
+            addrs: None,
+            xattrs: entry.xattrs(),
+            changed_during_backup: entry.changed_during_backup().unwrap_or(false),
+        }
+    }
+
+    fn with_addrs(entry: &'e IndexEntry) -> EntryJson<'e> {
+        let addrs = if entry.kind == Kind::File {
+            Some(
+                entry
+                    .addrs
+                    .iter()
+                    .map(|addr| AddressJson {
+                        hash: addr.hash.to_string(),
+                        start: addr.start,
+                        len: addr.len,
+                    })
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        EntryJson {
+            addrs,
+            ..EntryJson::new(entry)
+        }
+    }
+}
+
 pub fn show_entry_names<E: Entry, I: Iterator<Item = E>>(it: I, w: &mut dyn Write) -> Result<()> {
     let mut bw = BufWriter::new(w);
     for entry in it {
-        writeln!(bw, "{}", entry.apath())?;
+        if let Err(err) = entry.apath().check_valid() {
+            ui::report_problem(Problem::from_error(None, &err));
+            continue;
+        }
+        writeln!(bw, "{}", names::printable(entry.apath()))?;
+    }
+    Ok(())
+}
+
+/// Show entries in the style of `ls -l`, with their kind, size, and mtime,
+/// and a note on files that changed while they were backed up.
+pub fn show_entry_details<E: Entry, I: Iterator<Item = E>>(it: I, w: &mut dyn Write) -> Result<()> {
+    let mut bw = BufWriter::new(w);
+    for entry in it {
+        if let Err(err) = entry.apath().check_valid() {
+            ui::report_problem(Problem::from_error(None, &err));
+            continue;
+        }
+        let kind_char = match entry.kind() {
+            Kind::File => '-',
+            Kind::Dir => 'd',
+            Kind::Symlink => 'l',
+            Kind::Unknown => '?',
+        };
+        let size_str = entry.size().map(|s| s.to_string()).unwrap_or_default();
+        let mtime = entry.mtime();
+        let mtime_str = Local
+            .timestamp_opt(mtime.secs, mtime.nanosecs)
+            .single()
+            .map(|t| t.format(crate::TIMESTAMP_FORMAT).to_string())
+            .unwrap_or_default();
+        write!(
+            bw,
+            "{} {:>12} {:<19} {}",
+            kind_char,
+            size_str,
+            mtime_str,
+            names::printable(entry.apath())
+        )?;
+        if let Some(target) = entry.symlink_target() {
+            write!(bw, " -> {}", names::printable(target))?;
+        }
+        if entry.changed_during_backup() == Some(true) {
+            write!(bw, " [changed during backup]")?;
+        }
+        writeln!(bw)?;
     }
     Ok(())
 }
@@ -0,0 +1,89 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Check quickly, before a backup, that an archive can be written.
+//!
+//! Opening the archive checks that its header can be read. Preflight then
+//! writes a small probe file through the archive's transport, which on a
+//! local filesystem also checks that files can be renamed into place, reads
+//! it back, and deletes it. Finally it compares the space available to the
+//! size of the last backup.
+
+use std::io;
+
+use crate::stats::PreflightReport;
+use crate::transport::Transport;
+use crate::*;
+
+/// Start of the name of the probe file, which is followed by the process id.
+///
+/// Like other temporary files it starts with `TMP_PREFIX`, which no permanent
+/// archive file does.
+const PROBE_PREFIX: &str = "tmp-conserve-preflight-";
+
+const PROBE_CONTENT: &[u8] = b"Conserve preflight probe\n";
+
+/// Check that a backup could be written to the archive.
+///
+/// Returns [Error::PreflightProbe] if the probe file can't be written, read
+/// back, or deleted, and [Error::InsufficientSpace] if the transport reports
+/// less space available than the last backup wrote.
+pub fn preflight(archive: &Archive) -> Result<PreflightReport> {
+    probe(archive.transport())?;
+    let mut report = PreflightReport {
+        capacity: archive.transport().capacity()?,
+        ..PreflightReport::default()
+    };
+    for band_id in archive.band_ids()? {
+        if let Some(stats) = Band::open(archive, &band_id)?.read_stats()? {
+            report.estimated_archive_bytes += stats.compressed_bytes;
+            report.last_backup_bytes = stats.compressed_bytes;
+        }
+    }
+    if let Some(capacity) = report.capacity {
+        if capacity.available < report.last_backup_bytes {
+            return Err(Error::InsufficientSpace {
+                available: capacity.available,
+                needed: report.last_backup_bytes,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Write, read back, and delete a probe file.
+///
+/// The probe is deleted even if reading it back fails.
+fn probe(transport: &dyn Transport) -> Result<()> {
+    let path = format!("{}{}", PROBE_PREFIX, std::process::id());
+    let written = transport.write_file(&path, PROBE_CONTENT).and_then(|()| {
+        let mut buf = Vec::new();
+        transport.read_file(&path, &mut buf)?;
+        if buf == PROBE_CONTENT {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Probe file read back with different content",
+            ))
+        }
+    });
+    let removed = match transport.remove_file(&path) {
+        // If it couldn't be written, there may be nothing to delete.
+        Err(err) if err.kind() == io::ErrorKind::NotFound && written.is_err() => Ok(()),
+        other => other,
+    };
+    written
+        .and(removed)
+        .map_err(|source| Error::PreflightProbe { path, source })
+}
@@ -67,6 +67,11 @@ impl ProgressBar {
         self.total_work = total_work
     }
 
+    /// Add to the total work, as more is discovered.
+    pub fn increment_total_work(&mut self, inc: usize) {
+        self.total_work += inc
+    }
+
     pub fn increment_work_done(&mut self, inc: usize) {
         self.set_work_done(self.work_done + inc)
     }
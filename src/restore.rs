@@ -12,14 +12,17 @@
 
 //! Restore from the archive to the filesystem.
 
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use filetime::{set_file_handle_times, set_symlink_file_times};
-use globset::GlobSet;
 
 use crate::copy_tree::copy_tree;
 use crate::entry::Entry;
@@ -29,16 +32,38 @@ use crate::unix_time::UnixTime;
 use crate::*;
 use crate::{band::BandSelectionPolicy, copy_tree::CopyOptions};
 
+/// Start of the names of temporary files that are renamed into place once
+/// they're restored. The rest of the name is random.
+///
+/// The names are short, so that restoring a file with a name near the
+/// filesystem's limit doesn't fail.
+pub(crate) const RESTORE_TEMP_PREFIX: &str = ".conserve-tmp-";
+
 /// Description of how to restore a tree.
 #[derive(Debug)]
 pub struct RestoreOptions {
     pub print_filenames: bool,
-    pub excludes: Option<GlobSet>,
+    /// Restore only entries that match this filter, and the directories
+    /// holding them.
+    pub filter: EntryFilter,
     /// Restore only this subdirectory.
     pub only_subtree: Option<Apath>,
     pub overwrite: bool,
+    /// When overwriting, recursively delete existing directories where the
+    /// archive has a file or symlink.
+    pub replace_dirs: bool,
+    /// When overwriting, refuse to write through symlinks that are already in
+    /// the destination, such as `etc -> /etc`.
+    pub secure_overwrite: bool,
     // The band to select, or by default the last complete one.
     pub band_selection: BandSelectionPolicy,
+    /// Restore extended attributes of files and directories.
+    pub restore_xattrs: bool,
+    /// Stop restoring, between files, when this is cancelled.
+    pub cancel: CancellationToken,
+    /// Told about entries that couldn't be restored as stored, or by default
+    /// the UI's problem sink.
+    pub problems: Option<Arc<dyn ProblemSink>>,
 }
 
 impl Default for RestoreOptions {
@@ -46,9 +71,14 @@ impl Default for RestoreOptions {
         RestoreOptions {
             print_filenames: false,
             overwrite: false,
+            replace_dirs: false,
+            secure_overwrite: false,
             band_selection: BandSelectionPolicy::LatestClosed,
-            excludes: None,
+            filter: EntryFilter::default(),
             only_subtree: None,
+            restore_xattrs: true,
+            cancel: CancellationToken::new(),
+            problems: None,
         }
     }
 }
@@ -60,18 +90,54 @@ pub fn restore(
     options: &RestoreOptions,
 ) -> Result<CopyStats> {
     let st = archive.open_stored_tree(options.band_selection.clone())?;
-    let rt = if options.overwrite {
+    let mut rt = if options.overwrite {
         RestoreTree::create_overwrite(destination_path)
     } else {
         RestoreTree::create(destination_path)
-    }?;
+    }?
+    .restore_xattrs(options.restore_xattrs)
+    .replace_dirs(options.replace_dirs)
+    .secure_overwrite(options.secure_overwrite)
+    .problems(ui::problem_sink_or(&options.problems));
+    restore_stored_tree(&st, &mut rt, options)
+}
+
+/// Restore a selected version, or by default the latest, into any
+/// [WriteTree], such as a [TarWriteTree].
+///
+/// Band selection, filtering, cancellation and statistics are the same as for
+/// [restore]. Options about how to write to the filesystem, such as
+/// `overwrite`, are up to the destination tree and are ignored here.
+pub fn restore_into(
+    archive: &Archive,
+    dest: &mut dyn WriteTree,
+    options: &RestoreOptions,
+) -> Result<CopyStats> {
+    let st = archive.open_stored_tree(options.band_selection.clone())?;
+    restore_stored_tree(&st, dest, options)
+}
+
+fn restore_stored_tree(
+    st: &StoredTree,
+    dest: &mut dyn WriteTree,
+    options: &RestoreOptions,
+) -> Result<CopyStats> {
+    // The size recorded in the band is only right if the whole tree is
+    // restored.
+    let expected_bytes = if options.only_subtree.is_none() && options.filter.includes_everything() {
+        st.band().get_info()?.total_file_bytes
+    } else {
+        None
+    };
     let opts = CopyOptions {
         print_filenames: options.print_filenames,
         only_subtree: options.only_subtree.clone(),
-        excludes: options.excludes.clone(),
+        filter: options.filter.clone(),
+        expected_bytes,
+        cancel: options.cancel.clone(),
         ..CopyOptions::default()
     };
-    copy_tree(&st, rt, &opts)
+    copy_tree(st, dest, &opts)
 }
 
 /// A write-only tree on the filesystem, as a restore destination.
@@ -79,14 +145,90 @@ pub fn restore(
 pub struct RestoreTree {
     path: PathBuf,
 
-    dir_mtimes: Vec<(PathBuf, UnixTime)>,
+    /// Metadata for directories, applied in `finish` once their contents have
+    /// been restored.
+    deferred_dirs: Vec<DeferredDir>,
+
+    restore_xattrs: bool,
+
+    /// True if the destination may already contain files, which are replaced.
+    overwrite: bool,
+
+    /// When overwriting, whether to delete directories that are in the way.
+    replace_dirs: bool,
+
+    /// Don't follow symlinks within the destination.
+    secure_overwrite: bool,
+
+    /// Apaths of the symlinks created by this restore. Entries at or below
+    /// them are refused, since writing them would follow the link, perhaps
+    /// out of the destination.
+    restored_symlinks: BTreeSet<String>,
+
+    /// Counts of things noticed while restoring, such as renamed files.
+    stats: CopyStats,
+
+    /// Told about entries that can't be restored as stored.
+    problems: Arc<dyn ProblemSink>,
 }
 
 impl RestoreTree {
     fn new(path: PathBuf) -> RestoreTree {
+        // On Windows, use an extended-length `\\?\` path so that deep trees
+        // can be restored beyond MAX_PATH.
+        #[cfg(windows)]
+        let path = fs::canonicalize(&path).unwrap_or(path);
         RestoreTree {
             path,
-            dir_mtimes: Vec::new(),
+            deferred_dirs: Vec::new(),
+            restore_xattrs: true,
+            overwrite: false,
+            replace_dirs: false,
+            secure_overwrite: false,
+            restored_symlinks: BTreeSet::new(),
+            stats: CopyStats::default(),
+            problems: ui::problem_sink(),
+        }
+    }
+
+    /// Set where to report problems restoring entries; by default the UI's
+    /// problem sink.
+    pub fn problems(self, problems: Arc<dyn ProblemSink>) -> RestoreTree {
+        RestoreTree { problems, ..self }
+    }
+
+    /// Set whether to restore extended attributes; by default they are.
+    pub fn restore_xattrs(self, restore_xattrs: bool) -> RestoreTree {
+        RestoreTree {
+            restore_xattrs,
+            ..self
+        }
+    }
+
+    /// Set whether an overwriting restore may recursively delete existing
+    /// directories that are in the way of a file or symlink; by default it
+    /// won't.
+    pub fn replace_dirs(self, replace_dirs: bool) -> RestoreTree {
+        RestoreTree {
+            replace_dirs,
+            ..self
+        }
+    }
+
+    /// Set whether an overwriting restore refuses to write through symlinks
+    /// that are already within the destination.
+    ///
+    /// Entries below a symlinked directory are reported as problems and not
+    /// restored. On Unix, directories are opened without following symlinks
+    /// and entries are created relative to them. Elsewhere the check is made
+    /// before each entry is written, so a symlink created concurrently with
+    /// the restore could still be followed. Directory permissions and mtimes,
+    /// which are set at the end of the restore, are only checked beforehand,
+    /// on all platforms.
+    pub fn secure_overwrite(self, secure_overwrite: bool) -> RestoreTree {
+        RestoreTree {
+            secure_overwrite,
+            ..self
         }
     }
 
@@ -103,97 +245,660 @@ impl RestoreTree {
     }
 
     /// Create a RestoreTree, even if the destination directory is not empty.
+    ///
+    /// Existing files are replaced by restored entries of the same name. An
+    /// existing entry of a different kind is removed first, except that
+    /// directories are only removed if `replace_dirs` is set.
     pub fn create_overwrite(path: &Path) -> Result<RestoreTree> {
-        Ok(RestoreTree::new(path.to_path_buf()))
+        Ok(RestoreTree {
+            overwrite: true,
+            ..RestoreTree::new(path.to_path_buf())
+        })
+    }
+
+    /// Map an apath to a path within the destination.
+    ///
+    /// Apaths that aren't well-formed, for example with `..` components that
+    /// could point outside the destination, are rejected with
+    /// `Error::InvalidApath`. Apaths at or below a symlink restored earlier
+    /// are rejected with `Error::SymlinkInDestination`.
+    ///
+    /// On Windows, names that can't be created there are escaped: if the
+    /// entry's own name is changed this is reported and counted.
+    fn rooted_path(&mut self, apath: &Apath) -> Result<PathBuf> {
+        apath.check_valid()?;
+        let mut path = self.path.clone();
+        let mut renamed = false;
+        // Push each component separately, because `/` is not a separator in
+        // Windows extended-length paths.
+        for name in apath.split('/').filter(|name| !name.is_empty()) {
+            let local_name = local_file_name(name);
+            // Only Unix can restore names that weren't Unicode.
+            renamed = matches!(local_name, Cow::Owned(_))
+                || (cfg!(not(unix)) && !names::is_unicode(name));
+            path.push(names::to_os_str(&local_name));
+        }
+        if self.is_at_restored_symlink(apath) {
+            return Err(Error::SymlinkInDestination { path });
+        }
+        if renamed {
+            self.problems.report(Problem::new(
+                ProblemKind::RenamedEntry,
+                Some(apath),
+                format!("Restoring as {:?}", path),
+            ));
+            self.stats.renamed_entries += 1;
+        }
+        Ok(path)
     }
 
-    fn rooted_path(&self, apath: &Apath) -> PathBuf {
-        // Remove initial slash so that the apath is relative to the destination.
-        self.path.join(&apath[1..])
+    /// True if `apath` or one of its parents is a symlink restored earlier.
+    fn is_at_restored_symlink(&self, apath: &Apath) -> bool {
+        !self.restored_symlinks.is_empty()
+            && apath
+                .match_indices('/')
+                .skip(1)
+                .map(|(i, _)| &apath[..i])
+                .chain(std::iter::once(apath.as_ref()))
+                .any(|prefix| self.restored_symlinks.contains(prefix))
     }
-}
 
-impl tree::WriteTree for RestoreTree {
-    fn finish(self) -> Result<CopyStats> {
-        for (path, time) in self.dir_mtimes {
-            if let Err(err) = filetime::set_file_mtime(path, time.into()) {
-                ui::problem(&format!("Failed to set directory mtime: {:?}", err));
+    /// Find where a relative symlink points within the destination, or None
+    /// if it's absolute or points outside.
+    #[cfg(windows)]
+    fn resolve_symlink_target<E: Entry>(&self, entry: &E) -> Option<PathBuf> {
+        let target = entry.symlink_target().as_ref()?;
+        if target.starts_with('/') {
+            return None;
+        }
+        // The directory holding the link, as a stack of local names.
+        let mut names: Vec<String> = entry
+            .apath()
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .map(|name| local_file_name(name).into_owned())
+            .collect();
+        names.pop();
+        // Extended-length paths aren't normalized, so resolve `..` here.
+        for name in target.split('/') {
+            match name {
+                "" | "." => (),
+                ".." => {
+                    names.pop()?;
+                }
+                name => names.push(local_file_name(name).into_owned()),
             }
         }
-        Ok(CopyStats::default())
+        Some(
+            names
+                .iter()
+                .fold(self.path.clone(), |path, name| path.join(name)),
+        )
     }
 
-    fn copy_dir<E: Entry>(&mut self, entry: &E) -> Result<()> {
-        let path = self.rooted_path(entry.apath());
-        if let Err(source) = fs::create_dir_all(&path) {
-            if source.kind() != io::ErrorKind::AlreadyExists {
-                return Err(Error::Restore { path, source });
+    /// When overwriting, remove whatever is at `path` if it's not of the kind
+    /// about to be restored there.
+    ///
+    /// Existing symlinks are always removed, never followed, so that a
+    /// restored file can't be written through a link to somewhere else. In
+    /// secure mode a symlink where a directory should be is an error, rather
+    /// than being replaced.
+    fn clear_conflict(&mut self, path: &Path, kind: Kind) -> Result<()> {
+        if !self.overwrite {
+            return Ok(());
+        }
+        if self.secure_overwrite {
+            let names = relative_names(&self.path, path);
+            nofollow::check_dirs(&self.path, &names[..names.len().saturating_sub(1)])?;
+        }
+        let existing_kind = match fs::symlink_metadata(path) {
+            Ok(metadata) => Kind::from(metadata.file_type()),
+            // If it's not there, or can't be examined, let creating the new
+            // entry report any problem.
+            Err(_) => return Ok(()),
+        };
+        let result = match existing_kind {
+            Kind::Dir if kind == Kind::Dir => return Ok(()),
+            Kind::File if kind == Kind::File => return Ok(()),
+            Kind::Symlink if kind == Kind::Dir && self.secure_overwrite => {
+                return Err(Error::SymlinkInDestination {
+                    path: path.to_owned(),
+                })
+            }
+            Kind::Dir if !self.replace_dirs => {
+                return Err(Error::DestinationIsDirectory {
+                    path: path.to_owned(),
+                })
             }
+            Kind::Dir => fs::remove_dir_all(path),
+            Kind::Symlink => remove_symlink(path),
+            Kind::File | Kind::Unknown => fs::remove_file(path),
+        };
+        result.map_err(|source| Error::Restore {
+            path: path.to_owned(),
+            source,
+        })?;
+        if existing_kind != kind {
+            self.stats.replaced_entries += 1;
         }
-        self.dir_mtimes.push((path, entry.mtime()));
         Ok(())
     }
 
-    /// Copy in the contents of a file from another tree.
-    fn copy_file<R: ReadTree>(
+    fn write_xattrs<E: Entry>(&mut self, path: &Path, entry: &E) {
+        if let (true, Some(xattrs)) = (self.restore_xattrs, entry.xattrs()) {
+            self.stats.warnings +=
+                crate::xattrs::write_xattrs(path, xattrs, self.problems.as_ref());
+        }
+    }
+
+    /// Create a new temporary file, with a unique name, in the directory that
+    /// will hold `path`.
+    ///
+    /// The file is created exclusively, so nothing already in the directory
+    /// is overwritten.
+    fn create_temp_file(&self, path: &Path) -> Result<(File, PathBuf)> {
+        let dir = path.parent().expect("restored file has a parent");
+        if self.secure_overwrite {
+            let (file, name) = nofollow::create_temp_file(
+                &self.path,
+                &relative_names(&self.path, dir),
+                RESTORE_TEMP_PREFIX,
+            )?;
+            Ok((file, dir.join(name)))
+        } else {
+            tempfile::Builder::new()
+                .prefix(RESTORE_TEMP_PREFIX)
+                .rand_bytes(8)
+                .tempfile_in(dir)
+                .and_then(|temp| temp.keep().map_err(|err| err.error))
+                .map_err(|source| Error::Restore {
+                    path: path.to_owned(),
+                    source,
+                })
+        }
+    }
+
+    /// Write a file's content and metadata to `restore_file`, which is open
+    /// at `temp_path`, then rename it to `path`.
+    fn write_file_content(
         &mut self,
-        source_entry: &R::Entry,
-        from_tree: &R,
+        path: &Path,
+        mut restore_file: File,
+        temp_path: &Path,
+        source_entry: &IndexEntry,
+        content: &mut dyn Read,
     ) -> Result<CopyStats> {
-        // TODO: Restore permissions.
-        let path = self.rooted_path(source_entry.apath());
         let restore_err = |source| Error::Restore {
-            path: path.clone(),
+            path: path.to_owned(),
             source,
         };
-        let mut restore_file = File::create(&path).map_err(restore_err)?;
-        // TODO: Read one block at a time: don't pull all the contents into memory.
-        let content = &mut from_tree.file_contents(&source_entry)?;
-        let bytes_copied = std::io::copy(content, &mut restore_file).map_err(restore_err)?;
-        restore_file.flush().map_err(restore_err)?;
+        let bytes_copied = if source_entry.size() == Some(0) {
+            // Empty files have no blocks to read.
+            0
+        } else {
+            let bytes_copied = std::io::copy(content, &mut restore_file).map_err(restore_err)?;
+            restore_file.flush().map_err(restore_err)?;
+            bytes_copied
+        };
+        self.write_xattrs(temp_path, source_entry);
+        #[cfg(unix)]
+        if let Some(mode) = source_entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            restore_file
+                .set_permissions(fs::Permissions::from_mode(mode))
+                .map_err(restore_err)?;
+        }
 
         let mtime = Some(source_entry.mtime().into());
         set_file_handle_times(&restore_file, mtime, mtime).map_err(|source| {
             Error::RestoreModificationTime {
-                path: path.clone(),
+                path: path.to_owned(),
                 source,
             }
         })?;
+        drop(restore_file);
+
+        if self.secure_overwrite {
+            let names = relative_names(&self.path, path);
+            let (name, dir_names) = names.split_last().unwrap();
+            nofollow::rename(&self.path, dir_names, temp_path.file_name().unwrap(), name)?;
+        } else {
+            fs::rename(temp_path, path).map_err(restore_err)?;
+        }
 
-        // TODO: Accumulate more stats.
         Ok(CopyStats {
             uncompressed_bytes: bytes_copied,
+            empty_files: (bytes_copied == 0) as usize,
             ..CopyStats::default()
         })
     }
+}
+
+/// Metadata for a restored directory that can't be set until its contents
+/// are restored: creating a child changes the directory's mtime, and a
+/// read-only mode would prevent creating children at all.
+#[derive(Debug)]
+struct DeferredDir {
+    apath: Apath,
+    path: PathBuf,
+    mtime: UnixTime,
+    unix_mode: Option<u32>,
+}
+
+impl DeferredDir {
+    /// Set the directory's metadata, failing rather than following a symlink
+    /// that has replaced it since it was restored.
+    fn apply(&self) -> Result<()> {
+        if let Some(mode) = self.unix_mode {
+            set_unix_mode(&self.path, mode)?;
+        }
+        set_mtime(&self.path, self.mtime)
+    }
+}
+
+/// The names of each component of `path` below `root`.
+fn relative_names<'p>(root: &Path, path: &'p Path) -> Vec<&'p OsStr> {
+    path.strip_prefix(root)
+        .map(|relative| relative.iter().collect())
+        .unwrap_or_default()
+}
+
+/// Remove a symlink itself, not its target.
+fn remove_symlink(path: &Path) -> io::Result<()> {
+    let result = fs::remove_file(path);
+    // Windows directory symlinks and junctions are removed as directories,
+    // which again doesn't touch the target.
+    #[cfg(windows)]
+    let result = result.or_else(|_| fs::remove_dir(path));
+    result
+}
+
+/// Set the mtime of a restored entry by path.
+///
+/// If `path` is a symlink, the link's own mtime is set and its target is
+/// untouched.
+fn set_mtime(path: &Path, mtime: UnixTime) -> Result<()> {
+    let mtime = mtime.into();
+    set_symlink_file_times(path, mtime, mtime).map_err(|source| Error::RestoreModificationTime {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Set the Unix permissions of a restored file or directory by path.
+///
+/// Symlinks have no permissions of their own, and changing them through the
+/// link would change the target, so if `path` is a symlink this fails with
+/// `Error::SymlinkInDestination`.
+#[cfg(unix)]
+fn set_unix_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let restore_err = |source| Error::Restore {
+        path: path.to_owned(),
+        source,
+    };
+    // Open without following a symlink, and without blocking on a fifo, then
+    // set the mode on the open file.
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+    {
+        Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+            return Err(Error::SymlinkInDestination {
+                path: path.to_owned(),
+            })
+        }
+        result => result.map_err(restore_err)?,
+    };
+    file.set_permissions(fs::Permissions::from_mode(mode))
+        .map_err(restore_err)
+}
+
+/// Unix permissions can't be represented on this platform, so are ignored.
+#[cfg(not(unix))]
+fn set_unix_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Convert one component of an apath to a name that can be created on this
+/// platform.
+fn local_file_name(name: &str) -> Cow<'_, str> {
+    if cfg!(windows) {
+        windows_file_name(name)
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Device names that Windows won't allow as the base of a filename, whatever
+/// their case or extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Escape a filename so that it can be created on Windows, or return it
+/// unchanged if it already can be.
+///
+/// Characters that are illegal on Windows are replaced by `%` and their hex
+/// value, as are a trailing dot or space, and the first character of a
+/// reserved device name like `CON` or `nul.txt`. `%` itself is escaped as
+/// `%25`, so that different names never map to the same escaped name.
+pub(crate) fn windows_file_name(name: &str) -> Cow<'_, str> {
+    let base = name.split('.').next().unwrap();
+    let is_reserved = WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(base.trim_end()));
+    let last = name.len() - name.chars().last().map_or(0, char::len_utf8);
+    let needs_escape = |i: usize, c: char| {
+        matches!(c, '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*' | '%')
+            || (c as u32) < 0x20
+            || (i == 0 && is_reserved)
+            || (i == last && (c == '.' || c == ' '))
+    };
+    if !name.char_indices().any(|(i, c)| needs_escape(i, c)) {
+        return Cow::Borrowed(name);
+    }
+    let mut escaped = String::with_capacity(name.len() + 6);
+    for (i, c) in name.char_indices() {
+        if needs_escape(i, c) {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+impl tree::WriteTree for RestoreTree {
+    /// Set the mtime and permissions of directories, deepest first, so that
+    /// a directory's mode can't prevent reaching its children.
+    fn finish(&mut self) -> Result<CopyStats> {
+        let mut deferred_dirs = std::mem::take(&mut self.deferred_dirs);
+        deferred_dirs.sort_by_key(|dir| std::cmp::Reverse(dir.apath.split('/').count()));
+        for dir in deferred_dirs {
+            let result = if self.secure_overwrite {
+                nofollow::check_dirs(&self.path, &relative_names(&self.path, &dir.path))
+                    .and_then(|()| dir.apply())
+            } else {
+                dir.apply()
+            };
+            if let Err(err) = result {
+                self.stats.problems.push_error(Some(&dir.apath), &err);
+                self.stats.directory_metadata_errors += 1;
+            }
+        }
+        Ok(std::mem::take(&mut self.stats))
+    }
+
+    fn copy_dir(&mut self, entry: &IndexEntry) -> Result<()> {
+        let path = self.rooted_path(entry.apath())?;
+        self.clear_conflict(&path, Kind::Dir)?;
+        if self.secure_overwrite {
+            nofollow::create_dir(&self.path, &relative_names(&self.path, &path))?;
+        } else if let Err(source) = fs::create_dir_all(&path) {
+            if source.kind() != io::ErrorKind::AlreadyExists {
+                return Err(Error::Restore { path, source });
+            }
+        }
+        self.write_xattrs(&path, entry);
+        self.deferred_dirs.push(DeferredDir {
+            apath: entry.apath().clone(),
+            path,
+            mtime: entry.mtime(),
+            unix_mode: entry.unix_mode(),
+        });
+        Ok(())
+    }
+
+    /// Copy in the contents of a file from another tree.
+    fn copy_file(
+        &mut self,
+        source_entry: &IndexEntry,
+        content: &mut dyn Read,
+    ) -> Result<CopyStats> {
+        let path = self.rooted_path(source_entry.apath())?;
+        self.clear_conflict(&path, Kind::File)?;
+        // Write to a temporary name and rename it into place once it's
+        // complete, so that an interrupted restore never leaves a partial
+        // file under the real name.
+        let (restore_file, temp_path) = self.create_temp_file(&path)?;
+        let result =
+            self.write_file_content(&path, restore_file, &temp_path, source_entry, content);
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
 
     #[cfg(unix)]
-    fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()> {
+    fn copy_symlink(&mut self, entry: &IndexEntry) -> Result<()> {
         use std::os::unix::fs as unix_fs;
         if let Some(ref target) = entry.symlink_target() {
-            let path = self.rooted_path(entry.apath());
-            if let Err(source) = unix_fs::symlink(target, &path) {
+            let target = names::to_os_str(target);
+            let path = self.rooted_path(entry.apath())?;
+            self.clear_conflict(&path, Kind::Symlink)?;
+            if self.secure_overwrite {
+                nofollow::create_symlink(&self.path, &relative_names(&self.path, &path), &target)?;
+            } else if let Err(source) = unix_fs::symlink(target, &path) {
                 return Err(Error::Restore { path, source });
             }
-            let mtime = entry.mtime().into();
-            if let Err(source) = set_symlink_file_times(&path, mtime, mtime) {
-                return Err(Error::RestoreModificationTime { path, source });
+            self.restored_symlinks.insert(entry.apath().to_string());
+            set_mtime(&path, entry.mtime())?;
+        } else {
+            self.problems.report(Problem::new(
+                ProblemKind::UnrestorableSymlink,
+                Some(entry.apath()),
+                "No target in symlink entry",
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn copy_symlink(&mut self, entry: &IndexEntry) -> Result<()> {
+        use std::os::windows::fs::{symlink_dir, symlink_file};
+        /// Windows error code when the process may not create symlinks.
+        const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+        let target = match entry.symlink_target() {
+            Some(target) => target.replace('/', "\\"),
+            None => {
+                self.problems.report(Problem::new(
+                    ProblemKind::UnrestorableSymlink,
+                    Some(entry.apath()),
+                    "No target in symlink entry",
+                ));
+                return Ok(());
             }
+        };
+        let path = self.rooted_path(entry.apath())?;
+        if !names::is_unicode(&target) {
+            self.problems.report(Problem::new(
+                ProblemKind::RenamedEntry,
+                Some(entry.apath()),
+                format!("Symlink target {:?} isn't Unicode", target),
+            ));
+            self.stats.renamed_entries += 1;
+        }
+        self.clear_conflict(&path, Kind::Symlink)?;
+        if self.secure_overwrite {
+            let names = relative_names(&self.path, &path);
+            nofollow::check_dirs(&self.path, &names[..names.len() - 1])?;
+        }
+        // Windows symlinks are either to files or to directories. Guess from
+        // whatever is already restored at the target; a directory that sorts
+        // after the link won't exist yet, and gets a file symlink.
+        let target_dir = self.resolve_symlink_target(entry).filter(|t| t.is_dir());
+        let target = names::to_os_str(&target);
+        let result = if target_dir.is_some() {
+            symlink_dir(&target, &path)
         } else {
-            // TODO: Treat as an error.
-            ui::problem(&format!("No target in symlink entry {}", entry.apath()));
+            symlink_file(&target, &path)
+        };
+        match result {
+            Ok(()) => {
+                self.restored_symlinks.insert(entry.apath().to_string());
+                set_mtime(&path, entry.mtime())?
+            }
+            Err(err) if err.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => match target_dir {
+                Some(target_dir) => {
+                    if let Err(source) = junction::create(&target_dir, &path) {
+                        return Err(Error::Restore { path, source });
+                    }
+                    self.restored_symlinks.insert(entry.apath().to_string());
+                    self.stats.symlinks_as_junctions += 1;
+                }
+                None => {
+                    self.problems.report(Problem::new(
+                        ProblemKind::UnrestorableSymlink,
+                        Some(entry.apath()),
+                        "Not allowed to create symlink, and can't use a junction",
+                    ));
+                    self.stats.symlinks_skipped += 1;
+                }
+            },
+            Err(source) => return Err(Error::Restore { path, source }),
         }
         Ok(())
     }
 
-    #[cfg(not(unix))]
-    fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()> {
-        // TODO: Add a test with a canned index containing a symlink, and expect
-        // it cannot be restored on Windows and can be on Unix.
-        ui::problem(&format!(
-            "Can't restore symlinks on non-Unix: {}",
-            entry.apath()
+    #[cfg(not(any(unix, windows)))]
+    fn copy_symlink(&mut self, entry: &IndexEntry) -> Result<()> {
+        self.problems.report(Problem::new(
+            ProblemKind::UnrestorableSymlink,
+            Some(entry.apath()),
+            "Can't restore symlinks on this platform",
         ));
+        self.stats.symlinks_skipped += 1;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn deferred_dir_metadata_does_not_follow_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let outside = temp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let outside_meta = fs::metadata(&outside).unwrap();
+        let dir_path = temp.path().join("dir");
+        std::os::unix::fs::symlink(&outside, &dir_path).unwrap();
+
+        let deferred = DeferredDir {
+            apath: "/dir".into(),
+            path: dir_path.clone(),
+            mtime: UnixTime {
+                secs: 189216000,
+                nanosecs: 0,
+            },
+            unix_mode: Some(0o700),
+        };
+        match deferred.apply() {
+            Err(Error::SymlinkInDestination { path }) => assert_eq!(path, dir_path),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let deferred = DeferredDir {
+            unix_mode: None,
+            ..deferred
+        };
+        deferred.apply().unwrap();
+
+        let after = fs::metadata(&outside).unwrap();
+        assert_eq!(
+            after.permissions().mode(),
+            outside_meta.permissions().mode()
+        );
+        assert_eq!(after.modified().unwrap(), outside_meta.modified().unwrap());
+        let link_meta = fs::symlink_metadata(&dir_path).unwrap();
+        assert_eq!(
+            UnixTime::from(link_meta.modified().unwrap()),
+            deferred.mtime
+        );
+    }
+
+    #[test]
+    fn ordinary_windows_names_are_unchanged() {
+        for name in &[
+            "hello.txt",
+            "icon.d",
+            "CONSOLE",
+            "lpt10",
+            ".hidden",
+            "caf\u{e9}",
+        ] {
+            assert!(matches!(windows_file_name(name), Cow::Borrowed(n) if n == *name));
+        }
+    }
+
+    #[test]
+    fn illegal_windows_characters_are_escaped() {
+        assert_eq!(windows_file_name("a:b"), "a%3Ab");
+        assert_eq!(windows_file_name("what?*"), "what%3F%2A");
+        assert_eq!(windows_file_name("<\"|>"), "%3C%22%7C%3E");
+        assert_eq!(windows_file_name("back\\slash"), "back%5Cslash");
+        assert_eq!(windows_file_name("tab\there"), "tab%09here");
+    }
+
+    #[test]
+    fn percent_is_escaped() {
+        assert_eq!(windows_file_name("100%"), "100%25");
+        assert_eq!(windows_file_name("a%3Fb"), "a%253Fb");
+        assert_ne!(windows_file_name("a%3Fb"), windows_file_name("a?b"));
+    }
+
+    #[test]
+    fn trailing_dot_or_space_is_escaped() {
+        assert_eq!(windows_file_name("name."), "name%2E");
+        assert_eq!(windows_file_name("name "), "name%20");
+        assert_eq!(windows_file_name("..."), "..%2E");
+    }
+
+    #[test]
+    fn reserved_windows_names_are_escaped() {
+        assert_eq!(windows_file_name("CON"), "%43ON");
+        assert_eq!(windows_file_name("nul.txt"), "%6Eul.txt");
+        assert_eq!(windows_file_name("Com1.tar.gz"), "%43om1.tar.gz");
+        assert_eq!(windows_file_name("aux "), "%61ux%20");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn restore_names_illegal_on_windows() {
+        let af = crate::test_fixtures::ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        let mut ib = band.index_builder();
+        for apath in &["/", "/CON", "/a:b", "/a:b/what?"] {
+            ib.push_entry(IndexEntry {
+                apath: (*apath).into(),
+                kind: Kind::Dir,
+                mtime: 0,
+                mtime_nanos: 0,
+                addrs: Vec::new(),
+                target: None,
+                xattrs: Xattrs::new(),
+                unix_mode: None,
+                changed_during_backup: false,
+                dev: None,
+                ino: None,
+                unknown_fields: UnknownFields::default(),
+            });
+        }
+        let hunks = ib.finish().unwrap().index_hunks;
+        band.close(hunks as u64).unwrap();
+
+        let destdir = crate::test_fixtures::TreeFixture::new();
+        let stats = restore(&af, destdir.path(), &RestoreOptions::default()).expect("restore");
+        assert_eq!(stats.renamed_entries, 3);
+        let dest = destdir.path();
+        assert!(dest.join("%43ON").is_dir());
+        assert!(dest.join("a%3Ab").join("what%3F").is_dir());
+    }
+}
@@ -11,13 +11,16 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io;
 use std::time::Duration;
 
 use derive_more::{Add, AddAssign};
+use serde::{Deserialize, Serialize};
 use thousands::Separable;
 
+use crate::transport::Capacity;
 use crate::ui::duration_to_hms;
 use crate::*;
 
@@ -47,6 +50,11 @@ fn write_compressed_size(w: &mut fmt::Formatter<'_>, compressed: u64, uncompress
     );
 }
 
+/// Write a size in whichever unit is most readable, aligned with counts.
+fn write_human_size(w: &mut fmt::Formatter<'_>, label: &str, bytes: u64) {
+    writeln!(w, "{:>15}   {}", crate::misc::bytes_to_human(bytes), label).unwrap();
+}
+
 fn write_count<I: Into<usize>>(w: &mut fmt::Formatter<'_>, label: &str, value: I) {
     writeln!(
         w,
@@ -93,6 +101,22 @@ pub struct ValidateStats {
     /// Number of blocks that failed to read back.
     pub block_error_count: usize,
     pub block_missing_count: usize,
+    /// Number of index entries whose apath is not well-formed.
+    pub invalid_apath_count: usize,
+    /// Number of stored files that changed while they were being backed up,
+    /// so may not hold a consistent version of the file.
+    pub changed_during_backup_count: usize,
+    /// Problems found in index entries, such as references to missing blocks.
+    pub problems: Problems,
+    /// Number of blocks present but referenced by no band, which could be
+    /// removed by gc.
+    pub unreferenced_block_count: usize,
+    /// Number of index hunks that are missing, unexpected, or don't match
+    /// the hash in their index's manifest.
+    pub index_hunk_problems: usize,
+    /// Number of bands that started before some lower-numbered band, which
+    /// suggests the clock was wrong when one of them was made.
+    pub skewed_bands: usize,
 }
 
 impl ValidateStats {
@@ -107,27 +131,45 @@ impl ValidateStats {
         //         .separate_with_commas(),
         //     duration_to_hms(self.elapsed_time()),
         // )
-        writeln!(write, "{:#?}", self).map_err(Error::from)
+        for problem in self.problems.iter() {
+            writeln!(write, "{}", problem)?;
+        }
+        let counts = ValidateStats {
+            problems: Problems::new(),
+            ..self.clone()
+        };
+        writeln!(write, "{:#?}", counts).map_err(Error::from)
     }
 
     pub fn has_problems(&self) -> bool {
-        self.block_error_count > 0 || self.io_errors > 0 || self.block_missing_count > 0
+        self.block_error_count > 0
+            || self.io_errors > 0
+            || self.block_missing_count > 0
+            || self.invalid_apath_count > 0
+            || self.index_hunk_problems > 0
     }
 }
 
 #[derive(Default, Debug, Clone, Eq, PartialEq)]
 pub struct IndexReadStats {
     pub index_hunks: usize,
+    /// Hunks not read because they're outside the requested subtree.
+    pub index_hunks_skipped: usize,
     pub uncompressed_index_bytes: u64,
     pub compressed_index_bytes: u64,
     pub errors: usize,
 }
 
-#[derive(Add, AddAssign, Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Add, AddAssign, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct IndexWriterStats {
     pub index_hunks: usize,
     pub uncompressed_index_bytes: u64,
     pub compressed_index_bytes: u64,
+    /// Number of entries written to the index.
+    pub index_entries: u64,
+    /// Total size of the files described by the index.
+    pub file_bytes: u64,
 }
 
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
@@ -169,41 +211,103 @@ pub struct CopyStats {
     pub multi_block_files: usize,
 
     pub errors: usize,
+    /// Errors that affected single entries, which were not copied.
+    pub problems: Problems,
+    /// Problems that didn't stop an entry being copied, such as xattrs that
+    /// couldn't be restored.
+    pub warnings: usize,
+    /// Entries restored under a different name, because their name can't be
+    /// created on this platform.
+    pub renamed_entries: usize,
+    /// Symlinks restored as directory junctions, because the process isn't
+    /// allowed to make symlinks.
+    pub symlinks_as_junctions: usize,
+    /// How many entries each exclude pattern matched.
+    pub pattern_matches: PatternStats,
+    /// Symlinks that couldn't be restored at all.
+    pub symlinks_skipped: usize,
+    /// Directories whose mtime or permissions couldn't be set.
+    pub directory_metadata_errors: usize,
+    /// Existing destination entries of a different kind that were removed
+    /// to make way for the restored entry.
+    pub replaced_entries: usize,
 
     pub index_builder_stats: IndexWriterStats,
-    // TODO: Include elapsed time.
+
+    /// Wall-clock time taken by the whole copy.
+    pub elapsed: Duration,
 }
 
 impl CopyStats {
-    pub fn summarize_restore(&self, _to_stream: &mut dyn io::Write) -> Result<()> {
-        // format!(
-        //     "{:>12} MB   in {} files, {} directories, {} symlinks.\n\
-        //      {:>12} MB/s output rate.\n\
-        //      {:>12} MB   after deduplication.\n\
-        //      {:>12} MB   in {} blocks after {:.1}x compression.\n\
-        //      {:>12} MB   in {} compressed index hunks.\n\
-        //      {:>12}      elapsed.\n",
-        //     (self.get_size("file.bytes").uncompressed / M).separate_with_commas(),
-        //     self.get_count("file").separate_with_commas(),
-        //     self.get_count("dir").separate_with_commas(),
-        //     self.get_count("symlink").separate_with_commas(),
-        //     (mbps_rate(
-        //         self.get_size("file.bytes").uncompressed,
-        //         self.elapsed_time()
-        //     ) as u64)
-        //         .separate_with_commas(),
-        //     (self.get_size("block").uncompressed / M).separate_with_commas(),
-        //     (self.get_size("block").compressed / M).separate_with_commas(),
-        //     self.get_count("block.read").separate_with_commas(),
-        //     compression_ratio(&self.get_size("block")),
-        //     (self.get_size("index").compressed / M).separate_with_commas(),
-        //     self.get_count("index.hunk").separate_with_commas(),
-        //     duration_to_hms(self.elapsed_time()),
+    /// Describe the stats as an aligned table, as shown after a restore.
+    pub fn summary(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn summarize_restore(&self, to_stream: &mut dyn io::Write) -> Result<()> {
+        write!(to_stream, "{}", self).map_err(Error::from)
+    }
+}
+
+impl fmt::Display for CopyStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "files:", self.files);
+        write_count(w, "  empty files", self.empty_files);
+        write_count(w, "directories", self.directories);
+        write_count(w, "symlinks", self.symlinks);
+        write_count(w, "unsupported file kind", self.unknown_kind);
+        writeln!(w)?;
+
+        write_human_size(w, "file content copied", self.uncompressed_bytes);
+        if self.compressed_bytes > 0 {
+            write_human_size(
+                w,
+                &format!(
+                    "compressed, {:.1}x",
+                    ratio(self.uncompressed_bytes, self.compressed_bytes)
+                ),
+                self.compressed_bytes,
+            );
+        }
+        if self.deduplicated_bytes > 0 {
+            write_human_size(w, "deduplicated", self.deduplicated_bytes);
+        }
+        writeln!(
+            w,
+            "{:>15}   rate",
+            format!(
+                "{:.1} MB/s",
+                ui::mbps_rate(self.uncompressed_bytes, self.elapsed)
+            )
+        )?;
+        write_duration(w, "elapsed", self.elapsed)?;
+        writeln!(w)?;
+
+        // These are rare, or only relevant on some platforms, so are only
+        // shown if they happened.
+        for (label, count) in &[
+            ("renamed entries", self.renamed_entries),
+            ("symlinks restored as junctions", self.symlinks_as_junctions),
+            ("symlinks skipped", self.symlinks_skipped),
+            ("directory metadata errors", self.directory_metadata_errors),
+            ("replaced entries of another kind", self.replaced_entries),
+        ] {
+            if *count > 0 {
+                write_count(w, label, *count);
+            }
+        }
+        write_count(w, "warnings", self.warnings);
+        write_count(w, "errors", self.errors);
         Ok(())
     }
 }
 
-#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone)]
+/// Counts of what was stored by a backup.
+///
+/// These are also recorded in the band, so they can be shown later by
+/// `conserve stats`.
+#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BackupStats {
     // TODO: Have separate more-specific stats for backup and restore, and then
     // each can have a single Display method.
@@ -233,7 +337,33 @@ pub struct BackupStats {
     pub single_block_files: usize,
     pub multi_block_files: usize,
 
+    /// Extended attributes that exist on source files but couldn't be read,
+    /// typically because they're in a privileged namespace.
+    pub unreadable_xattrs: usize,
+
+    /// Files whose size or mtime changed while they were read, and that were
+    /// read again.
+    pub changed_files_retried: usize,
+    /// Files that were still changing after all retries, and were stored
+    /// marked as changed during backup.
+    pub changed_during_backup: usize,
+    /// Files that were moved or renamed since the last backup, whose stored
+    /// content was reused without reading them.
+    pub moved_files: usize,
+    /// Files skipped because they're larger than the `max_file_size` option.
+    pub oversized_files: usize,
+    /// In a dry run that doesn't read files, the total size of new and
+    /// modified files, which is the most new data the backup could store.
+    pub unread_bytes: u64,
+
+    /// How many entries each exclude pattern matched.
+    #[serde(skip_serializing_if = "PatternStats::is_empty")]
+    pub pattern_matches: PatternStats,
+
     pub errors: usize,
+    /// Errors that affected single entries, which were skipped.
+    #[serde(skip_serializing_if = "Problems::is_empty")]
+    pub problems: Problems,
 
     pub index_builder_stats: IndexWriterStats,
     // TODO: Include elapsed time.
@@ -245,11 +375,25 @@ impl fmt::Display for BackupStats {
         write_count(w, "  unmodified files", self.unmodified_files);
         write_count(w, "  modified files", self.modified_files);
         write_count(w, "  new files", self.new_files);
+        write_count(w, "  moved files, blocks reused", self.moved_files);
         write_count(w, "symlinks", self.symlinks);
         write_count(w, "directories", self.directories);
         write_count(w, "unsupported file kind", self.unknown_kind);
+        write_count(w, "unreadable xattrs", self.unreadable_xattrs);
+        write_count(
+            w,
+            "files re-read after changing",
+            self.changed_files_retried,
+        );
+        write_count(w, "files changed during backup", self.changed_during_backup);
+        write_count(w, "files too large, skipped", self.oversized_files);
         writeln!(w).unwrap();
 
+        if self.unread_bytes > 0 {
+            write_size(w, "new and modified files, not read", self.unread_bytes);
+            writeln!(w).unwrap();
+        }
+
         write_count(w, "files stored:", self.new_files + self.modified_files);
         write_count(w, "  empty files", self.empty_files);
         write_count(w, "  small combined files", self.small_combined_files);
@@ -277,6 +421,273 @@ impl fmt::Display for BackupStats {
     }
 }
 
+/// How many entries were matched by each exclude pattern, in the order the
+/// patterns were given.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PatternStats(Vec<PatternCount>);
+
+/// The number of entries matched by one pattern.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PatternCount {
+    pub pattern: String,
+    pub matches: usize,
+}
+
+impl PatternStats {
+    /// Add matches for a pattern, merging them with any already counted for
+    /// the same pattern.
+    pub fn push(&mut self, pattern: &str, matches: usize) {
+        match self.0.iter_mut().find(|pc| pc.pattern == pattern) {
+            Some(pc) => pc.matches += matches,
+            None => self.0.push(PatternCount {
+                pattern: pattern.to_owned(),
+                matches,
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PatternCount> {
+        self.0.iter()
+    }
+
+    /// Return the patterns that matched no entries.
+    pub fn unmatched(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .iter()
+            .filter(|pc| pc.matches == 0)
+            .map(|pc| pc.pattern.as_str())
+    }
+
+    /// Warn through the UI about each pattern that matched no entries.
+    pub fn warn_unmatched(&self) {
+        for pattern in self.unmatched() {
+            crate::ui::report_problem(Problem::new(
+                ProblemKind::UnmatchedPattern,
+                None,
+                format!("Exclude pattern {:?} matched nothing", pattern),
+            ));
+        }
+    }
+}
+
+impl std::ops::Add for PatternStats {
+    type Output = PatternStats;
+
+    fn add(mut self, other: PatternStats) -> PatternStats {
+        self += other;
+        self
+    }
+}
+
+impl std::ops::AddAssign for PatternStats {
+    fn add_assign(&mut self, other: PatternStats) {
+        for pc in other.0 {
+            self.push(&pc.pattern, pc.matches)
+        }
+    }
+}
+
+/// Storage used by a whole archive, from `Archive::summary`.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+pub struct ArchiveSummary {
+    /// Number of data blocks present.
+    pub block_count: usize,
+    /// Total size of the block files, after compression.
+    pub compressed_block_bytes: u64,
+    /// The latest complete band, if any.
+    pub latest_band: Option<BandId>,
+    /// Total size of the files in the latest complete band.
+    pub latest_logical_bytes: u64,
+}
+
+impl ArchiveSummary {
+    /// Bytes of files in the latest backup for each byte stored in blocks,
+    /// which reflects both compression and deduplication.
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.latest_logical_bytes, self.compressed_block_bytes)
+    }
+}
+
+impl fmt::Display for ArchiveSummary {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "data blocks", self.block_count);
+        write_size(w, "stored in blocks", self.compressed_block_bytes);
+        match &self.latest_band {
+            Some(band_id) => {
+                write_size(
+                    w,
+                    &format!("in latest backup {}", band_id),
+                    self.latest_logical_bytes,
+                );
+                writeln!(
+                    w,
+                    "{:>11.1}x      latest backup size per stored byte",
+                    self.dedup_ratio()
+                )?;
+            }
+            None => writeln!(w, "{:>12}      complete backups", "no")?,
+        }
+        Ok(())
+    }
+}
+
+/// What `preflight` found out about an archive it could write to.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+pub struct PreflightReport {
+    /// Space on the archive's storage, if the transport can tell.
+    pub capacity: Option<Capacity>,
+    /// Total size of the blocks written by the recorded backups, which
+    /// approximates the archive's size without listing every block.
+    pub estimated_archive_bytes: u64,
+    /// Size of the blocks written by the most recent backup with recorded
+    /// stats, as an estimate for the next one.
+    pub last_backup_bytes: u64,
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_size(w, "estimated archive size", self.estimated_archive_bytes);
+        write_size(w, "written by last backup", self.last_backup_bytes);
+        match &self.capacity {
+            Some(capacity) => {
+                write_size(w, "available", capacity.available);
+                write_size(w, "total capacity", capacity.total);
+            }
+            None => writeln!(w, "{:>12}      available", "unknown")?,
+        }
+        Ok(())
+    }
+}
+
+/// How much of the archive's block storage is used by one band, from
+/// `Archive::band_usage`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BandUsage {
+    /// Compressed size of the blocks referenced by this band and no other,
+    /// which would be freed by deleting the band and then running gc.
+    pub unique_bytes: u64,
+    /// Compressed size of the blocks this band shares with at least one
+    /// other band.
+    pub shared_bytes: u64,
+}
+
+/// Describes how the blocks in an archive are used, without changing anything.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct BlockReport {
+    /// Number of blocks present in the blockdir.
+    pub present_blocks: usize,
+    /// Number of distinct blocks referenced by any band.
+    pub referenced_blocks: usize,
+    /// Number of blocks referenced by some band but not present.
+    pub missing_blocks: usize,
+    /// Blocks present but referenced by no band, in order.
+    pub unreferenced_blocks: Vec<BlockHash>,
+    /// Compressed size of the unreferenced blocks, which gc would free.
+    pub reclaimable_bytes: u64,
+    /// For each number of bands, how many blocks are referenced by exactly
+    /// that many bands.
+    pub bands_per_block: BTreeMap<usize, usize>,
+    /// Files in the blockdir that aren't blocks, relative to the blockdir.
+    pub unexpected_files: Vec<String>,
+}
+
+impl fmt::Display for BlockReport {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "blocks present", self.present_blocks);
+        write_count(w, "blocks referenced", self.referenced_blocks);
+        write_count(w, "  missing", self.missing_blocks);
+        write_count(w, "unreferenced blocks", self.unreferenced_blocks.len());
+        write_human_size(w, "  reclaimable by gc", self.reclaimable_bytes);
+        writeln!(w)?;
+
+        for (bands, blocks) in &self.bands_per_block {
+            let plural = if *bands == 1 { "" } else { "s" };
+            write_count(
+                w,
+                &format!("referenced by {} band{}", bands, plural),
+                *blocks,
+            );
+        }
+        if !self.bands_per_block.is_empty() {
+            writeln!(w)?;
+        }
+
+        write_count(w, "unexpected files", self.unexpected_files.len());
+        for name in &self.unexpected_files {
+            writeln!(w, "{:>12}      {}", "", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts and compressed sizes of the blocks in a blockdir, from
+/// `BlockDir::stats`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct BlockDirStats {
+    /// Number of blocks present.
+    pub block_count: usize,
+    /// Total compressed size of all the blocks.
+    pub total_bytes: u64,
+    /// Compressed size of the smallest block, or 0 if there are none.
+    pub min_block_bytes: u64,
+    /// Compressed size of the largest block.
+    pub max_block_bytes: u64,
+    /// Mean compressed block size, rounded down.
+    pub mean_block_bytes: u64,
+    /// For each power of two, the number of blocks at least that large but
+    /// smaller than the next power of two. Empty blocks are counted under 0.
+    pub size_histogram: BTreeMap<u64, usize>,
+}
+
+impl BlockDirStats {
+    /// Count one more block of the given compressed size.
+    pub(crate) fn add_block(&mut self, bytes: u64) {
+        if self.block_count == 0 || bytes < self.min_block_bytes {
+            self.min_block_bytes = bytes;
+        }
+        self.max_block_bytes = self.max_block_bytes.max(bytes);
+        self.block_count += 1;
+        self.total_bytes += bytes;
+        self.mean_block_bytes = self.total_bytes / self.block_count as u64;
+        let bucket = if bytes == 0 {
+            0
+        } else {
+            1 << (u64::BITS - 1 - bytes.leading_zeros())
+        };
+        *self.size_histogram.entry(bucket).or_default() += 1;
+    }
+}
+
+impl fmt::Display for BlockDirStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "blocks", self.block_count);
+        write_human_size(w, "total compressed size", self.total_bytes);
+        write_human_size(w, "smallest block", self.min_block_bytes);
+        write_human_size(w, "mean block", self.mean_block_bytes);
+        write_human_size(w, "largest block", self.max_block_bytes);
+        writeln!(w)?;
+
+        for (&bucket, &blocks) in &self.size_histogram {
+            let label = if bucket == 0 {
+                "empty".to_owned()
+            } else {
+                format!(
+                    "{} to {} bytes",
+                    bucket.separate_with_commas(),
+                    (bucket * 2 - 1).separate_with_commas()
+                )
+            };
+            write_count(w, &label, blocks);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct DeleteStats {
     pub deleted_band_count: usize,
@@ -307,3 +718,61 @@ impl fmt::Display for DeleteStats {
         Ok(())
     }
 }
+
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SyncStats {
+    pub bands_copied: usize,
+    /// Bands that were already complete in the destination.
+    pub bands_already_present: usize,
+    /// Incomplete bands in the source, which are not copied.
+    pub incomplete_bands_skipped: usize,
+    pub index_hunks_copied: usize,
+    pub blocks_copied: usize,
+    /// Referenced blocks that were already in the destination.
+    pub blocks_already_present: usize,
+    /// Compressed size of the blocks that were copied.
+    pub copied_block_bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for SyncStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "bands copied", self.bands_copied);
+        write_count(w, "bands already present", self.bands_already_present);
+        write_count(w, "incomplete bands skipped", self.incomplete_bands_skipped);
+        write_count(w, "index hunks copied", self.index_hunks_copied);
+        writeln!(w)?;
+
+        write_count(w, "blocks copied", self.blocks_copied);
+        write_size(w, "  copied, compressed", self.copied_block_bytes);
+        write_count(w, "blocks already present", self.blocks_already_present);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MigrateStats {
+    pub header_upgraded: usize,
+    pub bands_examined: usize,
+    pub band_heads_upgraded: usize,
+    pub band_tails_upgraded: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for MigrateStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "archive header upgraded", self.header_upgraded);
+        write_count(w, "bands examined", self.bands_examined);
+        write_count(w, "  band heads upgraded", self.band_heads_upgraded);
+        write_count(w, "  band tails upgraded", self.band_tails_upgraded);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
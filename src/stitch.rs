@@ -28,7 +28,7 @@
 //!   seen.
 //! * Bands might be deleted, so their numbers are not contiguous.
 
-use crate::index::IndexEntryIter;
+use crate::index::{IndexEntryIter, IndexHunkIter};
 use crate::*;
 
 pub struct IterStitchedIndexHunks {
@@ -39,7 +39,13 @@ pub struct IterStitchedIndexHunks {
     last_apath: Option<Apath>,
 
     /// Currently pending index hunks.
-    index_hunks: Option<crate::index::IndexHunkIter>,
+    index_hunks: Option<IndexHunkIter>,
+
+    /// If set, return only entries within this subtree.
+    subtree: Option<Apath>,
+
+    /// If true, report index hunks that can't be read and continue past them.
+    lenient: bool,
 
     archive: Archive,
 }
@@ -51,9 +57,36 @@ impl IterStitchedIndexHunks {
             band_id: band_id.clone(),
             last_apath: None,
             index_hunks: None,
+            subtree: None,
+            lenient: false,
+        }
+    }
+
+    /// Return only entries within `subtree`, skipping index hunks outside it
+    /// where possible.
+    pub fn subtree(self, subtree: &Apath) -> IterStitchedIndexHunks {
+        IterStitchedIndexHunks {
+            subtree: Some(subtree.clone()),
+            ..self
+        }
+    }
+
+    /// Report index hunks that can't be read and continue past them, rather
+    /// than stopping; see [IndexHunkIter::lenient](crate::index::IndexHunkIter::lenient).
+    pub fn lenient(self) -> IterStitchedIndexHunks {
+        IterStitchedIndexHunks {
+            lenient: true,
+            ..self
         }
     }
 
+    /// Take the error that stopped iteration, if any.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.index_hunks
+            .as_mut()
+            .and_then(IndexHunkIter::take_error)
+    }
+
     pub fn iter_entries(self) -> IndexEntryIter<IterStitchedIndexHunks> {
         IndexEntryIter::new(self)
     }
@@ -66,13 +99,24 @@ impl Iterator for IterStitchedIndexHunks {
         loop {
             // If we're already reading an index, and it has more content, return that.
             if let Some(index_hunks) = &mut self.index_hunks {
-                for hunk in index_hunks {
-                    if let Some(last_entry) = hunk.last() {
-                        self.last_apath = Some(last_entry.apath().clone());
-                        return Some(hunk);
-                    } // otherwise, empty, try the next
+                // Remember the last apath seen in this band, even if it was
+                // outside the subtree, so that older bands don't contribute
+                // entries this band has already covered.
+                let hunk = index_hunks.next();
+                if let Some(last_apath) = index_hunks.last_apath() {
+                    self.last_apath = Some(last_apath.clone());
                 }
-                if self.archive.band_is_closed(&self.band_id).unwrap_or(false) {
+                if let Some(hunk) = hunk {
+                    return Some(hunk);
+                }
+                if index_hunks.has_failed() {
+                    // Don't fill in from older bands what this band should
+                    // have held.
+                    return None;
+                }
+                if index_hunks.is_past_subtree()
+                    || self.archive.band_is_closed(&self.band_id).unwrap_or(false)
+                {
                     return None;
                 }
                 self.index_hunks = None;
@@ -90,6 +134,12 @@ impl Iterator for IterStitchedIndexHunks {
             if let Some(last) = &self.last_apath {
                 iter_hunks = iter_hunks.advance_to_after(last)
             }
+            if let Some(subtree) = &self.subtree {
+                iter_hunks = iter_hunks.subtree(subtree)
+            }
+            if self.lenient {
+                iter_hunks = iter_hunks.lenient()
+            }
             self.index_hunks = Some(iter_hunks);
         }
     }
@@ -122,6 +172,12 @@ mod test {
             mtime: 0,
             mtime_nanos: 0,
             addrs: Vec::new(),
+            xattrs: Xattrs::new(),
+            unix_mode: None,
+            changed_during_backup: false,
+            dev: None,
+            ino: None,
+            unknown_fields: UnknownFields::default(),
         }
     }
 
@@ -223,4 +279,48 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn stitch_subtree() -> Result<()> {
+        let af = ScratchArchive::new();
+
+        // b0 is complete; b1 is incomplete, and was interrupted in the middle of /a.
+        let band = Band::create(&af)?;
+        let mut ib = band.index_builder();
+        for apath in &["/a", "/b", "/c", "/a/x", "/a/y", "/b/z", "/c/w"] {
+            ib.push_entry(symlink(apath, "b0"));
+            ib.finish_hunk()?;
+        }
+        let stats = ib.finish()?;
+        band.close(stats.index_hunks as u64)?;
+
+        let band = Band::create(&af)?;
+        let mut ib = band.index_builder();
+        ib.push_entry(symlink("/a", "b1"));
+        ib.push_entry(symlink("/c", "b1"));
+        ib.finish_hunk()?;
+        ib.push_entry(symlink("/a/x", "b1"));
+        ib.finish_hunk()?;
+
+        let archive = Archive::open_path(&af.path())?;
+        let subtree_ls = |band_id: &BandId, subtree: &str| -> String {
+            let strs: Vec<String> = archive
+                .iter_stitched_index_hunks(band_id)
+                .subtree(&subtree.into())
+                .flatten()
+                .map(|entry| format!("{}:{}", &entry.apath, entry.target.unwrap()))
+                .collect();
+            strs.join(" ")
+        };
+        assert_eq!(subtree_ls(&BandId::new(&[0]), "/b"), "/b:b0 /b/z:b0");
+        assert_eq!(
+            subtree_ls(&BandId::new(&[1]), "/a"),
+            "/a:b1 /a/x:b1 /a/y:b0"
+        );
+        // /b was deleted before b1 was interrupted, but its contents are
+        // carried over from b0.
+        assert_eq!(subtree_ls(&BandId::new(&[1]), "/b"), "/b/z:b0");
+        assert_eq!(subtree_ls(&BandId::new(&[1]), "/c"), "/c:b1 /c/w:b0");
+        Ok(())
+    }
 }
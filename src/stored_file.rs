@@ -1,4 +1,4 @@
-// Copyright 2017, 2018, 2019 Martin Pool.
+// Copyright 2017, 2018, 2019, 2021 Martin Pool.
 
 // This program is free software; you can redistribute it and/or modify
 // it under the terms of the GNU General Public License as published by
@@ -11,6 +11,8 @@
 // GNU General Public License for more details.
 
 ///! Access a file stored in the archive.
+use std::io;
+
 use crate::stats::Sizes;
 use crate::*;
 
@@ -31,12 +33,22 @@ impl StoredFile {
         StoredFile { block_dir, addrs }
     }
 
-    /// Open a cursor on this file that implements `std::io::Read`.
+    /// Open a cursor on this file that implements `std::io::Read` and
+    /// `std::io::Seek`.
     pub(crate) fn into_read(self) -> ReadStoredFile {
+        let mut block_starts = Vec::with_capacity(self.addrs.len());
+        let mut len = 0;
+        for addr in &self.addrs {
+            block_starts.push(len);
+            len += addr.len;
+        }
         ReadStoredFile {
-            remaining_addrs: self.addrs.into_iter(),
-            buf: Vec::<u8>::new(),
-            buf_cursor: 0,
+            addrs: self.addrs,
+            block_starts,
+            len,
+            pos: 0,
+            buf: Vec::new(),
+            buf_index: None,
             block_dir: self.block_dir,
         }
     }
@@ -52,46 +64,196 @@ impl ReadBlocks for StoredFile {
     }
 }
 
-/// Adapt a StoredFile to `std::io::Read`, which requires keeping a cursor position.
+/// Adapt a StoredFile to `std::io::Read` and `std::io::Seek`.
+///
+/// Only the block holding the current position is read and decompressed, so
+/// seeking into a large file doesn't read the blocks before it.
 pub struct ReadStoredFile {
-    /// Block addresses remaining to be read.
-    remaining_addrs: std::vec::IntoIter<blockdir::Address>,
+    /// All addresses for this file.
+    addrs: Vec<blockdir::Address>,
 
-    // TODO: buf, buf_cursor, remaining_addrs all really belong in some kind of `Read` adapter, not
-    // the StoredFile itself.
-    /// Already-read but not yet returned data.
-    buf: Vec<u8>,
+    /// The offset within the file where each address's content starts.
+    block_starts: Vec<u64>,
 
-    /// How far through buf has been returned?
-    buf_cursor: usize,
+    /// Total length of the file.
+    len: u64,
+
+    /// Current position within the file.
+    pos: u64,
+
+    /// Content of the address `buf_index`, if any has been read.
+    buf: Vec<u8>,
+    buf_index: Option<usize>,
 
     block_dir: BlockDir,
 }
 
-impl std::io::Read for ReadStoredFile {
-    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+impl ReadStoredFile {
+    /// The length of the file, from the index, without reading any blocks.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Make sure the content of address `i` is in `buf`.
+    fn load(&mut self, i: usize) -> io::Result<()> {
+        if self.buf_index == Some(i) {
+            return Ok(());
+        }
+        // TODO: Remember the sizes somewhere, maybe by changing this not to be
+        // std::io::Read.
+        // TODO: Read directly into the caller's buffer, if it will fit. Requires changing
+        // BlockDir::get to take a caller-provided buffer.
+        let (buf, _sizes) = self
+            .block_dir
+            .get(&self.addrs[i])
+            .map_err(io::Error::other)?;
+        if buf.len() as u64 != self.addrs[i].len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Wrong length of content from {:?}", self.addrs[i]),
+            ));
+        }
+        self.buf = buf;
+        self.buf_index = Some(i);
+        Ok(())
+    }
+}
+
+impl io::Read for ReadStoredFile {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
         // TODO: Readahead n_cpus blocks into memory, using futures-cpupool or similar.
-        loop {
-            // If there's already buffered data, return as much of that as will fit.
-            let avail = self.buf.len() - self.buf_cursor;
-            if avail > 0 {
-                let s = std::cmp::min(out.len(), avail);
-                let r = &self.buf[self.buf_cursor..self.buf_cursor + s];
-                out[..s].copy_from_slice(r);
-                self.buf_cursor += s;
-                return Ok(s);
-            } else if let Some(addr) = self.remaining_addrs.next() {
-                // TODO: Handle errors nicely, but they need to convert to std::io::Error.
-                // TODO: Remember the sizes somewhere, maybe by changing this not to be
-                // std::io::Read.
-                self.buf = self.block_dir.get(&addr).unwrap().0;
-                self.buf_cursor = 0;
-            // TODO: Read directly into the caller's buffer, if it will fit. Requires changing
-            // BlockDir::get to take a caller-provided buffer.
-            } else {
-                // No data buffered and no more to read, end of file.
-                return Ok(0);
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+        // The last address starting at or before the position; this skips any
+        // empty addresses.
+        let i = self
+            .block_starts
+            .partition_point(|&start| start <= self.pos)
+            - 1;
+        self.load(i)?;
+        let buf_cursor = (self.pos - self.block_starts[i]) as usize;
+        let s = std::cmp::min(out.len(), self.buf.len() - buf_cursor);
+        out[..s].copy_from_slice(&self.buf[buf_cursor..buf_cursor + s]);
+        self.pos += s as u64;
+        Ok(s)
+    }
+}
+
+impl io::Seek for ReadStoredFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(delta) => offset_by(self.len, delta),
+            io::SeekFrom::Current(delta) => offset_by(self.pos, delta),
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
             }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )),
         }
     }
 }
+
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Store `content` as three blocks, the last of them taken from the
+    /// middle of a larger block.
+    fn three_block_file(content: &[u8]) -> (TempDir, ReadStoredFile) {
+        let testdir = TempDir::new().unwrap();
+        let mut block_dir = BlockDir::create_path(testdir.path()).unwrap();
+        let mut stats = BackupStats::default();
+        let (a, rest) = content.split_at(1000);
+        let (b, c) = rest.split_at(1000);
+        let mut padded_c = b"prefix".to_vec();
+        padded_c.extend_from_slice(c);
+        padded_c.extend_from_slice(b"suffix");
+        let mut addrs = Vec::new();
+        for (data, start, len) in &[
+            (a, 0, a.len()),
+            (b, 0, b.len()),
+            (&padded_c[..], 6, c.len()),
+        ] {
+            addrs.push(blockdir::Address {
+                hash: block_dir.store_or_deduplicate(data, &mut stats).unwrap(),
+                start: *start,
+                len: *len as u64,
+            });
+        }
+        let file = StoredFile::open(block_dir, addrs).into_read();
+        (testdir, file)
+    }
+
+    fn sample_content() -> Vec<u8> {
+        (0..2500u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_whole_file() {
+        let content = sample_content();
+        let (_testdir, mut file) = three_block_file(&content);
+        assert_eq!(file.len(), 2500);
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, content);
+    }
+
+    #[test]
+    fn seek_and_read_across_block_boundary() {
+        let content = sample_content();
+        let (_testdir, mut file) = three_block_file(&content);
+        assert_eq!(file.seek(SeekFrom::Start(900)).unwrap(), 900);
+        let mut buf = vec![0; 1200];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &content[900..2100]);
+
+        assert_eq!(file.seek(SeekFrom::Current(-1500)).unwrap(), 600);
+        let mut buf = vec![0; 10];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &content[600..610]);
+
+        assert_eq!(file.seek(SeekFrom::End(-5)).unwrap(), 2495);
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, &content[2495..]);
+    }
+
+    #[test]
+    fn seek_beyond_end_reads_nothing() {
+        let (_testdir, mut file) = three_block_file(&sample_content());
+        assert_eq!(file.seek(SeekFrom::End(100)).unwrap(), 2600);
+        assert_eq!(file.read(&mut [0; 10]).unwrap(), 0);
+    }
+
+    #[test]
+    fn seek_before_start_is_an_error() {
+        let (_testdir, mut file) = three_block_file(&sample_content());
+        file.seek(SeekFrom::Start(10)).unwrap();
+        let err = file.seek(SeekFrom::Current(-11)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(file.stream_position().unwrap(), 10);
+    }
+}
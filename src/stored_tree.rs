@@ -19,17 +19,26 @@
 //! multiple index files, bands, and blocks.
 
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::vec;
 
 use crate::blockdir::BlockDir;
 use crate::kind::Kind;
+use crate::stitch::IterStitchedIndexHunks;
 use crate::stored_file::{ReadStoredFile, StoredFile};
 use crate::*;
 
 /// Read index and file contents for a version stored in the archive.
+///
+/// `StoredTree::iter_entries`, and reading file contents through
+/// [ReadTree::file_contents], are part of the stable API.
 pub struct StoredTree {
     band: Band,
     archive: Archive,
     block_dir: BlockDir,
+    /// The error that stopped the last [ReadTree] iteration short, since
+    /// those iterators can't return it themselves.
+    iter_error: Arc<Mutex<Option<Error>>>,
 }
 
 impl StoredTree {
@@ -38,6 +47,7 @@ impl StoredTree {
             band: Band::open(archive, band_id)?,
             block_dir: archive.block_dir().clone(),
             archive: archive.clone(),
+            iter_error: Arc::default(),
         })
     }
 
@@ -49,33 +59,117 @@ impl StoredTree {
         self.band.is_closed()
     }
 
-    pub fn validate(
+    /// Iterate, in apath order, the entries within `subtree` (or the whole
+    /// tree) that don't match `excludes`.
+    ///
+    /// Index hunks wholly outside the subtree are skipped where possible.
+    ///
+    /// If part of the index can't be read, an error is returned and iteration
+    /// stops.
+    ///
+    /// This is part of the stable API.
+    pub fn iter_entries(
+        &self,
+        subtree: Option<&Apath>,
+        excludes: &Exclude,
+    ) -> impl Iterator<Item = Result<IndexEntry>> {
+        let excludes = excludes.clone();
+        StoredEntryIter::new(self.iter_stitched(subtree)).filter(move |entry| match entry {
+            Ok(entry) => !excludes.is_excluded(&entry.apath, entry.kind()),
+            Err(_) => true,
+        })
+    }
+
+    /// Iterate all the entries, reporting index hunks that can't be read and
+    /// continuing past them, for validation.
+    fn iter_entries_lenient(&self) -> impl Iterator<Item = IndexEntry> {
+        self.iter_stitched(None).lenient().flatten()
+    }
+
+    /// Return the entry for one apath, or None if it's not in this tree.
+    ///
+    /// Only the index hunks that might hold the apath are read, if the index
+    /// has a hunk manifest. If one of them can't be read, that's an error
+    /// rather than None.
+    pub fn entry(&self, apath: &Apath) -> Result<Option<IndexEntry>> {
+        // The subtree starts with the entry itself, if it's present.
+        match StoredEntryIter::new(self.iter_stitched(Some(apath))).next() {
+            Some(Ok(entry)) if entry.apath == *apath => Ok(Some(entry)),
+            Some(Err(err)) => Err(err),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_stitched(&self, subtree: Option<&Apath>) -> IterStitchedIndexHunks {
+        let hunks = self.archive.iter_stitched_index_hunks(self.band.id());
+        match subtree {
+            Some(subtree) => hunks.subtree(subtree),
+            None => hunks,
+        }
+    }
+
+    /// Check the index of this tree, and return each block it references,
+    /// with the furthest offset into the block that any entry reads.
+    pub fn validate(&self, stats: &mut ValidateStats) -> Result<HashMap<BlockHash, u64>> {
+        let band_id = self.band().id();
+        let mut extents: HashMap<BlockHash, u64> = HashMap::new();
+        for entry in self.iter_entries_lenient() {
+            if let Err(err) = entry.apath.check_valid() {
+                stats.problems.push(Problem {
+                    kind: ProblemKind::Index,
+                    apath: None,
+                    category: err.category(),
+                    message: format!("{} in {}", err, band_id),
+                });
+                stats.invalid_apath_count += 1;
+                continue;
+            }
+            if entry.kind() != Kind::File {
+                continue;
+            }
+            if entry.changed_during_backup {
+                stats.changed_during_backup_count += 1;
+            }
+            for addr in entry.addrs {
+                let end = extents.entry(addr.hash).or_default();
+                *end = (*end).max(addr.start + addr.len);
+            }
+        }
+        Ok(extents)
+    }
+
+    /// Report each address in this tree that points to a missing block, or
+    /// beyond the end of its block.
+    ///
+    /// `block_lengths` gives the uncompressed length of every block that
+    /// could be read.
+    pub fn validate_addresses(
         &self,
         block_lengths: &HashMap<BlockHash, usize>,
         stats: &mut ValidateStats,
     ) -> Result<()> {
         let band_id = self.band().id();
-        for entry in self
-            .iter_entries()?
-            .filter(|entry| entry.kind() == Kind::File)
-        {
+        for entry in self.iter_entries_lenient() {
+            if entry.kind() != Kind::File || entry.apath.check_valid().is_err() {
+                continue;
+            }
             for addr in entry.addrs {
-                if let Some(block_len) = block_lengths.get(&addr.hash) {
+                let message = match block_lengths.get(&addr.hash) {
                     // Present, but the address is out of range.
-                    if (addr.start + addr.len) > (*block_len as u64) {
-                        ui::problem(&format!(
-                            "Address {:?} in {:?} in {:?} extends beyond compressed data length {}",
-                            addr, &entry.apath, band_id, block_len
-                        ));
-                        stats.block_missing_count += 1;
-                    }
-                } else {
-                    ui::problem(&format!(
-                        "Address {:?} in {:?} in {:?} points to missing block",
-                        &entry.apath, band_id, addr
-                    ));
-                    stats.block_missing_count += 1;
-                }
+                    Some(block_len) if (addr.start + addr.len) > (*block_len as u64) => format!(
+                        "Address {:?} in {} extends beyond compressed data length {}",
+                        addr, band_id, block_len
+                    ),
+                    Some(_) => continue,
+                    None => format!("Address {:?} in {} points to missing block", addr, band_id),
+                };
+                stats.problems.push(Problem {
+                    kind: ProblemKind::Block,
+                    apath: Some(entry.apath.clone()),
+                    category: ErrorCategory::PerEntry,
+                    message,
+                });
+                stats.block_missing_count += 1;
             }
         }
         Ok(())
@@ -85,6 +179,54 @@ impl StoredTree {
     fn open_stored_file(&self, entry: &IndexEntry) -> StoredFile {
         StoredFile::open(self.block_dir.clone(), entry.addrs.clone())
     }
+
+    /// Iterate entries for [ReadTree], keeping any error for
+    /// [ReadTree::take_iter_error].
+    fn iter_keeping_error(
+        &self,
+        subtree: Option<&Apath>,
+    ) -> impl Iterator<Item = IndexEntry> + 'static {
+        let iter_error = self.iter_error.clone();
+        StoredEntryIter::new(self.iter_stitched(subtree)).map_while(move |entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                *iter_error.lock().unwrap() = Some(err);
+                None
+            }
+        })
+    }
+}
+
+/// Iterate the entries from stitched index hunks, returning the error that
+/// stopped them, if any, as the last item.
+struct StoredEntryIter {
+    hunks: IterStitchedIndexHunks,
+    entries: vec::IntoIter<IndexEntry>,
+}
+
+impl StoredEntryIter {
+    fn new(hunks: IterStitchedIndexHunks) -> StoredEntryIter {
+        StoredEntryIter {
+            hunks,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for StoredEntryIter {
+    type Item = Result<IndexEntry>;
+
+    fn next(&mut self) -> Option<Result<IndexEntry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            match self.hunks.next() {
+                Some(hunk) => self.entries = hunk.into_iter(),
+                None => return self.hunks.take_error().map(Err),
+            }
+        }
+    }
 }
 
 impl ReadTree for StoredTree {
@@ -93,17 +235,35 @@ impl ReadTree for StoredTree {
 
     /// Return an iter of index entries in this stored tree.
     fn iter_entries(&self) -> Result<Box<dyn Iterator<Item = index::IndexEntry>>> {
-        Ok(Box::new(
-            self.archive
-                .iter_stitched_index_hunks(self.band.id())
-                .flatten(),
-        ))
+        Ok(Box::new(self.iter_keeping_error(None)))
+    }
+
+    /// Return entries within the subtree and not excluded.
+    ///
+    /// Index hunks wholly outside the subtree are skipped where possible.
+    fn iter_filtered(
+        &self,
+        subtree: Option<Apath>,
+        excludes: Option<Exclude>,
+    ) -> Result<Box<dyn Iterator<Item = index::IndexEntry>>> {
+        Ok(Box::new(self.iter_keeping_error(subtree.as_ref()).filter(
+            move |entry| {
+                excludes
+                    .as_ref()
+                    .map(|e| !e.is_excluded(&entry.apath, entry.kind()))
+                    .unwrap_or(true)
+            },
+        )))
     }
 
     fn file_contents(&self, entry: &Self::Entry) -> Result<Self::R> {
         Ok(self.open_stored_file(entry).into_read())
     }
 
+    fn take_iter_error(&self) -> Option<Error> {
+        self.iter_error.lock().unwrap().take()
+    }
+
     fn estimate_count(&self) -> Result<u64> {
         self.band.index().estimate_entry_count()
     }
@@ -126,7 +286,10 @@ mod test {
 
         assert_eq!(*st.band().id(), last_band_id);
 
-        let names: Vec<String> = st.iter_entries().unwrap().map(|e| e.apath.into()).collect();
+        let names: Vec<String> = st
+            .iter_entries(None, &Exclude::nothing())
+            .map(|e| e.unwrap().apath.into())
+            .collect();
         let expected = if SYMLINKS_SUPPORTED {
             vec![
                 "/",
@@ -142,6 +305,20 @@ mod test {
         assert_eq!(expected, names);
     }
 
+    #[test]
+    fn find_one_entry() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+
+        let entry = st.entry(&"/subdir/subfile".into()).unwrap().unwrap();
+        assert_eq!(entry.apath, "/subdir/subfile");
+        assert_eq!(entry.kind(), Kind::File);
+        assert_eq!(st.entry(&"/".into()).unwrap().unwrap().kind(), Kind::Dir);
+        assert!(st.entry(&"/hell".into()).unwrap().is_none());
+        assert!(st.entry(&"/subdir/nothing".into()).unwrap().is_none());
+    }
+
     #[test]
     pub fn cant_open_no_versions() {
         let af = ScratchArchive::new();
@@ -167,4 +344,46 @@ mod test {
 
         assert_eq!(names.as_slice(), ["/subdir", "/subdir/subfile"]);
     }
+
+    #[test]
+    fn iter_filtered_with_many_hunks() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        for i in 0..10 {
+            srcdir.create_dir(&format!("d{}", i));
+            for j in 0..5 {
+                srcdir.create_file(&format!("d{}/f{}", i, j));
+            }
+        }
+        let options = BackupOptions {
+            max_entries_per_hunk: 3,
+            ..Default::default()
+        };
+        af.backup(srcdir.path(), &options).unwrap();
+        let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        assert!(
+            st.band()
+                .index()
+                .read_hunk_manifest()
+                .unwrap()
+                .unwrap()
+                .len()
+                > 10
+        );
+
+        for subtree in &["/", "/d0", "/d4", "/d9", "/d4/f3", "/nonexistent"] {
+            let subtree = Apath::from(*subtree);
+            let expected: Vec<Apath> = ReadTree::iter_entries(&st)
+                .unwrap()
+                .map(|entry| entry.apath)
+                .filter(|apath| subtree.is_prefix_of(apath))
+                .collect();
+            let names: Vec<Apath> = st
+                .iter_filtered(Some(subtree.clone()), None)
+                .unwrap()
+                .map(|entry| entry.apath)
+                .collect();
+            assert_eq!(names, expected, "subtree {:?}", subtree);
+        }
+    }
 }
@@ -0,0 +1,129 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Copy bands from one archive to another.
+//!
+//! Bands are copied verbatim and keep their ids, so the history in the
+//! destination matches the source. Only blocks missing from the destination are
+//! transferred.
+
+use std::time::Instant;
+
+use crate::stats::SyncStats;
+use crate::*;
+
+/// Options for `sync`.
+#[derive(Debug, Default, Clone)]
+pub struct SyncOptions {
+    /// Bands to copy. If empty, copy all complete bands.
+    pub band_ids: Vec<BandId>,
+
+    /// Break any existing lock on the destination archive.
+    pub break_lock: bool,
+}
+
+/// Copy bands from `source` to `dest`.
+///
+/// Bands that are already complete in the destination are skipped, so syncing
+/// again copies only new bands. When all bands are copied, incomplete bands in
+/// the source are skipped; asking for an incomplete band by id is an error.
+pub fn sync(source: &Archive, dest: &Archive, options: &SyncOptions) -> Result<SyncStats> {
+    let start = Instant::now();
+    let mut stats = SyncStats::default();
+    let _lock = dest.lock(options.break_lock)?;
+    let band_ids = if options.band_ids.is_empty() {
+        let mut band_ids = Vec::new();
+        for band_id in source.band_ids()? {
+            if source.band_is_closed(&band_id)? {
+                band_ids.push(band_id);
+            } else {
+                stats.incomplete_bands_skipped += 1;
+            }
+        }
+        band_ids
+    } else {
+        options.band_ids.clone()
+    };
+    for band_id in band_ids {
+        stats += source.copy_band_to_locked(&band_id, dest)?;
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::ScratchArchive;
+
+    #[test]
+    fn sync_all_bands() {
+        let source = ScratchArchive::new();
+        source.store_two_versions();
+        let dest = ScratchArchive::new();
+
+        let stats = sync(&source, &dest, &SyncOptions::default()).unwrap();
+        assert_eq!(stats.bands_copied, 2);
+        assert_eq!(stats.bands_already_present, 0);
+        assert_eq!(stats.blocks_copied, 2);
+        assert_eq!(dest.band_ids().unwrap(), source.band_ids().unwrap());
+        assert_eq!(
+            dest.referenced_blocks().unwrap(),
+            source.referenced_blocks().unwrap()
+        );
+        assert!(!dest
+            .validate(&ValidateOptions::default())
+            .unwrap()
+            .has_problems());
+    }
+
+    #[test]
+    fn incomplete_band_skipped() {
+        let source = ScratchArchive::new();
+        source.store_two_versions();
+        source.setup_incomplete_empty_band();
+        let dest = ScratchArchive::new();
+
+        let stats = sync(&source, &dest, &SyncOptions::default()).unwrap();
+        assert_eq!(stats.bands_copied, 2);
+        assert_eq!(stats.incomplete_bands_skipped, 1);
+        assert_eq!(dest.band_ids().unwrap().len(), 2);
+
+        let options = SyncOptions {
+            band_ids: vec![BandId::new(&[2])],
+            ..Default::default()
+        };
+        assert!(matches!(
+            sync(&source, &dest, &options),
+            Err(Error::BandIncomplete { .. })
+        ));
+    }
+
+    #[test]
+    fn conflicting_band_id() {
+        let source = ScratchArchive::new();
+        source.store_two_versions();
+        let dest = ScratchArchive::new();
+        // A different band b0000, started at a different time.
+        Band::create(&dest).unwrap();
+        crate::jsonio::write_json(
+            &dest.transport().sub_transport("b0000"),
+            BAND_HEAD_FILENAME,
+            &serde_json::json!({"start_time": 0, "band_format_version": "0.6.3"}),
+        )
+        .unwrap();
+
+        let result = source.copy_band_to(&BandId::zero(), &dest);
+        assert!(matches!(result, Err(Error::BandConflict { .. })));
+    }
+}
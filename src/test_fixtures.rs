@@ -16,13 +16,17 @@
 /// Fixtures that create directories will be automatically deleted when the object
 /// is deleted.
 use std::fs;
+use std::io;
 use std::io::Write;
-use std::ops::Deref;
+use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use tempfile::TempDir;
 
 use crate::backup::BackupOptions;
+use crate::transport::local::LocalTransport;
+use crate::transport::{Capacity, DirEntry, Metadata};
 use crate::*;
 
 /// A temporary archive, deleted when it goes out of scope.
@@ -65,10 +69,10 @@ impl ScratchArchive {
         }
 
         let options = &BackupOptions::default();
-        backup(&self.archive, &srcdir.live_tree(), &options).unwrap();
+        self.archive.backup(srcdir.path(), options).unwrap();
 
         srcdir.create_file("hello2");
-        backup(&self.archive, &srcdir.live_tree(), &options).unwrap();
+        self.archive.backup(srcdir.path(), options).unwrap();
     }
 
     pub fn transport(&self) -> &dyn Transport {
@@ -85,6 +89,12 @@ impl Deref for ScratchArchive {
     }
 }
 
+impl DerefMut for ScratchArchive {
+    fn deref_mut(&mut self) -> &mut Archive {
+        &mut self.archive
+    }
+}
+
 impl Default for ScratchArchive {
     fn default() -> Self {
         Self::new()
@@ -177,3 +187,154 @@ impl Default for TreeFixture {
         Self::new()
     }
 }
+
+/// How a [TestTransport] misbehaves.
+#[derive(Clone, Copy, Debug)]
+pub enum TransportFailure {
+    /// Writing any file fails with this error.
+    Write(io::ErrorKind),
+    /// Reading files whose path in the archive starts with this fails.
+    Read(&'static str),
+    /// The storage reports this many bytes available.
+    Available(u64),
+}
+
+/// A local transport that records the paths, relative to the archive, of
+/// files read and written through it, and can be made to fail.
+#[derive(Clone, Debug)]
+pub struct TestTransport {
+    inner: LocalTransport,
+    /// Path of this transport within the archive, ending in a slash, or empty
+    /// at the top.
+    prefix: String,
+    failure: Option<TransportFailure>,
+    reads: Arc<Mutex<Vec<String>>>,
+    writes: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestTransport {
+    pub fn new(path: &Path) -> TestTransport {
+        TestTransport {
+            inner: LocalTransport::new(path),
+            prefix: String::new(),
+            failure: None,
+            reads: Arc::default(),
+            writes: Arc::default(),
+        }
+    }
+
+    /// Fail in this way, from now on.
+    pub fn failing(self, failure: TransportFailure) -> TestTransport {
+        TestTransport {
+            failure: Some(failure),
+            ..self
+        }
+    }
+
+    /// Paths of the files read so far through this transport or any cloned or
+    /// derived from it, whether or not the read succeeded.
+    pub fn reads(&self) -> Vec<String> {
+        self.reads.lock().unwrap().clone()
+    }
+
+    /// Paths of the files written so far, like [TestTransport::reads].
+    pub fn writes(&self) -> Vec<String> {
+        self.writes.lock().unwrap().clone()
+    }
+}
+
+impl Transport for TestTransport {
+    fn iter_dir_entries(
+        &self,
+        path: &str,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<DirEntry>>>> {
+        self.inner.iter_dir_entries(path)
+    }
+
+    fn read_file(&self, path: &str, out_buf: &mut Vec<u8>) -> io::Result<()> {
+        let archive_path = format!("{}{}", self.prefix, path);
+        self.reads.lock().unwrap().push(archive_path.clone());
+        match self.failure {
+            Some(TransportFailure::Read(prefix)) if archive_path.starts_with(prefix) => {
+                Err(io::Error::other("simulated read failure"))
+            }
+            _ => self.inner.read_file(path, out_buf),
+        }
+    }
+
+    fn exists(&self, path: &str) -> io::Result<bool> {
+        self.inner.exists(path)
+    }
+
+    fn create_dir(&self, relpath: &str) -> io::Result<()> {
+        self.inner.create_dir(relpath)
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        self.writes
+            .lock()
+            .unwrap()
+            .push(format!("{}{}", self.prefix, relpath));
+        match self.failure {
+            Some(TransportFailure::Write(kind)) => {
+                Err(io::Error::new(kind, "simulated write failure"))
+            }
+            _ => self.inner.write_file(relpath, content),
+        }
+    }
+
+    fn write_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        self.writes
+            .lock()
+            .unwrap()
+            .push(format!("{}{}", self.prefix, relpath));
+        match self.failure {
+            Some(TransportFailure::Write(kind)) => {
+                Err(io::Error::new(kind, "simulated write failure"))
+            }
+            _ => self.inner.write_new_file(relpath, content),
+        }
+    }
+
+    fn metadata(&self, relpath: &str) -> io::Result<Metadata> {
+        self.inner.metadata(relpath)
+    }
+
+    fn remove_file(&self, relpath: &str) -> io::Result<()> {
+        self.inner.remove_file(relpath)
+    }
+
+    fn remove_dir(&self, relpath: &str) -> io::Result<()> {
+        self.inner.remove_dir(relpath)
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> io::Result<()> {
+        self.inner.remove_dir_all(relpath)
+    }
+
+    fn capacity(&self) -> io::Result<Option<Capacity>> {
+        match self.failure {
+            Some(TransportFailure::Available(available)) => Ok(Some(Capacity {
+                total: available * 2,
+                available,
+            })),
+            _ => self.inner.capacity(),
+        }
+    }
+
+    fn local_path(&self) -> Option<PathBuf> {
+        self.inner.local_path()
+    }
+
+    fn sub_transport(&self, relpath: &str) -> Box<dyn Transport> {
+        Box::new(TestTransport {
+            inner: LocalTransport::new(&self.inner.full_path(relpath)),
+            prefix: format!("{}{}/", self.prefix, relpath),
+            ..self.clone()
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn Transport> {
+        Box::new(self.clone())
+    }
+}
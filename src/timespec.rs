@@ -0,0 +1,169 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Parse user-supplied points in time, such as `--as-of` arguments.
+//!
+//! Accepted forms are:
+//!
+//! * RFC 3339 timestamps, like `2021-06-01T12:00:00Z` or `2021-06-01T12:00:00+10:00`.
+//! * Local date and time, like `2021-06-01T12:00:00` or `2021-06-01 12:00`.
+//! * A local date, like `2021-06-01`, meaning the end of that day.
+//! * Relative times, like `3 days ago` or `90 minutes ago`, and `now`.
+
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+use crate::*;
+
+/// Parse a point in time, with relative times counted back from now.
+pub fn parse_timestamp(s: &str) -> Result<DateTime<Utc>> {
+    parse_timestamp_at(s, Local::now())
+}
+
+/// Parse a point in time, with relative times counted back from `now`.
+pub fn parse_timestamp_at(s: &str, now: DateTime<Local>) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    let invalid = || Error::InvalidTimestamp {
+        timestamp: s.to_owned(),
+    };
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    for format in &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(s, format) {
+            return local_to_utc(&naive).ok_or_else(invalid);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return local_to_utc(&date.and_hms(23, 59, 59)).ok_or_else(invalid);
+    }
+    if s == "now" {
+        return Ok(now.with_timezone(&Utc));
+    }
+    parse_relative(s)
+        .and_then(|duration| now.checked_sub_signed(duration))
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok_or_else(invalid)
+}
+
+/// Interpret a local time, taking the earlier time if it's ambiguous.
+fn local_to_utc(naive: &NaiveDateTime) -> Option<DateTime<Utc>> {
+    Local
+        .from_local_datetime(naive)
+        .earliest()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// Parse an expression like `3 days ago` into a duration.
+///
+/// The count must be positive, and not so large that the duration overflows.
+fn parse_relative(s: &str) -> Option<Duration> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    if words.len() != 3 || words[2] != "ago" {
+        return None;
+    }
+    let count: i64 = words[0].parse().ok().filter(|count| *count > 0)?;
+    let unit = words[1];
+    let unit = unit.strip_suffix('s').unwrap_or(unit);
+    let unit_seconds = match unit {
+        "second" | "sec" => 1,
+        "minute" | "min" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    count
+        .checked_mul(unit_seconds)
+        .filter(|seconds| *seconds <= Duration::max_value().num_seconds())
+        .map(Duration::seconds)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn now() -> DateTime<Local> {
+        Local.ymd(2021, 6, 15).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn rfc3339() {
+        assert_eq!(
+            parse_timestamp_at("2021-06-01T00:00:00Z", now()).unwrap(),
+            Utc.ymd(2021, 6, 1).and_hms(0, 0, 0)
+        );
+        assert_eq!(
+            parse_timestamp_at("2021-06-01T10:00:00+10:00", now()).unwrap(),
+            Utc.ymd(2021, 6, 1).and_hms(0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn local_date_and_time() {
+        let expected = Local.ymd(2021, 6, 1).and_hms(8, 30, 0);
+        assert_eq!(
+            parse_timestamp_at("2021-06-01T08:30:00", now()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_timestamp_at("2021-06-01 08:30", now()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn date_means_end_of_day() {
+        assert_eq!(
+            parse_timestamp_at("2021-06-01", now()).unwrap(),
+            Local.ymd(2021, 6, 1).and_hms(23, 59, 59)
+        );
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(
+            parse_timestamp_at("3 days ago", now()).unwrap(),
+            Local.ymd(2021, 6, 12).and_hms(12, 0, 0)
+        );
+        assert_eq!(
+            parse_timestamp_at("1 hour ago", now()).unwrap(),
+            Local.ymd(2021, 6, 15).and_hms(11, 0, 0)
+        );
+        assert_eq!(parse_timestamp_at("now", now()).unwrap(), now());
+    }
+
+    #[test]
+    fn invalid() {
+        for s in &[
+            "",
+            "yesterday",
+            "3 fortnights ago",
+            "2021-13-01",
+            "days ago",
+            "0 days ago",
+            "-3 days ago",
+            "99999999999999 days ago",
+            "9223372036854775807 seconds ago",
+            "100000000000 weeks ago",
+        ] {
+            assert!(
+                matches!(
+                    parse_timestamp_at(s, now()),
+                    Err(Error::InvalidTimestamp { .. })
+                ),
+                "{:?} should be invalid",
+                s
+            );
+        }
+    }
+}
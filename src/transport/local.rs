@@ -18,6 +18,7 @@ use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
+use crate::io::{create_temp_file_in, retry_out_of_handles};
 use crate::transport::{DirEntry, Metadata, Transport};
 
 #[derive(Clone, Debug)]
@@ -64,7 +65,8 @@ impl Transport for LocalTransport {
         out_buf.truncate(0);
         // read_to_end reads in gradually increasing parts, but here we can probably read one large
         // buffer.
-        let mut file = File::open(&self.full_path(relpath))?;
+        let path = self.full_path(relpath);
+        let mut file = retry_out_of_handles(|| File::open(&path))?;
         let prefetch_len: usize = file.metadata()?.len().try_into().unwrap();
         out_buf.resize(prefetch_len, 0);
         let actual_len = file.read(out_buf)?;
@@ -93,19 +95,29 @@ impl Transport for LocalTransport {
     fn write_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
         let full_path = self.full_path(relpath);
         let dir = full_path.parent().unwrap();
-        let mut temp = tempfile::Builder::new()
-            .prefix(crate::TMP_PREFIX)
-            .tempfile_in(dir)?;
-        if let Err(err) = temp.write_all(content) {
-            let _ = temp.close();
-            return Err(err);
-        }
-        if let Err(persist_error) = temp.persist(&full_path) {
-            let _ = persist_error.file.close()?;
-            Err(persist_error.error)
-        } else {
-            Ok(())
+        let (mut temp, temp_path) = create_temp_file_in(dir, crate::TMP_PREFIX)?;
+        let result = temp.write_all(content).and_then(|()| {
+            drop(temp);
+            std::fs::rename(&temp_path, &full_path)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
         }
+        result
+    }
+
+    fn write_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        let full_path = self.full_path(relpath);
+        let dir = full_path.parent().unwrap();
+        let (mut temp, temp_path) = create_temp_file_in(dir, crate::TMP_PREFIX)?;
+        // Unlike renaming, linking fails if the name is already taken, and
+        // the file only becomes visible with its complete content.
+        let result = temp.write_all(content).and_then(|()| {
+            drop(temp);
+            std::fs::hard_link(&temp_path, &full_path)
+        });
+        let _ = std::fs::remove_file(&temp_path);
+        result
     }
 
     fn remove_file(&self, relpath: &str) -> io::Result<()> {
@@ -130,6 +142,29 @@ impl Transport for LocalTransport {
         let fsmeta = self.root.join(relpath).metadata()?;
         Ok(Metadata { len: fsmeta.len() })
     }
+
+    fn local_path(&self) -> Option<PathBuf> {
+        Some(self.root.clone())
+    }
+
+    #[cfg(unix)]
+    fn capacity(&self) -> io::Result<Option<crate::transport::Capacity>> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        use crate::transport::Capacity;
+
+        let c_path = CString::new(self.root.as_os_str().as_bytes())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let block_size = stat.f_frsize as u64;
+        Ok(Some(Capacity {
+            total: stat.f_blocks as u64 * block_size,
+            available: stat.f_bavail as u64 * block_size,
+        }))
+    }
 }
 
 impl AsRef<dyn Transport> for LocalTransport {
@@ -260,6 +295,21 @@ mod test {
         temp.close().unwrap();
     }
 
+    #[test]
+    fn write_new_file_refuses_to_replace() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let transport = LocalTransport::new(temp.path());
+
+        transport.write_new_file("LOCK", b"first").unwrap();
+        let err = transport.write_new_file("LOCK", b"second").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        temp.child("LOCK").assert("first");
+        // The temporary file is cleaned up either way.
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+
+        temp.close().unwrap();
+    }
+
     #[test]
     fn create_existing_dir() {
         let temp = assert_fs::TempDir::new().unwrap();
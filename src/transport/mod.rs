@@ -18,6 +18,8 @@ use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use serde::Serialize;
+
 use crate::errors::Error;
 use crate::kind::Kind;
 use crate::Result;
@@ -91,6 +93,13 @@ pub trait Transport: Send + Sync + std::fmt::Debug {
     /// If a temporary file is used, the name should start with `crate::TMP_PREFIX`.
     fn write_file(&self, relpath: &str, content: &[u8]) -> io::Result<()>;
 
+    /// Write a complete file that must not already exist.
+    ///
+    /// Fails with `io::ErrorKind::AlreadyExists` if there's already a file of
+    /// that name. The check and the write should be atomic, so that of several
+    /// processes racing to create the same file, only one succeeds.
+    fn write_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()>;
+
     /// Get metadata about a file.
     fn metadata(&self, relpath: &str) -> io::Result<Metadata>;
 
@@ -106,6 +115,18 @@ pub trait Transport: Send + Sync + std::fmt::Debug {
     /// Make a new transport addressing a subdirectory.
     fn sub_transport(&self, relpath: &str) -> Box<dyn Transport>;
 
+    /// Measure the space on the storage holding this transport, or None if
+    /// the transport can't tell.
+    fn capacity(&self) -> io::Result<Option<Capacity>> {
+        Ok(None)
+    }
+
+    /// The local directory holding this transport, or None if it's not on a
+    /// local filesystem.
+    fn local_path(&self) -> Option<PathBuf> {
+        None
+    }
+
     /// Clone this object into a new box.
     fn box_clone(&self) -> Box<dyn Transport>;
 }
@@ -139,6 +160,15 @@ pub struct Metadata {
     pub len: u64,
 }
 
+/// Space on the storage holding a transport, in bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Capacity {
+    /// Size of the whole filesystem or volume.
+    pub total: u64,
+    /// Space that can be used by this process.
+    pub available: u64,
+}
+
 /// A list of all the files and directories in a directory.
 #[derive(Debug, Default, Eq, PartialEq)]
 pub struct ListDirNames {
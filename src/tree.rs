@@ -13,6 +13,7 @@
 
 //! Abstract Tree trait.
 
+use std::io::Read;
 use std::ops::Range;
 
 use crate::stats::{CopyStats, Sizes};
@@ -28,14 +29,15 @@ pub trait ReadTree {
     ///
     /// Errors reading individual paths or directories are sent to the UI and
     /// counted, but are not treated as fatal, and don't appear as Results in the
-    /// iterator.
+    /// iterator. An error that stops the iteration is kept for
+    /// [ReadTree::take_iter_error].
     fn iter_entries(&self) -> Result<Box<dyn Iterator<Item = Self::Entry>>>;
 
     /// Return entries within the subtree and not excluded.
     fn iter_filtered(
         &self,
         subtree: Option<Apath>,
-        excludes: Option<GlobSet>,
+        excludes: Option<Exclude>,
     ) -> Result<Box<dyn Iterator<Item = Self::Entry>>> {
         Ok(Box::new(self.iter_entries()?.filter(move |entry| {
             subtree
@@ -44,11 +46,20 @@ pub trait ReadTree {
                 .unwrap_or(true)
                 && excludes
                     .as_ref()
-                    .map(|e| !e.is_match(entry.apath()))
+                    .map(|e| !e.is_excluded(entry.apath(), entry.kind()))
                     .unwrap_or(true)
         })))
     }
 
+    /// Take the error, if any, that stopped an iteration of this tree before
+    /// it reached the end.
+    ///
+    /// The iterators can't return errors themselves, so callers that need
+    /// the whole tree should check this once iteration is done.
+    fn take_iter_error(&self) -> Option<Error> {
+        None
+    }
+
     /// Read file contents as a `std::io::Read`.
     // TODO: Remove this and use ReadBlocks or similar.
     fn file_contents(&self, entry: &Self::Entry) -> Result<Self::R>;
@@ -60,7 +71,7 @@ pub trait ReadTree {
     /// Measure the tree size.
     ///
     /// This typically requires walking all entries, which may take a while.
-    fn size(&self, excludes: Option<GlobSet>) -> Result<TreeSize> {
+    fn size(&self, excludes: Option<Exclude>) -> Result<TreeSize> {
         let mut progress_bar = ProgressBar::new();
         progress_bar.set_phase("Measuring".to_owned());
         let mut tot = 0u64;
@@ -71,6 +82,9 @@ pub trait ReadTree {
                 progress_bar.increment_bytes_done(bytes);
             }
         }
+        if let Some(err) = self.take_iter_error() {
+            return Err(err);
+        }
         Ok(TreeSize { file_bytes: tot })
     }
 }
@@ -81,21 +95,28 @@ pub trait ReadTree {
 /// still underway.
 ///
 /// Entries must be written in Apath order, since that's a requirement of the index.
+///
+/// The trait is object-safe, so destinations can be chosen at runtime.
 pub trait WriteTree {
-    fn finish(self) -> Result<CopyStats>;
+    /// Complete the tree once every entry has been copied, and return stats
+    /// about the tree as a whole.
+    ///
+    /// This isn't called if copying is cancelled or fails, so that an
+    /// incomplete tree isn't made to look finished.
+    fn finish(&mut self) -> Result<CopyStats>;
 
     /// Copy a directory entry from a source tree to this tree.
-    fn copy_dir<E: Entry>(&mut self, entry: &E) -> Result<()>;
+    fn copy_dir(&mut self, entry: &IndexEntry) -> Result<()>;
 
     /// Copy a symlink entry from a source tree to this tree.
-    fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()>;
+    fn copy_symlink(&mut self, entry: &IndexEntry) -> Result<()>;
 
-    /// Copy in the contents of a file from another tree.
+    /// Copy in a file entry and its contents, read from `content`.
     ///
     /// Returns Sizes describing the compressed and uncompressed sizes copied.
     // TODO: Use some better interface than IO::Read, that permits getting sizes
     // from the source file when restoring.
-    fn copy_file<R: ReadTree>(&mut self, entry: &R::Entry, from_tree: &R) -> Result<CopyStats>;
+    fn copy_file(&mut self, entry: &IndexEntry, content: &mut dyn Read) -> Result<CopyStats>;
 }
 
 /// Read a file as a series of blocks of bytes.
@@ -16,7 +16,8 @@
 use std::fmt::Write;
 use std::io;
 use std::io::Write as IoWrite;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crossterm::{cursor, queue, style, terminal};
@@ -24,7 +25,7 @@ use lazy_static::lazy_static;
 use unicode_segmentation::UnicodeSegmentation;
 
 use crate::stats::Sizes;
-use crate::ProgressBar;
+use crate::{Problem, ProblemSink, ProgressBar, Severity};
 
 /// A terminal/text UI.
 ///
@@ -43,10 +44,15 @@ pub(crate) struct UIState {
 
     /// Should a progress bar be drawn?
     progress_enabled: bool,
+
+    /// Should messages go to stderr, because stdout is carrying data?
+    messages_to_stderr: bool,
 }
 
 lazy_static! {
     static ref UI_STATE: Mutex<UIState> = Mutex::new(UIState::default());
+    static ref PROBLEM_SINK: Mutex<Arc<dyn ProblemSink>> =
+        Mutex::new(Arc::new(PrintProblems::default()));
 }
 
 // TODO: Rather than a directly-called function, hook this into logging.
@@ -54,9 +60,51 @@ pub fn println(s: &str) {
     with_locked_ui(|ui| ui.println(s))
 }
 
-// TODO: Rather than a directly-called function, hook this into logging.
-pub fn problem(s: &str) {
-    with_locked_ui(|ui| ui.problem(s));
+/// Prints each problem through the UI, and counts those that aren't warnings.
+#[derive(Debug, Default)]
+pub struct PrintProblems {
+    count: AtomicUsize,
+}
+
+impl PrintProblems {
+    /// The number of problems reported so far, not counting warnings.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl ProblemSink for PrintProblems {
+    fn report(&self, problem: Problem) {
+        match problem.severity() {
+            Severity::Warning => with_locked_ui(|ui| ui.warning(&problem.to_string())),
+            Severity::Error => {
+                self.count.fetch_add(1, Ordering::Relaxed);
+                with_locked_ui(|ui| ui.problem(&problem.to_string()));
+            }
+        }
+    }
+}
+
+/// The sink for problems found outside of an operation that holds its own
+/// sink, by default a [PrintProblems].
+pub fn problem_sink() -> Arc<dyn ProblemSink> {
+    PROBLEM_SINK.lock().unwrap().clone()
+}
+
+/// Replace the default problem sink, for example to count the problems
+/// found by a command.
+pub fn set_problem_sink(sink: Arc<dyn ProblemSink>) {
+    *PROBLEM_SINK.lock().unwrap() = sink;
+}
+
+/// The sink an operation was given, or otherwise the default.
+pub(crate) fn problem_sink_or(sink: &Option<Arc<dyn ProblemSink>>) -> Arc<dyn ProblemSink> {
+    sink.clone().unwrap_or_else(problem_sink)
+}
+
+/// Report a problem to the default problem sink.
+pub fn report_problem(problem: Problem) {
+    problem_sink().report(problem)
 }
 
 pub(crate) fn with_locked_ui<F>(mut cb: F)
@@ -67,9 +115,10 @@ where
     cb(UI_STATE.lock().unwrap().deref_mut())
 }
 
-/// Report that a non-fatal error occurred.
+/// Show an error that stopped the program.
 ///
-/// The program will continue.
+/// Problems that the program continues past should be reported as a
+/// [Problem] instead.
 pub fn show_error(e: &dyn std::error::Error) {
     // TODO: Convert to logging.
     let mut buf = e.to_string();
@@ -78,7 +127,7 @@ pub fn show_error(e: &dyn std::error::Error) {
         write!(&mut buf, "\n  caused by: {}", c).expect("Failed to format error cause");
         cause = c;
     }
-    problem(&buf);
+    with_locked_ui(|ui| ui.problem(&buf));
 }
 
 /// Enable drawing progress bars, only if stdout is a tty.
@@ -90,11 +139,24 @@ pub fn enable_progress(enabled: bool) {
     ui.progress_enabled = io::stdout().is_tty() && enabled;
 }
 
+/// Send messages and problems to stderr rather than stdout, so that stdout
+/// can carry data such as a tar stream.
+///
+/// This also turns off progress bars.
+pub fn messages_to_stderr(enabled: bool) {
+    let mut ui = UI_STATE.lock().unwrap();
+    ui.messages_to_stderr = enabled;
+    if enabled {
+        ui.progress_enabled = false;
+    }
+}
+
 impl Default for UIState {
     fn default() -> UIState {
         UIState {
             progress_present: false,
             progress_enabled: false,
+            messages_to_stderr: false,
         }
     }
 }
@@ -208,12 +270,24 @@ impl UIState {
 
     pub(crate) fn println(&mut self, s: &str) {
         self.clear_progress();
-        println!("{}", s);
+        if self.messages_to_stderr {
+            eprintln!("{}", s);
+        } else {
+            println!("{}", s);
+        }
+    }
+
+    fn warning(&mut self, s: &str) {
+        self.println(&format!("conserve warning: {}", s));
     }
 
     fn problem(&mut self, s: &str) {
         self.clear_progress();
-        println!("conserve error: {}", s);
+        if self.messages_to_stderr {
+            eprintln!("conserve error: {}", s);
+        } else {
+            println!("conserve error: {}", s);
+        }
         // Drawing this way makes messages leak from tests, for unclear reasons.
 
         // queue!(
@@ -0,0 +1,188 @@
+// Conserve backup system.
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Extended attributes (xattrs) of files and directories.
+//!
+//! Xattrs are read from the live tree on Unix, stored in the index as a map
+//! from name to base64-encoded value, and written back on restore.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::{Problem, ProblemKind, ProblemSink};
+
+/// Extended attributes of one file or directory, from name to value.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Extended attributes read from a live file.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub(crate) struct ReadXattrs {
+    pub xattrs: Xattrs,
+
+    /// The number of attributes that exist but couldn't be read, typically
+    /// because they're in a namespace this process isn't privileged to see.
+    pub unreadable: usize,
+}
+
+/// Read the extended attributes of a file, without following symlinks.
+///
+/// Attributes that can't be read because of permissions are counted but not
+/// reported. If the filesystem doesn't support xattrs, none are returned.
+#[cfg(unix)]
+pub(crate) fn read_xattrs(path: &Path, problems: &dyn ProblemSink) -> ReadXattrs {
+    use std::io::ErrorKind;
+
+    let mut result = ReadXattrs::default();
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(err) => {
+            if !is_unsupported(&err) {
+                problems.report(Problem::new(
+                    ProblemKind::Xattr,
+                    None,
+                    format!("Failed to list xattrs of {:?}: {}", path, err),
+                ));
+            }
+            return result;
+        }
+    };
+    for name in names {
+        let name_str = match name.to_str() {
+            Some(name_str) => name_str.to_owned(),
+            None => {
+                problems.report(Problem::new(
+                    ProblemKind::Xattr,
+                    None,
+                    format!("Can't decode xattr name {:?} on {:?}", name, path),
+                ));
+                result.unreadable += 1;
+                continue;
+            }
+        };
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => {
+                result.xattrs.insert(name_str, value);
+            }
+            // Removed since it was listed.
+            Ok(None) => (),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => result.unreadable += 1,
+            Err(err) => {
+                problems.report(Problem::new(
+                    ProblemKind::Xattr,
+                    None,
+                    format!("Failed to read xattr {:?} of {:?}: {}", name_str, path, err),
+                ));
+                result.unreadable += 1;
+            }
+        }
+    }
+    result
+}
+
+#[cfg(not(unix))]
+pub(crate) fn read_xattrs(_path: &Path, _problems: &dyn ProblemSink) -> ReadXattrs {
+    ReadXattrs::default()
+}
+
+/// Set all the given extended attributes on a file, without following
+/// symlinks.
+///
+/// Returns the number of attributes that could not be set, after reporting
+/// each of them.
+#[cfg(unix)]
+pub(crate) fn write_xattrs(path: &Path, xattrs: &Xattrs, problems: &dyn ProblemSink) -> usize {
+    let mut failures = 0;
+    for (name, value) in xattrs {
+        if let Err(err) = xattr::set(path, name, value) {
+            problems.report(Problem::new(
+                ProblemKind::Xattr,
+                None,
+                format!("Failed to restore xattr {:?} on {:?}: {}", name, path, err),
+            ));
+            failures += 1;
+        }
+    }
+    failures
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_xattrs(path: &Path, xattrs: &Xattrs, problems: &dyn ProblemSink) -> usize {
+    if !xattrs.is_empty() {
+        problems.report(Problem::new(
+            ProblemKind::Xattr,
+            None,
+            format!("Can't restore xattrs on non-Unix: {:?}", path),
+        ));
+    }
+    xattrs.len()
+}
+
+/// True if the error means the filesystem or platform doesn't have xattrs.
+#[cfg(unix)]
+fn is_unsupported(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::Unsupported || err.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// Serialize xattr values as base64 strings, so that they're readable in json.
+pub(crate) mod base64_values {
+    use std::collections::BTreeMap;
+
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Xattrs;
+
+    pub fn serialize<S: Serializer>(xattrs: &Xattrs, serializer: S) -> Result<S::Ok, S::Error> {
+        let encoded: BTreeMap<&str, String> = xattrs
+            .iter()
+            .map(|(name, value)| (name.as_str(), base64::encode(value)))
+            .collect();
+        encoded.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Xattrs, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, value)| {
+                base64::decode(&value)
+                    .map(|value| (name, value))
+                    .map_err(D::Error::custom)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Holder {
+            #[serde(with = "base64_values")]
+            xattrs: Xattrs,
+        }
+        let mut xattrs = Xattrs::new();
+        xattrs.insert("user.hello".to_owned(), b"world".to_vec());
+        xattrs.insert("user.binary".to_owned(), vec![0, 255, 10]);
+        let holder = Holder { xattrs };
+        let json = serde_json::to_string(&holder).unwrap();
+        assert_eq!(
+            json,
+            r#"{"xattrs":{"user.binary":"AP8K","user.hello":"d29ybGQ="}}"#
+        );
+        assert_eq!(serde_json::from_str::<Holder>(&json).unwrap(), holder);
+        assert!(serde_json::from_str::<Holder>(r#"{"xattrs":{"user.x":"!!"}}"#).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Exercise the stable library API, as a program built on Conserve would.
+//!
+//! Only the archive is made with test fixtures: everything after that should
+//! stick to what's documented as stable, so that accidental breakage fails
+//! here.
+
+use std::io::Read;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::{
+    Apath, Archive, BandId, BandSelectionPolicy, Entry, Exclude, IndexEntry, Kind, ReadTree, Result,
+};
+
+fn make_archive() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"hello world\n");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/inner", b"inner");
+    af.backup(srcdir.path(), &Default::default()).unwrap();
+    srcdir.create_file_with_contents("later", b"");
+    af.backup(srcdir.path(), &Default::default()).unwrap();
+    af
+}
+
+#[test]
+fn list_bands_and_entries() -> Result<()> {
+    let af = make_archive();
+    let archive = Archive::open_path(af.path())?;
+
+    assert_eq!(
+        archive.band_ids()?,
+        vec![BandId::new(&[0]), BandId::new(&[1])]
+    );
+
+    let tree = archive.open_stored_tree(BandSelectionPolicy::Specified(BandId::new(&[0])))?;
+    let entries: Vec<IndexEntry> = tree
+        .iter_entries(None, &Exclude::nothing())
+        .collect::<Result<_>>()?;
+    let summary: Vec<(String, Kind, Option<u64>)> = entries
+        .iter()
+        .map(|entry| (entry.apath().to_string(), entry.kind(), entry.size()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("/".to_owned(), Kind::Dir, None),
+            ("/hello".to_owned(), Kind::File, Some(12)),
+            ("/subdir".to_owned(), Kind::Dir, None),
+            ("/subdir/inner".to_owned(), Kind::File, Some(5)),
+        ]
+    );
+    assert!(entries.iter().all(|entry| entry.symlink_target().is_none()));
+    assert!(entries[1].mtime().secs > 0);
+    assert_eq!(entries[1].addrs().len(), 1);
+    assert_eq!(entries[1].addrs()[0].len, 12);
+    Ok(())
+}
+
+#[test]
+fn iterate_subtree_with_excludes() -> Result<()> {
+    let af = make_archive();
+    let archive = Archive::open_path(af.path())?;
+    let tree = archive.open_stored_tree(BandSelectionPolicy::Latest)?;
+
+    let apaths = |subtree: Option<&Apath>, excludes: &Exclude| -> Result<Vec<String>> {
+        tree.iter_entries(subtree, excludes)
+            .map(|entry| entry.map(|entry| entry.apath().to_string()))
+            .collect()
+    };
+    assert_eq!(
+        apaths(None, &Exclude::nothing())?,
+        ["/", "/hello", "/later", "/subdir", "/subdir/inner"]
+    );
+    assert_eq!(
+        apaths(Some(&"/subdir".into()), &Exclude::nothing())?,
+        ["/subdir", "/subdir/inner"]
+    );
+    let excludes = conserve::excludes::from_strings(&["/hello"])?.unwrap();
+    assert_eq!(
+        apaths(None, &excludes)?,
+        ["/", "/later", "/subdir", "/subdir/inner"]
+    );
+    Ok(())
+}
+
+#[test]
+fn read_file_contents() -> Result<()> {
+    let af = make_archive();
+    let archive = Archive::open_path(af.path())?;
+    let tree = archive.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let entry = tree
+        .iter_entries(Some(&"/hello".into()), &Exclude::nothing())
+        .next()
+        .unwrap()?;
+    let mut content = String::new();
+    tree.file_contents(&entry)?.read_to_string(&mut content)?;
+    assert_eq!(content, "hello world\n");
+    Ok(())
+}
@@ -18,12 +18,12 @@ use conserve::*;
 
 #[test]
 fn small_files_combined_two_backups() {
-    let mut af = ScratchArchive::new();
+    let af = ScratchArchive::new();
     let srcdir = TreeFixture::new();
     srcdir.create_file("file1");
     srcdir.create_file("file2");
 
-    let stats1 = backup(&mut af, &srcdir.live_tree(), &BackupOptions::default()).unwrap();
+    let stats1 = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
     // Although the two files have the same content, we do not yet dedupe them
     // within a combined block, so the block is different to when one identical
     // file is stored alone. This could be fixed.
@@ -35,7 +35,7 @@ fn small_files_combined_two_backups() {
     // Add one more file, also identical, but it is not combined with the previous blocks.
     // This is a shortcoming of the current dedupe approach.
     srcdir.create_file("file3");
-    let stats2 = backup(&mut af, &srcdir.live_tree(), &BackupOptions::default()).unwrap();
+    let stats2 = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
     assert_eq!(stats2.new_files, 1);
     assert_eq!(stats2.unmodified_files, 2);
     assert_eq!(stats2.written_blocks, 1);
@@ -57,7 +57,9 @@ fn many_small_files_combined_to_one_block() {
             format!("something about {}", i).as_bytes(),
         );
     }
-    let stats = backup(&af, &srcdir.live_tree(), &BackupOptions::default()).expect("backup");
+    let stats = af
+        .backup(srcdir.path(), &BackupOptions::default())
+        .expect("backup");
     assert_eq!(
         stats.index_builder_stats.index_hunks, 2,
         "expect exactly 2 hunks"
@@ -74,12 +76,19 @@ fn many_small_files_combined_to_one_block() {
     assert_eq!(stats.combined_blocks, 2);
 
     let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
-    let mut entry_iter = tree.iter_entries().unwrap();
+    let mut entry_iter = tree
+        .iter_entries(None, &Exclude::nothing())
+        .map(Result::unwrap);
     assert_eq!(entry_iter.next().unwrap().apath(), "/");
     for (i, entry) in entry_iter.enumerate() {
         assert_eq!(entry.apath().to_string(), format!("/file{:04}", i));
     }
-    assert_eq!(tree.iter_entries().unwrap().count(), 2000);
+    assert_eq!(
+        tree.iter_entries(None, &Exclude::nothing())
+            .map(Result::unwrap)
+            .count(),
+        2000
+    );
 }
 
 #[test]
@@ -96,7 +105,9 @@ pub fn mixed_medium_small_files_two_hunks() {
             srcdir.create_file(&name);
         }
     }
-    let stats = backup(&af, &srcdir.live_tree(), &BackupOptions::default()).expect("backup");
+    let stats = af
+        .backup(srcdir.path(), &BackupOptions::default())
+        .expect("backup");
     assert_eq!(
         stats.index_builder_stats.index_hunks, 2,
         "expect exactly 2 hunks"
@@ -113,12 +124,19 @@ pub fn mixed_medium_small_files_two_hunks() {
     assert_eq!(stats.written_blocks, 3);
 
     let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
-    let mut entry_iter = tree.iter_entries().unwrap();
+    let mut entry_iter = tree
+        .iter_entries(None, &Exclude::nothing())
+        .map(Result::unwrap);
     assert_eq!(entry_iter.next().unwrap().apath(), "/");
     for (i, entry) in entry_iter.enumerate() {
         assert_eq!(entry.apath().to_string(), format!("/file{:04}", i));
     }
-    assert_eq!(tree.iter_entries().unwrap().count(), 2000);
+    assert_eq!(
+        tree.iter_entries(None, &Exclude::nothing())
+            .map(Result::unwrap)
+            .count(),
+        2000
+    );
 }
 
 #[test]
@@ -128,51 +146,885 @@ fn detect_unchanged_from_stitched_index() {
     srcdir.create_file("a");
     srcdir.create_file("b");
     // Use small hunks for easier manipulation.
-    let stats = backup(
-        &af,
-        &srcdir.live_tree(),
-        &BackupOptions {
-            max_entries_per_hunk: 1,
-            ..Default::default()
-        },
-    )
-    .unwrap();
+    let stats = af
+        .backup(
+            srcdir.path(),
+            &BackupOptions {
+                max_entries_per_hunk: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
     assert_eq!(stats.new_files, 2);
     assert_eq!(stats.small_combined_files, 2);
     assert_eq!(stats.index_builder_stats.index_hunks, 3);
 
     // Make a second backup, with the first file changed.
     srcdir.create_file_with_contents("a", b"new a contents");
-    let stats = backup(
-        &af,
-        &srcdir.live_tree(),
-        &BackupOptions {
-            max_entries_per_hunk: 1,
-            ..Default::default()
-        },
-    )
-    .unwrap();
+    let stats = af
+        .backup(
+            srcdir.path(),
+            &BackupOptions {
+                max_entries_per_hunk: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
     assert_eq!(stats.unmodified_files, 1);
     assert_eq!(stats.modified_files, 1);
     assert_eq!(stats.index_builder_stats.index_hunks, 3);
 
-    // Delete the last hunk and reopen the last band.
+    // Delete the last hunk and reopen the last band. An interrupted backup
+    // wouldn't have written the hunk manifest either.
     af.transport().remove_file("b0001/BANDTAIL").unwrap();
+    af.transport().remove_file("b0001/i/MANIFEST").unwrap();
     af.transport()
         .remove_file("b0001/i/00000/000000002")
         .unwrap();
 
     // The third backup should see nothing changed, by looking at the stitched
     // index from both b0 and b1.
-    let stats = backup(
-        &af,
-        &srcdir.live_tree(),
-        &BackupOptions {
-            max_entries_per_hunk: 1,
-            ..Default::default()
-        },
-    )
-    .unwrap();
+    let stats = af
+        .backup(
+            srcdir.path(),
+            &BackupOptions {
+                max_entries_per_hunk: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
     assert_eq!(stats.unmodified_files, 2, "both files are unmodified");
     assert_eq!(stats.index_builder_stats.index_hunks, 3);
 }
+
+#[test]
+fn cbor_index_matches_json_index() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/bigger", &[7u8; 100_000]);
+    srcdir.create_symlink("link", "target");
+
+    let json_archive = ScratchArchive::new();
+    json_archive
+        .backup(srcdir.path(), &BackupOptions::default())
+        .unwrap();
+
+    let cbor_archive = ScratchArchive::new();
+    let options = BackupOptions {
+        index_format: IndexFormat::Cbor,
+        ..Default::default()
+    };
+    let stats = cbor_archive.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(stats.index_builder_stats.index_hunks, 1);
+    let head = std::fs::read_to_string(cbor_archive.path().join("b0000").join("BANDHEAD")).unwrap();
+    assert!(head.contains(r#""index_format":"cbor""#), "{}", head);
+    assert!(
+        head.contains(r#""band_format_version":"0.6.11""#),
+        "{}",
+        head
+    );
+
+    let read_entries = |archive: &Archive| -> Vec<IndexEntry> {
+        archive
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap()
+            .iter_entries(None, &Exclude::nothing())
+            .collect::<Result<_>>()
+            .unwrap()
+    };
+    assert_eq!(read_entries(&cbor_archive), read_entries(&json_archive));
+    assert!(!cbor_archive
+        .validate(&ValidateOptions::default())
+        .unwrap()
+        .has_problems());
+
+    // A later JSON backup can use the CBOR band as its basis.
+    let stats = cbor_archive
+        .backup(srcdir.path(), &BackupOptions::default())
+        .unwrap();
+    assert_eq!(stats.new_files, 0);
+    assert_eq!(stats.unmodified_files, 2);
+}
+
+#[test]
+fn packed_index_matches_unpacked_index() {
+    let srcdir = TreeFixture::new();
+    for i in 0..9 {
+        srcdir.create_file(&format!("file{}", i));
+    }
+    let options = BackupOptions {
+        max_entries_per_hunk: 2,
+        ..Default::default()
+    };
+    let unpacked_archive = ScratchArchive::new();
+    unpacked_archive.backup(srcdir.path(), &options).unwrap();
+
+    let packed_archive = ScratchArchive::new();
+    let options = options.index_hunks_per_pack(Some(3));
+    let stats = packed_archive.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(stats.index_builder_stats.index_hunks, 5);
+    let band_dir = packed_archive.path().join("b0000");
+    let head = std::fs::read_to_string(band_dir.join("BANDHEAD")).unwrap();
+    assert!(head.contains(r#""index_hunks_per_pack":3"#), "{}", head);
+    assert!(
+        head.contains(r#""band_format_version":"0.6.11""#),
+        "{}",
+        head
+    );
+    let mut names: Vec<String> = std::fs::read_dir(band_dir.join("i").join("00000"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["000000000.pack", "000000003.pack"]);
+
+    let band = Band::open(&packed_archive, &BandId::zero()).unwrap();
+    assert_eq!(band.get_info().unwrap().index_hunk_count, Some(5));
+    assert_eq!(band.index().count_hunks().unwrap(), 5);
+    let read_entries = |archive: &Archive| -> Vec<IndexEntry> {
+        archive
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap()
+            .iter_entries(None, &Exclude::nothing())
+            .collect::<Result<_>>()
+            .unwrap()
+    };
+    assert_eq!(
+        read_entries(&packed_archive),
+        read_entries(&unpacked_archive)
+    );
+    assert!(!packed_archive
+        .validate(&ValidateOptions::default())
+        .unwrap()
+        .has_problems());
+
+    let restore_dir = TreeFixture::new();
+    let stats = restore(
+        &packed_archive,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(stats.files, 9);
+}
+
+#[test]
+fn backup_stats_are_recorded_in_band() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert!(af.path().join("b0000").join("stats.json").is_file());
+
+    let band = Band::open(&af, &BandId::zero()).unwrap();
+    assert_eq!(band.read_stats().unwrap(), Some(stats));
+
+    let summary = af.summary().unwrap();
+    assert_eq!(summary.block_count, 1);
+    assert_eq!(summary.latest_band, Some(BandId::zero()));
+    assert_eq!(summary.latest_logical_bytes, 8);
+    assert!(summary.compressed_block_bytes > 0);
+
+    let validate_stats = af.validate(&ValidateOptions::default()).unwrap();
+    assert_eq!(validate_stats.unexpected_files, 0);
+}
+
+#[test]
+fn band_tail_records_totals() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_of_length_with_prefix("subdir/big", 2 << 20, b"big");
+    srcdir.create_file_with_contents("subdir/small", b"small");
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+
+    let band = Band::open(&af, &BandId::zero()).unwrap();
+    let info = band.get_info().unwrap();
+    let entries: Vec<IndexEntry> = band.iter_entries().collect();
+    assert_eq!(info.index_entry_count, Some(entries.len() as u64));
+    assert_eq!(info.index_entry_count, Some(5));
+    assert_eq!(
+        info.total_file_bytes,
+        Some(entries.iter().filter_map(|entry| entry.size()).sum())
+    );
+    assert_eq!(
+        info.total_file_bytes,
+        Some(
+            af.open_stored_tree(BandSelectionPolicy::Latest)
+                .unwrap()
+                .size(None)
+                .unwrap()
+                .file_bytes
+        )
+    );
+    assert_eq!(info.block_count_written, Some(stats.written_blocks as u64));
+    assert_eq!(info.index_hunk_count, Some(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn moved_files_reuse_blocks_without_reading() {
+    use conserve::test_fixtures::TestTransport;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("photos");
+    srcdir.create_file_with_contents("photos/big", &vec![b'x'; 2 << 20]);
+    srcdir.create_file_with_contents("photos/small", b"small file");
+    srcdir.create_file("unmoved");
+    let options = BackupOptions {
+        detect_moves: true,
+        ..BackupOptions::default()
+    };
+    af.backup(srcdir.path(), &options).unwrap();
+
+    std::fs::rename(srcdir.path().join("photos"), srcdir.path().join("pictures")).unwrap();
+    srcdir.create_file_with_contents("new", b"a new file");
+
+    let transport = TestTransport::new(af.path());
+    let archive = Archive::open(Box::new(transport.clone())).unwrap();
+    let stats = archive.backup(srcdir.path(), &options).unwrap();
+
+    assert_eq!(stats.moved_files, 2);
+    assert_eq!(stats.new_files, 1);
+    assert_eq!(stats.unmodified_files, 1);
+    // Only the new file's combined block was written, and the moved files
+    // weren't read and hashed.
+    assert_eq!(stats.written_blocks, 1);
+    assert_eq!(stats.deduplicated_blocks, 0);
+    let block_writes = transport
+        .writes()
+        .iter()
+        .filter(|path| path.starts_with("d/"))
+        .count();
+    assert_eq!(block_writes, 1);
+
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let before = af
+        .open_stored_tree(BandSelectionPolicy::Specified(BandId::new(&[0])))
+        .unwrap();
+    assert_eq!(
+        st.entry(&"/pictures/big".into()).unwrap().unwrap().addrs,
+        before.entry(&"/photos/big".into()).unwrap().unwrap().addrs
+    );
+    let restore_dir = TreeFixture::new();
+    restore(&af, restore_dir.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("pictures/small")).unwrap(),
+        b"small file"
+    );
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("pictures/big"))
+            .unwrap()
+            .len(),
+        2 << 20
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn moved_file_with_new_mtime_is_read_again() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"original");
+    let options = BackupOptions {
+        detect_moves: true,
+        ..BackupOptions::default()
+    };
+    af.backup(srcdir.path(), &options).unwrap();
+
+    std::fs::rename(srcdir.path().join("a"), srcdir.path().join("b")).unwrap();
+    filetime::set_file_mtime(
+        srcdir.path().join("b"),
+        filetime::FileTime::from_unix_time(1_000_000_000, 0),
+    )
+    .unwrap();
+    let stats = af.backup(srcdir.path(), &options).unwrap();
+
+    assert_eq!(stats.moved_files, 0);
+    assert_eq!(stats.new_files, 1);
+}
+
+#[test]
+fn builder_options_are_set() {
+    let options = BackupOptions::default()
+        .print_filenames(true)
+        .exclude_caches(true)
+        .one_file_system(true)
+        .max_file_size(Some(1000))
+        .break_lock(true)
+        .tags(vec!["nightly".to_owned()])
+        .index_format(IndexFormat::Cbor)
+        .retry_changed(2)
+        .detect_moves(true);
+    assert!(options.print_filenames);
+    assert!(options.exclude_caches);
+    assert!(options.one_file_system);
+    assert_eq!(options.max_file_size, Some(1000));
+    assert!(options.break_lock);
+    assert_eq!(options.tags, ["nightly"]);
+    assert_eq!(options.index_format, IndexFormat::Cbor);
+    assert_eq!(options.retry_changed, 2);
+    assert!(options.detect_moves);
+}
+
+#[test]
+fn backup_with_print_filenames() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let stats = af
+        .backup(
+            srcdir.path(),
+            &BackupOptions::default().print_filenames(true),
+        )
+        .unwrap();
+    assert_eq!(stats.new_files, 1);
+}
+
+#[test]
+fn backup_with_excludes() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("keep");
+    srcdir.create_file("skip.tmp");
+    let options = BackupOptions::default().excludes(excludes::from_strings(&["*.tmp"]).unwrap());
+    let stats = af.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(stats.files, 1);
+}
+
+#[test]
+fn backup_with_exclude_caches() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("cache");
+    srcdir.create_file_with_contents(
+        "cache/CACHEDIR.TAG",
+        b"Signature: 8a477f597d28d172789f06886806bc55\n",
+    );
+    srcdir.create_file("cache/junk");
+    srcdir.create_file("keep");
+
+    let stats = af
+        .backup(
+            srcdir.path(),
+            &BackupOptions::default().exclude_caches(true),
+        )
+        .unwrap();
+    assert_eq!(stats.directories, 2, "the cache directory itself is kept");
+    assert_eq!(stats.files, 1);
+
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.files, 3);
+}
+
+#[test]
+fn backup_with_one_file_system_keeps_same_filesystem() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/hello");
+    let stats = af
+        .backup(
+            srcdir.path(),
+            &BackupOptions::default().one_file_system(true),
+        )
+        .unwrap();
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.files, 1);
+}
+
+#[test]
+fn backup_with_max_file_size_skips_larger_files() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("small", b"small");
+    srcdir.create_file_with_contents("large", &[0u8; 1000]);
+    let stats = af
+        .backup(
+            srcdir.path(),
+            &BackupOptions::default().max_file_size(Some(100)),
+        )
+        .unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.oversized_files, 1);
+    let names: Vec<String> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_entries(None, &Exclude::nothing())
+        .map(|entry| entry.unwrap().apath().to_string())
+        .collect();
+    assert_eq!(names, ["/", "/small"]);
+}
+
+#[test]
+fn backup_monitor_is_told_about_each_entry() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct RecordingMonitor {
+        stored: Mutex<Vec<String>>,
+    }
+
+    impl BackupMonitor for RecordingMonitor {
+        fn entry_stored(&self, entry: &LiveEntry) {
+            self.stored.lock().unwrap().push(entry.apath().to_string());
+        }
+    }
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    let monitor = Arc::new(RecordingMonitor::default());
+    af.backup(
+        srcdir.path(),
+        &BackupOptions::default().monitor(monitor.clone()),
+    )
+    .unwrap();
+    assert_eq!(*monitor.stored.lock().unwrap(), ["/", "/hello", "/subdir"]);
+}
+
+#[test]
+fn backup_with_gitignore_style_excludes() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("build");
+    srcdir.create_file("build/out.o");
+    srcdir.create_dir("src");
+    srcdir.create_file("src/build");
+    srcdir.create_file("src/debug.log");
+    srcdir.create_file("src/keep.log");
+    let options = BackupOptions::default()
+        .excludes(excludes::from_strings(&["build/", "*.log", "!keep.log"]).unwrap());
+    let stats = af.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(stats.files, 2);
+    let names: Vec<String> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_entries(None, &Exclude::nothing())
+        .map(|entry| entry.unwrap().apath().to_string())
+        .collect();
+    assert_eq!(names, ["/", "/src", "/src/build", "/src/keep.log"]);
+}
+
+/// Read the entries in each hunk of the latest band.
+fn read_hunks(af: &Archive) -> Vec<Vec<IndexEntry>> {
+    let band = Band::open(af, &af.last_band_id().unwrap().unwrap()).unwrap();
+    let index = band.index();
+    (0..).map_while(|i| index.read_hunk(i).unwrap()).collect()
+}
+
+#[test]
+fn hunks_never_exceed_max_entries() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for d in 0..5 {
+        srcdir.create_dir(&format!("d{}", d));
+        for f in 0..100 {
+            // A mix of empty, small, and larger files, so that entries come
+            // both directly and through the small file combiner.
+            let content = vec![b'x'; (f % 3) * 100_000 + (f % 2) * 10];
+            srcdir.create_file_with_contents(&format!("d{}/f{:03}", d, f), &content);
+        }
+    }
+    let options = BackupOptions::default().max_entries_per_hunk(37);
+    let stats = af.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(stats.files, 500);
+
+    let hunks = read_hunks(&af);
+    assert_eq!(hunks.iter().map(Vec::len).sum::<usize>(), 506);
+    assert!(hunks.iter().all(|hunk| hunk.len() <= 37));
+    assert_eq!(hunks.len(), 14, "all but the last hunk are full");
+    assert_eq!(stats.index_builder_stats.index_hunks, 14);
+}
+
+#[test]
+fn hunks_are_bounded_by_serialized_size() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..200 {
+        srcdir.create_file(&format!("{:0>100}", i));
+    }
+    let max_hunk_bytes = 5000;
+    let options = BackupOptions::default().max_hunk_bytes(max_hunk_bytes);
+    af.backup(srcdir.path(), &options).unwrap();
+
+    let hunks = read_hunks(&af);
+    assert!(hunks.len() > 5, "{} hunks", hunks.len());
+    // The hunk is finished after the entry that reaches the limit, so may be
+    // one entry over.
+    let max_entry_len = serde_json::to_vec(&hunks[0][1]).unwrap().len();
+    for hunk in &hunks {
+        let len = serde_json::to_vec(hunk).unwrap().len();
+        assert!(
+            len <= max_hunk_bytes + max_entry_len,
+            "hunk of {} bytes",
+            len
+        );
+    }
+}
+
+/// Rewrite the start time in a band head, as if the clock was wrong when it
+/// was made.
+fn set_band_start_time(af: &ScratchArchive, band: &str, start_time: i64) {
+    let head_path = af.path().join(band).join("BANDHEAD");
+    let mut head: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&head_path).unwrap()).unwrap();
+    head["start_time"] = start_time.into();
+    std::fs::write(&head_path, head.to_string()).unwrap();
+}
+
+#[test]
+fn backup_after_clock_went_backwards() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    // Pretend the previous backup was made a day in the future.
+    let tomorrow = chrono::Utc::now().timestamp() + 86_400;
+    set_band_start_time(&af, "b0000", tomorrow);
+
+    let result = af.backup(srcdir.path(), &BackupOptions::default().strict_time(true));
+    match result {
+        Err(Error::ClockSkew {
+            previous_band,
+            previous_start,
+            ..
+        }) => {
+            assert_eq!(previous_band, BandId::zero());
+            assert_eq!(previous_start.timestamp(), tomorrow);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(af.band_ids().unwrap(), [BandId::zero()]);
+
+    // Without strict_time it only warns.
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(af.band_ids().unwrap().len(), 2);
+
+    let stats = af.validate(&ValidateOptions::default()).unwrap();
+    assert_eq!(stats.skewed_bands, 1);
+    assert!(!stats.has_problems());
+}
+
+#[test]
+fn empty_files_backup_and_restore() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("empty", b"");
+    srcdir.create_file_with_contents("shrinks", b"some content");
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.empty_files, 1);
+
+    // Replace a previously non-empty file with an empty one.
+    srcdir.create_file_with_contents("shrinks", b"");
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.unmodified_files, 1);
+    assert_eq!(stats.modified_files, 1);
+    assert_eq!(stats.empty_files, 1);
+    assert_eq!(stats.written_blocks, 0);
+
+    let entries: Vec<IndexEntry> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_filtered(None, None)
+        .unwrap()
+        .filter(|entry| entry.kind() == Kind::File)
+        .collect();
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|entry| entry.addrs.is_empty()));
+
+    let restore_dir = TreeFixture::new();
+    let stats = restore(&af, restore_dir.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.empty_files, 2);
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("empty")).unwrap(),
+        b""
+    );
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("shrinks")).unwrap(),
+        b""
+    );
+
+    // The first version still has the content.
+    let restore_dir = TreeFixture::new();
+    let options = RestoreOptions {
+        band_selection: BandSelectionPolicy::Specified(BandId::zero()),
+        ..RestoreOptions::default()
+    };
+    let stats = restore(&af, restore_dir.path(), &options).unwrap();
+    assert_eq!(stats.empty_files, 1);
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("shrinks")).unwrap(),
+        b"some content"
+    );
+
+    assert!(!af
+        .validate(&ValidateOptions::default())
+        .unwrap()
+        .has_problems());
+}
+
+#[test]
+fn many_empty_files() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..1000 {
+        srcdir.create_file_with_contents(&format!("empty{:04}", i), b"");
+    }
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.files, 1000);
+    assert_eq!(stats.empty_files, 1000);
+    assert_eq!(stats.written_blocks, 0);
+}
+
+#[test]
+fn backup_several_roots_and_restore() {
+    let etc = TreeFixture::new();
+    etc.create_file_with_contents("passwd", b"root:x:0:0");
+    etc.create_dir("ssh");
+    etc.create_file_with_contents("ssh/config", b"Host *");
+    let pg = TreeFixture::new();
+    pg.create_file_with_contents("data", b"rows");
+    let af = ScratchArchive::new();
+
+    let roots = vec![
+        SourceRoot::new(etc.path(), "/etc".into()),
+        SourceRoot::new(pg.path(), "/var/lib/postgresql".into()),
+    ];
+    let stats = af.backup_roots(roots, &BackupOptions::default()).unwrap();
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.errors, 0);
+
+    let apaths: Vec<String> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_filtered(None, None)
+        .unwrap()
+        .map(|entry| entry.apath().to_string())
+        .collect();
+    assert_eq!(
+        apaths,
+        [
+            "/",
+            "/etc",
+            "/var",
+            "/etc/passwd",
+            "/etc/ssh",
+            "/etc/ssh/config",
+            "/var/lib",
+            "/var/lib/postgresql",
+            "/var/lib/postgresql/data",
+        ]
+    );
+
+    let dest = TreeFixture::new();
+    restore(&af, dest.path(), &RestoreOptions::default()).unwrap();
+    let read = |path: &str| std::fs::read(dest.path().join(path)).unwrap();
+    assert_eq!(read("etc/ssh/config"), b"Host *");
+    assert_eq!(read("var/lib/postgresql/data"), b"rows");
+
+    let dest = TreeFixture::new();
+    let options = RestoreOptions {
+        only_subtree: Some("/var/lib/postgresql".into()),
+        ..RestoreOptions::default()
+    };
+    restore(&af, dest.path(), &options).unwrap();
+    assert!(!dest.path().join("etc").exists());
+    assert_eq!(
+        std::fs::read(dest.path().join("var/lib/postgresql/data")).unwrap(),
+        b"rows"
+    );
+}
+
+#[test]
+fn overlapping_backup_roots_are_refused() {
+    let tf = TreeFixture::new();
+    let af = ScratchArchive::new();
+    let roots = vec![
+        SourceRoot::new(tf.path(), "/home".into()),
+        SourceRoot::new(tf.path(), "/home/user".into()),
+    ];
+    assert!(matches!(
+        af.backup_roots(roots, &BackupOptions::default()),
+        Err(Error::OverlappingSourceRoots { .. })
+    ));
+    assert!(af.band_ids().unwrap().is_empty());
+}
+
+/// Back up a large file, insert a few bytes near its start, and back it up
+/// again, returning the blocks written by each backup.
+fn blocks_written_after_insertion(chunking: Chunking) -> (usize, usize) {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    // Incompressible, and without repeats that would dedupe.
+    let mut state: u64 = 1;
+    let content: Vec<u8> = (0..8 << 20)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    srcdir.create_file_with_contents("big", &content);
+    let options = BackupOptions::default().chunking(chunking);
+    let stats1 = af.backup(srcdir.path(), &options).unwrap();
+
+    let mut edited = content[..1000].to_vec();
+    edited.extend_from_slice(b"a few inserted bytes");
+    edited.extend_from_slice(&content[1000..]);
+    srcdir.create_file_with_contents("big", &edited);
+    let stats2 = af.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(stats2.modified_files, 1);
+
+    let band = Band::open(&af, &BandId::new(&[1])).unwrap();
+    assert_eq!(band.chunking().unwrap(), chunking);
+    let restore_dir = TreeFixture::new();
+    restore(&af, restore_dir.path(), &RestoreOptions::default()).unwrap();
+    assert!(std::fs::read(restore_dir.path().join("big")).unwrap() == edited);
+    assert!(!af
+        .validate(&ValidateOptions::default())
+        .unwrap()
+        .has_problems());
+
+    (stats1.written_blocks, stats2.written_blocks)
+}
+
+#[test]
+fn content_defined_chunking_dedupes_after_insertion() {
+    let (fixed1, fixed2) = blocks_written_after_insertion(Chunking::Fixed);
+    // Every block after the insertion is shifted.
+    assert!(fixed2 * 4 >= fixed1 * 3, "{} then {}", fixed1, fixed2);
+
+    let (cdc1, cdc2) = blocks_written_after_insertion(Chunking::content_defined());
+    // Only the blocks around the insertion are new.
+    assert!(cdc1 >= 16, "{}", cdc1);
+    assert!(cdc2 * 4 <= cdc1, "{} then {}", cdc1, cdc2);
+}
+
+#[test]
+fn blockdir_stats_count_blocks_and_sizes() {
+    let af = ScratchArchive::new();
+    assert_eq!(af.block_dir().stats().unwrap(), BlockDirStats::default());
+
+    let srcdir = TreeFixture::new();
+    let mut compressed_bytes = 0;
+    for i in 0..3 {
+        // Each backup writes one new block holding the new file.
+        srcdir.create_file_of_length_with_prefix(&format!("file{}", i), 1000 * (i + 1), b"x");
+        let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+        assert_eq!(stats.written_blocks, 1);
+        compressed_bytes += stats.compressed_bytes;
+    }
+
+    let stats = af.block_dir().stats().unwrap();
+    assert_eq!(stats.block_count, 3);
+    assert_eq!(stats.total_bytes, compressed_bytes);
+    assert_eq!(stats.mean_block_bytes, compressed_bytes / 3);
+    assert!(stats.min_block_bytes <= stats.mean_block_bytes);
+    assert!(stats.mean_block_bytes <= stats.max_block_bytes);
+    assert_eq!(stats.size_histogram.values().sum::<usize>(), 3);
+    for (&bucket, _) in &stats.size_histogram {
+        assert!(bucket.is_power_of_two());
+    }
+}
+
+#[test]
+fn archive_inside_source_is_excluded() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("backups");
+    let archive = Archive::create_path(&srcdir.path().join("backups").join("arch")).unwrap();
+
+    // Back up through a relative path with `..`, which is canonicalized.
+    let source = srcdir.path().join("backups").join("..");
+    for _ in 0..3 {
+        let stats = archive.backup(&source, &BackupOptions::default()).unwrap();
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.files, 1);
+    }
+
+    let stored_tree = archive
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap();
+    let apaths: Vec<String> = ReadTree::iter_entries(&stored_tree)
+        .unwrap()
+        .map(|entry| entry.apath().to_string())
+        .collect();
+    assert_eq!(apaths, ["/", "/backups", "/hello", "/backups/arch"]);
+}
+
+/// Back up a tree, then change it so that the next backup has unmodified,
+/// modified, and new files, including one whose content is already stored.
+fn tree_changed_since_backup(af: &ScratchArchive) -> TreeFixture {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("same", &[1u8; 200_000]);
+    srcdir.create_file_with_contents("grown", &[2u8; 200_000]);
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    srcdir.create_file_with_contents("grown", &[2u8; 300_000]);
+    srcdir.create_file_with_contents("copy", &[1u8; 200_000]);
+    srcdir.create_file("small");
+    srcdir
+}
+
+fn archive_contents(af: &ScratchArchive) -> (Vec<BandId>, Vec<BlockHash>) {
+    let mut blocks: Vec<BlockHash> = af.block_dir().block_names().unwrap().collect();
+    blocks.sort();
+    (af.band_ids().unwrap(), blocks)
+}
+
+#[test]
+fn dry_run_with_hashes_matches_backup() {
+    let af = ScratchArchive::new();
+    let srcdir = tree_changed_since_backup(&af);
+    let before = archive_contents(&af);
+
+    let options = BackupOptions::default().dry_run(Some(DryRun::Hash));
+    let dry_stats = af.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(archive_contents(&af), before);
+    assert_eq!(dry_stats.deduplicated_blocks, 1);
+    assert_eq!(dry_stats.written_blocks, 2);
+
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(dry_stats, stats);
+}
+
+#[test]
+fn dry_run_without_reading_files() {
+    let af = ScratchArchive::new();
+    let srcdir = tree_changed_since_backup(&af);
+    let before = archive_contents(&af);
+
+    let options = BackupOptions::default().dry_run(Some(DryRun::Metadata));
+    let dry_stats = af.backup(srcdir.path(), &options).unwrap();
+    assert_eq!(archive_contents(&af), before);
+    assert_eq!(dry_stats.unmodified_files, 1);
+    assert_eq!(dry_stats.modified_files, 1);
+    assert_eq!(dry_stats.new_files, 2);
+    assert_eq!(
+        dry_stats.unread_bytes,
+        300_000 + 200_000 + srcdir.path().join("small").metadata().unwrap().len()
+    );
+    assert_eq!(dry_stats.written_blocks, 0);
+
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(
+        (
+            stats.unmodified_files,
+            stats.modified_files,
+            stats.new_files
+        ),
+        (1, 1, 2)
+    );
+    // Entries are counted, although unread files have no addresses.
+    assert_eq!(
+        dry_stats.index_builder_stats.index_entries,
+        stats.index_builder_stats.index_entries
+    );
+}
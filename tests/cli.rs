@@ -234,12 +234,25 @@ both     /subdir/subfile
         .success()
         .stderr(predicate::str::is_empty())
         .stdout(
-            predicate::str::is_match(
-                r"^b0000 *complete   20\d\d-\d\d-\d\d \d\d:\d\d:\d\d +0:\d+ *0 MB\n$",
-            )
+            predicate::str::is_match(concat!(
+                r"^version +status +started +took +size +unique +shared\n",
+                r"b0000 *complete   20\d\d-\d\d-\d\d \d\d:\d\d:\d\d +0:\d+ +\d+ B +\d+ B +0 B\n$",
+            ))
             .unwrap(),
         );
 
+    let output = run_conserve()
+        .args(&["versions", "--sizes", "--json"])
+        .arg(&arch_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let versions: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(versions[0]["band_id"], "b0000");
+    assert_eq!(versions[0]["is_complete"], true);
+    assert_eq!(versions[0]["shared_bytes"], 0);
+    assert!(versions[0]["unique_bytes"].as_u64().unwrap() > 0);
+
     run_conserve()
         .arg("ls")
         .arg(&arch_dir)
@@ -253,6 +266,25 @@ both     /subdir/subfile
              /subdir/subfile\n",
         );
 
+    run_conserve()
+        .args(&["ls", "--files-only"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout("/hello\n/subdir/subfile\n");
+
+    run_conserve()
+        .args(&["ls", "-l", "--files-only"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::is_match(
+                r"^- +\d+ 20\d\d-\d\d-\d\d \d\d:\d\d:\d\d /hello\n- +\d+ 20\d\d-\d\d-\d\d \d\d:\d\d:\d\d /subdir/subfile\n$",
+            )
+            .unwrap(),
+        );
+
     // TODO: Factor out comparison to expected tree.
     let restore_dir = TempDir::new().unwrap();
 
@@ -381,6 +413,37 @@ fn incomplete_version() {
         .stdout(predicate::str::contains("incomplete and may be in use"));
 }
 
+/// Warnings go to stderr when stdout carries json.
+#[test]
+fn json_output_with_warning() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    std::fs::remove_file(af.path().join("b0001").join("BANDTAIL")).unwrap();
+
+    let output = run_conserve()
+        .args(&["ls", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("b0001 is incomplete"), "{}", stderr);
+    for line in std::str::from_utf8(&output.stdout).unwrap().lines() {
+        let _: serde_json::Value = serde_json::from_str(line).unwrap();
+    }
+
+    let output = run_conserve()
+        .args(&["stats", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("b0001 is incomplete"), "{}", stderr);
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["band_id"], "b0001");
+}
+
 #[test]
 fn exclude_option_ordering() {
     // Regression caused by the move to structopt(?) in 7ddb02d0cf47467f1cccc2dcdedb005e8c4e3f25.
@@ -412,6 +475,82 @@ fn validate_non_fatal_problems_nonzero_result() {
         .code(2);
 }
 
+#[test]
+fn validate_selected_bands() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .arg("validate")
+        .arg(af.path())
+        .args(&["--bands", "b0001", "--quick"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Archive is OK."));
+    run_conserve()
+        .arg("validate")
+        .arg(af.path())
+        .args(&["--bands", "b0007"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("No backup b0007 in archive"));
+}
+
+#[test]
+fn backup_dry_run() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    run_conserve()
+        .args(&["backup", "--dry-run", "-v"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("/hello (new)"))
+        .stdout(predicate::str::contains(
+            "Dry run complete; nothing was written.",
+        ));
+    let output = run_conserve()
+        .args(&["backup", "--dry-run=hash", "--json"])
+        .arg(af.path())
+        .arg(src.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["new_files"], 1);
+    assert_eq!(stats["written_blocks"], 1);
+    assert!(!af.path().join("b0000").exists());
+}
+
+#[test]
+fn backup_json_sends_messages_to_stderr() {
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let archive_path = src.path().join("archive");
+    run_conserve()
+        .arg("init")
+        .arg(&archive_path)
+        .assert()
+        .success();
+    let output = run_conserve()
+        .args(&["backup", "--json", "-v"])
+        .arg(&archive_path)
+        .arg(src.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["new_files"], 1);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Archive is inside the source tree"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("/hello (new)"), "{}", stderr);
+}
+
 #[test]
 fn restore_only_subtree() {
     let dest = TempDir::new().unwrap();
@@ -469,6 +608,19 @@ fn delete_nonexistent_band() {
         .failure();
 }
 
+#[test]
+fn ls_nonexistent_band() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["ls", "-b", "b0009"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout("conserve error: No backup b0009 in archive; available backups: b0000, b0001\n");
+}
+
 #[test]
 fn size_exclude() {
     let source = TreeFixture::new();
@@ -510,4 +662,902 @@ fn verbose_versions_sort_recent_first() {
         .success()
         .stderr(predicate::str::is_empty())
         .stdout(predicate::str::is_match("b0001.*\nb0000.*").unwrap());
-}
\ No newline at end of file
+}
+#[test]
+fn encrypted_archive() {
+    let temp = TempDir::new().unwrap();
+    let archive = temp.path().join("archive");
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(&["init", "--encrypt"])
+        .arg(&archive)
+        .env("CONSERVE_PASSPHRASE", "correct horse")
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("backup")
+        .arg(&archive)
+        .arg(src.path())
+        .env("CONSERVE_PASSPHRASE", "correct horse")
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("ls")
+        .arg(&archive)
+        .env("CONSERVE_PASSPHRASE", "correct horse")
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    run_conserve()
+        .arg("ls")
+        .arg(&archive)
+        .env("CONSERVE_PASSPHRASE", "battery staple")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Wrong passphrase or key file for encrypted archive",
+        ));
+
+    // Without a passphrase, and with no terminal to prompt on, it fails cleanly.
+    run_conserve()
+        .arg("ls")
+        .arg(&archive)
+        .env_remove("CONSERVE_PASSPHRASE")
+        .env_remove("CONSERVE_KEY_FILE")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Archive is encrypted, and no passphrase or key file was given",
+        ));
+}
+
+#[test]
+fn encrypted_archive_with_key_file() {
+    let temp = TempDir::new().unwrap();
+    let archive = temp.path().join("archive");
+    let key_file = temp.path().join("key");
+    std::fs::write(&key_file, [7u8; 64]).unwrap();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(&["init", "--key-file"])
+        .arg(&key_file)
+        .arg(&archive)
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "A key file was given, but the new archive is not encrypted",
+        ));
+
+    run_conserve()
+        .args(&["init", "--encrypt", "--key-file"])
+        .arg(&key_file)
+        .arg(&archive)
+        .env_remove("CONSERVE_PASSPHRASE")
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("backup")
+        .arg(&archive)
+        .arg(src.path())
+        .arg("--key-file")
+        .arg(&key_file)
+        .env_remove("CONSERVE_PASSPHRASE")
+        .assert()
+        .success();
+
+    // The option can also come before the command.
+    run_conserve()
+        .arg("--key-file")
+        .arg(&key_file)
+        .arg("ls")
+        .arg(&archive)
+        .env_remove("CONSERVE_PASSPHRASE")
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    run_conserve()
+        .arg("validate")
+        .arg(&archive)
+        .arg("--key-file")
+        .arg(&key_file)
+        .env_remove("CONSERVE_PASSPHRASE")
+        .assert()
+        .success();
+
+    // The key file takes precedence over a passphrase in the environment.
+    run_conserve()
+        .arg("versions")
+        .arg(&archive)
+        .arg("--key-file")
+        .arg(&key_file)
+        .env("CONSERVE_PASSPHRASE", "wrong")
+        .assert()
+        .success();
+}
+
+#[test]
+fn archive_config_excludes() {
+    let temp = TempDir::new().unwrap();
+    let archive = temp.path().join("archive");
+    let src = TreeFixture::new();
+    src.create_file("keep");
+    src.create_file("junk.tmp");
+
+    run_conserve()
+        .args(&["init", "--exclude", "*.tmp"])
+        .arg(&archive)
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("config")
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"*.tmp\""));
+
+    // Backup with no flags uses the excludes from the archive config.
+    run_conserve()
+        .arg("backup")
+        .arg(&archive)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("ls")
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout("/\n/keep\n");
+}
+
+#[test]
+fn command_line_overrides_configured_exclude_caches() {
+    let temp = TempDir::new().unwrap();
+    let archive = temp.path().join("archive");
+    let src = TreeFixture::new();
+    src.create_dir("cache");
+    src.create_file_with_contents(
+        "cache/CACHEDIR.TAG",
+        b"Signature: 8a477f597d28d172789f06886806bc55\n",
+    );
+    src.create_file("cache/data");
+
+    run_conserve()
+        .args(&["init", "--exclude-caches"])
+        .arg(&archive)
+        .assert()
+        .success();
+    run_conserve()
+        .arg("backup")
+        .arg(&archive)
+        .arg(src.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(&["backup", "--no-exclude-caches"])
+        .arg(&archive)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(&["ls", "-b", "b0"])
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout("/\n/cache\n");
+    run_conserve()
+        .args(&["ls", "-b", "b1"])
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout("/\n/cache\n/cache/CACHEDIR.TAG\n/cache/data\n");
+}
+
+#[test]
+fn init_index_format_is_used_by_backups() {
+    let temp = TempDir::new().unwrap();
+    let archive = temp.path().join("archive");
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(&["init", "--index-format", "cbor", "--exclude", "*.tmp"])
+        .arg(&archive)
+        .assert()
+        .success();
+    run_conserve()
+        .arg("backup")
+        .arg(&archive)
+        .arg(src.path())
+        .assert()
+        .success();
+    let head = std::fs::read_to_string(archive.join("b0000").join("BANDHEAD")).unwrap();
+    assert!(head.contains(r#""index_format":"cbor""#), "{}", head);
+
+    // The format can still be chosen for one backup.
+    run_conserve()
+        .args(&["backup", "--index-format", "json"])
+        .arg(&archive)
+        .arg(src.path())
+        .assert()
+        .success();
+    let head = std::fs::read_to_string(archive.join("b0001").join("BANDHEAD")).unwrap();
+    assert!(!head.contains("cbor"), "{}", head);
+}
+
+#[test]
+fn init_refuses_existing_archive_and_non_empty_directory() {
+    let af = ScratchArchive::new();
+    run_conserve()
+        .arg("init")
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Directory for new archive already holds an archive",
+        ));
+
+    let temp = TempDir::new().unwrap();
+    temp.child("something").touch().unwrap();
+    run_conserve()
+        .arg("init")
+        .arg(temp.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Directory for new archive is not empty",
+        ));
+    assert!(!temp.child("CONSERVE").path().exists());
+}
+
+#[test]
+fn tag_and_select_backups() {
+    let temp = TempDir::new().unwrap();
+    let archive = temp.path().join("archive");
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve().arg("init").arg(&archive).assert().success();
+    run_conserve()
+        .args(&["backup", "--tag", "pre-upgrade"])
+        .arg(&archive)
+        .arg(src.path())
+        .assert()
+        .success();
+    src.create_file("world");
+    run_conserve()
+        .arg("backup")
+        .arg(&archive)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(&["ls", "--backup", "pre-upgrade"])
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    run_conserve()
+        .arg("versions")
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"^b0000 .* \[pre-upgrade\]\nb0001 .*[^\]]\n$").unwrap());
+
+    // Retro-tag the second backup; the newest band with a tag is selected.
+    run_conserve()
+        .args(&["tag", "--add", "pre-upgrade", "--add", "latest"])
+        .arg(&archive)
+        .arg("b0001")
+        .assert()
+        .success()
+        .stdout("pre-upgrade\nlatest\n");
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(&["restore", "--backup", "pre-upgrade"])
+        .arg(&archive)
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    restore_dir
+        .child("world")
+        .assert(predicate::path::is_file());
+
+    run_conserve()
+        .args(&["tag", "--remove", "pre-upgrade"])
+        .arg(&archive)
+        .arg("b0001")
+        .assert()
+        .success()
+        .stdout("latest\n");
+    run_conserve()
+        .args(&["ls", "--backup", "pre-upgrade"])
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    run_conserve()
+        .args(&["ls", "--backup", "nonesuch"])
+        .arg(&archive)
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "No backup is tagged \"nonesuch\"; available tags: [\"latest\", \"pre-upgrade\"]",
+        ));
+}
+
+#[test]
+fn select_backup_by_time() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["ls", "--as-of", "2000-01-01"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "No complete backup started at or before 2000-01-01",
+        ));
+
+    run_conserve()
+        .args(&["ls", "--backup-before", "now"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("/hello2"));
+
+    run_conserve()
+        .args(&["ls", "--as-of", "last tuesday"])
+        .arg(af.path())
+        .assert()
+        .failure();
+}
+
+#[cfg(unix)]
+#[test]
+fn export_tar_to_stdout_and_file() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let output = run_conserve()
+        .args(&["export-tar", "--backup", "b0"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Export complete.\n"), "{}", stderr);
+    assert!(stderr.contains("           2      files:\n"), "{}", stderr);
+    let paths: Vec<String> = tar::Archive::new(output.stdout.as_slice())
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect();
+    assert_eq!(paths, ["hello", "link", "subdir/", "subdir/subfile"]);
+
+    let temp = TempDir::new().unwrap();
+    let tar_path = temp.child("out.tar.gz");
+    run_conserve()
+        .args(&["export-tar", "--gz", "-o"])
+        .arg(tar_path.path())
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Export complete.\n"))
+        .stdout(predicate::str::contains("           3      files:\n"));
+    let gz = flate2::read::GzDecoder::new(std::fs::File::open(tar_path.path()).unwrap());
+    let entries = tar::Archive::new(gz).entries().unwrap().count();
+    assert_eq!(entries, 5);
+}
+
+#[test]
+fn import_gzipped_tar() {
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    {
+        let mut builder = tar::Builder::new(&mut encoder);
+        builder.append_dir_all(".", tf.path()).unwrap();
+        builder.finish().unwrap();
+    }
+    let temp = TempDir::new().unwrap();
+    let tar_path = temp.child("in.tar.gz");
+    tar_path.write_binary(&encoder.finish().unwrap()).unwrap();
+
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(&["import-tar", "--tag", "from-tar"])
+        .arg(af.path())
+        .arg(tar_path.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Import complete.\n"));
+    run_conserve()
+        .args(&["ls", "--backup", "from-tar"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+}
+
+#[test]
+fn stats_of_band_without_recorded_stats() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    std::fs::remove_file(af.path().join("b0000").join("stats.json")).unwrap();
+
+    run_conserve()
+        .args(&["stats", "-b", "b0"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Backup b0000: stats unknown\n"))
+        .stdout(predicate::str::contains("in latest backup b0001"));
+
+    let output = run_conserve()
+        .args(&["stats", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["band_id"], "b0001");
+    assert_eq!(json["backup_stats"]["new_files"], 1);
+    assert_eq!(json["archive"]["latest_band"], "b0001");
+}
+
+#[test]
+fn debug_blocks_report() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    std::fs::write(af.path().join("d").join("tmp-leftover"), b"junk").unwrap();
+
+    run_conserve()
+        .args(&["debug", "blocks", "--report"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("unreferenced blocks"))
+        .stdout(predicate::str::contains("tmp-leftover"));
+
+    let output = run_conserve()
+        .args(&["debug", "blocks", "--report", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["unreferenced_blocks"], serde_json::json!([]));
+    assert_eq!(json["reclaimable_bytes"], 0);
+    assert_eq!(
+        json["unexpected_files"],
+        serde_json::json!(["tmp-leftover"])
+    );
+}
+
+#[test]
+fn debug_blockdir_stats() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["debug", "blockdir-stats"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("           2      blocks\n"))
+        .stdout(predicate::str::contains("total compressed size"));
+
+    let output = run_conserve()
+        .args(&["debug", "blockdir-stats", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["block_count"], 2);
+    assert!(json["total_bytes"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn debug_entry_shows_index_entry_json() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let output = run_conserve()
+        .args(&["debug", "entry"])
+        .arg(af.path())
+        .arg("/hello")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["apath"], "/hello");
+    assert_eq!(json["kind"], "File");
+    assert_eq!(json["size"], 8);
+    assert!(json["mtime"].as_str().unwrap().ends_with('Z'));
+    let addrs = json["addrs"].as_array().unwrap();
+    assert_eq!(addrs.len(), 1);
+    assert_eq!(addrs[0]["hash"].as_str().unwrap().len(), 128);
+    assert_eq!(addrs[0]["len"], 8);
+    assert!(addrs[0]["start"].is_u64());
+
+    if conserve::SYMLINKS_SUPPORTED {
+        let output = run_conserve()
+            .args(&["debug", "entry", "-b", "b0"])
+            .arg(af.path())
+            .arg("/link")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(json["kind"], "Symlink");
+        assert_eq!(json["target"], "target");
+        assert!(json.get("addrs").is_none());
+    }
+
+    // hello2 was only added in the second version.
+    run_conserve()
+        .args(&["debug", "entry", "-b", "b0"])
+        .arg(af.path())
+        .arg("/hello2")
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("No entry /hello2 in b0000"));
+}
+
+#[test]
+fn debug_index_hunks() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    for flag in &["--hunks", "--raw"] {
+        let output = run_conserve()
+            .args(&["debug", "index", flag])
+            .arg(af.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let hunks = json.as_array().unwrap();
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0]["hunk"], 0);
+        assert_eq!(hunks[0]["entries"][1]["apath"], "/hello");
+    }
+}
+
+#[test]
+fn ls_json() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let output = run_conserve()
+        .arg("ls")
+        .arg("--json")
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let entries: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(entries[0]["apath"], "/");
+    assert_eq!(entries[0]["kind"], "Dir");
+    assert_eq!(entries[1]["apath"], "/hello");
+    assert_eq!(entries[1]["size"], 8);
+    assert!(entries[1].get("addrs").is_none());
+}
+
+#[test]
+fn backup_warns_about_unmatched_exclude() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("junk.tmp");
+
+    let output = run_conserve()
+        .args(&["backup", "-e", "*.tmp", "-e", "*.tpm"])
+        .arg(af.path())
+        .arg(src.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let warnings: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.contains("matched nothing"))
+        .collect();
+    assert_eq!(
+        warnings,
+        ["conserve warning: Exclude pattern \"*.tpm\" matched nothing"]
+    );
+    assert!(!stdout.contains("problem encountered"), "{}", stdout);
+
+    let output = run_conserve()
+        .args(&["stats", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        stats["backup_stats"]["pattern_matches"],
+        serde_json::json!([
+            {"pattern": "*.tmp", "matches": 1},
+            {"pattern": "*.tpm", "matches": 0},
+        ])
+    );
+}
+
+#[test]
+fn restore_require_matches_fails_if_only_matches_nothing() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+
+    run_conserve()
+        .args(&["restore", "--only", "/nothing", "--require-matches"])
+        .arg(af.path())
+        .arg(destdir.path().join("a"))
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("--only /nothing matched nothing"));
+
+    run_conserve()
+        .args(&["restore", "--only", "/subdir", "--require-matches"])
+        .arg(af.path())
+        .arg(destdir.path().join("b"))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("matched nothing").not());
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_to_stdout_tar() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let output = run_conserve()
+        .args(&["restore", "--to-stdout-tar", "--backup", "b0"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Restore complete.\n"), "{}", stderr);
+    let paths: Vec<String> = tar::Archive::new(output.stdout.as_slice())
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect();
+    assert_eq!(paths, ["hello", "link", "subdir/", "subdir/subfile"]);
+}
+
+#[test]
+fn restore_to_stdout_tar_conflicts_with_destination() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+
+    run_conserve()
+        .args(&["restore", "--to-stdout-tar"])
+        .arg(af.path())
+        .arg(destdir.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+#[test]
+fn backup_warns_if_clock_went_backwards() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(srcdir.path())
+        .assert()
+        .success();
+    let head_path = af.path().join("b0000").join("BANDHEAD");
+    let mut head: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&head_path).unwrap()).unwrap();
+    head["start_time"] = (chrono::Utc::now().timestamp() + 86_400).into();
+    std::fs::write(&head_path, head.to_string()).unwrap();
+
+    run_conserve()
+        .args(&["backup", "--strict-time"])
+        .arg(af.path())
+        .arg(srcdir.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "earlier than the start of the previous backup b0000",
+        ));
+
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(srcdir.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "earlier than the start of the previous backup b0000",
+        ));
+
+    run_conserve()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Band b0001 started at"))
+        .stdout(predicate::str::contains("before band b0000"));
+}
+
+#[test]
+fn diff_trees() {
+    let left = TreeFixture::new();
+    let right = TreeFixture::new();
+    left.create_file_with_contents("hello", b"hello");
+    right.create_file_with_contents("hello", b"jello");
+    right.create_file("added");
+
+    run_conserve()
+        .arg("diff-trees")
+        .arg(left.path())
+        .arg(right.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty())
+        .stdout("both     /\nright    /added\nboth     /hello\n");
+
+    run_conserve()
+        .args(&["diff-trees", "--bytes", "--json", "--exclude", "/added"])
+        .arg(left.path())
+        .arg(right.path())
+        .assert()
+        .success()
+        .stdout(
+            "{\"apath\":\"/\",\"change\":\"both\"}\n\
+             {\"apath\":\"/hello\",\"change\":\"changed\"}\n",
+        );
+}
+
+#[test]
+fn backup_several_sources() {
+    let af = ScratchArchive::new();
+    let etc = TreeFixture::new();
+    etc.create_file("hosts");
+    let home = TreeFixture::new();
+    home.create_file("notes");
+
+    run_conserve()
+        .args(&["backup", "--as", "/home/user", "--as", "/home/user"])
+        .arg(af.path())
+        .arg(etc.path())
+        .arg(home.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Source roots /home/user and /home/user overlap",
+        ));
+    run_conserve()
+        .args(&["backup", "--as", "/etc"])
+        .arg(af.path())
+        .arg(etc.path())
+        .arg(home.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Given 1 --as mappings for 2 sources",
+        ));
+
+    run_conserve()
+        .args(&["backup", "--as", "/etc", "--as", "/home/user"])
+        .arg(af.path())
+        .arg(etc.path())
+        .arg(home.path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/etc\n/home\n/etc/hosts\n/home/user\n/home/user/notes\n");
+}
+
+#[test]
+fn backup_with_cdc_records_chunking() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file_with_contents("big", &vec![b'x'; 300_000]);
+
+    run_conserve()
+        .args(&["backup", "--cdc", "--cdc-min-size", "10"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("Invalid chunk sizes"));
+
+    run_conserve()
+        .args(&["backup", "--cdc", "--cdc-avg-size", "131072"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let head = std::fs::read_to_string(af.path().join("b0000").join("BANDHEAD")).unwrap();
+    assert!(
+        head.contains(r#""chunking":{"content_defined":{"min_size":65536,"avg_size":131072"#),
+        "{}",
+        head
+    );
+}
+
+#[test]
+fn preflight() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .arg("preflight")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "Archive is ready for backup.\n",
+        ));
+
+    let not_archive = TempDir::new().unwrap();
+    run_conserve()
+        .arg("preflight")
+        .arg(not_archive.path())
+        .assert()
+        .code(4)
+        .stdout(predicate::str::contains("Not a Conserve archive"));
+}
+
+#[cfg(unix)]
+#[test]
+fn ls_escapes_names_that_are_not_utf8() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    let name = std::ffi::OsStr::from_bytes(b"caf\xe9");
+    std::fs::write(src.path().join(name), b"latin-1").unwrap();
+    std::os::unix::fs::symlink(name, src.path().join("link")).unwrap();
+    af.backup(src.path(), &Default::default()).unwrap();
+
+    run_conserve()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/caf\\xe9\n/link\n");
+    run_conserve()
+        .args(&["ls", "-l"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("/link -> caf\\xe9\n"));
+}
@@ -15,14 +15,116 @@
 
 use std::path::Path;
 
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use conserve::*;
 
 #[test]
 fn missing_block() -> Result<()> {
     let archive = Archive::open_path(Path::new("testdata/damaged/missing-block"))?;
 
-    let validate_stats = archive.validate()?;
+    let validate_stats = archive.validate(&ValidateOptions::default())?;
     assert_eq!(validate_stats.has_problems(), true);
     assert_eq!(validate_stats.block_missing_count, 1);
     Ok(())
 }
+
+#[test]
+fn same_findings_with_one_or_many_threads() {
+    let archive = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    for i in 0..8 {
+        tf.create_file_of_length_with_prefix(
+            &format!("file{}", i),
+            2_000_000,
+            format!("prefix {}", i).as_bytes(),
+        );
+        archive
+            .backup(tf.path(), &BackupOptions::default())
+            .unwrap();
+    }
+    let mut block_paths: Vec<_> = walkdir::WalkDir::new(archive.path().join("d"))
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+    block_paths.sort();
+    assert!(block_paths.len() > 2);
+    std::fs::remove_file(&block_paths[0]).unwrap();
+    std::fs::write(&block_paths[1], b"not a block").unwrap();
+
+    let one_thread = archive
+        .validate(&ValidateOptions {
+            threads: Some(1),
+            ..ValidateOptions::default()
+        })
+        .unwrap();
+    assert!(one_thread.has_problems());
+    assert!(!one_thread.problems.is_empty());
+    assert!(one_thread
+        .problems
+        .iter()
+        .all(|problem| problem.kind == ProblemKind::Block));
+    assert_eq!(one_thread.block_error_count, 1);
+    assert!(one_thread.block_missing_count >= 2);
+    let many_threads = archive
+        .validate(&ValidateOptions {
+            threads: Some(4),
+            ..ValidateOptions::default()
+        })
+        .unwrap();
+    assert_eq!(one_thread, many_threads);
+}
+
+#[test]
+fn damaged_index_hunk() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    for i in 0..6 {
+        tf.create_file(&format!("file{}", i));
+    }
+    af.backup(tf.path(), &BackupOptions::default().max_entries_per_hunk(2))
+        .unwrap();
+    let hunk_path = af.path().join("b0000/i/00000/000000001");
+    let mut stored = std::fs::read(&hunk_path).unwrap();
+    stored.truncate(stored.len() - 1);
+    std::fs::write(&hunk_path, stored).unwrap();
+
+    let stats = af.validate(&ValidateOptions::default()).unwrap();
+    assert!(stats.has_problems());
+    assert_eq!(stats.index_hunk_problems, 1);
+    let messages: Vec<String> = stats.problems.iter().map(|p| p.to_string()).collect();
+    assert_eq!(
+        messages,
+        ["Index hunk \"00000/000000001\" doesn't match the hash in the manifest in b0000"]
+    );
+
+    // Restore stops at the damaged hunk.
+    let restore_dir = TreeFixture::new();
+    let err = restore(&af, restore_dir.path(), &RestoreOptions::default()).unwrap_err();
+    assert!(
+        matches!(err, Error::IndexHunkHashMismatch { .. }),
+        "unexpected error {:?}",
+        err
+    );
+
+    // So does iterating the stored tree, after the entries before it.
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let entries: Vec<Result<IndexEntry>> = tree.iter_entries(None, &Exclude::nothing()).collect();
+    assert_eq!(entries.len(), 3);
+    assert!(entries[..2].iter().all(Result::is_ok));
+    assert!(entries[2].is_err());
+
+    // Looking up an entry in the damaged hunk is an error, not just missing.
+    assert!(tree.entry(&Apath::from("/file1")).is_err());
+    assert!(tree.entry(&Apath::from("/file3")).unwrap().is_some());
+
+    // Entries in the other hunks can still be read when asked for.
+    let names: Vec<String> = af
+        .iter_stitched_index_hunks(&BandId::new(&[0]))
+        .lenient()
+        .iter_entries()
+        .map(|entry| entry.apath.to_string())
+        .collect();
+    assert_eq!(names, ["/", "/file0", "/file3", "/file4", "/file5"]);
+}
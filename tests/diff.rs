@@ -0,0 +1,119 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for comparing trees.
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Make two trees that differ in one removed, one added, one size-changed,
+/// and one content-only-changed file.
+fn differing_trees() -> (TreeFixture, TreeFixture) {
+    let left = TreeFixture::new();
+    let right = TreeFixture::new();
+    for tf in &[&left, &right] {
+        tf.create_dir("subdir");
+        tf.create_file_with_contents("same", b"unchanged");
+    }
+    left.create_file_with_contents("removed", b"only on the left");
+    right.create_file_with_contents("subdir/added", b"only on the right");
+    left.create_file_with_contents("grown", b"short");
+    right.create_file_with_contents("grown", b"a bit longer");
+    left.create_file_with_contents("subdir/edited", b"hello");
+    right.create_file_with_contents("subdir/edited", b"jello");
+    (left, right)
+}
+
+fn diff_kinds(left: &TreeFixture, right: &TreeFixture, compare_content: bool) -> Vec<String> {
+    let options = DiffOptions {
+        compare_content,
+        ..DiffOptions::default()
+    };
+    iter_diff(&left.live_tree(), &right.live_tree(), &options)
+        .unwrap()
+        .map(|de| format!("{} {}", de.change.name(), de.apath))
+        .collect()
+}
+
+#[test]
+fn diff_live_trees_by_metadata() {
+    let (left, right) = differing_trees();
+    assert_eq!(
+        diff_kinds(&left, &right, false),
+        [
+            "both /",
+            "changed /grown",
+            "left /removed",
+            "both /same",
+            "both /subdir",
+            "right /subdir/added",
+            "both /subdir/edited",
+        ]
+    );
+}
+
+#[test]
+fn diff_live_trees_by_content() {
+    let (left, right) = differing_trees();
+    assert_eq!(
+        diff_kinds(&left, &right, true),
+        [
+            "both /",
+            "changed /grown",
+            "left /removed",
+            "both /same",
+            "both /subdir",
+            "right /subdir/added",
+            "changed /subdir/edited",
+        ]
+    );
+}
+
+#[test]
+fn diff_live_trees_as_json() {
+    let (left, right) = differing_trees();
+    let options = DiffOptions {
+        compare_content: true,
+        json: true,
+        excludes: excludes::from_strings(&["/subdir"]).unwrap(),
+        ..DiffOptions::default()
+    };
+    let mut out: Vec<u8> = Vec::new();
+    diff_trees(&left.live_tree(), &right.live_tree(), &options, &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+{\"apath\":\"/\",\"change\":\"both\"}
+{\"apath\":\"/grown\",\"change\":\"changed\"}
+{\"apath\":\"/removed\",\"change\":\"left\"}
+{\"apath\":\"/same\",\"change\":\"both\"}
+"
+    );
+}
+
+#[test]
+fn diff_stored_tree_by_content() {
+    let (left, right) = differing_trees();
+    let af = ScratchArchive::new();
+    af.backup(left.path(), &BackupOptions::default()).unwrap();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let options = DiffOptions {
+        compare_content: true,
+        ..DiffOptions::default()
+    };
+    let changed: Vec<String> = iter_diff(&st, &right.live_tree(), &options)
+        .unwrap()
+        .filter(|de| de.change == DiffKind::Changed)
+        .map(|de| de.apath.to_string())
+        .collect();
+    assert_eq!(changed, ["/grown", "/subdir/edited"]);
+}
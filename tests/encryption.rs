@@ -0,0 +1,124 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test encrypted archives.
+
+use std::fs;
+
+use assert_fs::prelude::*;
+use assert_fs::TempDir;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+fn source_tree() -> TreeFixture {
+    let tree = TreeFixture::new();
+    tree.create_file_with_contents("hello", b"a secret greeting");
+    tree.create_dir("subdir");
+    tree.create_file_with_contents("subdir/subfile", b"more secret content");
+    tree
+}
+
+#[test]
+fn encrypted_round_trip() {
+    let temp = TempDir::new().unwrap();
+    let archive_path = temp.path().join("archive");
+    let secret = Secret::from_passphrase("correct horse");
+    let archive = Archive::create_path_encrypted(&archive_path, &secret).unwrap();
+    assert!(archive.is_encrypted());
+    let source = source_tree();
+    let backup_stats = archive
+        .backup(source.path(), &BackupOptions::default())
+        .expect("backup");
+    assert_eq!(backup_stats.files, 2);
+
+    // Nothing in the archive contains the plaintext or its file names.
+    for entry in walkdir::WalkDir::new(&archive_path) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let content = fs::read(entry.path()).unwrap();
+            for needle in &[&b"secret"[..], b"subfile"] {
+                assert!(
+                    !content.windows(needle.len()).any(|w| w == *needle),
+                    "plaintext found in {:?}",
+                    entry.path()
+                );
+            }
+        }
+    }
+
+    let archive = Archive::open_path_with_secret(&archive_path, Some(&secret)).unwrap();
+    assert!(!archive
+        .validate(&ValidateOptions::default())
+        .unwrap()
+        .has_problems());
+    let dest = TempDir::new().unwrap();
+    restore(&archive, dest.path(), &RestoreOptions::default()).expect("restore");
+    dest.child("hello").assert("a secret greeting");
+    dest.child("subdir/subfile").assert("more secret content");
+}
+
+#[test]
+fn wrong_passphrase() {
+    let temp = TempDir::new().unwrap();
+    let archive_path = temp.path().join("archive");
+    Archive::create_path_encrypted(&archive_path, &Secret::from_passphrase("right")).unwrap();
+
+    let err =
+        Archive::open_path_with_secret(&archive_path, Some(&Secret::from_passphrase("wrong")))
+            .unwrap_err();
+    assert!(matches!(err, Error::WrongPassphrase));
+    assert_eq!(
+        err.to_string(),
+        "Wrong passphrase or key file for encrypted archive"
+    );
+}
+
+#[test]
+fn open_encrypted_archive_without_secret() {
+    let temp = TempDir::new().unwrap();
+    let archive_path = temp.path().join("archive");
+    Archive::create_path_encrypted(&archive_path, &Secret::from_passphrase("right")).unwrap();
+
+    assert!(matches!(
+        Archive::open_path(&archive_path),
+        Err(Error::ArchiveEncrypted)
+    ));
+}
+
+#[test]
+fn key_file_and_sync_from_plain_archive() {
+    let temp = TempDir::new().unwrap();
+    let key_file = temp.child("key");
+    key_file.write_binary(&[7u8; 64]).unwrap();
+    let secret = Secret::from_key_file(key_file.path()).unwrap();
+    let archive_path = temp.path().join("archive");
+    let encrypted = Archive::create_path_encrypted(&archive_path, &secret).unwrap();
+
+    let plain = ScratchArchive::new();
+    plain.store_two_versions();
+    let stats = sync(&plain, &encrypted, &SyncOptions::default()).unwrap();
+    assert_eq!(stats.bands_copied, 2);
+
+    let encrypted = Archive::open_path_with_secret(
+        &archive_path,
+        Some(&Secret::from_key_file(key_file.path()).unwrap()),
+    )
+    .unwrap();
+    assert!(!encrypted
+        .validate(&ValidateOptions::default())
+        .unwrap()
+        .has_problems());
+    let dest = TempDir::new().unwrap();
+    let restore_stats = restore(&encrypted, dest.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(restore_stats.files, 3);
+}
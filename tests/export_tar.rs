@@ -0,0 +1,264 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for exporting a stored tree as a tar stream, and restoring into one.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Description of one entry in a tree on disk, for comparing trees.
+#[derive(Debug, PartialEq)]
+enum Described {
+    Dir,
+    File { contents: Vec<u8>, mtime: i64 },
+    Symlink { target: PathBuf },
+}
+
+fn describe_tree(root: &Path) -> Vec<(PathBuf, Described)> {
+    walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let path = entry.path();
+            let file_type = entry.file_type();
+            let described = if file_type.is_dir() {
+                Described::Dir
+            } else if file_type.is_symlink() {
+                Described::Symlink {
+                    target: fs::read_link(path).unwrap(),
+                }
+            } else {
+                Described::File {
+                    contents: fs::read(path).unwrap(),
+                    mtime: filetime::FileTime::from_last_modification_time(
+                        &entry.metadata().unwrap(),
+                    )
+                    .unix_seconds(),
+                }
+            };
+            (path.strip_prefix(root).unwrap().to_owned(), described)
+        })
+        .collect()
+}
+
+fn unpack(tar_bytes: &[u8]) -> TreeFixture {
+    let dest = TreeFixture::new();
+    tar::Archive::new(tar_bytes).unpack(dest.path()).unwrap();
+    dest
+}
+
+#[test]
+fn export_matches_restore() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let mut tar_bytes = Vec::new();
+    let stats = export_tar(&af, &mut tar_bytes, &ExportTarOptions::default()).unwrap();
+    assert_eq!(stats.files, 3);
+    // As in a restore, the root is counted although it's not in the tar.
+    assert_eq!(stats.directories, 2);
+
+    let restored = TreeFixture::new();
+    restore(&af, restored.path(), &RestoreOptions::default()).unwrap();
+    let unpacked = unpack(&tar_bytes);
+    assert_eq!(
+        describe_tree(unpacked.path()),
+        describe_tree(restored.path())
+    );
+}
+
+#[test]
+fn export_large_file() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    srcdir.create_file_with_contents("big", &contents);
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+
+    let mut tar_bytes = Vec::new();
+    export_tar(&af, &mut tar_bytes, &ExportTarOptions::default()).unwrap();
+    let unpacked = unpack(&tar_bytes);
+    assert_eq!(fs::read(unpacked.path().join("big")).unwrap(), contents);
+}
+
+#[test]
+fn export_long_names_as_posix_tar() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let dir_name = "d".repeat(120);
+    let file_name = format!("{}/{}", dir_name, "f".repeat(150));
+    srcdir.create_dir(&dir_name);
+    srcdir.create_file_with_contents(&file_name, b"long");
+    let link_target = format!("../{}", "t".repeat(200));
+    srcdir.create_symlink("link", &link_target);
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+
+    let mut tar_bytes = Vec::new();
+    export_tar(&af, &mut tar_bytes, &ExportTarOptions::default()).unwrap();
+    let mut archive = tar::Archive::new(tar_bytes.as_slice());
+    for entry in archive.entries().unwrap() {
+        let entry = entry.unwrap();
+        assert!(entry.header().as_ustar().is_some());
+        assert!(!entry.header().entry_type().is_gnu_longname());
+        assert!(!entry.header().entry_type().is_gnu_longlink());
+    }
+
+    let unpacked = unpack(&tar_bytes);
+    assert_eq!(fs::read(unpacked.path().join(&file_name)).unwrap(), b"long");
+    #[cfg(unix)]
+    assert_eq!(
+        fs::read_link(unpacked.path().join("link")).unwrap(),
+        Path::new(&link_target)
+    );
+}
+
+#[test]
+fn export_subtree_with_excludes() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let options = ExportTarOptions {
+        only_subtree: Some(Apath::from("/subdir")),
+        ..ExportTarOptions::default()
+    };
+    let mut tar_bytes = Vec::new();
+    export_tar(&af, &mut tar_bytes, &options).unwrap();
+    let paths: Vec<String> = tar::Archive::new(tar_bytes.as_slice())
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect();
+    assert_eq!(paths, ["subdir/", "subdir/subfile"]);
+
+    let options = ExportTarOptions {
+        filter: excludes::from_strings(&["/hello"]).unwrap().into(),
+        band_selection: BandSelectionPolicy::Specified(BandId::zero()),
+        ..ExportTarOptions::default()
+    };
+    let mut tar_bytes = Vec::new();
+    let stats = export_tar(&af, &mut tar_bytes, &options).unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.directories, 2);
+}
+
+#[test]
+fn restore_into_tar_matches_restore() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let mut tar_bytes = Vec::new();
+    let tar_stats = restore_into(
+        &af,
+        &mut TarWriteTree::new(&mut tar_bytes),
+        &RestoreOptions::default(),
+    )
+    .unwrap();
+
+    let restored = TreeFixture::new();
+    let stats = restore(&af, restored.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(tar_stats.files, stats.files);
+    assert_eq!(tar_stats.directories, stats.directories);
+    assert_eq!(tar_stats.symlinks, stats.symlinks);
+    assert_eq!(tar_stats.uncompressed_bytes, stats.uncompressed_bytes);
+    assert_eq!(tar_stats.errors, 0);
+
+    let unpacked = unpack(&tar_bytes);
+    assert_eq!(
+        describe_tree(unpacked.path()),
+        describe_tree(restored.path())
+    );
+}
+
+#[test]
+fn restore_into_tar_with_band_and_excludes() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let options = RestoreOptions {
+        filter: excludes::from_strings(&["/hello"]).unwrap().into(),
+        band_selection: BandSelectionPolicy::Specified(BandId::zero()),
+        ..RestoreOptions::default()
+    };
+    let mut tar_bytes = Vec::new();
+    let stats = restore_into(&af, &mut TarWriteTree::new(&mut tar_bytes), &options).unwrap();
+    assert_eq!(stats.files, 1);
+
+    let restored = TreeFixture::new();
+    restore(&af, restored.path(), &options).unwrap();
+    let unpacked = unpack(&tar_bytes);
+    assert_eq!(
+        describe_tree(unpacked.path()),
+        describe_tree(restored.path())
+    );
+}
+
+/// A writer that accepts some bytes and then fails.
+struct FailingWriter {
+    remaining: usize,
+}
+
+impl Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Err(io::Error::other("disk full"));
+        }
+        let len = buf.len().min(self.remaining);
+        self.remaining -= len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn restore_into_tar_fails_after_write_error() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let mut tree = TarWriteTree::new(FailingWriter { remaining: 1000 });
+    let result = restore_into(&af, &mut tree, &RestoreOptions::default());
+    assert!(
+        matches!(result, Err(Error::WriteTar { .. })),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn cancelled_restore_into_tar_has_no_end_marker() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let options = RestoreOptions::default();
+    options.cancel.cancel();
+
+    let mut tar_bytes = Vec::new();
+    let mut tree: Box<dyn WriteTree> = Box::new(TarWriteTree::new(&mut tar_bytes));
+    let result = restore_into(&af, tree.as_mut(), &options);
+    assert!(
+        matches!(result, Err(Error::RestoreCancelled { .. })),
+        "{:?}",
+        result
+    );
+    drop(tree);
+    // Not even the two zero blocks that end a tar stream were written, so
+    // the truncated stream can't be mistaken for a complete one.
+    assert!(tar_bytes.is_empty());
+}
@@ -0,0 +1,107 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests that backup works with few file handles available.
+//!
+//! The limit applies to the whole process, so these are kept apart from other
+//! tests that would run concurrently in the same test binary.
+
+#![cfg(unix)]
+
+use std::fs::File;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Held while a test changes the limit, since it applies to every thread.
+static LIMIT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Lower the soft limit on open files, returning the old limit.
+fn set_open_file_limit(limit: libc::rlim_t) -> libc::rlim_t {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) },
+        0
+    );
+    let old = rlimit.rlim_cur;
+    rlimit.rlim_cur = limit.min(rlimit.rlim_max);
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) }, 0);
+    old
+}
+
+#[test]
+fn backup_wide_and_deep_tree_with_few_file_handles() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..300 {
+        let dir = format!("dir{:03}", i);
+        srcdir.create_dir(&dir);
+        srcdir.create_file_with_contents(&format!("{}/file", dir), dir.as_bytes());
+    }
+    let mut deep = String::from("deep");
+    for _ in 0..100 {
+        srcdir.create_dir(&deep);
+        deep.push_str("/d");
+    }
+
+    let _guard = LIMIT_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let old_limit = set_open_file_limit(64);
+    let result = af.backup(srcdir.path(), &BackupOptions::default());
+    let restore_dir = TreeFixture::new();
+    let restore_result = restore(&af, restore_dir.path(), &RestoreOptions::default());
+    set_open_file_limit(old_limit);
+
+    let stats = result.unwrap();
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.files, 300);
+    assert_eq!(stats.directories, 1 + 300 + 100);
+    let restore_stats = restore_result.unwrap();
+    assert_eq!(restore_stats.files, 300);
+    assert_eq!(
+        std::fs::read_to_string(restore_dir.path().join("dir123").join("file")).unwrap(),
+        "dir123"
+    );
+}
+
+#[test]
+fn backup_waits_for_file_handles_held_by_another_thread() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/file");
+
+    let _guard = LIMIT_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let old_limit = set_open_file_limit(64);
+    // Use up every handle, and give them back only after the backup has
+    // started and found none free.
+    let mut held = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        held.push(file);
+    }
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(30));
+        drop(held);
+    });
+    let result = af.backup(srcdir.path(), &BackupOptions::default());
+    releaser.join().unwrap();
+    set_open_file_limit(old_limit);
+
+    let stats = result.unwrap();
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.files, 1);
+}
@@ -0,0 +1,139 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test reading indexes written by a later version, with fields and kinds
+//! this version doesn't know.
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde_cbor::Value;
+use tempfile::TempDir;
+
+use conserve::test_fixtures::ScratchArchive;
+use conserve::*;
+
+const MTIME: i64 = 1_600_000_000;
+
+/// A json hunk with unknown fields, and an entry of an unknown kind.
+fn future_json_hunk() -> Vec<u8> {
+    format!(
+        r#"[{{"apath":"/","kind":"Dir","mtime":{0},"future_dir_field":{{"a":[1,2]}}}},
+        {{"apath":"/fifo","kind":"Fifo","mtime":{0}}},
+        {{"apath":"/hello","kind":"File","mtime":{0},"blake3":"af1349b9","flags":7}}]"#,
+        MTIME
+    )
+    .into_bytes()
+}
+
+/// A CBOR hunk like [future_json_hunk], with fields numbered past the known
+/// ones.
+fn future_cbor_hunk() -> Vec<u8> {
+    let entry = |fields: Vec<(u64, Value)>| fields.into_iter().collect::<BTreeMap<u64, Value>>();
+    let mtime = Value::Integer(MTIME.into());
+    let entries = vec![
+        entry(vec![
+            (0, Value::Text("/".to_owned())),
+            (1, Value::Integer(1)),
+            (2, mtime.clone()),
+            (13, Value::Array(vec![Value::Integer(1), Value::Integer(2)])),
+        ]),
+        entry(vec![
+            (0, Value::Text("/fifo".to_owned())),
+            (1, Value::Integer(9)),
+            (2, mtime.clone()),
+        ]),
+        entry(vec![
+            (0, Value::Text("/hello".to_owned())),
+            (1, Value::Integer(0)),
+            (2, mtime),
+            (11, Value::Bytes(vec![0xaf, 0x13, 0x49, 0xb9])),
+            (12, Value::Integer(7)),
+        ]),
+    ];
+    serde_cbor::to_vec(&entries).unwrap()
+}
+
+/// Write a band holding one hunk, as a later version might have.
+fn write_future_band(af: &ScratchArchive, index_format: IndexFormat, hunk: &[u8]) {
+    let band = Band::create_with_options(
+        af,
+        &BandOptions {
+            index_format,
+            ..BandOptions::default()
+        },
+    )
+    .unwrap();
+    let hunk_dir = af.path().join("b0000").join("i").join("00000");
+    fs::create_dir_all(&hunk_dir).unwrap();
+    fs::write(
+        hunk_dir.join("000000000"),
+        snap::raw::Encoder::new().compress_vec(hunk).unwrap(),
+    )
+    .unwrap();
+    band.close(1).unwrap();
+}
+
+/// Restore, validate, and back up again a band written by a later version,
+/// and return the entry for the file in the new band.
+fn restore_and_backup_again(af: &ScratchArchive, index_format: IndexFormat) -> IndexEntry {
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let kinds: Vec<Kind> = tree
+        .iter_entries(None, &Exclude::nothing())
+        .map(|entry| entry.unwrap().kind())
+        .collect();
+    assert_eq!(kinds, [Kind::Dir, Kind::Unknown, Kind::File]);
+
+    let destdir = TempDir::new().unwrap();
+    let restored = destdir.path().join("restored");
+    let stats = restore(af, &restored, &RestoreOptions::default()).unwrap();
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.unknown_kind, 1);
+    assert!(restored.join("hello").is_file());
+    assert!(!restored.join("fifo").exists());
+
+    let validate_stats = af.validate(&ValidateOptions::default()).unwrap();
+    assert!(!validate_stats.has_problems(), "{:#?}", validate_stats);
+
+    let stats = af
+        .backup(
+            &restored,
+            &BackupOptions::default().index_format(index_format),
+        )
+        .unwrap();
+    assert_eq!(stats.unmodified_files, 1);
+
+    Band::open(af, &BandId::new(&[1]))
+        .unwrap()
+        .iter_entries()
+        .find(|entry| entry.apath == "/hello")
+        .unwrap()
+}
+
+#[test]
+fn json_index_from_later_version() {
+    let af = ScratchArchive::new();
+    write_future_band(&af, IndexFormat::Json, &future_json_hunk());
+    let entry = restore_and_backup_again(&af, IndexFormat::Json);
+    assert_eq!(
+        entry.unknown_fields.names().collect::<Vec<_>>(),
+        ["blake3", "flags"]
+    );
+}
+
+#[test]
+fn cbor_index_from_later_version() {
+    let af = ScratchArchive::new();
+    write_future_band(&af, IndexFormat::Cbor, &future_cbor_hunk());
+    let entry = restore_and_backup_again(&af, IndexFormat::Cbor);
+    assert_eq!(entry.unknown_fields.numbers().collect::<Vec<_>>(), [11, 12]);
+}
@@ -26,7 +26,9 @@ fn unreferenced_blocks() {
             .parse()
             .unwrap();
 
-    let _copy_stats = backup(&archive, &tf.live_tree(), &BackupOptions::default()).expect("backup");
+    let _copy_stats = archive
+        .backup(tf.path(), &BackupOptions::default())
+        .expect("backup");
 
     // Delete the band and index
     std::fs::remove_dir_all(archive.path().join("b0000")).unwrap();
@@ -97,7 +99,7 @@ fn backup_prevented_by_gc_lock() -> Result<()> {
     let lock1 = GarbageCollectionLock::new(&archive)?;
 
     // Backup should fail while gc lock is held.
-    let backup_result = backup(&archive, &tf.live_tree(), &BackupOptions::default());
+    let backup_result = archive.backup(tf.path(), &BackupOptions::default());
     match backup_result {
         Err(Error::GarbageCollectionLockHeld) => (),
         other => panic!("unexpected result {:?}", other),
@@ -111,8 +113,199 @@ fn backup_prevented_by_gc_lock() -> Result<()> {
     })?;
 
     // Backup should now succeed.
-    let backup_result = backup(&archive, &tf.live_tree(), &BackupOptions::default());
+    let backup_result = archive.backup(tf.path(), &BackupOptions::default());
     assert!(backup_result.is_ok());
 
     Ok(())
 }
+
+#[test]
+fn block_report_finds_orphans_and_unexpected_files() {
+    let archive = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    archive
+        .backup(tf.path(), &BackupOptions::default())
+        .unwrap();
+    tf.create_file_with_contents("orphan", b"only in the deleted band");
+    archive
+        .backup(tf.path(), &BackupOptions::default())
+        .unwrap();
+    std::fs::remove_file(tf.path().join("orphan")).unwrap();
+    archive
+        .backup(tf.path(), &BackupOptions::default())
+        .unwrap();
+
+    // Removing the middle band leaves its block orphaned; the other block is
+    // still used by two bands.
+    std::fs::remove_dir_all(archive.path().join("b0001")).unwrap();
+    let block_dir = archive.path().join("d");
+    let subdir = std::fs::read_dir(&block_dir)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .file_name()
+        .into_string()
+        .unwrap();
+    std::fs::write(block_dir.join(&subdir).join("tmp12345"), b"partial").unwrap();
+    std::fs::write(block_dir.join("stray"), b"stray").unwrap();
+
+    let report = archive.block_report().unwrap();
+    assert_eq!(report.present_blocks, 2);
+    assert_eq!(report.referenced_blocks, 1);
+    assert_eq!(report.missing_blocks, 0);
+    assert_eq!(report.unreferenced_blocks.len(), 1);
+    assert!(report.reclaimable_bytes > 0);
+    assert_eq!(
+        report.bands_per_block.into_iter().collect::<Vec<_>>(),
+        [(2, 1)]
+    );
+    // Block subdirectories are hex, so they sort before "stray".
+    assert_eq!(
+        report.unexpected_files,
+        [format!("{}/tmp12345", subdir), "stray".to_owned()]
+    );
+
+    let validate_stats = archive.validate(&ValidateOptions::default()).unwrap();
+    assert_eq!(validate_stats.unreferenced_block_count, 1);
+
+    // The report agrees with what gc would delete.
+    let delete_stats = archive
+        .delete_unreferenced(&DeleteOptions {
+            dry_run: true,
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(delete_stats.unreferenced_block_count, 1);
+    assert_eq!(
+        delete_stats.unreferenced_block_bytes,
+        report.reclaimable_bytes
+    );
+}
+
+#[test]
+fn band_usage_splits_unique_and_shared_blocks() {
+    let archive = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    // Each backup stores its new small files in one new combined block, and
+    // unchanged files keep referring to the block from an earlier backup.
+    tf.create_file_with_contents("a", b"in the first two bands");
+    archive
+        .backup(tf.path(), &BackupOptions::default())
+        .unwrap();
+    tf.create_file_with_contents("b", b"in the last two bands");
+    archive
+        .backup(tf.path(), &BackupOptions::default())
+        .unwrap();
+    std::fs::remove_file(tf.path().join("a")).unwrap();
+    tf.create_file_with_contents("c", b"only in the last band");
+    archive
+        .backup(tf.path(), &BackupOptions::default())
+        .unwrap();
+
+    let block_size = |apath: &str| {
+        let entry = archive
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap()
+            .iter_filtered(None, None)
+            .unwrap()
+            .chain(
+                archive
+                    .open_stored_tree(BandSelectionPolicy::Specified(BandId::new(&[0])))
+                    .unwrap()
+                    .iter_filtered(None, None)
+                    .unwrap(),
+            )
+            .find(|entry| entry.apath() == apath)
+            .unwrap();
+        archive
+            .block_dir()
+            .compressed_size(&entry.addrs()[0].hash)
+            .unwrap()
+    };
+    let (a, b, c) = (block_size("/a"), block_size("/b"), block_size("/c"));
+    let usage = |unique_bytes, shared_bytes| BandUsage {
+        unique_bytes,
+        shared_bytes,
+    };
+
+    let band_usage = archive.band_usage().unwrap();
+    assert_eq!(
+        band_usage.into_iter().collect::<Vec<_>>(),
+        [
+            (BandId::new(&[0]), usage(0, a)),
+            (BandId::new(&[1]), usage(0, a + b)),
+            (BandId::new(&[2]), usage(c, b)),
+        ]
+    );
+
+    // Later calls use the cached results.
+    let cache_path = archive.path().join("b0002/usage.json");
+    let cache = std::fs::read_to_string(&cache_path).unwrap();
+    let unique_json = format!("\"unique_bytes\":{}", c);
+    assert!(cache.contains(&unique_json));
+    std::fs::write(
+        &cache_path,
+        cache.replace(&unique_json, "\"unique_bytes\":12345"),
+    )
+    .unwrap();
+    assert_eq!(
+        archive.band_usage().unwrap()[&BandId::new(&[2])].unique_bytes,
+        12345
+    );
+    let validate_stats = archive.validate(&ValidateOptions::default()).unwrap();
+    assert!(!validate_stats.has_problems());
+    assert_eq!(validate_stats.unexpected_files, 0);
+
+    // The cached usage is out of date once a band is deleted.
+    archive
+        .delete_bands(&[BandId::new(&[0])], &DeleteOptions::default())
+        .unwrap();
+    let band_usage = archive.band_usage().unwrap();
+    assert_eq!(
+        band_usage.into_iter().collect::<Vec<_>>(),
+        [
+            (BandId::new(&[1]), usage(a, b)),
+            (BandId::new(&[2]), usage(c, b)),
+        ]
+    );
+}
+
+#[test]
+fn band_usage_is_not_cached_while_archive_is_locked() {
+    let archive = ScratchArchive::new();
+    archive.store_two_versions();
+    let lock = archive.lock(false).unwrap();
+
+    archive.band_usage().unwrap();
+    assert!(!archive.path().join("b0001/usage.json").exists());
+
+    drop(lock);
+    archive.band_usage().unwrap();
+    assert!(archive.path().join("b0001/usage.json").exists());
+    assert!(!archive.path().join("LOCK").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn band_usage_of_read_only_archive() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let archive = ScratchArchive::new();
+    archive.store_two_versions();
+    let read_only = |mode| {
+        for name in ["", "b0000", "b0001"] {
+            std::fs::set_permissions(
+                archive.path().join(name),
+                std::fs::Permissions::from_mode(mode),
+            )
+            .unwrap();
+        }
+    };
+    read_only(0o555);
+    let result = archive.band_usage();
+    read_only(0o755);
+
+    assert_eq!(result.unwrap().len(), 2);
+}
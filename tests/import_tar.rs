@@ -0,0 +1,211 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for importing a tar stream as a new backup version.
+
+use std::fs;
+
+use tar::{EntryType, Header};
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+fn entry_apaths(af: &ScratchArchive) -> Vec<String> {
+    af.open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_entries(None, &Exclude::nothing())
+        .map(|entry| entry.unwrap().apath.to_string())
+        .collect()
+}
+
+/// Append an entry with the given type and contents to a tar.
+///
+/// The path is written as given, even if it's absolute or contains `..`.
+fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, entry_type: EntryType, data: &[u8]) {
+    let mut header = Header::new_gnu();
+    header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+    header.set_entry_type(entry_type);
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(1_600_000_000);
+    header.set_cksum();
+    builder.append(&header, data).unwrap();
+}
+
+#[test]
+fn import_tar_of_tree_round_trip() {
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    tf.create_dir("subdir");
+    tf.create_file_with_contents("subdir/big", &vec![7u8; 2_500_000]);
+    tf.create_file_with_contents("subdir/empty", b"");
+    #[cfg(unix)]
+    tf.create_symlink("link", "hello");
+
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", tf.path()).unwrap();
+    let tar_bytes = builder.into_inner().unwrap();
+
+    let af = ScratchArchive::new();
+    let stats = import_tar(&af, &mut tar_bytes.as_slice(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.errors, 0);
+    assert!(af.band_is_closed(&BandId::zero()).unwrap());
+
+    let dest = TreeFixture::new();
+    restore(&af, dest.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(fs::read(dest.path().join("hello")).unwrap(), b"contents");
+    assert_eq!(
+        fs::read(dest.path().join("subdir/big")).unwrap(),
+        vec![7u8; 2_500_000]
+    );
+    assert_eq!(fs::read(dest.path().join("subdir/empty")).unwrap(), b"");
+    #[cfg(unix)]
+    assert_eq!(
+        fs::read_link(dest.path().join("link")).unwrap(),
+        std::path::Path::new("hello")
+    );
+
+    // Importing the same tar again stores no new data blocks.
+    let stats = import_tar(&af, &mut tar_bytes.as_slice(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.written_blocks, 0);
+    assert!(stats.deduplicated_blocks > 0);
+}
+
+#[test]
+fn unsorted_tar_without_directories() {
+    let mut builder = tar::Builder::new(Vec::new());
+    append(&mut builder, "b/file", EntryType::Regular, b"one");
+    append(&mut builder, "./a/z", EntryType::Regular, b"two");
+    append(&mut builder, "a/", EntryType::Directory, b"");
+    append(&mut builder, "a/b/c", EntryType::Regular, b"three");
+    append(&mut builder, "/a/y", EntryType::Regular, b"four");
+    let tar_bytes = builder.into_inner().unwrap();
+
+    let af = ScratchArchive::new();
+    let stats = import_tar(&af, &mut tar_bytes.as_slice(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.files, 4);
+    assert_eq!(stats.directories, 4);
+    assert_eq!(
+        entry_apaths(&af),
+        ["/", "/a", "/b", "/a/b", "/a/y", "/a/z", "/a/b/c", "/b/file"]
+    );
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let a = st
+        .iter_entries(None, &Exclude::nothing())
+        .map(Result::unwrap)
+        .find(|e| e.apath == Apath::from("/a"))
+        .unwrap();
+    assert_eq!(a.mtime, 1_600_000_000);
+}
+
+#[test]
+fn unsupported_entries_are_skipped() {
+    let mut builder = tar::Builder::new(Vec::new());
+    append(&mut builder, "fifo", EntryType::Fifo, b"");
+    append(&mut builder, "dev", EntryType::Char, b"");
+    append(&mut builder, "../escape", EntryType::Regular, b"nope");
+    append(&mut builder, "file", EntryType::Regular, b"data");
+    let tar_bytes = builder.into_inner().unwrap();
+
+    let af = ScratchArchive::new();
+    let stats = import_tar(&af, &mut tar_bytes.as_slice(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.unknown_kind, 2);
+    assert_eq!(stats.errors, 1);
+    let problems: Vec<(Option<String>, &str)> = stats
+        .problems
+        .iter()
+        .map(|p| (p.apath.as_ref().map(Apath::to_string), p.message.as_str()))
+        .collect();
+    assert_eq!(
+        problems,
+        [
+            (
+                Some("/fifo".to_owned()),
+                "Skipping tar entry of unsupported type Fifo"
+            ),
+            (
+                Some("/dev".to_owned()),
+                "Skipping tar entry of unsupported type Char"
+            ),
+            (None, "Can't import tar entry with path \"../escape\""),
+        ]
+    );
+    assert_eq!(entry_apaths(&af), ["/", "/file"]);
+}
+
+#[test]
+fn hard_links_and_repeated_paths() {
+    let mut builder = tar::Builder::new(Vec::new());
+    append(&mut builder, "original", EntryType::Regular, b"shared");
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Link);
+    header.set_size(0);
+    header.set_mtime(1_600_000_000);
+    builder
+        .append_link(&mut header, "linked", "original")
+        .unwrap();
+    append(&mut builder, "twice", EntryType::Regular, b"first");
+    append(&mut builder, "twice", EntryType::Regular, b"second");
+    let tar_bytes = builder.into_inner().unwrap();
+
+    let af = ScratchArchive::new();
+    import_tar(&af, &mut tar_bytes.as_slice(), &BackupOptions::default()).unwrap();
+    let dest = TreeFixture::new();
+    restore(&af, dest.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(fs::read(dest.path().join("linked")).unwrap(), b"shared");
+    assert_eq!(fs::read(dest.path().join("twice")).unwrap(), b"second");
+}
+
+#[test]
+fn later_entry_replaces_small_file() {
+    let mut builder = tar::Builder::new(Vec::new());
+    append(&mut builder, "becomes_dir", EntryType::Regular, b"small");
+    append(&mut builder, "becomes_empty", EntryType::Regular, b"small");
+    append(&mut builder, "becomes_dir/", EntryType::Directory, b"");
+    append(&mut builder, "becomes_empty", EntryType::Regular, b"");
+    let tar_bytes = builder.into_inner().unwrap();
+
+    let af = ScratchArchive::new();
+    import_tar(&af, &mut tar_bytes.as_slice(), &BackupOptions::default()).unwrap();
+    let dest = TreeFixture::new();
+    restore(&af, dest.path(), &RestoreOptions::default()).unwrap();
+    assert!(dest.path().join("becomes_dir").is_dir());
+    assert_eq!(fs::read(dest.path().join("becomes_empty")).unwrap(), b"");
+}
+
+#[test]
+fn import_with_excludes_and_tags() {
+    let mut builder = tar::Builder::new(Vec::new());
+    append(&mut builder, "keep", EntryType::Regular, b"data");
+    append(&mut builder, "cache/", EntryType::Directory, b"");
+    append(&mut builder, "cache/junk", EntryType::Regular, b"junk");
+    let tar_bytes = builder.into_inner().unwrap();
+
+    let af = ScratchArchive::new();
+    let options = BackupOptions {
+        excludes: excludes::from_strings(&["/cache"]).unwrap(),
+        tags: vec!["imported".to_owned()],
+        ..BackupOptions::default()
+    };
+    import_tar(&af, &mut tar_bytes.as_slice(), &options).unwrap();
+    assert_eq!(entry_apaths(&af), ["/", "/keep"]);
+    assert_eq!(
+        af.open_stored_tree(BandSelectionPolicy::Tagged("imported".to_owned()))
+            .unwrap()
+            .band()
+            .id(),
+        &BandId::zero()
+    );
+}
@@ -34,7 +34,9 @@ pub fn simple_backup() {
     let srcdir = TreeFixture::new();
     srcdir.create_file("hello");
     // TODO: Include a symlink only on Unix.
-    let copy_stats = backup(&af, &srcdir.live_tree(), &BackupOptions::default()).expect("backup");
+    let copy_stats = af
+        .backup(srcdir.path(), &BackupOptions::default())
+        .expect("backup");
     assert_eq!(copy_stats.index_builder_stats.index_hunks, 1);
     assert_eq!(copy_stats.files, 1);
     check_backup(&af);
@@ -61,12 +63,11 @@ pub fn simple_backup_with_excludes() -> Result<()> {
     srcdir.create_file("baz");
     // TODO: Include a symlink only on Unix.
     let excludes = excludes::from_strings(&["/**/baz", "/**/bar", "/**/fooo*"]).unwrap();
-    let source = srcdir.live_tree();
     let options = BackupOptions {
         excludes,
         ..BackupOptions::default()
     };
-    let copy_stats = backup(&af, &source, &options).expect("backup");
+    let copy_stats = af.backup(srcdir.path(), &options).expect("backup");
 
     check_backup(&af);
 
@@ -97,7 +98,7 @@ pub fn simple_backup_with_excludes() -> Result<()> {
     // TODO: Check index stats.
     // TODO: Check what was restored.
 
-    let validate_stats = af.validate().unwrap();
+    let validate_stats = af.validate(&ValidateOptions::default()).unwrap();
     assert!(!validate_stats.has_problems());
     Ok(())
 }
@@ -117,13 +118,12 @@ pub fn backup_more_excludes() {
     srcdir.create_file("bar");
 
     let excludes = excludes::from_strings(&["/**/foo*", "/**/baz"]).unwrap();
-    let source = srcdir.live_tree();
     let options = BackupOptions {
         excludes,
         print_filenames: false,
         ..Default::default()
     };
-    let stats = backup(&af, &source, &options).expect("backup");
+    let stats = af.backup(srcdir.path(), &options).expect("backup");
 
     assert_eq!(1, stats.written_blocks);
     assert_eq!(1, stats.files);
@@ -134,7 +134,7 @@ pub fn backup_more_excludes() {
 }
 
 fn check_backup(af: &ScratchArchive) {
-    let band_ids = af.list_band_ids().unwrap();
+    let band_ids = af.band_ids().unwrap();
     assert_eq!(1, band_ids.len());
     assert_eq!("b0000", band_ids[0].to_string());
     assert_eq!(
@@ -185,7 +185,9 @@ fn large_file() {
     let tf = TreeFixture::new();
     let large_content = String::from("abcd").repeat(1 << 20);
     tf.create_file_with_contents("large", &large_content.as_bytes());
-    let copy_stats = backup(&af, &tf.live_tree(), &BackupOptions::default()).expect("backup");
+    let copy_stats = af
+        .backup(tf.path(), &BackupOptions::default())
+        .expect("backup");
     assert_eq!(copy_stats.new_files, 1);
     // First 1MB should be new; remainder should be deduplicated.
     assert_eq!(copy_stats.uncompressed_bytes, 1 << 20);
@@ -222,11 +224,21 @@ fn source_unreadable() {
 
     tf.make_file_unreadable("b_unreadable");
 
-    let stats = backup(&af, &tf.live_tree(), &BackupOptions::default()).expect("backup");
+    let stats = af
+        .backup(tf.path(), &BackupOptions::default())
+        .expect("backup");
     assert_eq!(stats.errors, 1);
     assert_eq!(stats.new_files, 3);
     assert_eq!(stats.files, 3);
 
+    // The error is returned to the caller, and recorded in the band.
+    let problems: Vec<&Problem> = stats.problems.iter().collect();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].apath, Some(Apath::from("/b_unreadable")));
+    assert_eq!(problems[0].category, ErrorCategory::PerEntry);
+    let band = Band::open(&af, &BandId::zero()).unwrap();
+    assert_eq!(band.read_stats().unwrap().unwrap().problems, stats.problems);
+
     // TODO: On Windows change the ACL to make the file unreadable to the current user or to
     // everyone.
 }
@@ -250,7 +262,7 @@ fn mtime_before_epoch() {
     dbg!(&entries[1].mtime());
 
     let af = ScratchArchive::new();
-    backup(&af, &tf.live_tree(), &BackupOptions::default())
+    af.backup(tf.path(), &BackupOptions::default())
         .expect("backup shouldn't crash on before-epoch mtimes");
 }
 
@@ -260,13 +272,15 @@ pub fn symlink() {
     let af = ScratchArchive::new();
     let srcdir = TreeFixture::new();
     srcdir.create_symlink("symlink", "/a/broken/destination");
-    let copy_stats = backup(&af, &srcdir.live_tree(), &BackupOptions::default()).expect("backup");
+    let copy_stats = af
+        .backup(srcdir.path(), &BackupOptions::default())
+        .expect("backup");
 
     assert_eq!(0, copy_stats.files);
     assert_eq!(1, copy_stats.symlinks);
     assert_eq!(0, copy_stats.unknown_kind);
 
-    let band_ids = af.list_band_ids().unwrap();
+    let band_ids = af.band_ids().unwrap();
     assert_eq!(1, band_ids.len());
     assert_eq!("b0000", band_ids[0].to_string());
 
@@ -289,7 +303,7 @@ pub fn empty_file_uses_zero_blocks() {
     let af = ScratchArchive::new();
     let srcdir = TreeFixture::new();
     srcdir.create_file_with_contents("empty", &[]);
-    let stats = backup(&af, &srcdir.live_tree(), &BackupOptions::default()).unwrap();
+    let stats = af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
 
     assert_eq!(1, stats.files);
     assert_eq!(stats.written_blocks, 0);
@@ -297,8 +311,8 @@ pub fn empty_file_uses_zero_blocks() {
     // Read back the empty file
     let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
     let empty_entry = st
-        .iter_entries()
-        .unwrap()
+        .iter_entries(None, &Exclude::nothing())
+        .map(Result::unwrap)
         .find(|ref i| &i.apath == "/empty")
         .expect("found one entry");
     let mut sf = st.file_contents(&empty_entry).unwrap();
@@ -321,7 +335,7 @@ pub fn detect_unmodified() {
     srcdir.create_file("bbb");
 
     let options = BackupOptions::default();
-    let stats = backup(&af, &srcdir.live_tree(), &options).unwrap();
+    let stats = af.backup(srcdir.path(), &options).unwrap();
 
     assert_eq!(stats.files, 2);
     assert_eq!(stats.new_files, 2);
@@ -329,7 +343,7 @@ pub fn detect_unmodified() {
 
     // Make a second backup from the same tree, and we should see that
     // both files are unmodified.
-    let stats = backup(&af, &srcdir.live_tree(), &options).unwrap();
+    let stats = af.backup(srcdir.path(), &options).unwrap();
 
     assert_eq!(stats.files, 2);
     assert_eq!(stats.new_files, 0);
@@ -339,7 +353,7 @@ pub fn detect_unmodified() {
     // as unmodified.
     srcdir.create_file_with_contents("bbb", b"longer content for bbb");
 
-    let stats = backup(&af, &srcdir.live_tree(), &options).unwrap();
+    let stats = af.backup(srcdir.path(), &options).unwrap();
 
     assert_eq!(stats.files, 2);
     assert_eq!(stats.new_files, 0);
@@ -355,7 +369,7 @@ pub fn detect_minimal_mtime_change() {
     srcdir.create_file_with_contents("bbb", b"longer content for bbb");
 
     let options = BackupOptions::default();
-    let stats = backup(&af, &srcdir.live_tree(), &options).unwrap();
+    let stats = af.backup(srcdir.path(), &options).unwrap();
 
     assert_eq!(stats.files, 2);
     assert_eq!(stats.new_files, 2);
@@ -377,7 +391,7 @@ pub fn detect_minimal_mtime_change() {
         }
     }
 
-    let stats = backup(&af, &srcdir.live_tree(), &options).unwrap();
+    let stats = af.backup(srcdir.path(), &options).unwrap();
     assert_eq!(stats.files, 2);
     assert_eq!(stats.unmodified_files, 1);
 }
@@ -0,0 +1,109 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for mounting an archive through FUSE.
+//!
+//! These only run on Linux with the `fuse` feature, and pass vacuously if
+//! FUSE isn't available, as in many CI containers.
+
+#![cfg(all(target_os = "linux", feature = "fuse"))]
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use conserve::mount::Mount;
+use conserve::test_fixtures::ScratchArchive;
+use conserve::{Apath, BandSelectionPolicy};
+
+/// Mount the archive, or return None if FUSE isn't available here.
+fn try_mount(archive: &ScratchArchive, mountpoint: &Path) -> Option<Mount> {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("/dev/fuse doesn't exist; skipping test");
+        return None;
+    }
+    match Mount::new(archive, mountpoint) {
+        Ok(mount) => Some(mount),
+        Err(err) => {
+            eprintln!("failed to mount ({}); skipping test", err);
+            None
+        }
+    }
+}
+
+fn dir_names(path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn read_files_through_mount() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let mountpoint = tempfile::tempdir().unwrap();
+    let mount = match try_mount(&af, mountpoint.path()) {
+        Some(mount) => mount,
+        None => return,
+    };
+    let root = mountpoint.path();
+
+    assert_eq!(dir_names(root), ["b0000", "b0001", "latest"]);
+    assert_eq!(
+        fs::read_link(root.join("latest")).unwrap(),
+        Path::new("b0001")
+    );
+    assert_eq!(dir_names(&root.join("b0000")), ["hello", "link", "subdir"]);
+    assert_eq!(
+        dir_names(&root.join("latest")),
+        ["hello", "hello2", "link", "subdir"]
+    );
+
+    assert_eq!(fs::read(root.join("b0000/hello")).unwrap(), b"contents");
+    assert_eq!(
+        fs::read(root.join("latest/subdir/subfile")).unwrap(),
+        b"contents"
+    );
+    assert_eq!(
+        fs::read_link(root.join("b0001/link")).unwrap(),
+        Path::new("target")
+    );
+
+    let mut file = fs::File::open(root.join("latest/hello2")).unwrap();
+    file.seek(SeekFrom::Start(4)).unwrap();
+    let mut buf = String::new();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "ents");
+
+    let metadata = fs::metadata(root.join("b0001/hello")).unwrap();
+    assert_eq!(metadata.len(), 8);
+    // Permissions are those stored in the index, but nothing can be written.
+    let stored_mode = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .entry(&Apath::from("/hello"))
+        .unwrap()
+        .unwrap()
+        .unix_mode
+        .unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, stored_mode & 0o7777);
+    assert!(fs::write(root.join("b0001/new"), b"").is_err());
+    assert!(fs::write(root.join("b0001/hello"), b"").is_err());
+
+    drop(file);
+    mount.unmount();
+    assert!(dir_names(root).is_empty());
+}
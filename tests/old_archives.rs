@@ -54,7 +54,7 @@ fn examine_archive() {
         println!("examine {}", ver);
         let archive = open_old_archive(ver, "minimal-1");
 
-        let band_ids = archive.list_band_ids().expect("Failed to list band ids");
+        let band_ids = archive.band_ids().expect("Failed to list band ids");
         assert_eq!(band_ids, &[BandId::zero()]);
 
         assert_eq!(
@@ -73,7 +73,9 @@ fn validate_archive() {
         println!("validate {}", ver);
         let archive = open_old_archive(ver, "minimal-1");
 
-        let stats = archive.validate().expect("validate archive");
+        let stats = archive
+            .validate(&ValidateOptions::default())
+            .expect("validate archive");
         assert_eq!(stats.structure_problems, 0);
         assert_eq!(stats.io_errors, 0);
         assert_eq!(stats.block_error_count, 0);
@@ -152,15 +154,15 @@ fn restore_modify_backup() {
         .expect("overwrite file");
 
         let new_archive = Archive::open_path(&new_archive_path).expect("Open new archive");
-        let backup_stats = backup(
-            &new_archive,
-            &LiveTree::open(working_tree.path()).unwrap(),
-            &BackupOptions {
-                print_filenames: true,
-                ..Default::default()
-            },
-        )
-        .expect("Backup modified tree");
+        let backup_stats = new_archive
+            .backup(
+                working_tree.path(),
+                &BackupOptions {
+                    print_filenames: true,
+                    ..Default::default()
+                },
+            )
+            .expect("Backup modified tree");
 
         // Expected results for files:
         // "/empty" is empty and new
@@ -201,3 +203,59 @@ fn restore_modify_backup() {
         new_archive_temp.close().expect("Cleanup copied archive");
     }
 }
+
+/// Migrate a copy of each old archive, which fills in metadata missing from
+/// older formats, and check it's still readable.
+#[test]
+fn migrate_old_archive() {
+    for ver in ARCHIVE_VERSIONS {
+        println!("migrate {}", ver);
+        let temp = TempDir::new().unwrap();
+        let archive_path = temp.path().join("archive");
+        copy_dir(
+            format!("testdata/archive/v{}/minimal-1", ver),
+            &archive_path,
+        )
+        .expect("copy archive tree");
+        let archive = Archive::open_path(&archive_path).expect("open archive copy");
+
+        let dry_run_stats = migrate(
+            &archive,
+            &MigrateOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .expect("dry-run migrate");
+        let stats = migrate(&archive, &MigrateOptions::default()).expect("migrate");
+        assert_eq!(dry_run_stats.band_heads_upgraded, stats.band_heads_upgraded);
+        assert_eq!(dry_run_stats.band_tails_upgraded, stats.band_tails_upgraded);
+
+        assert_eq!(stats.header_upgraded, 0);
+        assert_eq!(stats.bands_examined, 1);
+        let (heads, tails) = match *ver {
+            "0.6.0" | "0.6.2" => (1, 1),
+            "0.6.3" => (0, 1),
+            _ => (0, 0),
+        };
+        assert_eq!(stats.band_heads_upgraded, heads);
+        assert_eq!(stats.band_tails_upgraded, tails);
+
+        let band_dir = archive_path.join("b0000");
+        let head = fs::read_to_string(band_dir.join("BANDHEAD")).unwrap();
+        assert!(head.contains("\"band_format_version\""), "{}", head);
+        let tail = fs::read_to_string(band_dir.join("BANDTAIL")).unwrap();
+        assert!(tail.contains("\"index_hunk_count\":1"), "{}", tail);
+
+        // Migrating again changes nothing, and the archive is still good.
+        let stats = migrate(&archive, &MigrateOptions::default()).expect("migrate again");
+        assert_eq!(stats.band_heads_upgraded + stats.band_tails_upgraded, 0);
+        assert!(!archive
+            .validate(&ValidateOptions::default())
+            .unwrap()
+            .has_problems());
+        let dest = TempDir::new().unwrap();
+        restore(&archive, dest.path(), &RestoreOptions::default()).expect("restore");
+        dest.child("hello").assert("hello world\n");
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test checking that an archive can be backed up to.
+
+use std::io;
+use std::path::Path;
+
+use conserve::test_fixtures::{ScratchArchive, TestTransport, TransportFailure};
+use conserve::*;
+
+fn open_failing(path: &Path, failure: TransportFailure) -> Archive {
+    Archive::open(Box::new(TestTransport::new(path).failing(failure))).unwrap()
+}
+
+/// Names of the files in the top of the archive directory.
+fn top_level_names(path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn preflight_writable_archive() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let names_before = top_level_names(af.path());
+
+    let report = preflight(&af).unwrap();
+    assert!(report.last_backup_bytes > 0);
+    assert!(report.estimated_archive_bytes >= report.last_backup_bytes);
+    if cfg!(unix) {
+        assert!(report.capacity.unwrap().available > 0);
+    }
+    assert_eq!(top_level_names(af.path()), names_before);
+}
+
+#[test]
+fn preflight_fails_when_storage_is_full() {
+    let af = ScratchArchive::new();
+    let names_before = top_level_names(af.path());
+    let archive = open_failing(
+        af.path(),
+        TransportFailure::Write(io::ErrorKind::StorageFull),
+    );
+
+    match preflight(&archive) {
+        Err(Error::PreflightProbe { source, .. }) => {
+            assert_eq!(source.kind(), io::ErrorKind::StorageFull)
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(top_level_names(af.path()), names_before);
+}
+
+#[test]
+fn preflight_deletes_probe_after_read_failure() {
+    let af = ScratchArchive::new();
+    let names_before = top_level_names(af.path());
+    let archive = open_failing(af.path(), TransportFailure::Read("tmp-conserve-preflight-"));
+
+    assert!(matches!(
+        preflight(&archive),
+        Err(Error::PreflightProbe { .. })
+    ));
+    assert_eq!(top_level_names(af.path()), names_before);
+}
+
+#[test]
+fn preflight_fails_without_space_for_another_backup() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let archive = open_failing(af.path(), TransportFailure::Available(1));
+
+    assert!(matches!(
+        preflight(&archive),
+        Err(Error::InsufficientSpace { available: 1, .. })
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn preflight_read_only_archive() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let af = ScratchArchive::new();
+    let set_mode =
+        |mode| std::fs::set_permissions(af.path(), std::fs::Permissions::from_mode(mode)).unwrap();
+    set_mode(0o555);
+    // Permissions don't stop root, so the probe is only refused for others.
+    let writable = std::fs::write(af.path().join("probe"), b"").is_ok();
+    let result = preflight(&af);
+    set_mode(0o755);
+    if writable {
+        return;
+    }
+    match result {
+        Err(Error::PreflightProbe { source, .. }) => {
+            assert_eq!(source.kind(), io::ErrorKind::PermissionDenied)
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
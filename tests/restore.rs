@@ -15,6 +15,7 @@
 #[cfg(unix)]
 use std::fs::{read_link, symlink_metadata};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 use filetime::{set_symlink_file_times, FileTime};
 use tempfile::TempDir;
@@ -35,6 +36,21 @@ fn simple_restore() {
     let stats = restore(&restore_archive, &destdir.path(), &options).expect("restore");
 
     assert_eq!(stats.files, 3);
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.empty_files, 0);
+    assert_eq!(stats.uncompressed_bytes, 24);
+    assert_eq!(stats.errors, 0);
+    let summary = stats.summary();
+    assert!(
+        summary.contains("           3      files:\n"),
+        "{}",
+        summary
+    );
+    assert!(
+        summary.contains("           24 B   file content copied\n"),
+        "{}",
+        summary
+    );
 
     let dest = &destdir.path();
     assert!(dest.join("hello").is_file());
@@ -65,6 +81,42 @@ fn restore_specified_band() {
     assert_eq!(stats.files, 2);
 }
 
+#[test]
+fn restore_nonexistent_band() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    let options = RestoreOptions {
+        band_selection: BandSelectionPolicy::Specified(BandId::new(&[12])),
+        ..RestoreOptions::default()
+    };
+    match restore(&af, destdir.path(), &options) {
+        Err(Error::BandNotFound { band_id, available }) => {
+            assert_eq!(band_id, BandId::new(&[12]));
+            assert_eq!(available, [BandId::new(&[0]), BandId::new(&[1])]);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn restore_with_only_an_incomplete_band() {
+    let af = ScratchArchive::new();
+    af.setup_incomplete_empty_band();
+    let destdir = TreeFixture::new();
+    match restore(&af, destdir.path(), &RestoreOptions::default()) {
+        Err(Error::NoCompleteBands { newest }) => assert_eq!(newest, BandId::zero()),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let options = RestoreOptions {
+        band_selection: BandSelectionPolicy::Latest,
+        ..RestoreOptions::default()
+    };
+    let stats = restore(&af, destdir.path(), &options).expect("restore");
+    assert_eq!(stats.files, 0);
+}
+
 #[test]
 pub fn decline_to_overwrite() {
     let af = ScratchArchive::new();
@@ -104,7 +156,7 @@ fn exclude_files() {
     let restore_archive = Archive::open_path(af.path()).unwrap();
     let options = RestoreOptions {
         overwrite: true,
-        excludes: excludes::from_strings(&["/**/subfile"]).unwrap(),
+        filter: excludes::from_strings(&["/**/subfile"]).unwrap().into(),
         ..RestoreOptions::default()
     };
     let stats = restore(&restore_archive, &destdir.path(), &options).expect("restore");
@@ -116,6 +168,52 @@ fn exclude_files() {
     assert_eq!(stats.files, 2);
 }
 
+#[test]
+fn restore_with_max_size() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/big", &[b'x'; 100]);
+    srcdir.create_file_with_contents("subdir/small", b"hello");
+    srcdir.create_file_with_contents("top", b"hi");
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+
+    let destdir = TreeFixture::new();
+    let options = RestoreOptions {
+        overwrite: true,
+        filter: EntryFilter {
+            max_size: Some(50),
+            ..EntryFilter::default()
+        },
+        ..RestoreOptions::default()
+    };
+    let stats = restore(&af, destdir.path(), &options).expect("restore");
+
+    let dest = destdir.path();
+    assert!(!dest.join("subdir/big").exists());
+    assert!(dest.join("subdir/small").is_file());
+    assert!(dest.join("top").is_file());
+    assert_eq!(stats.files, 2);
+}
+
+#[test]
+fn restore_files_only_creates_parents() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    let options = RestoreOptions {
+        overwrite: true,
+        filter: EntryFilter::new().files_only(),
+        ..RestoreOptions::default()
+    };
+    let stats = restore(&af, destdir.path(), &options).expect("restore");
+
+    assert!(destdir.path().join("subdir/subfile").is_file());
+    assert!(!destdir.path().join("link").exists());
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.symlinks, 0);
+}
+
 #[test]
 #[cfg(unix)]
 fn restore_symlink() {
@@ -130,7 +228,7 @@ fn restore_symlink() {
     let mtime: FileTime = years_ago.into();
     set_symlink_file_times(&srcdir.path().join("symlink"), mtime, mtime).unwrap();
 
-    backup(&af, &srcdir.live_tree(), &Default::default()).unwrap();
+    af.backup(srcdir.path(), &Default::default()).unwrap();
 
     let restore_dir = TempDir::new().unwrap();
     restore(&af, &restore_dir.path(), &Default::default()).unwrap();
@@ -144,3 +242,488 @@ fn restore_symlink() {
         PathBuf::from("target")
     );
 }
+
+#[test]
+#[cfg(unix)]
+fn restore_symlink_mtime_without_touching_target() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let target_mtime = UnixTime {
+        secs: 1_000_000_000,
+        nanosecs: 0,
+    };
+    let link_mtime = UnixTime {
+        secs: 189216000,
+        nanosecs: 0,
+    };
+    let target_path = srcdir.create_file("target");
+    filetime::set_file_mtime(&target_path, target_mtime.into()).unwrap();
+    srcdir.create_symlink("link", "target");
+    let link_filetime: FileTime = link_mtime.into();
+    set_symlink_file_times(&srcdir.path().join("link"), link_filetime, link_filetime).unwrap();
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+
+    let stored: Vec<IndexEntry> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_filtered(None, None)
+        .unwrap()
+        .collect();
+    let stored_link = stored.iter().find(|e| e.apath() == "/link").unwrap();
+    assert_eq!(stored_link.mtime(), link_mtime);
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(&af, restore_dir.path(), &RestoreOptions::default()).unwrap();
+    let link_meta = symlink_metadata(restore_dir.path().join("link")).unwrap();
+    assert!(link_meta.file_type().is_symlink());
+    assert_eq!(UnixTime::from(link_meta.modified().unwrap()), link_mtime);
+    let target_meta = std::fs::metadata(restore_dir.path().join("link")).unwrap();
+    assert_eq!(
+        UnixTime::from(target_meta.modified().unwrap()),
+        target_mtime
+    );
+}
+
+#[test]
+#[cfg(windows)]
+fn restore_beyond_max_path() {
+    let srcdir = TreeFixture::new();
+    let deep = vec!["a_fairly_long_directory_name_to_take_up_room"; 8].join("/");
+    std::fs::create_dir_all(srcdir.path().join(&deep)).unwrap();
+    let af = ScratchArchive::new();
+    af.backup(srcdir.path(), &BackupOptions::default())
+        .expect("backup");
+
+    let destdir = TreeFixture::new();
+    let stats = restore(&af, destdir.path(), &RestoreOptions::default()).expect("restore");
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.directories, 9);
+}
+
+#[test]
+fn invalid_apaths_are_not_restored() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("ok", b"");
+    af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+    // Replace the index with a hand-crafted hunk whose apaths try to escape
+    // the destination, and no manifest.
+    let hunk_json = r#"[
+        {"apath": "/", "kind": "Dir", "mtime": 0},
+        {"apath": "/ok", "kind": "File", "mtime": 0},
+        {"apath": "/../escaped", "kind": "File", "mtime": 0},
+        {"apath": "/../../escaped_dir", "kind": "Dir", "mtime": 0}
+    ]"#;
+    let compressed = snap::raw::Encoder::new()
+        .compress_vec(hunk_json.as_bytes())
+        .unwrap();
+    std::fs::write(af.path().join("b0000/i/00000/000000000"), compressed).unwrap();
+    std::fs::remove_file(af.path().join("b0000/i/MANIFEST")).unwrap();
+
+    let parent = TempDir::new().unwrap();
+    std::fs::create_dir(parent.path().join("x")).unwrap();
+    let destdir = parent.path().join("x").join("dest");
+    let stats = restore(&af, &destdir, &RestoreOptions::default()).unwrap();
+    assert_eq!(stats.errors, 2);
+    let problem_apaths: Vec<String> = stats
+        .problems
+        .iter()
+        .map(|p| p.apath.as_ref().unwrap().to_string())
+        .collect();
+    assert_eq!(problem_apaths, ["/../escaped", "/../../escaped_dir"]);
+    assert!(stats
+        .problems
+        .iter()
+        .all(|p| p.category == ErrorCategory::PerEntry));
+    assert!(destdir.join("ok").is_file());
+    assert!(!parent.path().join("x").join("escaped").exists());
+    assert!(!parent.path().join("escaped_dir").exists());
+
+    let validate_stats = af.validate(&ValidateOptions::default()).unwrap();
+    assert_eq!(validate_stats.invalid_apath_count, 2);
+    assert_eq!(validate_stats.problems.len(), 2);
+    assert!(validate_stats.has_problems());
+}
+
+#[test]
+fn symlink_without_target_is_reported_to_problem_sink() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("ok", b"");
+    af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+    // Replace the index with a hand-crafted hunk holding a symlink that has
+    // no target.
+    let hunk_json = r#"[
+        {"apath": "/", "kind": "Dir", "mtime": 0},
+        {"apath": "/bad-link", "kind": "Symlink", "mtime": 0},
+        {"apath": "/ok", "kind": "File", "mtime": 0}
+    ]"#;
+    let compressed = snap::raw::Encoder::new()
+        .compress_vec(hunk_json.as_bytes())
+        .unwrap();
+    std::fs::write(af.path().join("b0000/i/00000/000000000"), compressed).unwrap();
+    std::fs::remove_file(af.path().join("b0000/i/MANIFEST")).unwrap();
+
+    let problems = Arc::new(Mutex::new(Problems::new()));
+    let destdir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        problems: Some(problems.clone()),
+        ..RestoreOptions::default()
+    };
+    let stats = restore(&af, destdir.path(), &options).unwrap();
+    assert!(stats.problems.is_empty());
+    assert!(destdir.path().join("ok").is_file());
+
+    let problems = problems.lock().unwrap();
+    assert_eq!(problems.len(), 1);
+    let problem = problems.iter().next().unwrap();
+    assert_eq!(problem.kind, ProblemKind::UnrestorableSymlink);
+    assert_eq!(problem.apath, Some(Apath::from("/bad-link")));
+}
+
+#[cfg(unix)]
+#[test]
+fn entries_under_restored_symlink_are_not_restored() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("ok", b"");
+    af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+    // Replace the index with a hand-crafted hunk that restores a symlink to a
+    // directory outside the destination, and then entries through it.
+    let outside = TreeFixture::new();
+    let hunk_json = format!(
+        r#"[
+        {{"apath": "/", "kind": "Dir", "mtime": 0}},
+        {{"apath": "/a", "kind": "Symlink", "mtime": 0, "target": {:?}}},
+        {{"apath": "/ok", "kind": "File", "mtime": 0}},
+        {{"apath": "/a/passwd", "kind": "File", "mtime": 0}},
+        {{"apath": "/a/sub", "kind": "Dir", "mtime": 0}}
+    ]"#,
+        outside.path()
+    );
+    let compressed = snap::raw::Encoder::new()
+        .compress_vec(hunk_json.as_bytes())
+        .unwrap();
+    std::fs::write(af.path().join("b0000/i/00000/000000000"), compressed).unwrap();
+    std::fs::remove_file(af.path().join("b0000/i/MANIFEST")).unwrap();
+
+    let destdir = TempDir::new().unwrap();
+    let stats = restore(&af, destdir.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(stats.errors, 2);
+    let problem_apaths: Vec<String> = stats
+        .problems
+        .iter()
+        .map(|p| p.apath.as_ref().unwrap().to_string())
+        .collect();
+    assert_eq!(problem_apaths, ["/a/passwd", "/a/sub"]);
+    assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+    assert!(symlink_metadata(destdir.path().join("a"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(destdir.path().join("ok").is_file());
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_directory_mtime_and_mode_after_children() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("locked");
+    srcdir.create_file("locked/file");
+    let dir = srcdir.path().join("locked");
+    let old_mtime = FileTime::from_unix_time(1_000_000_000, 0);
+    fs::set_permissions(dir.join("file"), fs::Permissions::from_mode(0o640)).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+    filetime::set_file_mtime(&dir, old_mtime).unwrap();
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let destdir = TreeFixture::new();
+    let stats = restore(&af, destdir.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.directory_metadata_errors, 0);
+
+    let restored = destdir.path().join("locked");
+    let metadata = fs::metadata(&restored).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o555);
+    assert_eq!(FileTime::from_last_modification_time(&metadata), old_mtime);
+    assert_eq!(fs::read(restored.join("file")).unwrap(), b"contents");
+    assert_eq!(
+        fs::metadata(restored.join("file"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777,
+        0o640
+    );
+    // Allow the tree to be cleaned up.
+    fs::set_permissions(&restored, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+fn overwrite_options(replace_dirs: bool) -> RestoreOptions {
+    RestoreOptions {
+        overwrite: true,
+        replace_dirs,
+        ..RestoreOptions::default()
+    }
+}
+
+#[test]
+fn file_replaces_directory_only_if_enabled() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    destdir.create_dir("hello");
+    destdir.create_file("hello/inner");
+
+    let stats = restore(&af, destdir.path(), &overwrite_options(false)).expect("restore");
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.replaced_entries, 0);
+    assert!(matches!(
+        stats.problems.iter().next().unwrap().category,
+        ErrorCategory::PerEntry
+    ));
+    assert!(destdir.path().join("hello/inner").is_file());
+
+    let stats = restore(&af, destdir.path(), &overwrite_options(true)).expect("restore");
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.replaced_entries, 1);
+    assert!(destdir.path().join("hello").is_file());
+}
+
+#[test]
+fn directory_replaces_file() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    destdir.create_file("subdir");
+
+    let stats = restore(&af, destdir.path(), &overwrite_options(false)).expect("restore");
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.replaced_entries, 1);
+    assert!(destdir.path().join("subdir/subfile").is_file());
+}
+
+#[cfg(unix)]
+#[test]
+fn file_replaces_symlink_without_following_it() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let outside = TreeFixture::new();
+    let outside_file = outside.create_file_with_contents("precious", b"untouched");
+    let destdir = TreeFixture::new();
+    std::os::unix::fs::symlink(&outside_file, destdir.path().join("hello")).unwrap();
+
+    let stats = restore(&af, destdir.path(), &overwrite_options(false)).expect("restore");
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.replaced_entries, 1);
+    assert!(symlink_metadata(destdir.path().join("hello"))
+        .unwrap()
+        .file_type()
+        .is_file());
+    assert_eq!(std::fs::read(&outside_file).unwrap(), b"untouched");
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_replaces_file() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    destdir.create_file("link");
+
+    let stats = restore(&af, destdir.path(), &overwrite_options(false)).expect("restore");
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.replaced_entries, 1);
+    assert_eq!(
+        read_link(destdir.path().join("link")).unwrap(),
+        PathBuf::from("target")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn secure_overwrite_does_not_write_through_symlinked_directory() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let outside = TreeFixture::new();
+    let destdir = TreeFixture::new();
+    std::os::unix::fs::symlink(outside.path(), destdir.path().join("subdir")).unwrap();
+
+    let options = RestoreOptions {
+        overwrite: true,
+        secure_overwrite: true,
+        ..RestoreOptions::default()
+    };
+    let stats = restore(&af, destdir.path(), &options).expect("restore");
+
+    assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 0);
+    assert!(symlink_metadata(destdir.path().join("subdir"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(destdir.path().join("hello").is_file());
+    // Both the directory and the file inside it are refused.
+    assert_eq!(stats.errors, 2);
+    assert!(stats
+        .problems
+        .iter()
+        .all(|problem| problem.message.contains("Not restoring through symlink")));
+}
+
+#[test]
+fn cancelled_restore_stops_before_next_entry() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    let options = RestoreOptions::default();
+    options.cancel.cancel();
+
+    match restore(&af, destdir.path(), &options) {
+        Err(Error::RestoreCancelled { stats }) => {
+            assert_eq!(stats.files, 0);
+            assert_eq!(stats.directories, 0);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(std::fs::read_dir(destdir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn restore_leaves_no_temporary_files() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+
+    restore(&af, destdir.path(), &RestoreOptions::default()).expect("restore");
+
+    for entry in walkdir::WalkDir::new(destdir.path()) {
+        let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+        assert!(!name.starts_with(".conserve-tmp"), "{:?} left behind", name);
+    }
+    assert_eq!(
+        std::fs::read_to_string(destdir.path().join("hello")).unwrap(),
+        "contents"
+    );
+}
+
+#[test]
+fn restore_long_names_and_names_like_temporary_files() {
+    let long_name = "l".repeat(250);
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents(&long_name, b"long");
+    srcdir.create_file_with_contents("foo", b"foo");
+    srcdir.create_file_with_contents(".foo.conserve-tmp", b"not temporary");
+    let af = ScratchArchive::new();
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+
+    for &secure_overwrite in &[false, true] {
+        let destdir = TreeFixture::new();
+        let options = RestoreOptions {
+            secure_overwrite,
+            ..RestoreOptions::default()
+        };
+        let stats = restore(&af, destdir.path(), &options).expect("restore");
+        assert_eq!(stats.errors, 0);
+        let read = |name: &str| std::fs::read_to_string(destdir.path().join(name)).unwrap();
+        assert_eq!(read(&long_name), "long");
+        assert_eq!(read("foo"), "foo");
+        assert_eq!(read(".foo.conserve-tmp"), "not temporary");
+        assert_eq!(std::fs::read_dir(destdir.path()).unwrap().count(), 3);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn restore_names_and_symlink_targets_that_are_not_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let dir_name = OsStr::from_bytes(b"caf\xe9");
+    let file_name = OsStr::from_bytes(b"\xff\xfe.txt");
+    // A name that really contains one of the escape characters.
+    let pua_name = OsStr::new("caf\u{F7E9}");
+    let target = OsStr::from_bytes(b"caf\xe9/\xff\xfe.txt");
+    std::fs::create_dir(srcdir.path().join(dir_name)).unwrap();
+    std::fs::write(srcdir.path().join(dir_name).join(file_name), b"latin-1").unwrap();
+    std::fs::write(srcdir.path().join(pua_name), b"private use").unwrap();
+    std::os::unix::fs::symlink(target, srcdir.path().join("link")).unwrap();
+
+    let backup_stats = af.backup(srcdir.path(), &Default::default()).unwrap();
+    assert_eq!(backup_stats.errors, 0);
+
+    let restore_dir = TempDir::new().unwrap();
+    let stats = restore(&af, restore_dir.path(), &Default::default()).unwrap();
+    assert_eq!(stats.renamed_entries, 0);
+
+    let mut top_names: Vec<Vec<u8>> = std::fs::read_dir(restore_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().as_bytes().to_owned())
+        .collect();
+    top_names.sort();
+    assert_eq!(
+        top_names,
+        [
+            b"caf\xe9".to_vec(),
+            "caf\u{F7E9}".as_bytes().to_vec(),
+            b"link".to_vec()
+        ]
+    );
+    assert_eq!(
+        std::fs::read(restore_dir.path().join(dir_name).join(file_name)).unwrap(),
+        b"latin-1"
+    );
+    assert_eq!(
+        std::fs::read(restore_dir.path().join(pua_name)).unwrap(),
+        b"private use"
+    );
+    assert_eq!(
+        read_link(restore_dir.path().join("link"))
+            .unwrap()
+            .as_os_str(),
+        target
+    );
+}
+
+/// Bands written before names were escaped might hold names with the escape
+/// characters, which are restored as themselves.
+#[test]
+fn restore_unescaped_names_from_older_band() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("ok", b"");
+    af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+    let hunk_json = r#"[
+        {"apath": "/", "kind": "Dir", "mtime": 0},
+        {"apath": "/caf", "kind": "File", "mtime": 0}
+    ]"#;
+    let compressed = snap::raw::Encoder::new()
+        .compress_vec(hunk_json.as_bytes())
+        .unwrap();
+    std::fs::write(af.path().join("b0000/i/00000/000000000"), compressed).unwrap();
+    std::fs::remove_file(af.path().join("b0000/i/MANIFEST")).unwrap();
+    let head_path = af.path().join("b0000/BANDHEAD");
+    let mut head: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&head_path).unwrap()).unwrap();
+    // Older versions can't read escaped names, even from a json index.
+    assert_eq!(head["escaped_names"], true);
+    assert_eq!(head["band_format_version"], "0.6.11");
+    head.as_object_mut().unwrap().remove("escaped_names");
+    head["band_format_version"] = "0.6.3".into();
+    std::fs::write(&head_path, serde_json::to_vec(&head).unwrap()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let stats = restore(&af, restore_dir.path(), &Default::default()).unwrap();
+    assert_eq!(stats.renamed_entries, 0);
+    assert!(restore_dir.path().join("caf\u{F7E9}").is_file());
+}
@@ -0,0 +1,100 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test copying bands between archives.
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+#[test]
+fn sync_one_band_then_restore() {
+    let source = ScratchArchive::new();
+    source.store_two_versions();
+    let dest = ScratchArchive::new();
+
+    let stats = source.copy_band_to(&BandId::new(&[1]), &dest).unwrap();
+    assert_eq!(stats.bands_copied, 1);
+    assert_eq!(stats.blocks_copied, 2);
+    assert_eq!(stats.blocks_already_present, 0);
+    assert_eq!(dest.band_ids().unwrap(), vec![BandId::new(&[1])]);
+    let band_id = BandId::new(&[1]);
+    assert_eq!(
+        Band::open(&dest, &band_id).unwrap().read_stats().unwrap(),
+        Band::open(&source, &band_id).unwrap().read_stats().unwrap()
+    );
+
+    let restore_dir = TreeFixture::new();
+    let restore_stats = restore(&dest, restore_dir.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(restore_stats.files, 3);
+    assert!(restore_dir.path().join("hello2").is_file());
+    assert!(restore_dir.path().join("subdir/subfile").is_file());
+
+    // The earlier band shares its blocks, so adding it copies no more blocks.
+    let stats = sync(&source, &dest, &SyncOptions::default()).unwrap();
+    assert_eq!(stats.bands_copied, 1);
+    assert_eq!(stats.bands_already_present, 1);
+    assert_eq!(stats.blocks_copied, 0);
+    assert_eq!(
+        dest.band_ids().unwrap(),
+        vec![BandId::new(&[0]), BandId::new(&[1])]
+    );
+}
+
+#[test]
+fn resync_is_nearly_noop() {
+    let source = ScratchArchive::new();
+    source.store_two_versions();
+    let dest = ScratchArchive::new();
+
+    sync(&source, &dest, &SyncOptions::default()).unwrap();
+    let stats = sync(&source, &dest, &SyncOptions::default()).unwrap();
+    assert_eq!(stats.bands_copied, 0);
+    assert_eq!(stats.bands_already_present, 2);
+    assert_eq!(stats.blocks_copied, 0);
+    assert_eq!(stats.index_hunks_copied, 0);
+}
+
+#[test]
+fn backup_to_destination_after_sync() {
+    let source = ScratchArchive::new();
+    source.store_two_versions();
+    let dest = ScratchArchive::new();
+    sync(&source, &dest, &SyncOptions::default()).unwrap();
+
+    // A new backup into the destination gets the next id after the synced bands.
+    let tree = TreeFixture::new();
+    tree.create_file("new");
+    dest.backup(tree.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(dest.last_band_id().unwrap(), Some(BandId::new(&[2])));
+}
+
+#[test]
+fn sync_band_with_packed_index() {
+    let source = ScratchArchive::new();
+    let tree = TreeFixture::new();
+    for i in 0..5 {
+        tree.create_file(&format!("file{}", i));
+    }
+    let options = BackupOptions {
+        max_entries_per_hunk: 2,
+        ..BackupOptions::default()
+    }
+    .index_hunks_per_pack(Some(2));
+    source.backup(tree.path(), &options).unwrap();
+    let dest = ScratchArchive::new();
+
+    let stats = sync(&source, &dest, &SyncOptions::default()).unwrap();
+    assert_eq!(stats.index_hunks_copied, 3);
+    let restore_dir = TreeFixture::new();
+    let restore_stats = restore(&dest, restore_dir.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(restore_stats.files, 5);
+}
@@ -0,0 +1,113 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test validating selected bands of an archive.
+
+use std::path::Path;
+
+use conserve::test_fixtures::{ScratchArchive, TestTransport, TreeFixture};
+use conserve::*;
+
+fn open_recording(path: &Path) -> (Archive, TestTransport) {
+    let transport = TestTransport::new(path);
+    let archive = Archive::open(Box::new(transport.clone())).unwrap();
+    (archive, transport)
+}
+
+/// Make two backups, the first holding a file removed before the second.
+///
+/// The files are too large to be combined into one block, so each has its
+/// own. Returns the hash of the block only the first backup references.
+fn backup_removed_file(af: &ScratchArchive) -> BlockHash {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("old", &[1u8; 200_000]);
+    srcdir.create_file_with_contents("same", &[2u8; 200_000]);
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+    std::fs::remove_file(srcdir.path().join("old")).unwrap();
+    srcdir.create_file_with_contents("new", &[3u8; 200_000]);
+    af.backup(srcdir.path(), &BackupOptions::default()).unwrap();
+
+    let first = af
+        .open_stored_tree(BandSelectionPolicy::Specified(BandId::zero()))
+        .unwrap();
+    first.entry(&"/old".into()).unwrap().unwrap().addrs()[0]
+        .hash
+        .clone()
+}
+
+fn select(band_id: BandId) -> ValidateOptions {
+    ValidateOptions {
+        bands: Some(vec![band_id]),
+        ..ValidateOptions::default()
+    }
+}
+
+#[test]
+fn validate_one_band_reads_only_its_blocks() {
+    let af = ScratchArchive::new();
+    let old_hash = backup_removed_file(&af);
+    let (archive, transport) = open_recording(af.path());
+
+    let stats = archive.validate(&select(BandId::new(&[1]))).unwrap();
+    assert!(!stats.has_problems(), "{:#?}", stats);
+    assert_eq!(stats.block_read_count, 2);
+    assert_eq!(stats.unreferenced_block_count, 0);
+    let reads = transport.reads();
+    let block_reads: Vec<&String> = reads.iter().filter(|p| p.starts_with("d/")).collect();
+    assert_eq!(block_reads.len(), 2);
+    assert!(!block_reads
+        .iter()
+        .any(|path| path.ends_with(&old_hash.to_string())));
+    assert!(!reads.iter().any(|path| path.starts_with("b0000/i/")));
+}
+
+#[test]
+fn validate_one_band_ignores_damage_elsewhere() {
+    let af = ScratchArchive::new();
+    let old_hash = backup_removed_file(&af);
+    let old_hash = old_hash.to_string();
+    std::fs::remove_file(af.path().join("d").join(&old_hash[..3]).join(&old_hash)).unwrap();
+
+    assert!(!af
+        .validate(&select(BandId::new(&[1])))
+        .unwrap()
+        .has_problems());
+    let stats = af.validate(&select(BandId::zero())).unwrap();
+    assert_eq!(stats.block_missing_count, 1);
+}
+
+#[test]
+fn quick_validate_reads_no_blocks() {
+    let af = ScratchArchive::new();
+    backup_removed_file(&af);
+    let (archive, transport) = open_recording(af.path());
+
+    let stats = archive
+        .validate(&ValidateOptions {
+            quick: true,
+            ..ValidateOptions::default()
+        })
+        .unwrap();
+    assert!(!stats.has_problems(), "{:#?}", stats);
+    assert_eq!(stats.block_read_count, 0);
+    assert!(!transport.reads().iter().any(|p| p.starts_with("d/")));
+}
+
+#[test]
+fn validate_nonexistent_band() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    match af.validate(&select(BandId::new(&[5]))) {
+        Err(Error::BandNotFound { band_id, .. }) => assert_eq!(band_id, BandId::new(&[5])),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2021 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for backup and restore of extended attributes.
+//!
+//! These only run on Linux, and pass vacuously if the temporary directory's
+//! filesystem doesn't support `user.*` xattrs.
+
+#![cfg(target_os = "linux")]
+
+use std::path::Path;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// True if user xattrs can be set in this directory.
+fn user_xattrs_supported(dir: &Path) -> bool {
+    let probe = dir.join("xattr-probe");
+    std::fs::write(&probe, b"").unwrap();
+    let supported = xattr::set(&probe, "user.conserve.probe", b"1").is_ok();
+    std::fs::remove_file(&probe).unwrap();
+    if !supported {
+        eprintln!("user xattrs not supported in {:?}; skipping test", dir);
+    }
+    supported
+}
+
+fn tree_with_xattrs() -> TreeFixture {
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    tf.create_dir("subdir");
+    tf.create_file("subdir/plain");
+    xattr::set(tf.path().join("hello"), "user.comment", b"greetings").unwrap();
+    xattr::set(tf.path().join("hello"), "user.binary", &[0, 1, 255]).unwrap();
+    xattr::set(tf.path().join("subdir"), "user.color", b"blue").unwrap();
+    tf
+}
+
+#[test]
+fn backup_and_restore_user_xattrs() {
+    let af = ScratchArchive::new();
+    if !user_xattrs_supported(af.path()) {
+        return;
+    }
+    let tf = tree_with_xattrs();
+    let stats = af.backup(tf.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.unreadable_xattrs, 0);
+
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let entries: Vec<IndexEntry> = st
+        .iter_entries(None, &Exclude::nothing())
+        .map(Result::unwrap)
+        .collect();
+    let hello = entries
+        .iter()
+        .find(|e| e.apath == Apath::from("/hello"))
+        .unwrap();
+    assert_eq!(hello.xattrs["user.comment"], b"greetings");
+    let plain = entries
+        .iter()
+        .find(|e| e.apath == Apath::from("/subdir/plain"))
+        .unwrap();
+    assert!(plain.xattrs.is_empty());
+
+    let dest = TreeFixture::new();
+    let stats = restore(&af, dest.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(stats.warnings, 0);
+    let hello_path = dest.path().join("hello");
+    assert_eq!(
+        xattr::get(&hello_path, "user.comment").unwrap().unwrap(),
+        b"greetings"
+    );
+    assert_eq!(
+        xattr::get(&hello_path, "user.binary").unwrap().unwrap(),
+        [0, 1, 255]
+    );
+    assert_eq!(
+        xattr::get(dest.path().join("subdir"), "user.color")
+            .unwrap()
+            .unwrap(),
+        b"blue"
+    );
+    assert_eq!(
+        xattr::list(dest.path().join("subdir/plain"))
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn restore_without_xattrs() {
+    let af = ScratchArchive::new();
+    if !user_xattrs_supported(af.path()) {
+        return;
+    }
+    let tf = tree_with_xattrs();
+    af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+    let dest = TreeFixture::new();
+    let options = RestoreOptions {
+        restore_xattrs: false,
+        ..RestoreOptions::default()
+    };
+    restore(&af, dest.path(), &options).unwrap();
+    assert_eq!(
+        xattr::get(dest.path().join("hello"), "user.comment").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn changed_xattrs_on_unchanged_file_are_stored() {
+    let af = ScratchArchive::new();
+    if !user_xattrs_supported(af.path()) {
+        return;
+    }
+    let tf = tree_with_xattrs();
+    af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+    // Setting an xattr doesn't change the mtime, so the file is unmodified.
+    xattr::set(tf.path().join("hello"), "user.comment", b"farewell").unwrap();
+    let stats = af.backup(tf.path(), &BackupOptions::default()).unwrap();
+    assert_eq!(stats.unmodified_files, 2);
+
+    let dest = TreeFixture::new();
+    restore(&af, dest.path(), &RestoreOptions::default()).unwrap();
+    assert_eq!(
+        xattr::get(dest.path().join("hello"), "user.comment")
+            .unwrap()
+            .unwrap(),
+        b"farewell"
+    );
+}